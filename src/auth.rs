@@ -0,0 +1,106 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+/// Outcome of checking a joining user's credential.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthResult {
+    Accepted,
+    /// Rejected, with a reason shown to the client
+    Rejected(String),
+}
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = AuthResult> + Send + 'a>>;
+
+/// Decides whether a client may join. The server calls it once per
+/// connection, after reading the username and, if `requires_credential`,
+/// the credential line that follows it.
+pub trait Authenticator: Debug + Send + Sync {
+    fn authenticate<'a>(&'a self, username: &'a str, credential: &'a [u8]) -> AuthFuture<'a>;
+
+    /// Whether clients must send a credential line after their username.
+    fn requires_credential(&self) -> bool {
+        true
+    }
+}
+
+/// Lets everyone in; the default.
+#[derive(Debug)]
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn authenticate<'a>(&'a self, _username: &'a str, _credential: &'a [u8]) -> AuthFuture<'a> {
+        Box::pin(async { AuthResult::Accepted })
+    }
+
+    fn requires_credential(&self) -> bool {
+        false
+    }
+}
+
+/// One password shared by every user.
+pub struct StaticPassword {
+    password: String,
+}
+
+impl StaticPassword {
+    pub fn new(password: String) -> Self {
+        StaticPassword { password }
+    }
+}
+
+impl Debug for StaticPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StaticPassword")
+    }
+}
+
+impl Authenticator for StaticPassword {
+    fn authenticate<'a>(&'a self, _username: &'a str, credential: &'a [u8]) -> AuthFuture<'a> {
+        Box::pin(async move {
+            if constant_time_eq(self.password.as_bytes(), credential) {
+                AuthResult::Accepted
+            } else {
+                AuthResult::Rejected("wrong password".to_string())
+            }
+        })
+    }
+}
+
+/// Per-user tokens read from a file of `username token` lines. The file is
+/// read on every join, so tokens can be rotated without a restart.
+#[derive(Debug)]
+pub struct TokenFile {
+    path: PathBuf,
+}
+
+impl TokenFile {
+    pub fn new(path: PathBuf) -> Self {
+        TokenFile { path }
+    }
+}
+
+impl Authenticator for TokenFile {
+    fn authenticate<'a>(&'a self, username: &'a str, credential: &'a [u8]) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let Ok(contents) = tokio::fs::read_to_string(&self.path).await else {
+                return AuthResult::Rejected("token file unavailable".to_string());
+            };
+            let accepted = contents
+                .lines()
+                .filter_map(|line| line.split_once(char::is_whitespace))
+                .any(|(name, token)| name == username && constant_time_eq(token.trim().as_bytes(), credential));
+            if accepted {
+                AuthResult::Accepted
+            } else {
+                AuthResult::Rejected("invalid token".to_string())
+            }
+        })
+    }
+}
+
+/// Compares secrets without returning early on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,154 @@
+use crate::message::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Append-only record of everything the server broadcasts, one JSON
+/// message per line, so the conversation survives a restart.
+pub struct ChatLog {
+    file: File,
+}
+
+/// A line of the log: the message, plus the room it went to unless it went
+/// to everyone. Lines from before rooms have no room.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room: Option<String>,
+    #[serde(flatten)]
+    message: Message,
+}
+
+impl ChatLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        // A line cut short by a crash mustn't swallow the next message
+        if file.seek(SeekFrom::End(-1)).is_ok() {
+            let mut last = [0u8];
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                writeln!(file)?;
+            }
+        }
+        Ok(ChatLog { file })
+    }
+
+    /// Writes `message`, sent to `room` or to everyone, leaving out file
+    /// contents so the log stays readable; the filename and size are kept.
+    pub fn append(&mut self, room: Option<&str>, message: &Message) -> io::Result<()> {
+        let mut message = message.clone();
+        if let Message::File { data, .. } = &mut message {
+            data.clear();
+        }
+        let entry = Entry { room: room.map(str::to_string), message };
+        let json = serde_json::to_string(&entry).map_err(io::Error::other)?;
+        writeln!(self.file, "{}", json)
+    }
+}
+
+/// The last `count` messages of each room in the log at `path` that `keep`
+/// accepts, as JSON, and how many lines couldn't be read. Messages sent to
+/// everyone count as `default_room`'s. A missing log is empty; unreadable
+/// lines, such as one cut short by a crash, are skipped.
+pub fn read_tail(
+    path: &Path,
+    count: usize,
+    default_room: &str,
+    keep: impl Fn(&Message) -> bool,
+) -> io::Result<(HashMap<String, VecDeque<String>>, usize)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((HashMap::new(), 0)),
+        Err(e) => return Err(e),
+    };
+    let mut tails: HashMap<String, VecDeque<String>> = HashMap::new();
+    let mut skipped = 0;
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        let entry = std::str::from_utf8(&line).ok().and_then(|line| serde_json::from_str::<Entry>(line.trim()).ok());
+        match entry {
+            Some(Entry { room, message }) if keep(&message) && count > 0 => {
+                let tail = tails.entry(room.unwrap_or_else(|| default_room.to_string())).or_default();
+                if tail.len() == count {
+                    tail.pop_front();
+                }
+                tail.push_back(message.to_json().map_err(io::Error::other)?);
+            }
+            Some(_) => {}
+            None if line.iter().all(u8::is_ascii_whitespace) => {}
+            None => skipped += 1,
+        }
+    }
+    Ok((tails, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_is_kept_per_room_and_old_lines_count_as_the_default_room() {
+        let path = std::env::temp_dir().join(format!("terminal-chat-log-tests-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let text = |content: &str| Message::new_text("ana".to_string(), content.to_string(), None, None);
+        // Written before rooms existed
+        fs::write(&path, format!("{}\n", text("old").to_json().unwrap())).unwrap();
+
+        let mut log = ChatLog::open(&path).unwrap();
+        log.append(Some("#rust"), &text("one")).unwrap();
+        log.append(None, &text("everyone")).unwrap();
+        log.append(Some("#rust"), &text("two")).unwrap();
+        log.append(Some("#rust"), &text("three")).unwrap();
+
+        let (tails, skipped) = read_tail(&path, 2, "#general", |_| true).unwrap();
+        let contents = |room: &str| -> Vec<String> {
+            tails[room]
+                .iter()
+                .map(|json| match Message::from_json(json).unwrap() {
+                    Message::Text { content, .. } => content,
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        assert_eq!(skipped, 0);
+        assert_eq!(contents("#general"), ["old", "everyone"]);
+        assert_eq!(contents("#rust"), ["two", "three"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn messages_reload_without_file_contents_past_a_torn_line() {
+        let path = std::env::temp_dir().join(format!("terminal-chat-log-reload-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(read_tail(&path, 10, "#general", |_| true).unwrap().0.is_empty());
+
+        let mut log = ChatLog::open(&path).unwrap();
+        log.append(None, &Message::new_text("ana".to_string(), "before".to_string(), None, None)).unwrap();
+        log.append(None, &Message::new_file("ana".to_string(), "notes.txt".to_string(), 5, b"notes".to_vec(), None)).unwrap();
+        drop(log);
+        // A crash mid-write leaves half a line, which the next open ends
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"type\":\"Te").unwrap();
+        let mut log = ChatLog::open(&path).unwrap();
+        log.append(None, &Message::new_text("bob".to_string(), "after".to_string(), None, None)).unwrap();
+
+        let (tails, skipped) = read_tail(&path, 10, "#general", |_| true).unwrap();
+        assert_eq!(skipped, 1);
+        let messages: Vec<Message> = tails["#general"].iter().map(|json| Message::from_json(json).unwrap()).collect();
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(&messages[0], Message::Text { content, .. } if content == "before"));
+        match &messages[1] {
+            Message::File { filename, size, data, .. } => {
+                assert_eq!((filename.as_str(), *size), ("notes.txt", 5));
+                assert!(data.is_empty());
+            }
+            other => panic!("expected the file, got {:?}", other),
+        }
+        assert!(matches!(&messages[2], Message::Text { username, content, .. } if username == "bob" && content == "after"));
+        let _ = fs::remove_file(&path);
+    }
+}
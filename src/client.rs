@@ -1,53 +1,286 @@
-use crate::message::Message;
-use crate::ui::ChatUI;
+use crate::file_transfer::{FileStream, Reassembler, TRANSFER_TIMEOUT};
+use crate::framing::{self, Frame, Framing, FRAME_OVERHEAD, MAX_FRAME};
+use crate::identity::Identity;
+use crate::message::{Message, RejectReason};
+use crate::netsim::NetSim;
+use crate::tls::{self, StreamReader};
+use crate::ui::{ChatUI, UiConfig};
+use std::collections::VecDeque;
 use std::error::Error;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls;
+
+/// How long connecting and the join handshake may take before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Details of the connection captured when it was opened, for `/conn`.
+pub struct ConnectionInfo {
+    /// The address as given by the user
+    pub server: String,
+    pub address: String,
+    pub port: u16,
+    pub peer: Option<SocketAddr>,
+    pub local: Option<SocketAddr>,
+    pub connected_at: SystemTime,
+    /// Whether the connection is encrypted with TLS
+    pub tls: bool,
+}
+
+/// A live session with a server. Dropping it closes the connection and stops
+/// relaying the server's messages to the UI.
+pub struct Connection {
+    pub info: ConnectionInfo,
+    sender: mpsc::UnboundedSender<Message>,
+    uploads: mpsc::UnboundedSender<FileStream>,
+    reader: JoinHandle<()>,
+}
+
+impl Connection {
+    /// Queues a message for the server, returning false once the connection
+    /// has failed.
+    pub fn send(&self, msg: Message) -> bool {
+        self.sender.send(msg).is_ok()
+    }
+
+    /// Queues a chunked file upload. Chunks are read from disk only as they
+    /// are written, between any other messages.
+    pub fn send_file(&self, stream: FileStream) -> bool {
+        self.uploads.send(stream).is_ok()
+    }
+
+    /// Whether the server has closed the connection or it has dropped.
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_finished()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The writer task ends, shutting down our side, when `sender` drops
+        self.reader.abort();
+    }
+}
+
+/// The server turned us away during the join.
+#[derive(Debug)]
+pub struct Rejection {
+    pub reason: RejectReason,
+    pub detail: String,
+}
+
+impl Rejection {
+    /// Process exit status for this rejection, distinct per reason so
+    /// scripts can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self.reason {
+            RejectReason::Full => 3,
+            RejectReason::AuthFailed => 4,
+            RejectReason::InvalidUsername => 5,
+            RejectReason::Other => 6,
+            RejectReason::UsernameTaken => 7,
+            RejectReason::Replaced => 8,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            RejectReason::Full => write!(f, "The server is full: {}", self.detail),
+            RejectReason::AuthFailed => write!(f, "Authentication failed: {}", self.detail),
+            RejectReason::InvalidUsername => write!(f, "Username not accepted: {}", self.detail),
+            RejectReason::UsernameTaken => write!(f, "Username taken: {}", self.detail),
+            RejectReason::Replaced => write!(f, "Replaced by another session: {}", self.detail),
+            RejectReason::Other => write!(f, "The server refused the connection: {}", self.detail),
+        }
+    }
+}
+
+impl Error for Rejection {}
 
 pub async fn start_client(
     address: &str,
     port: u16,
     username: &str,
+    credential: Option<&str>,
+    config: UiConfig,
 ) -> Result<(), Box<dyn Error>> {
+    let netsim = config.netsim;
+    let identity = config.identity.clone();
+    let max_file_size = config.max_file_size;
+    let tls = config.tls.clone();
+    let framing = config.framing;
+    let mut ui = ChatUI::new(username.to_string(), credential.map(str::to_string), config)?;
+    let connection = connect(address, port, username, credential, ui.get_sender(), netsim, identity, max_file_size, tls, framing).await?;
+    ui.set_connection(connection);
+
+    // Run the UI
+    ui.run().await?;
+
+    Ok(())
+}
+
+/// Joins the server at `address:port` as `username`, forwarding everything it
+/// sends to `ui_tx`. Incoming files over `max_file_size` bytes are refused,
+/// and frames too large to hold one are skipped rather than buffered.
+/// `framing` must match what the server accepts.
+#[allow(clippy::too_many_arguments)]
+pub async fn connect(
+    address: &str,
+    port: u16,
+    username: &str,
+    credential: Option<&str>,
+    ui_tx: mpsc::UnboundedSender<Message>,
+    netsim: Option<NetSim>,
+    identity: Option<Arc<Identity>>,
+    max_file_size: u64,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    framing: Framing,
+) -> Result<Connection, Box<dyn Error>> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, open(address, port, username, credential, ui_tx, netsim, identity, max_file_size, tls, framing)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}s", CONNECT_TIMEOUT.as_secs()).into()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn open(
+    address: &str,
+    port: u16,
+    username: &str,
+    credential: Option<&str>,
+    ui_tx: mpsc::UnboundedSender<Message>,
+    netsim: Option<NetSim>,
+    identity: Option<Arc<Identity>>,
+    max_file_size: u64,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    framing: Framing,
+) -> Result<Connection, Box<dyn Error>> {
     let stream = TcpStream::connect(format!("{}:{}", address, port)).await?;
-    
-    // Split the stream for reading and writing
-    let (reader, mut writer) = stream.into_split();
-    
-    // Send username as first message
-    writer.write_all(format!("{}\n", username).as_bytes()).await?;
+    let info = ConnectionInfo {
+        server: format!("{}:{}", address, port),
+        address: address.to_string(),
+        port,
+        peer: stream.peer_addr().ok(),
+        local: stream.local_addr().ok(),
+        connected_at: SystemTime::now(),
+        tls: tls.is_some(),
+    };
 
-    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
-    let mut ui = ChatUI::new(username.to_string(), tx)?;
+    // Split the stream for reading and writing, after the handshake if encrypted
+    let (reader, mut writer, binding) = match tls {
+        Some(tls_config) => tls::connect(tls_config, address, stream)
+            .await
+            .map_err(|e| format!("TLS handshake with {}:{} failed: {}", address, port, e))?,
+        None => tls::split_plain(stream),
+    };
+
+    // Send username as first message
+    framing::write_frame(&mut writer, framing, username).await?;
+    writer.flush().await?;
 
     // Create a buffered reader
     let mut reader = BufReader::new(reader);
 
+    // A server that wants a credential asks before anything else; only then
+    // is it sent, so it can never end up posted as chat
+    let mut first_msg = read_join_reply(&mut reader, framing).await?;
+    if let Some(Message::AuthRequired { .. }) = first_msg {
+        framing::write_frame(&mut writer, framing, credential.unwrap_or("")).await?;
+        writer.flush().await?;
+        first_msg = read_join_reply(&mut reader, framing).await?;
+    }
+    if let Some(Message::Rejected { reason, detail, .. }) = first_msg {
+        return Err(Box::new(Rejection { reason, detail }));
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    let (uploads_tx, mut uploads_rx) = mpsc::unbounded_channel::<FileStream>();
+    let max_file_size = match &first_msg {
+        Some(Message::Welcome { server: Some(server), .. }) => server.max_file_size.min(max_file_size),
+        _ => max_file_size,
+    };
+
+    // Announce our public key to servers that pass identities on
+    if let (Some(Message::Welcome { username, server: Some(server), .. }), Some(identity)) = (&first_msg, &identity) {
+        if server.features.iter().any(|feature| feature == "identity") {
+            let _ = tx.send(Message::new_identity(username.clone(), identity.public_key()));
+        }
+    }
+
     // Handle incoming messages from server
-    let ui_tx = ui.get_sender();
-    tokio::spawn(async move {
+    if let Some(msg) = first_msg {
+        let _ = ui_tx.send(msg);
+    }
+    let mut incoming = netsim.map(|sim| sim.link(1));
+    let reply_tx = tx.clone();
+    let reader = tokio::spawn(async move {
         let mut line = String::new();
-        while reader.read_line(&mut line).await.unwrap_or(0) > 0 {
+        let mut assembler = Reassembler::new(max_file_size, TRANSFER_TIMEOUT);
+        loop {
+            let frame = match framing::read_frame(&mut reader, framing, &mut line, MAX_FRAME).await {
+                Ok(Frame::End) => break,
+                Ok(frame) => frame,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let _ = ui_tx.send(Message::new_system(format!("Dropped a message from the server: {}", e)));
+                    line.clear();
+                    continue;
+                }
+                Err(_) => break,
+            };
             let trimmed = line.trim();
-            if !trimmed.is_empty() {
-                if let Ok(msg) = Message::from_json(trimmed) {
-                    // Handle file messages and save them
-                    if let Message::File { filename, .. } = &msg {
-                        use crate::file_transfer::FileTransfer;
-                        match FileTransfer::save_file(&msg, "downloads") {
-                            Ok(saved_path) => {
-                                println!("File saved to: {}", saved_path);
+            let delivered = match &mut incoming {
+                Some(link) => link.pass().await,
+                None => true,
+            };
+            let decoded = match frame {
+                Frame::Message(message) => Some(Ok(message)),
+                _ if trimmed.is_empty() => None,
+                _ => Some(Message::from_json(trimmed)),
+            };
+            if let Some(decoded) = decoded.filter(|_| delivered) {
+                match decoded {
+                    // Answer heartbeats here so they work whatever the UI is doing
+                    Ok(Message::Ping { .. }) => {
+                        let _ = reply_tx.send(Message::new_pong());
+                    }
+                    // ...and prove the identity we announced
+                    Ok(Message::IdentityChallenge { challenge, .. }) => {
+                        if let Some(proof) = identity.as_ref().and_then(|identity| identity.prove(&challenge, binding)) {
+                            let _ = reply_tx.send(Message::new_identity_proof(proof));
+                        }
+                    }
+                    // Fetched files arrive in chunks; the UI gets them whole
+                    Ok(frame @ (Message::FileStart { .. } | Message::FileChunk { .. } | Message::FileEnd { .. })) => {
+                        for filename in assembler.expire() {
+                            let notice = format!("{} timed out before all of it arrived", filename);
+                            let _ = ui_tx.send(Message::new_system(notice));
+                        }
+                        match assembler.receive(frame) {
+                            Ok(Some(file)) => {
+                                let _ = ui_tx.send(file);
                             }
-                            Err(e) => {
-                                eprintln!("Error saving file {}: {}", filename, e);
+                            Ok(None) => {}
+                            Err(reason) => {
+                                let _ = ui_tx.send(Message::new_system(reason));
                             }
                         }
                     }
-                    let _ = ui_tx.send(msg);
-                } else {
+                    // File payloads are saved by the UI when the user downloads them
+                    Ok(msg) => {
+                        let _ = ui_tx.send(msg);
+                    }
                     // If JSON parsing fails, treat as raw text (fallback)
-                    eprintln!("Failed to parse message: {}", trimmed);
+                    Err(_) => eprintln!("Failed to parse message: {}", trimmed),
                 }
             }
             line.clear();
@@ -55,15 +288,202 @@ pub async fn start_client(
     });
 
     // Handle outgoing messages to server
+    let mut outgoing = netsim.map(|sim| sim.link(2));
     tokio::spawn(async move {
-        while let Some(text) = rx.recv().await {
-            // Send raw text instead of JSON to server
-            let _ = writer.write_all(format!("{}\n", text).as_bytes()).await;
+        let mut uploads: VecDeque<FileStream> = VecDeque::new();
+        loop {
+            let msg = if uploads.is_empty() {
+                tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    Some(stream) = uploads_rx.recv() => {
+                        uploads.push_back(stream);
+                        continue;
+                    }
+                }
+            } else {
+                // Other messages go out between chunks so an upload doesn't
+                // hold up the chat
+                match rx.try_recv() {
+                    Ok(msg) => msg,
+                    Err(TryRecvError::Disconnected) => break,
+                    Err(TryRecvError::Empty) => {
+                        while let Ok(stream) = uploads_rx.try_recv() {
+                            uploads.push_back(stream);
+                        }
+                        match uploads.front_mut().and_then(Iterator::next) {
+                            Some(Ok(frame)) => frame,
+                            // Done, or the file couldn't be read; the server
+                            // drops an unfinished transfer after a while
+                            _ => {
+                                uploads.pop_front();
+                                continue;
+                            }
+                        }
+                    }
+                }
+            };
+            if let Some(link) = &mut outgoing {
+                if !link.pass().await {
+                    continue;
+                }
+            }
+            // Every outgoing message, text or file, is one frame. Stop on a
+            // dead connection so later sends fail in the UI
+            if framing::write_message(&mut writer, framing, &msg).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
         }
     });
 
-    // Run the UI
-    ui.run().await?;
+    Ok(Connection { info, sender: tx, uploads: uploads_tx, reader })
+}
 
-    Ok(())
+/// Reads one frame of the server's side of the join, `None` if it isn't a
+/// message this build understands.
+async fn read_join_reply(reader: &mut BufReader<StreamReader>, framing: Framing) -> Result<Option<Message>, Box<dyn Error>> {
+    let mut line = String::new();
+    match framing::read_frame(reader, framing, &mut line, FRAME_OVERHEAD).await? {
+        Frame::End => Err("the server closed the connection while joining".into()),
+        Frame::Message(message) => Ok(Some(message)),
+        Frame::Text => Ok(Message::from_json(line.trim()).ok()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::StaticPassword;
+    use crate::file_transfer::FileTransfer;
+    use crate::server::tests::{start, test_config, TestClient};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn text_and_files_share_the_connection_and_arrive() {
+        let port = start(test_config()).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let (ui_tx, _ui_rx) = mpsc::unbounded_channel();
+        let connection = connect("127.0.0.1", port, "ana", None, ui_tx, None, None, 1024, None, Framing::Json).await.unwrap();
+
+        let file = Message::new_file("ana".to_string(), "whole.txt".to_string(), 5, b"whole".to_vec(), None);
+        let chunked = Message::new_file("ana".to_string(), "chunked.txt".to_string(), 7, b"chunked".to_vec(), None);
+        assert!(connection.send(Message::new_text("ana".to_string(), "hello".to_string(), None, None)));
+        assert!(connection.send(file));
+        assert!(connection.send_file(FileTransfer::stream_message(&chunked).unwrap()));
+
+        assert_eq!(bob.recv_text().await, "hello");
+        for expected in ["whole.txt", "chunked.txt"] {
+            match bob.recv_until(|msg| matches!(msg, Message::FileAvailable { .. })).await {
+                Message::FileAvailable { filename, sender, .. } => assert_eq!((filename.as_str(), sender.as_str()), (expected, "ana")),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn identity_is_kept_between_runs_and_announced_on_join() {
+        let dir = std::env::temp_dir().join(format!("terminal-chat-identity-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("identity");
+        let first = Identity::load_or_create(&path).unwrap();
+        let second = Identity::load_or_create(&path).unwrap();
+        assert_eq!(first.public_key(), second.public_key());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let port = start(test_config()).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let (ui_tx, _ui_rx) = mpsc::unbounded_channel();
+        let _ana = connect("127.0.0.1", port, "ana", None, ui_tx, None, Some(Arc::new(second)), 1024, None, Framing::Json).await.unwrap();
+        match bob.recv_until(|msg| matches!(msg, Message::Identity { .. })).await {
+            Message::Identity { username, public_key, .. } => assert_eq!((username.as_str(), public_key), ("ana", first.public_key())),
+            _ => unreachable!(),
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn chat_travels_over_a_loopback_tls_connection() {
+        let testdata = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let cert = testdata.join("localhost-cert.pem");
+        let mut config = test_config();
+        config.tls = Some(tls::server_config(&cert, &testdata.join("localhost-key.pem")).unwrap());
+        let port = start(config).await;
+
+        let (ana_tx, _ana_rx) = mpsc::unbounded_channel();
+        let ana = connect("127.0.0.1", port, "ana", None, ana_tx, None, None, 1024, Some(tls::client_config(Some(&cert)).unwrap()), Framing::Json).await.unwrap();
+        let (bob_tx, mut bob_rx) = mpsc::unbounded_channel();
+        let _bob = connect("127.0.0.1", port, "bob", None, bob_tx, None, None, 1024, Some(tls::client_config(Some(&cert)).unwrap()), Framing::Json).await.unwrap();
+
+        assert!(ana.send(Message::new_text("ana".to_string(), "over tls".to_string(), None, None)));
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(Message::Text { username, content, .. }) = bob_rx.recv().await {
+                    return (username, content);
+                }
+            }
+        });
+        assert_eq!(received.await.unwrap(), ("ana".to_string(), "over tls".to_string()));
+
+        // A plaintext client can't get in
+        let mut plain = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        plain.write_all(b"eve\n").await.unwrap();
+        let mut reply = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(2), plain.read_to_end(&mut reply)).await;
+        assert!(!String::from_utf8_lossy(&reply).contains("Welcome"));
+        // Nor can one that doesn't trust the certificate
+        let (eve_tx, _eve_rx) = mpsc::unbounded_channel();
+        let untrusted = connect("127.0.0.1", port, "eve", None, eve_tx, None, None, 1024, Some(tls::client_config(None).unwrap()), Framing::Json).await;
+        assert!(untrusted.is_err());
+    }
+
+    /// Joins as `username`, expecting to be turned away.
+    async fn rejected(port: u16, username: &str, credential: Option<&str>) -> Rejection {
+        let (ui_tx, _ui_rx) = mpsc::unbounded_channel();
+        let Err(e) = connect("127.0.0.1", port, username, credential, ui_tx, None, None, 1024, None, Framing::Json).await else {
+            panic!("{} was let in", username);
+        };
+        *e.downcast::<Rejection>().expect("a rejection")
+    }
+
+    #[tokio::test]
+    async fn each_rejection_has_its_own_message_and_exit_code() {
+        let mut config = test_config();
+        config.max_clients = Some(1);
+        let port = start(config).await;
+        let _bob = TestClient::join(port, "bob").await;
+        let full = rejected(port, "ana", None).await;
+
+        let mut config = test_config();
+        config.auth = Arc::new(StaticPassword::new("secret".to_string()));
+        let auth_failed = rejected(start(config).await, "ana", Some("guess")).await;
+
+        let mut config = test_config();
+        config.strict = true;
+        let invalid_username = rejected(start(config).await, "two words", None).await;
+
+        let port = start(test_config()).await;
+        let _ana = TestClient::join(port, "ana").await;
+        let username_taken = rejected(port, "ana", None).await;
+
+        let replaced = Rejection { reason: RejectReason::Replaced, detail: "signed in elsewhere".to_string() };
+        let other = Rejection { reason: RejectReason::Other, detail: "maintenance".to_string() };
+        let cases = [
+            (full, "The server is full: ", 3),
+            (auth_failed, "Authentication failed: ", 4),
+            (invalid_username, "Username not accepted: ", 5),
+            (other, "The server refused the connection: ", 6),
+            (username_taken, "Username taken: ", 7),
+            (replaced, "Replaced by another session: ", 8),
+        ];
+        for (rejection, message, code) in cases {
+            assert!(rejection.to_string().starts_with(message), "{}", rejection);
+            assert_eq!(rejection.exit_code(), code, "{}", rejection);
+        }
+    }
 }
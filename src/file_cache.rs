@@ -0,0 +1,142 @@
+use crate::message::Message;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Recently shared files kept by the server so clients can fetch them on
+/// demand, bounded by total payload size and a time-to-live.
+pub struct FileCache {
+    entries: HashMap<String, CachedFile>,
+    max_bytes: u64,
+    ttl: Duration,
+    total_bytes: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+struct CachedFile {
+    message: Message,
+    size: u64,
+    inserted: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    pub ttl: Duration,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl FileCache {
+    pub fn new(max_bytes: u64, ttl: Duration) -> Self {
+        FileCache {
+            entries: HashMap::new(),
+            max_bytes,
+            ttl,
+            total_bytes: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Caches a file message under `id`, evicting expired and then oldest
+    /// entries to make room. Returns false if the file can never fit.
+    pub fn insert(&mut self, id: String, message: Message, size: u64) -> bool {
+        if size > self.max_bytes {
+            return false;
+        }
+        self.purge_expired();
+        while self.total_bytes + size > self.max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.inserted)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+            self.evictions += 1;
+        }
+        self.total_bytes += size;
+        self.entries.insert(id, CachedFile { message, size, inserted: Instant::now() });
+        true
+    }
+
+    /// Returns the cached file message for `id` if it hasn't expired.
+    pub fn get(&mut self, id: &str) -> Option<Message> {
+        self.purge_expired();
+        match self.entries.get(id) {
+            Some(entry) => {
+                self.hits += 1;
+                Some(entry.message.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// All unexpired file messages, oldest first.
+    pub fn files(&mut self) -> Vec<Message> {
+        self.purge_expired();
+        let mut entries: Vec<&CachedFile> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.inserted);
+        entries.into_iter().map(|entry| entry.message.clone()).collect()
+    }
+
+    pub fn stats(&mut self) -> CacheStats {
+        self.purge_expired();
+        CacheStats {
+            files: self.entries.len(),
+            bytes: self.total_bytes,
+            max_bytes: self.max_bytes,
+            ttl: self.ttl,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+
+    fn purge_expired(&mut self) {
+        let ttl = self.ttl;
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.inserted.elapsed() >= ttl)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.remove(&id);
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.total_bytes -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_is_served_within_its_ttl_and_gone_after() {
+        let mut cache = FileCache::new(1024, Duration::from_millis(100));
+        let file = Message::new_file("ana".to_string(), "a.txt".to_string(), 3, b"abc".to_vec(), None);
+        assert!(cache.insert("f1".to_string(), file, 3));
+        assert!(cache.get("f1").is_some());
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(cache.get("f1").is_none());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+}
@@ -1,11 +1,55 @@
-use crate::message::Message;
+use crate::message::{Codec, Message};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
+
+/// Payload bytes carried by each `FileChunk`.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long a chunked transfer may go without progress before it is dropped.
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest zstd dictionary a server shares. It goes to each client as a
+/// single message, so it is held to the size of a chunk.
+pub const MAX_DICTIONARY_SIZE: usize = CHUNK_SIZE;
+
+/// Extensions of formats that are compressed already, which gzip won't shrink.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "avif", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus", "pdf", "png", "pptx", "rar", "tgz", "webm", "webp",
+    "woff2", "xlsx", "xz", "zip", "zst",
+];
 
 #[allow(dead_code)]
 pub struct FileTransfer;
 
+/// How outgoing file payloads are compressed, picked from what the server
+/// supports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compressor<'a> {
+    Gzip,
+    /// With the dictionary the server shared, if it shared one
+    Zstd { dictionary: Option<&'a [u8]> },
+}
+
+/// Where downloaded files go inside the download directory.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum DownloadLayout {
+    /// Everything directly in the download directory
+    Flat,
+    /// One subdirectory per sender, e.g. `downloads/alice/foo.pdf`
+    PerSender,
+    /// Sender name prefixed to the filename, e.g. `downloads/alice-foo.pdf`
+    Prefix,
+}
+
 impl FileTransfer {
     #[allow(dead_code)]
     pub fn read_file(filepath: &str) -> Result<Message, Box<dyn Error>> {
@@ -21,13 +65,17 @@ impl FileTransfer {
             .to_string();
 
         let data = fs::read(path)?;
+        let size = data.len() as u64;
         
         // For now, we'll use a placeholder username - this should come from the caller
-        Ok(Message::new_file("unknown".to_string(), filename, data))
+        Ok(Message::new_file("unknown".to_string(), filename, size, data, None))
     }
 
+    /// Reads a whole file to send, refusing one over `max_size` bytes
+    /// before any of it is read. With a `compressor`, the payload is
+    /// compressed when that makes it smaller.
     #[allow(dead_code)]
-    pub fn read_file_with_username(filepath: &str, username: &str) -> Result<Message, Box<dyn Error>> {
+    pub fn read_file_with_username(filepath: &str, username: &str, max_size: u64, compressor: Option<Compressor>) -> Result<Message, Box<dyn Error>> {
         let path = Path::new(filepath);
         
         if !path.exists() {
@@ -39,21 +87,82 @@ impl FileTransfer {
             .to_string_lossy()
             .to_string();
 
+        check_size(fs::metadata(path)?.len(), max_size)?;
         let data = fs::read(path)?;
+        let size = data.len() as u64;
+        let (data, codec) = match compressor {
+            Some(compressor) if Self::is_compressible(&filename) => Self::compress(data, compressor),
+            _ => (data, None),
+        };
         
-        Ok(Message::new_file(username.to_string(), filename, data))
+        Ok(Message::new_file(username.to_string(), filename, size, data, codec))
+    }
+
+    /// Opens `filepath` for a chunked transfer. The file is read one chunk at
+    /// a time as the stream is iterated, never all at once, unless it is to
+    /// be compressed: that needs the whole file up front.
+    pub fn stream_file(filepath: &str, username: &str, max_size: u64, compressor: Option<Compressor>) -> Result<FileStream, Box<dyn Error>> {
+        let path = Path::new(filepath);
+
+        if !path.exists() {
+            return Err(format!("File not found: {}", filepath).into());
+        }
+
+        let filename = path.file_name()
+            .ok_or("Invalid filename")?
+            .to_string_lossy()
+            .to_string();
+
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        check_size(size, max_size)?;
+        if let Some(compressor) = compressor.filter(|_| Self::is_compressible(&filename)) {
+            let mut data = Vec::with_capacity(size as usize);
+            file.take(size).read_to_end(&mut data)?;
+            let (data, codec) = Self::compress(data, compressor);
+            let compressed_size = codec.map(|_| data.len() as u64);
+            return Ok(FileStream::new(username.to_string(), filename, size, compressed_size, None, codec, Box::new(io::Cursor::new(data))));
+        }
+        Ok(FileStream::new(username.to_string(), filename, size, None, None, None, Box::new(file.take(size))))
     }
 
+    /// Splits an in-memory file message into a chunked transfer, keeping its
+    /// id so the receiver can match it to the file that was announced.
+    pub fn stream_message(msg: &Message) -> Option<FileStream> {
+        let Message::File { username, filename, size, data, id, codec, compressed_size, .. } = msg else {
+            return None;
+        };
+        Some(FileStream::new(username.clone(), filename.clone(), *size, *compressed_size, id.clone(), *codec, Box::new(io::Cursor::new(data.clone()))))
+    }
+
+    /// Writes a received file into `download_dir`, returning the path used.
+    /// An existing file of the same name is kept and the new one saved as
+    /// `name (1).ext`, `name (2).ext`, ... unless `overwrite` is set.
     #[allow(dead_code)]
-    pub fn save_file(msg: &Message, download_dir: &str) -> Result<String, Box<dyn Error>> {
-        if let Message::File { filename, data, .. } = msg {
-            let download_path = Path::new(download_dir);
+    pub fn save_file(msg: &Message, download_dir: &str, layout: DownloadLayout, overwrite: bool) -> Result<String, Box<dyn Error>> {
+        if let Message::File { username, filename, data, crc32, .. } = msg {
+            if !Self::checksum_matches(data, *crc32) {
+                return Err(format!("{} is corrupted: its checksum doesn't match", filename).into());
+            }
+            // Sender and file names come from other clients, so never use them as paths verbatim
+            let sender = safe_path_component(username);
+            let filename = safe_filename(filename)?;
+            let (download_path, filename) = match layout {
+                DownloadLayout::Flat => (Path::new(download_dir).to_path_buf(), filename),
+                DownloadLayout::PerSender => (Path::new(download_dir).join(sender), filename),
+                DownloadLayout::Prefix => (Path::new(download_dir).to_path_buf(), format!("{}-{}", sender, filename)),
+            };
             
             // Create download directory if it doesn't exist
-            fs::create_dir_all(download_path)?;
+            fs::create_dir_all(&download_path)?;
             
             let file_path = download_path.join(filename);
-            fs::write(&file_path, data)?;
+            let file_path = if overwrite {
+                write_whole(&file_path, data)?;
+                file_path
+            } else {
+                write_new(&file_path, data)?
+            };
             
             Ok(file_path.to_string_lossy().to_string())
         } else {
@@ -61,12 +170,88 @@ impl FileTransfer {
         }
     }
 
-    #[allow(dead_code)]
+    /// Whether a file payload matches the checksum it came with. Files from
+    /// peers that send none are taken as they are.
+    pub fn checksum_matches(data: &[u8], crc32: Option<u32>) -> bool {
+        crc32.is_none_or(|crc32| crc32fast::hash(data) == crc32)
+    }
+
+    /// Whether `filename` is worth compressing, going by its extension.
+    pub fn is_compressible(filename: &str) -> bool {
+        let extension = Path::new(filename).extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        !extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+    }
+
+    /// Compresses a payload, handing it back as it was, with no codec, when
+    /// that doesn't make it smaller.
+    pub fn compress(data: Vec<u8>, compressor: Compressor) -> (Vec<u8>, Option<Codec>) {
+        let compressed = match compressor {
+            Compressor::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&data).and_then(|_| encoder.finish()).map(|compressed| (compressed, Codec::Gzip))
+            }
+            Compressor::Zstd { dictionary } => zstd::bulk::Compressor::with_dictionary(0, dictionary.unwrap_or_default())
+                .and_then(|mut compressor| compressor.compress(&data))
+                .map(|compressed| (compressed, Codec::Zstd)),
+        };
+        match compressed {
+            Ok((compressed, codec)) if compressed.len() < data.len() => (compressed, Some(codec)),
+            _ => (data, None),
+        }
+    }
+
+    /// Undoes `compress`, with the `dictionary` a zstd payload was
+    /// compressed with, if any. Output over `max_size` bytes is refused, and
+    /// never read further than that, so a small payload can't expand
+    /// without bound.
+    pub fn decompress(data: &[u8], codec: Codec, dictionary: Option<&[u8]>, max_size: u64) -> Result<Vec<u8>, String> {
+        let decoder: Box<dyn Read + '_> = match codec {
+            Codec::Gzip => Box::new(GzDecoder::new(data)),
+            Codec::Zstd => Box::new(
+                zstd::stream::read::Decoder::with_dictionary(data, dictionary.unwrap_or_default())
+                    .map_err(|e| format!("it can't be decompressed: {}", e))?,
+            ),
+            Codec::Other => return Err("it is compressed in a way this version doesn't support".to_string()),
+        };
+        let mut decompressed = Vec::new();
+        decoder
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(|e| format!("it can't be decompressed: {}", e))?;
+        if decompressed.len() as u64 > max_size {
+            return Err(format!("it decompresses to over the {} byte limit", max_size));
+        }
+        Ok(decompressed)
+    }
+
+    /// Trains a zstd dictionary of at most `max_size` bytes on sample files
+    /// alike to the ones it will compress.
+    pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> io::Result<Vec<u8>> {
+        zstd::dict::from_samples(samples, max_size)
+    }
+
+    /// Reads a dictionary saved by `train-dictionary` for a server to share,
+    /// refusing one over `MAX_DICTIONARY_SIZE` bytes.
+    pub fn load_dictionary(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+        let dictionary = fs::read(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        if dictionary.len() > MAX_DICTIONARY_SIZE {
+            return Err(format!("{} is {} bytes, over the {} byte limit for a dictionary", path.display(), dictionary.len(), MAX_DICTIONARY_SIZE).into());
+        }
+        Ok(dictionary)
+    }
+
+    /// The name and size of the file at `filepath`, checking that it can
+    /// actually be opened for reading.
     pub fn get_file_info(filepath: &str) -> Result<(String, u64), Box<dyn Error>> {
         let path = Path::new(filepath);
-        
-        if !path.exists() {
-            return Err(format!("File not found: {}", filepath).into());
+        let file = File::open(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => format!("File not found: {}", filepath),
+            io::ErrorKind::PermissionDenied => format!("Permission denied: {}", filepath),
+            _ => format!("Can't open {}: {}", filepath, e),
+        })?;
+        let metadata = file.metadata()?;
+        if metadata.is_dir() {
+            return Err(format!("{} is a directory", filepath).into());
         }
 
         let filename = path.file_name()
@@ -74,9 +259,518 @@ impl FileTransfer {
             .to_string_lossy()
             .to_string();
 
-        let metadata = fs::metadata(path)?;
-        let size = metadata.len();
-        
-        Ok((filename, size))
+        Ok((filename, metadata.len()))
+    }
+}
+
+fn check_size(size: u64, max_size: u64) -> Result<(), Box<dyn Error>> {
+    if size > max_size {
+        return Err(format!("file is {} bytes, over the {} byte limit", size, max_size).into());
+    }
+    Ok(())
+}
+
+/// Bytes a file's payload takes on the wire: `compressed_size` when it is
+/// compressed, else the file's own `size`.
+pub fn payload_size(size: u64, compressed_size: Option<u64>) -> u64 {
+    compressed_size.unwrap_or(size)
+}
+
+/// The messages of one outgoing chunked transfer: a `FileStart`, the chunks
+/// in order, then a `FileEnd`.
+pub struct FileStream {
+    transfer_id: String,
+    start: Option<Message>,
+    source: Box<dyn Read + Send>,
+    seq: u64,
+    total: u64,
+    hasher: crc32fast::Hasher,
+    done: bool,
+}
+
+impl FileStream {
+    /// A transfer of a `size` byte file whose payload, read from `source`,
+    /// is `compressed_size` bytes if compressed.
+    fn new(
+        username: String,
+        filename: String,
+        size: u64,
+        compressed_size: Option<u64>,
+        id: Option<String>,
+        codec: Option<Codec>,
+        source: Box<dyn Read + Send>,
+    ) -> Self {
+        let transfer_id = Uuid::new_v4().to_string();
+        let total = payload_size(size, compressed_size).div_ceil(CHUNK_SIZE as u64);
+        FileStream {
+            start: Some(Message::new_file_start(transfer_id.clone(), username, filename, size, total, id, codec, compressed_size)),
+            transfer_id,
+            source,
+            seq: 0,
+            total,
+            hasher: crc32fast::Hasher::new(),
+            done: false,
+        }
+    }
+}
+
+impl Iterator for FileStream {
+    type Item = io::Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(start) = self.start.take() {
+            return Some(Ok(start));
+        }
+        if self.done {
+            return None;
+        }
+        if self.seq == self.total {
+            self.done = true;
+            let crc32 = self.hasher.clone().finalize();
+            return Some(Ok(Message::new_file_end(self.transfer_id.clone(), Some(crc32))));
+        }
+        let mut data = Vec::with_capacity(CHUNK_SIZE);
+        if let Err(e) = (&mut self.source).take(CHUNK_SIZE as u64).read_to_end(&mut data) {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if data.is_empty() {
+            // The file shrank after we announced its size
+            self.done = true;
+            return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file changed while it was being sent")));
+        }
+        self.hasher.update(&data);
+        let chunk = Message::FileChunk { transfer_id: self.transfer_id.clone(), seq: self.seq, total: self.total, data };
+        self.seq += 1;
+        Some(Ok(chunk))
+    }
+}
+
+/// An incoming chunked transfer waiting for the rest of its chunks.
+struct Transfer {
+    username: String,
+    filename: String,
+    size: u64,
+    /// Bytes the chunks add up to
+    payload: u64,
+    id: Option<String>,
+    timestamp: SystemTime,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u64,
+    ended: bool,
+    crc32: Option<u32>,
+    codec: Option<Codec>,
+    compressed_size: Option<u64>,
+    last_activity: Instant,
+}
+
+/// Puts chunked transfers back together, keyed by transfer id. Chunks may
+/// arrive in any order; a transfer that stops making progress for `timeout`
+/// is dropped, which is also how missing chunks are dealt with.
+pub struct Reassembler {
+    transfers: HashMap<String, Transfer>,
+    /// Largest file accepted, and total payload of the transfers held at
+    /// once
+    max_bytes: u64,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub fn new(max_bytes: u64, timeout: Duration) -> Self {
+        Self { transfers: HashMap::new(), max_bytes, timeout }
+    }
+
+    /// Takes one `FileStart`, `FileChunk` or `FileEnd`, returning the whole
+    /// file message once its last piece is in. Errors describe a transfer
+    /// that was refused or abandoned.
+    pub fn receive(&mut self, msg: Message) -> Result<Option<Message>, String> {
+        match msg {
+            Message::FileStart { transfer_id, username, filename, size, total, timestamp, id, codec, compressed_size } => {
+                if self.transfers.contains_key(&transfer_id) {
+                    return Err(format!("File {} rejected: transfer id already in use", filename));
+                }
+                // Judged by the file's own size, so a compressed payload
+                // can't hide how large it gets
+                let payload = payload_size(size, compressed_size);
+                if size.max(payload) > self.max_bytes {
+                    return Err(format!("File {} rejected: {} bytes is over the {} byte limit", filename, size.max(payload), self.max_bytes));
+                }
+                let pending: u64 = self.transfers.values().map(|t| t.payload).sum();
+                if pending.saturating_add(payload) > self.max_bytes {
+                    return Err(format!("File {} rejected: too much else is still being transferred", filename));
+                }
+                if total != payload.div_ceil(CHUNK_SIZE as u64) {
+                    return Err(format!("File {} rejected: expected {} byte chunks", filename, CHUNK_SIZE));
+                }
+                let transfer = Transfer {
+                    username,
+                    filename,
+                    size,
+                    payload,
+                    id,
+                    timestamp,
+                    chunks: vec![None; total as usize],
+                    received: 0,
+                    ended: false,
+                    crc32: None,
+                    codec,
+                    compressed_size,
+                    last_activity: Instant::now(),
+                };
+                self.transfers.insert(transfer_id.clone(), transfer);
+                Ok(self.complete(&transfer_id))
+            }
+            Message::FileChunk { transfer_id, seq, total, data } => {
+                let Some(transfer) = self.transfers.get_mut(&transfer_id) else {
+                    // Most likely a transfer that already timed out
+                    return Ok(None);
+                };
+                if total != transfer.chunks.len() as u64 || seq >= total {
+                    let filename = transfer.filename.clone();
+                    self.transfers.remove(&transfer_id);
+                    return Err(format!("File {} discarded: chunk {} of {} is out of range", filename, seq, total));
+                }
+                if transfer.chunks[seq as usize].is_none() {
+                    transfer.received += data.len() as u64;
+                    if transfer.received > transfer.payload {
+                        let filename = transfer.filename.clone();
+                        self.transfers.remove(&transfer_id);
+                        return Err(format!("File {} discarded: more data than its declared size", filename));
+                    }
+                    transfer.chunks[seq as usize] = Some(data);
+                }
+                transfer.last_activity = Instant::now();
+                Ok(self.complete(&transfer_id))
+            }
+            Message::FileEnd { transfer_id, crc32, .. } => {
+                if let Some(transfer) = self.transfers.get_mut(&transfer_id) {
+                    transfer.ended = true;
+                    transfer.crc32 = crc32;
+                    transfer.last_activity = Instant::now();
+                }
+                Ok(self.complete(&transfer_id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Drops transfers that have made no progress within the timeout,
+    /// returning their filenames.
+    pub fn expire(&mut self) -> Vec<String> {
+        let timeout = self.timeout;
+        let mut expired = Vec::new();
+        self.transfers.retain(|_, transfer| {
+            let alive = transfer.last_activity.elapsed() < timeout;
+            if !alive {
+                expired.push(transfer.filename.clone());
+            }
+            alive
+        });
+        expired
+    }
+
+    /// Hands back the file once it has ended and every chunk is in.
+    fn complete(&mut self, transfer_id: &str) -> Option<Message> {
+        let transfer = self.transfers.get(transfer_id)?;
+        if !transfer.ended || transfer.chunks.iter().any(Option::is_none) {
+            return None;
+        }
+        let transfer = self.transfers.remove(transfer_id)?;
+        let data: Vec<u8> = transfer.chunks.into_iter().flatten().flatten().collect();
+        // A short or damaged payload is left for the receiver's size and
+        // checksum checks to report
+        Some(Message::File {
+            username: transfer.username,
+            filename: transfer.filename,
+            size: transfer.size,
+            data,
+            timestamp: transfer.timestamp,
+            id: transfer.id,
+            crc32: transfer.crc32,
+            codec: transfer.codec,
+            compressed_size: transfer.compressed_size,
+        })
+    }
+}
+
+/// Writes `data` to a temporary file beside `path`, then renames it over
+/// `path`, so a write that fails partway leaves nothing under that name.
+fn write_whole(path: &Path, data: &[u8]) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.{}.part", name, Uuid::new_v4().simple()));
+    let written = fs::write(&temp, data).and_then(|()| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Writes `data` to `path`, or to the first free `stem (n).ext` beside it,
+/// never replacing an existing file.
+fn write_new(path: &Path, data: &[u8]) -> io::Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    for n in 0.. {
+        let candidate = match n {
+            0 => path.to_path_buf(),
+            n => path.with_file_name(format!("{} ({}){}", stem, n, extension)),
+        };
+        // Creating exclusively means a file that appears meanwhile is skipped,
+        // not clobbered; the name is held empty until the data is renamed over it
+        match fs::OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => {
+                if let Err(e) = write_whole(&candidate, data) {
+                    let _ = fs::remove_file(&candidate);
+                    return Err(e);
+                }
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("suffix space is unbounded")
+}
+
+/// Keeps only the last component of an untrusted filename so the file lands
+/// in the download directory. Absolute paths, and names that come down to
+/// nothing, `.` or `..`, are refused.
+fn safe_filename(name: &str) -> Result<String, Box<dyn Error>> {
+    // Treat backslashes as separators too, as a Windows sender would
+    let normalized = name.replace('\\', "/");
+    let path = Path::new(&normalized);
+    let drive = name.len() >= 2 && name.as_bytes()[0].is_ascii_alphabetic() && name.as_bytes()[1] == b':';
+    if path.is_absolute() || normalized.starts_with('/') || drive {
+        return Err(format!("refusing to save to the absolute path {}", name).into());
+    }
+    match path.file_name().map(|last| last.to_string_lossy()) {
+        Some(last) if !matches!(last.trim(), "" | "." | "..") => Ok(safe_path_component(&last)),
+        _ => Err(format!("refusing to save under the name {:?}", name).into()),
+    }
+}
+
+/// Turns an untrusted name into a single path component: separators and
+/// control characters become `_`, and `.`/`..`/empty names are replaced.
+fn safe_path_component(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if c == '/' || c == '\\' || c == ':' || c.is_control() { '_' } else { c })
+        .collect();
+    match cleaned.trim() {
+        "" | "." | ".." => "unknown".to_string(),
+        _ => cleaned,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_stream_keeps_the_original_size() {
+        let original = "all work and no play makes jack a dull boy\n".repeat(10_000).into_bytes();
+        let msg = Message::new_file("ana".to_string(), "notes.txt".to_string(), original.len() as u64, original.clone(), None);
+        let (data, codec) = FileTransfer::compress(original.clone(), Compressor::Gzip);
+        let compressed = Message::new_file("ana".to_string(), "notes.txt".to_string(), original.len() as u64, data.clone(), codec);
+        assert!(matches!(msg, Message::File { compressed_size: None, .. }));
+
+        let mut stream = FileTransfer::stream_message(&compressed).unwrap();
+        let Some(Ok(Message::FileStart { size, compressed_size, total, .. })) = stream.next() else {
+            panic!("a stream starts with FileStart");
+        };
+        assert_eq!(size, original.len() as u64);
+        assert_eq!(compressed_size, Some(data.len() as u64));
+        assert_eq!(total, (data.len() as u64).div_ceil(CHUNK_SIZE as u64));
+
+        let mut reassembler = Reassembler::new(original.len() as u64, TRANSFER_TIMEOUT);
+        let mut complete = None;
+        for msg in FileTransfer::stream_message(&compressed).unwrap() {
+            complete = reassembler.receive(msg.unwrap()).unwrap().or(complete);
+        }
+        let Some(Message::File { size, data: payload, codec, compressed_size, .. }) = complete else {
+            panic!("the transfer completes");
+        };
+        assert_eq!(size, original.len() as u64);
+        assert_eq!(compressed_size, Some(data.len() as u64));
+        assert_eq!(FileTransfer::decompress(&payload, codec.unwrap(), None, size).unwrap(), original);
+    }
+
+    #[test]
+    fn multi_chunk_file_round_trips_in_any_order() {
+        let original: Vec<u8> = (0..CHUNK_SIZE * 3 + 1000).map(|n| (n % 251) as u8).collect();
+        let msg = file_from("ana", "blob.bin", &original);
+        let mut pieces: Vec<Message> = FileTransfer::stream_message(&msg).unwrap().map(Result::unwrap).collect();
+        // Start, four chunks, end
+        assert_eq!(pieces.len(), 6);
+        pieces[1..5].reverse();
+
+        let mut reassembler = Reassembler::new(1 << 20, TRANSFER_TIMEOUT);
+        let mut complete = None;
+        for piece in pieces {
+            assert!(complete.is_none(), "completed before the last piece");
+            complete = reassembler.receive(piece).unwrap();
+        }
+        let Some(Message::File { username, filename, size, data, crc32, .. }) = complete else {
+            panic!("the transfer completes");
+        };
+        assert_eq!((username.as_str(), filename.as_str(), size), ("ana", "blob.bin", original.len() as u64));
+        assert_eq!(data, original);
+        assert!(FileTransfer::checksum_matches(&data, crc32));
+    }
+
+    #[test]
+    fn transfer_missing_a_chunk_times_out() {
+        let msg = file_from("ana", "gappy.bin", &vec![7u8; CHUNK_SIZE * 2]);
+        let mut reassembler = Reassembler::new(1 << 20, Duration::from_millis(50));
+        for (n, piece) in FileTransfer::stream_message(&msg).unwrap().enumerate() {
+            // The second chunk is lost on the way
+            if n != 2 {
+                assert!(reassembler.receive(piece.unwrap()).unwrap().is_none());
+            }
+        }
+        assert!(reassembler.expire().is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(reassembler.expire(), ["gappy.bin"]);
+    }
+
+    #[test]
+    fn reassembler_judges_compressed_files_by_their_original_size() {
+        let original = vec![0u8; 1 << 20];
+        let (data, codec) = FileTransfer::compress(original.clone(), Compressor::Gzip);
+        let msg = Message::new_file("ana".to_string(), "zeros.bin".to_string(), original.len() as u64, data, codec);
+        let start = FileTransfer::stream_message(&msg).unwrap().next().unwrap().unwrap();
+
+        let mut reassembler = Reassembler::new(64 * 1024, TRANSFER_TIMEOUT);
+        assert!(reassembler.receive(start).unwrap_err().contains("over the"));
+    }
+
+    #[test]
+    fn decompress_stops_at_the_limit() {
+        let (bomb, codec) = FileTransfer::compress(vec![0u8; 10 << 20], Compressor::Gzip);
+        assert!(bomb.len() < 64 * 1024);
+        let err = FileTransfer::decompress(&bomb, codec.unwrap(), None, 1 << 20).unwrap_err();
+        assert!(err.contains("over the 1048576 byte limit"), "{}", err);
+        assert_eq!(FileTransfer::decompress(&bomb, Codec::Gzip, None, 10 << 20).unwrap().len(), 10 << 20);
+    }
+
+    /// Small JSON log records, alike in shape but not in content.
+    fn log_record(n: u32) -> Vec<u8> {
+        format!(
+            "{{\"timestamp\":\"2026-10-16T12:{:02}:{:02}Z\",\"level\":\"{}\",\"service\":\"checkout-api\",\"request_id\":\"{:08x}\",\"message\":\"handled request for order {}\",\"duration_ms\":{}}}\n",
+            n / 60 % 60, n % 60, ["info", "warn", "debug"][n as usize % 3], n.wrapping_mul(2654435761), n * 7, n % 300,
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn zstd_dictionary_shrinks_small_similar_files() {
+        let samples: Vec<Vec<u8>> = (0..500).map(log_record).collect();
+        let dictionary = FileTransfer::train_dictionary(&samples, 4096).unwrap();
+        assert!(dictionary.len() <= 4096);
+
+        let (mut plain, mut trained) = (0, 0);
+        for n in 1000..1020 {
+            let original = log_record(n);
+            let (without, codec) = FileTransfer::compress(original.clone(), Compressor::Zstd { dictionary: None });
+            plain += without.len();
+            if let Some(codec) = codec {
+                assert_eq!(FileTransfer::decompress(&without, codec, None, 1 << 20).unwrap(), original);
+            }
+            let (with, codec) = FileTransfer::compress(original.clone(), Compressor::Zstd { dictionary: Some(&dictionary) });
+            assert_eq!(codec, Some(Codec::Zstd));
+            trained += with.len();
+            assert_eq!(FileTransfer::decompress(&with, Codec::Zstd, Some(&dictionary), 1 << 20).unwrap(), original);
+            // Without the dictionary it was made with, the payload can't be read
+            assert!(FileTransfer::decompress(&with, Codec::Zstd, None, 1 << 20).is_err());
+        }
+        assert!(trained * 2 < plain, "{} bytes with the dictionary, {} without", trained, plain);
+    }
+
+    /// An empty scratch download directory unique to `test`.
+    fn download_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("terminal-chat-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file_from(sender: &str, filename: &str, data: &[u8]) -> Message {
+        Message::new_file(sender.to_string(), filename.to_string(), data.len() as u64, data.to_vec(), None)
+    }
+
+    #[test]
+    fn adversarial_filenames_stay_inside_the_download_dir() {
+        let dir = download_dir("traversal");
+        let root = dir.to_str().unwrap();
+        for (name, saved_as) in [
+            ("../../escape.txt", "escape.txt"),
+            ("nested/../../up.txt", "up.txt"),
+            ("..\\..\\windows.txt", "windows.txt"),
+            ("sub/dir/plain.txt", "plain.txt"),
+        ] {
+            let path = FileTransfer::save_file(&file_from("ana", name, b"x"), root, DownloadLayout::Flat, false).unwrap();
+            assert_eq!(Path::new(&path), dir.join(saved_as), "{}", name);
+        }
+        for name in ["/etc/passwd", "\\server\\share", "C:\\boot.ini", "..", ".", "", "dir/.."] {
+            assert!(FileTransfer::save_file(&file_from("ana", name, b"x"), root, DownloadLayout::Flat, false).is_err(), "{:?} was saved", name);
+        }
+        // The sender's name can't climb out either
+        let path = FileTransfer::save_file(&file_from("../..", "a.txt", b"x"), root, DownloadLayout::PerSender, false).unwrap();
+        assert!(Path::new(&path).starts_with(&dir), "{}", path);
+        let path = FileTransfer::save_file(&file_from("../../x", "a.txt", b"x"), root, DownloadLayout::Prefix, false).unwrap();
+        assert_eq!(Path::new(&path).parent(), Some(dir.as_path()));
+
+        // Nothing was written anywhere but the download directory
+        let outside = dir.parent().unwrap();
+        assert!(!outside.join("escape.txt").exists() && !outside.join("up.txt").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn saving_one_name_three_times_keeps_three_files() {
+        let dir = download_dir("rename");
+        let root = dir.to_str().unwrap();
+        let saved: Vec<String> = ["first", "second", "third"]
+            .iter()
+            .map(|content| FileTransfer::save_file(&file_from("ana", "report.pdf", content.as_bytes()), root, DownloadLayout::Flat, false).unwrap())
+            .collect();
+        let expected = ["report.pdf", "report (1).pdf", "report (2).pdf"].map(|name| dir.join(name));
+        assert_eq!(saved.iter().map(PathBuf::from).collect::<Vec<_>>(), expected);
+        for (path, content) in expected.iter().zip(["first", "second", "third"]) {
+            assert_eq!(fs::read(path).unwrap(), content.as_bytes());
+        }
+
+        // Overwriting goes back to the one name
+        let path = FileTransfer::save_file(&file_from("ana", "report.pdf", b"fourth"), root, DownloadLayout::Flat, true).unwrap();
+        assert_eq!(PathBuf::from(path), expected[0]);
+        assert_eq!(fs::read(&expected[0]).unwrap(), b"fourth");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_save_leaves_no_partial_file_behind() {
+        let dir = download_dir("partial");
+        let root = dir.to_str().unwrap();
+        // A directory in the way makes the final rename fail
+        fs::create_dir_all(dir.join("report.pdf").join("inside")).unwrap();
+
+        assert!(FileTransfer::save_file(&file_from("ana", "report.pdf", b"data"), root, DownloadLayout::Flat, true).is_err());
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["report.pdf"]);
+        assert!(dir.join("report.pdf").join("inside").is_dir());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn per_sender_layout_keeps_each_senders_files_apart() {
+        let dir = download_dir("per-sender");
+        let root = dir.to_str().unwrap();
+        let ana = FileTransfer::save_file(&file_from("ana", "notes.txt", b"from ana"), root, DownloadLayout::PerSender, false).unwrap();
+        let bob = FileTransfer::save_file(&file_from("bob", "notes.txt", b"from bob"), root, DownloadLayout::PerSender, false).unwrap();
+        assert_eq!(Path::new(&ana), dir.join("ana").join("notes.txt"));
+        assert_eq!(Path::new(&bob), dir.join("bob").join("notes.txt"));
+        assert_eq!(fs::read(&ana).unwrap(), b"from ana");
+        assert_eq!(fs::read(&bob).unwrap(), b"from bob");
+        fs::remove_dir_all(dir).unwrap();
     }
 }
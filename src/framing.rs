@@ -0,0 +1,302 @@
+use crate::file_transfer::CHUNK_SIZE;
+use crate::message::Message;
+use crate::tls::{StreamReader, StreamWriter};
+use std::io;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// Room for the non-payload fields of a file message, and the most a join
+/// frame may take.
+pub const FRAME_OVERHEAD: usize = 64 * 1024;
+
+/// The largest frame read once joined: a file chunk, whose payload bytes
+/// take up to four bytes each as JSON, or any other single message. Files
+/// travel in chunks, so this doesn't grow with the file size limit.
+pub const MAX_FRAME: usize = CHUNK_SIZE * 4 + FRAME_OVERHEAD;
+
+/// How messages are delimited on the wire.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// One JSON message per line, the default
+    Json,
+    /// A 4-byte big-endian length, then the message as MessagePack. The
+    /// join lines (username, credential) go as plain UTF-8 frames. The
+    /// first byte of a binary connection is always 0, which no username
+    /// line starts with, so a server can tell the two apart.
+    Binary,
+}
+
+/// What `read_frame` found.
+#[derive(Debug)]
+pub enum Frame {
+    /// The end of the stream
+    End,
+    /// Text, now in `line`: a JSON line, or a join line. Empty for a binary
+    /// frame that is neither a known message nor text (e.g. a newer kind of
+    /// message).
+    Text,
+    /// A binary frame's message, decoded already
+    Message(Message),
+}
+
+/// Reads the next frame: JSON lines and join lines land in `line`, binary
+/// messages come back decoded.
+///
+/// A frame over `max_len` bytes is never buffered: it is skipped and
+/// reported as an `InvalidData` error, after which the next frame can be
+/// read as usual.
+pub async fn read_frame(reader: &mut BufReader<StreamReader>, framing: Framing, line: &mut String, max_len: usize) -> io::Result<Frame> {
+    if framing == Framing::Json {
+        let mut bytes = Vec::new();
+        let read = (&mut *reader).take(max_len as u64 + 1).read_until(b'\n', &mut bytes).await?;
+        if read == 0 {
+            return Ok(Frame::End);
+        }
+        if read > max_len && !bytes.ends_with(b"\n") {
+            skip_line(reader).await?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line exceeds the {} byte limit", max_len)));
+        }
+        let text = String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push_str(&text);
+        return Ok(Frame::Text);
+    }
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(Frame::End),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_len {
+        tokio::io::copy(&mut (&mut *reader).take(len as u64), &mut tokio::io::sink()).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the {} byte limit", len, max_len),
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    if let Ok(message) = rmp_serde::from_slice::<Message>(&payload) {
+        return Ok(Frame::Message(message));
+    }
+    if let Ok(text) = String::from_utf8(payload) {
+        line.push_str(text.trim_end_matches(['\r', '\n']));
+    }
+    line.push('\n');
+    Ok(Frame::Text)
+}
+
+/// Discards the rest of an overlong line, up to and including its newline.
+async fn skip_line(reader: &mut BufReader<StreamReader>) -> io::Result<()> {
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Writes `message` as one frame.
+pub async fn write_message(writer: &mut StreamWriter, framing: Framing, message: &Message) -> io::Result<()> {
+    match framing {
+        Framing::Json => write_frame(writer, framing, &message.to_json().map_err(io::Error::other)?).await,
+        Framing::Binary => write_payload(writer, &rmp_serde::to_vec_named(message).map_err(io::Error::other)?).await,
+    }
+}
+
+/// Writes `text` as one frame as it is: a join line, or a JSON line.
+pub async fn write_frame(writer: &mut StreamWriter, framing: Framing, text: &str) -> io::Result<()> {
+    match framing {
+        Framing::Json => writer.write_all(format!("{}\n", text).as_bytes()).await,
+        Framing::Binary => write_payload(writer, text.as_bytes()).await,
+    }
+}
+
+/// A JSON message on its way to one or more clients. Its MessagePack
+/// payload is worked out the first time a binary client needs it and kept,
+/// so a broadcast is encoded once however many clients it goes to.
+#[derive(Debug)]
+pub struct Outgoing {
+    json: String,
+    binary: OnceLock<Vec<u8>>,
+}
+
+impl Outgoing {
+    pub fn new(json: String) -> Arc<Self> {
+        Arc::new(Outgoing { json, binary: OnceLock::new() })
+    }
+
+    /// The payload of a binary frame: the message as MessagePack, or the
+    /// text as is if it doesn't parse.
+    fn binary(&self) -> &[u8] {
+        self.binary.get_or_init(|| {
+            Message::from_json(&self.json)
+                .ok()
+                .and_then(|message| rmp_serde::to_vec_named(&message).ok())
+                .unwrap_or_else(|| self.json.as_bytes().to_vec())
+        })
+    }
+}
+
+/// Writes `outgoing` as one frame.
+pub async fn write_outgoing(writer: &mut StreamWriter, framing: Framing, outgoing: &Outgoing) -> io::Result<()> {
+    match framing {
+        Framing::Json => write_frame(writer, framing, &outgoing.json).await,
+        Framing::Binary => write_payload(writer, outgoing.binary()).await,
+    }
+}
+
+/// Writes one length-prefixed binary frame.
+async fn write_payload(writer: &mut StreamWriter, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("frame of {} bytes is too large", payload.len())))?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(bytes: Vec<u8>) -> BufReader<StreamReader> {
+        BufReader::new(Box::new(io::Cursor::new(bytes)))
+    }
+
+    #[tokio::test]
+    async fn overlong_json_line_is_skipped_not_buffered() {
+        let mut input = vec![b'x'; 100];
+        input.extend_from_slice(b"\n{\"ok\":1}\n");
+        let mut reader = reader(input);
+        let mut line = String::new();
+
+        let err = read_frame(&mut reader, Framing::Json, &mut line, 10).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(line.is_empty());
+
+        assert!(matches!(read_frame(&mut reader, Framing::Json, &mut line, 10).await.unwrap(), Frame::Text));
+        assert_eq!(line, "{\"ok\":1}\n");
+    }
+
+    #[tokio::test]
+    async fn json_line_at_the_limit_is_read() {
+        let mut reader = reader(b"0123456789\n".to_vec());
+        let mut line = String::new();
+        assert!(matches!(read_frame(&mut reader, Framing::Json, &mut line, 10).await.unwrap(), Frame::Text));
+        assert_eq!(line, "0123456789\n");
+    }
+
+    #[tokio::test]
+    async fn oversized_binary_frame_is_skipped() {
+        let mut input = 100u32.to_be_bytes().to_vec();
+        input.extend_from_slice(&[0u8; 100]);
+        input.extend_from_slice(&5u32.to_be_bytes());
+        input.extend_from_slice(b"alice");
+        let mut reader = reader(input);
+        let mut line = String::new();
+
+        let err = read_frame(&mut reader, Framing::Binary, &mut line, 10).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        read_frame(&mut reader, Framing::Binary, &mut line, 10).await.unwrap();
+        assert_eq!(line, "alice\n");
+    }
+
+    /// Writes with `write`, then reads back everything written.
+    async fn written(write: impl AsyncFnOnce(&mut StreamWriter)) -> BufReader<StreamReader> {
+        let (near, mut far) = tokio::io::duplex(1 << 20);
+        let mut writer: StreamWriter = Box::new(near);
+        write(&mut writer).await;
+        drop(writer);
+        let mut bytes = Vec::new();
+        far.read_to_end(&mut bytes).await.unwrap();
+        reader(bytes)
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip_in_both_framings() {
+        let text = Message::new_text("ana".to_string(), "two\nlines".to_string(), None, None);
+        let file = Message::new_file("ana".to_string(), "a.bin".to_string(), 3, vec![0, 10, 255], None);
+        for framing in [Framing::Json, Framing::Binary] {
+            let mut reader = written(async |writer: &mut StreamWriter| {
+                write_message(writer, framing, &text).await.unwrap();
+                write_message(writer, framing, &file).await.unwrap();
+            })
+            .await;
+            for sent in [&text, &file] {
+                let mut line = String::new();
+                let received = match read_frame(&mut reader, framing, &mut line, FRAME_OVERHEAD).await.unwrap() {
+                    Frame::Message(message) => message,
+                    Frame::Text => Message::from_json(line.trim()).unwrap(),
+                    Frame::End => panic!("stream ended early"),
+                };
+                assert_eq!(received.to_json().unwrap(), sent.to_json().unwrap());
+            }
+            let mut line = String::new();
+            assert!(matches!(read_frame(&mut reader, framing, &mut line, FRAME_OVERHEAD).await.unwrap(), Frame::End));
+        }
+    }
+
+    #[tokio::test]
+    async fn outgoing_messages_are_encoded_once_for_every_binary_client() {
+        let text = Message::new_text("ana".to_string(), "hi".to_string(), None, None);
+        let outgoing = Outgoing::new(text.to_json().unwrap());
+        for _ in 0..2 {
+            let mut reader = written(async |writer: &mut StreamWriter| {
+                write_outgoing(writer, Framing::Binary, &outgoing).await.unwrap();
+            })
+            .await;
+            let mut line = String::new();
+            let Frame::Message(received) = read_frame(&mut reader, Framing::Binary, &mut line, FRAME_OVERHEAD).await.unwrap() else {
+                panic!("expected a binary message");
+            };
+            assert_eq!(received.to_json().unwrap(), text.to_json().unwrap());
+        }
+        let payload = outgoing.binary.get().unwrap();
+        assert!(std::ptr::eq(outgoing.binary(), payload.as_slice()));
+    }
+
+    #[test]
+    fn a_full_chunk_fits_in_a_frame() {
+        let chunk = Message::FileChunk { transfer_id: "t".to_string(), seq: 0, total: 1, data: vec![255; CHUNK_SIZE] };
+        assert!(chunk.to_json().unwrap().len() <= MAX_FRAME);
+        assert!(rmp_serde::to_vec_named(&chunk).unwrap().len() <= MAX_FRAME);
+    }
+
+    #[tokio::test]
+    async fn binary_join_lines_read_as_text() {
+        let mut reader = written(async |writer: &mut StreamWriter| {
+            write_frame(writer, Framing::Binary, "ana").await.unwrap();
+        })
+        .await;
+        let mut line = String::new();
+        assert!(matches!(read_frame(&mut reader, Framing::Binary, &mut line, FRAME_OVERHEAD).await.unwrap(), Frame::Text));
+        assert_eq!(line, "ana\n");
+    }
+
+    #[tokio::test]
+    async fn unknown_message_kind_is_skipped_and_the_stream_goes_on() {
+        let hello = Message::new_text("ana".to_string(), "hello".to_string(), None, None);
+        let input = format!("{{\"Hologram\":{{\"frames\":3}}}}\n{}\n", hello.to_json().unwrap());
+        let mut reader = reader(input.into_bytes());
+        let mut line = String::new();
+
+        assert!(matches!(read_frame(&mut reader, Framing::Json, &mut line, 1024).await.unwrap(), Frame::Text));
+        assert!(matches!(Message::from_json(line.trim()).unwrap(), Message::Unknown { .. }));
+        line.clear();
+        assert!(matches!(read_frame(&mut reader, Framing::Json, &mut line, 1024).await.unwrap(), Frame::Text));
+        assert!(matches!(Message::from_json(line.trim()).unwrap(), Message::Text { content, .. } if content == "hello"));
+
+        // A kind we know with fields we don't is still an error
+        assert!(Message::from_json("{\"Text\":{\"content\":1}}").is_err());
+    }
+}
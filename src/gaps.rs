@@ -0,0 +1,81 @@
+use std::ops::RangeInclusive;
+
+/// Spots chat the server numbered but this client never got. Numbers count
+/// up per room, and only the current room's chat reaches the client, so
+/// only that room is followed; chat of a room it was away from doesn't
+/// count as missed. Messages the client hides once they arrive still count
+/// as received.
+#[derive(Debug, Default)]
+pub struct GapDetector {
+    room: Option<String>,
+    /// The highest number seen in `room`
+    last: u64,
+    /// Ranges asked for again, whose messages arrive below `last`
+    requested: Vec<RangeInclusive<u64>>,
+}
+
+impl GapDetector {
+    /// Follows `room` from now on. Moving to another room starts afresh;
+    /// coming back to the same one, e.g. after a reconnect, does not.
+    pub fn enter(&mut self, room: &str) {
+        if self.room.as_deref() != Some(room) {
+            *self = GapDetector { room: Some(room.to_string()), ..GapDetector::default() };
+        }
+    }
+
+    /// Forgets the room, as when disconnecting on purpose.
+    pub fn leave(&mut self) {
+        *self = GapDetector::default();
+    }
+
+    /// Notes chat number `seq` as received, returning the room and the
+    /// numbers missed just before it, if any, to ask the server for.
+    pub fn receive(&mut self, seq: u64) -> Option<(String, RangeInclusive<u64>)> {
+        let room = self.room.clone()?;
+        if seq <= self.last {
+            if !self.requested.iter().any(|range| range.contains(&seq)) {
+                // Numbering started over, as when a server restarts without its log
+                self.last = seq;
+                self.requested.clear();
+            }
+            return None;
+        }
+        let missed = (self.last > 0 && seq > self.last + 1).then(|| self.last + 1..=seq - 1);
+        self.last = seq;
+        let missed = missed?;
+        self.requested.push(missed.clone());
+        Some((room, missed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_numbers_skipped_in_the_current_room_are_missed() {
+        let mut gaps = GapDetector::default();
+        // Nothing is followed until the server says which room we're in
+        assert_eq!(gaps.receive(7), None);
+        gaps.enter("#general");
+        assert_eq!(gaps.receive(7), None);
+        assert_eq!(gaps.receive(8), None);
+        assert_eq!(gaps.receive(11), Some(("#general".to_string(), 9..=10)));
+        // What was asked for arrives late without counting as a restart
+        assert_eq!(gaps.receive(9), None);
+        assert_eq!(gaps.receive(12), None);
+
+        // Coming back to the room after a reconnect keeps counting
+        gaps.enter("#general");
+        assert_eq!(gaps.receive(15), Some(("#general".to_string(), 13..=14)));
+
+        // Another room's numbers are its own
+        gaps.enter("#rust");
+        assert_eq!(gaps.receive(3), None);
+        assert_eq!(gaps.receive(4), None);
+
+        // A number already passed and never asked for means numbering restarted
+        assert_eq!(gaps.receive(1), None);
+        assert_eq!(gaps.receive(2), None);
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// Liveness and readiness flags the chat server updates for HTTP probes.
+#[derive(Debug, Default)]
+pub struct HealthState {
+    alive: AtomicBool,
+    ready: AtomicBool,
+}
+
+impl HealthState {
+    pub fn set_alive(&self, alive: bool) {
+        self.alive.store(alive, Ordering::SeqCst);
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+}
+
+/// Serves `GET /healthz` and `GET /readyz` on `port` until the process exits.
+pub async fn serve(port: u16, state: Arc<HealthState>) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    println!("Health checks on port {}", port);
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let (reader, mut writer) = socket.into_split();
+            let mut request_line = String::new();
+            if BufReader::new(reader).read_line(&mut request_line).await.is_err() {
+                return;
+            }
+
+            let mut parts = request_line.split_whitespace();
+            let (status, body) = match (parts.next(), parts.next()) {
+                (Some("GET"), Some("/healthz")) => probe(state.alive.load(Ordering::SeqCst)),
+                (Some("GET"), Some("/readyz")) => probe(state.ready.load(Ordering::SeqCst)),
+                _ => ("404 Not Found", "not found\n"),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = writer.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+fn probe(ok: bool) -> (&'static str, &'static str) {
+    if ok {
+        ("200 OK", "ok\n")
+    } else {
+        ("503 Service Unavailable", "unavailable\n")
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Previously submitted input lines, oldest first, persisted across sessions.
+pub struct InputHistory {
+    entries: Vec<String>,
+    max_entries: usize,
+}
+
+impl InputHistory {
+    pub fn new(max_entries: usize) -> Self {
+        InputHistory {
+            entries: Vec::new(),
+            max_entries,
+        }
+    }
+
+    /// Loads history from `path`, starting empty if the file is missing or
+    /// unreadable.
+    pub fn load(path: &Path, max_entries: usize) -> Self {
+        let mut history = InputHistory::new(max_entries);
+        if let Ok(contents) = fs::read_to_string(path) {
+            for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                history.push(line);
+            }
+        }
+        history
+    }
+
+    /// Writes every entry to `path`, one per line, except those mentioning
+    /// one of `secrets`. Multi-line entries are only kept for this session.
+    pub fn save(&self, path: &Path, secrets: &[&str]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = String::new();
+        for entry in self.entries.iter().filter(|entry| !is_secret(entry, secrets) && !entry.contains('\n')) {
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        fs::write(path, contents)
+    }
+
+    /// Records an entry as the newest, dropping an older copy of it and the
+    /// oldest entries beyond the cap.
+    pub fn push(&mut self, entry: &str) {
+        self.entries.retain(|existing| existing != entry);
+        self.entries.push(entry.to_string());
+        if self.entries.len() > self.max_entries {
+            let excess = self.entries.len() - self.max_entries;
+            self.entries.drain(..excess);
+        }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// `$XDG_DATA_HOME/terminal-chat/history`, falling back to
+    /// `~/.local/share/terminal-chat/history`.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(data_dir()?.join("history"))
+    }
+}
+
+/// Where the unsent input line is kept so it survives a crash, next to the
+/// history file.
+pub fn draft_path() -> Option<PathBuf> {
+    Some(data_dir()?.join("draft"))
+}
+
+/// The draft left behind by a previous run, if any.
+pub fn load_draft(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().filter(|draft| !draft.is_empty())
+}
+
+/// Persists `draft`, removing the file once there is nothing to keep.
+pub fn save_draft(path: &Path, draft: &str) -> io::Result<()> {
+    if draft.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, draft)
+}
+
+fn data_dir() -> Option<PathBuf> {
+    let data_dir = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+    Some(data_dir.join("terminal-chat"))
+}
+
+/// No command takes a password or identity key, those come from flags and
+/// files, so what must stay off the disk is the secrets themselves, in
+/// whatever line they were typed or pasted into.
+fn is_secret(entry: &str, secrets: &[&str]) -> bool {
+    secrets.iter().any(|secret| !secret.is_empty() && entry.contains(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_saved_in_one_session_is_loaded_in_the_next() {
+        let path = std::env::temp_dir().join(format!("terminal-chat-history-{}", std::process::id()));
+        let mut history = InputHistory::new(4);
+        for entry in ["hello", "/join #rust", "hello", "my password is hunter2", "two\nlines", "bye"] {
+            history.push(entry);
+        }
+        history.save(&path, &["hunter2"]).unwrap();
+
+        let loaded = InputHistory::load(&path, 4);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.entries(), ["hello", "bye"]);
+
+        // A missing file starts an empty history
+        assert!(InputHistory::load(&path, 4).entries().is_empty());
+    }
+}
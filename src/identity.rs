@@ -0,0 +1,178 @@
+use crate::tls::ChannelBinding;
+use ring::hmac;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The client's long-term X25519 keypair. The secret half stays in the
+/// identity file; the public half is announced to servers on join.
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Identity {
+    fn from_secret(bytes: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        Identity { secret, public }
+    }
+
+    /// `$XDG_CONFIG_HOME/terminal-chat/identity`, falling back to
+    /// `~/.config/terminal-chat/identity`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("terminal-chat").join("identity"))
+    }
+
+    /// Loads the identity kept at `path`, generating and saving a new one
+    /// on first run. An unreadable or corrupt file is replaced, with a
+    /// warning, since the old key can't be recovered from it anyway.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => match parse_key(contents.trim()) {
+                Some(bytes) => return Ok(Identity::from_secret(bytes)),
+                None => eprintln!("Warning: identity file {} is corrupt, generating a new identity", path.display()),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Warning: can't read identity file {} ({}), generating a new identity", path.display(), e),
+        }
+        let mut bytes = [0u8; 32];
+        getrandom::fill(&mut bytes).map_err(|e| io::Error::other(e.to_string()))?;
+        let identity = Identity::from_secret(bytes);
+        identity.save(path)?;
+        Ok(identity)
+    }
+
+    /// Writes the secret key, readable by the owner only.
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Replace rather than truncate, so a file left with looser
+        // permissions by something else doesn't keep them
+        let _ = fs::remove_file(path);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        writeln!(file, "{}", to_hex(self.secret.as_bytes()))
+    }
+
+    /// The public key as hex, the form it takes on the wire.
+    pub fn public_key(&self) -> String {
+        to_hex(self.public.as_bytes())
+    }
+
+    /// Answers a server's `Challenge` on the connection `binding` comes
+    /// from, showing we hold the secret key without giving it away.
+    pub fn prove(&self, challenge: &str, binding: ChannelBinding) -> Option<String> {
+        let challenge = PublicKey::from(parse_key(challenge)?);
+        let shared = self.secret.diffie_hellman(&challenge);
+        Some(to_hex(proof(shared.as_bytes(), challenge.as_bytes(), self.public.as_bytes(), binding).as_ref()))
+    }
+}
+
+/// A one-off key the server sends to a client claiming an identity. Only
+/// the holder of the identity's secret key can compute the shared secret
+/// between the two, from which the proof asked for in return is derived.
+pub struct Challenge {
+    secret: StaticSecret,
+}
+
+impl Challenge {
+    pub fn new() -> io::Result<Self> {
+        let mut bytes = [0u8; 32];
+        getrandom::fill(&mut bytes).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Challenge { secret: StaticSecret::from(bytes) })
+    }
+
+    /// The challenge as sent to the client.
+    pub fn public_key(&self) -> String {
+        to_hex(PublicKey::from(&self.secret).as_bytes())
+    }
+
+    /// Whether `answer` is what the holder of `public_key` would answer on
+    /// the connection `binding` comes from.
+    pub fn verify(&self, public_key: &str, answer: &str, binding: ChannelBinding) -> bool {
+        let Some(public_key) = parse_key(public_key) else {
+            return false;
+        };
+        let shared = self.secret.diffie_hellman(&PublicKey::from(public_key));
+        let expected = to_hex(proof(shared.as_bytes(), PublicKey::from(&self.secret).as_bytes(), &public_key, binding).as_ref());
+        // Compared in full whatever the input, so timing says nothing
+        expected.len() == answer.len() && expected.bytes().zip(answer.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+/// The proof for one challenge: an HMAC-SHA256 keyed with the shared
+/// secret over the challenge, the identity and the TLS channel binding.
+/// Bound that way, a server can't relay another server's challenge to a
+/// client and pass the answer off there, as the two TLS sessions differ.
+/// Plain connections have no binding, and so no such protection.
+fn proof(shared: &[u8; 32], challenge: &[u8; 32], identity: &[u8; 32], binding: ChannelBinding) -> hmac::Tag {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, shared);
+    let mut context = hmac::Context::with_key(&key);
+    context.update(b"terminal-chat identity proof v1");
+    context.update(challenge);
+    context.update(identity);
+    if let Some(binding) = binding {
+        context.update(&binding);
+    }
+    context.sign()
+}
+
+/// Decodes a 32 byte key written as 64 hex digits.
+pub fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proof_verifies_on_the_same_connection_only() {
+        let identity = Identity::from_secret([7u8; 32]);
+        let challenge = Challenge::new().unwrap();
+        let binding = Some([1u8; 32]);
+
+        let answer = identity.prove(&challenge.public_key(), binding).unwrap();
+        assert!(challenge.verify(&identity.public_key(), &answer, binding));
+        // Relayed from another TLS session, or onto a plain one
+        assert!(!challenge.verify(&identity.public_key(), &answer, Some([2u8; 32])));
+        assert!(!challenge.verify(&identity.public_key(), &answer, None));
+    }
+
+    #[test]
+    fn proof_is_not_the_bare_shared_secret() {
+        let identity = Identity::from_secret([7u8; 32]);
+        let challenge = Challenge::new().unwrap();
+        let shared = identity.secret.diffie_hellman(&PublicKey::from(parse_key(&challenge.public_key()).unwrap()));
+
+        assert!(!challenge.verify(&identity.public_key(), &to_hex(shared.as_bytes()), None));
+        let answer = identity.prove(&challenge.public_key(), None).unwrap();
+        assert!(challenge.verify(&identity.public_key(), &answer, None));
+        // Someone else's key gets nowhere
+        let other = Identity::from_secret([9u8; 32]);
+        assert!(!challenge.verify(&other.public_key(), &answer, None));
+    }
+}
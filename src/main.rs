@@ -1,11 +1,31 @@
-use clap::{Parser, Subcommand};
+use auth::{Authenticator, NoAuth, StaticPassword, TokenFile};
+use clap::builder::{BoolValueParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 mod message;
 mod server;
 mod client;
 mod ui;
 mod file_transfer;
+mod file_cache;
+mod health;
+mod history;
+mod auth;
+mod netsim;
+mod identity;
+mod chat_log;
+mod tls;
+mod framing;
+mod gaps;
 
 #[derive(Parser)]
 #[command(name = "terminal-chat")]
@@ -18,10 +38,110 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start a chat server
+    ///
+    /// Every option can also be set through a `TERMCHAT_<OPTION>` environment
+    /// variable (e.g. `TERMCHAT_PORT`, `TERMCHAT_SLOW_MODE`); list options take
+    /// comma-separated values. Command-line flags take precedence over the
+    /// environment, which takes precedence over the defaults.
     Server {
         /// Port to listen on
-        #[arg(short, long, default_value = "8080")]
+        #[arg(short, long, default_value = "8080", env = "TERMCHAT_PORT")]
         port: u16,
+        /// What to do when a username is already taken
+        #[arg(long, value_enum, default_value = "reject", env = "TERMCHAT_DEDUP_USERNAMES")]
+        dedup_usernames: server::DedupMode,
+        /// Maximum total size of cached shared files, in bytes
+        #[arg(long, default_value = "104857600", env = "TERMCHAT_FILE_CACHE_SIZE")]
+        file_cache_size: u64,
+        /// Seconds a shared file stays available for download
+        #[arg(long, default_value = "3600", env = "TERMCHAT_FILE_CACHE_TTL")]
+        file_cache_ttl: u64,
+        /// Serve HTTP /healthz and /readyz probes on this port
+        #[arg(long, env = "TERMCHAT_HEALTH_PORT")]
+        health_port: Option<u16>,
+        /// Maximum number of usernames per /who page
+        #[arg(long, default_value = "50", env = "TERMCHAT_WHO_PAGE_SIZE")]
+        who_page_size: usize,
+        /// Assign a role tag to a user, as NAME=ROLE (repeatable)
+        #[arg(long = "role", value_name = "NAME=ROLE", value_parser = parse_assignment, env = "TERMCHAT_ROLES", value_delimiter = ',')]
+        roles: Vec<(String, String)>,
+        /// Assign a display color to a user, as NAME=COLOR (repeatable)
+        #[arg(long = "user-color", value_name = "NAME=COLOR", value_parser = parse_user_color, env = "TERMCHAT_USER_COLORS", value_delimiter = ',')]
+        user_colors: Vec<(String, String)>,
+        /// Echo each client's messages back to it instead of broadcasting (for testing)
+        #[arg(long, env = "TERMCHAT_ECHO")]
+        echo: bool,
+        /// Minimum seconds between messages from each user (0 disables)
+        #[arg(long, default_value = "0", env = "TERMCHAT_SLOW_MODE")]
+        slow_mode: u64,
+        /// Messages per second each client may send on average; more are
+        /// dropped (off by default, 0 disables)
+        #[arg(long, value_name = "PER_SEC", default_value = "0", env = "TERMCHAT_RATE_LIMIT")]
+        rate_limit: f64,
+        /// Messages a client may send in a quick burst before the rate limit applies
+        #[arg(long, value_name = "MESSAGES", default_value = "10", env = "TERMCHAT_RATE_BURST")]
+        rate_burst: u32,
+        /// Cap the members of a room, as ROOM=COUNT (repeatable)
+        #[arg(long = "room-max-members", value_name = "ROOM=COUNT", value_parser = parse_room_members, env = "TERMCHAT_ROOM_MAX_MEMBERS", value_delimiter = ',')]
+        room_max_members: Vec<(String, usize)>,
+        /// Messages per second each member may send in a room, overriding
+        /// --rate-limit there, as ROOM=PER_SEC (repeatable)
+        #[arg(long = "room-rate-limit", value_name = "ROOM=PER_SEC", value_parser = parse_room_rate, env = "TERMCHAT_ROOM_RATE_LIMITS", value_delimiter = ',')]
+        room_rate_limits: Vec<(String, f64)>,
+        /// Seconds between heartbeat pings to each client (0 disables)
+        #[arg(long, value_name = "SECS", default_value = "30", env = "TERMCHAT_HEARTBEAT_INTERVAL")]
+        heartbeat_interval: u64,
+        /// Heartbeats a client may leave unanswered before it is disconnected
+        #[arg(long, default_value = "3", env = "TERMCHAT_HEARTBEAT_MISSES")]
+        heartbeat_misses: u32,
+        /// Recent messages of each room to replay to whoever joins it (0 disables)
+        #[arg(long, value_name = "MESSAGES", default_value = "200", env = "TERMCHAT_HISTORY_SIZE")]
+        history_size: usize,
+        /// Append every broadcast message, with its room, to this JSONL
+        /// file, and reload each room's history from it on startup
+        #[arg(long, value_name = "PATH", env = "TERMCHAT_LOG_FILE")]
+        log_file: Option<PathBuf>,
+        /// Disconnect clients that violate the protocol instead of tolerating it
+        #[arg(long, env = "TERMCHAT_STRICT")]
+        strict: bool,
+        /// Require every client to send this password
+        #[arg(long, env = "TERMCHAT_AUTH_PASSWORD", conflicts_with = "auth_token_file")]
+        auth_password: Option<String>,
+        /// Require a per-user token listed as `username token` lines in this file
+        #[arg(long, env = "TERMCHAT_AUTH_TOKEN_FILE")]
+        auth_token_file: Option<PathBuf>,
+        /// Turn away new connections while this many are connected
+        #[arg(long, env = "TERMCHAT_MAX_CLIENTS")]
+        max_clients: Option<usize>,
+        /// Accept only TLS connections, presenting this PEM certificate chain
+        #[arg(long, value_name = "PATH", env = "TERMCHAT_TLS_CERT", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key for `--tls-cert`
+        #[arg(long, value_name = "PATH", env = "TERMCHAT_TLS_KEY", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Also accept clients using length-prefixed MessagePack frames
+        /// (`client --binary`) alongside newline-delimited JSON
+        #[arg(long, env = "TERMCHAT_BINARY")]
+        binary: bool,
+        /// Share this zstd dictionary, made by `train-dictionary`, with every
+        /// client to compress files with
+        #[arg(long, value_name = "PATH", env = "TERMCHAT_ZSTD_DICTIONARY")]
+        zstd_dictionary: Option<PathBuf>,
+        #[command(flatten)]
+        netsim: netsim::NetSimArgs,
+    },
+    /// Train a zstd dictionary for `server --zstd-dictionary` on sample
+    /// files like the ones users will share, e.g. logs or JSON
+    TrainDictionary {
+        /// Where to save the dictionary
+        #[arg(short, long, value_name = "PATH")]
+        output: PathBuf,
+        /// Largest dictionary to make, in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = file_transfer::MAX_DICTIONARY_SIZE, value_parser = parse_dictionary_size)]
+        max_size: usize,
+        /// Sample files to train on; the more, the better
+        #[arg(required = true, value_name = "SAMPLE")]
+        samples: Vec<PathBuf>,
     },
     /// Connect to a chat server
     Client {
@@ -34,23 +154,349 @@ enum Commands {
         /// Your username
         #[arg(short, long)]
         username: String,
+        /// Blank the screen to a clock after this many seconds without
+        /// input or new messages
+        #[arg(long, value_name = "SECS")]
+        screensaver: Option<u64>,
+        /// Seconds to wait before reconnecting after the connection drops,
+        /// doubling on each failed attempt; 0 disables reconnecting
+        #[arg(long, value_name = "SECS", default_value = "1")]
+        reconnect_delay: u64,
+        /// Longest wait between reconnect attempts, in seconds
+        #[arg(long, value_name = "SECS", default_value = "30")]
+        reconnect_max_delay: u64,
+        /// Leave out the separator lines to fit more messages on screen
+        #[arg(long)]
+        compact: bool,
+        /// Show your own messages right-aligned
+        #[arg(long)]
+        bubble: bool,
+        /// When to draw in color; `auto` honors NO_COLOR and CLICOLOR_FORCE
+        #[arg(long, value_enum, default_value = "auto")]
+        color: ui::ColorChoice,
+        /// Same as `--color never`
+        #[arg(long, conflicts_with = "color")]
+        no_color: bool,
+        /// Password or token to send if the server requires one
+        #[arg(long, env = "TERMCHAT_CREDENTIAL")]
+        credential: Option<String>,
+        /// How to arrange downloaded files by sender
+        #[arg(long, value_enum, default_value = "flat")]
+        download_layout: file_transfer::DownloadLayout,
+        /// Replace existing downloads of the same name instead of saving
+        /// the new file as `name (1).ext`
+        #[arg(long)]
+        overwrite: bool,
+        /// Split outgoing messages longer than this many characters, at word boundaries
+        #[arg(long, value_name = "CHARS")]
+        max_line_width: Option<usize>,
+        /// What Enter does on an empty input line
+        #[arg(long, value_enum, default_value = "none")]
+        empty_enter: ui::EmptyEnter,
+        /// Largest file to send or accept, in bytes
+        #[arg(long, value_name = "BYTES", default_value = "52428800")]
+        max_file_size: u64,
+        /// Connect over TLS, trusting the usual public certificate authorities
+        #[arg(long)]
+        tls: bool,
+        /// Connect over TLS, trusting only the certificates in this PEM file,
+        /// e.g. a self-signed server certificate
+        #[arg(long, value_name = "PATH")]
+        ca_cert: Option<PathBuf>,
+        /// How to show message times, as a strftime pattern in local time
+        #[arg(long, value_name = "FORMAT", default_value = "%H:%M:%S", value_parser = parse_time_format)]
+        time_format: String,
+        /// Send and receive length-prefixed MessagePack frames instead of
+        /// JSON lines; the server must be started with `--binary`
+        #[arg(long)]
+        binary: bool,
+        #[command(flatten)]
+        netsim: netsim::NetSimArgs,
     },
 }
 
+impl Cli {
+    /// Parses `args` as clap would, but looks each option's environment
+    /// variable up through `env` instead of reading the process
+    /// environment, so tests can supply their own. Flags still take
+    /// precedence over the environment, and the environment over defaults.
+    fn try_parse_with_env(
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+        env: impl Fn(&OsStr) -> Option<OsString>,
+    ) -> Result<Cli, clap::Error> {
+        let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let command = Cli::command();
+        // A first pass only finds out which options were given as flags;
+        // errors such as a `requires` met from the environment wait for the second
+        let lenient = command.clone().ignore_errors(true).mut_subcommands(|sub| sub.ignore_errors(true));
+        let given = lenient.try_get_matches_from(&args)?;
+        let mut built = command.clone();
+        built.build();
+        if let Some((name, flags)) = given.subcommand() {
+            let subcommand = built.find_subcommand(name).expect("matched subcommands exist");
+            for arg in subcommand.get_arguments() {
+                let (Some(var), Some(long)) = (arg.get_env(), arg.get_long()) else {
+                    continue;
+                };
+                if flags.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+                    continue;
+                }
+                let Some(value) = env(var) else {
+                    continue;
+                };
+                if arg.get_action().takes_values() {
+                    args.extend([format!("--{}", long).into(), value]);
+                } else {
+                    // Switches take `true` or `false` from the environment
+                    if BoolValueParser::new().parse_ref(subcommand, Some(arg), &value)? {
+                        args.push(format!("--{}", long).into());
+                    }
+                }
+            }
+        }
+        let without_env = command.clone().mut_subcommands(|sub| {
+            let ids: Vec<clap::Id> = sub.get_arguments().filter(|arg| arg.get_env().is_some()).map(|arg| arg.get_id().clone()).collect();
+            ids.into_iter().fold(sub, |sub, id| sub.mut_arg(id, |arg| arg.env(None::<&str>)))
+        });
+        let matches = without_env.try_get_matches_from(&args).map_err(|e| match e.kind() {
+            // Help comes from the full command, which lists each option's variable
+            ErrorKind::DisplayHelp | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand => {
+                command.try_get_matches_from(&args).err().unwrap_or(e)
+            }
+            _ => e,
+        })?;
+        Cli::from_arg_matches(&matches)
+    }
+}
+
+fn parse_assignment(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, assigned)) if !name.is_empty() && !assigned.is_empty() => {
+            Ok((name.to_string(), assigned.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, got '{}'", value)),
+    }
+}
+
+fn parse_user_color(value: &str) -> Result<(String, String), String> {
+    let (name, color) = parse_assignment(value)?;
+    if ui::ansi_color(&color).is_none() {
+        return Err(format!("unknown color '{}', expected one of {}", color, ui::COLOR_NAMES.join(", ")));
+    }
+    Ok((name, color))
+}
+
+fn parse_room_members(value: &str) -> Result<(String, usize), String> {
+    let (room, count) = parse_assignment(value)?;
+    match count.parse() {
+        Ok(count) if count > 0 => Ok((room, count)),
+        _ => Err(format!("expected a positive member count, got '{}'", count)),
+    }
+}
+
+fn parse_room_rate(value: &str) -> Result<(String, f64), String> {
+    let (room, rate) = parse_assignment(value)?;
+    match rate.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate >= 0.0 => Ok((room, rate)),
+        _ => Err(format!("expected messages per second, got '{}'", rate)),
+    }
+}
+
+fn parse_dictionary_size(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(size) if size <= file_transfer::MAX_DICTIONARY_SIZE => Ok(size),
+        Ok(_) => Err(format!("dictionaries can be at most {} bytes", file_transfer::MAX_DICTIONARY_SIZE)),
+        Err(_) => Err(format!("expected a size in bytes, got '{}'", value)),
+    }
+}
+
+fn parse_time_format(value: &str) -> Result<String, String> {
+    chrono::format::StrftimeItems::new(value)
+        .parse()
+        .map_err(|_| format!("invalid time format '{}'", value))?;
+    Ok(value.to_string())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+    let cli = Cli::try_parse_with_env(std::env::args_os(), |name| std::env::var_os(name)).unwrap_or_else(|e| e.exit());
 
     match cli.command {
-        Commands::Server { port } => {
+        Commands::Server { port, dedup_usernames, file_cache_size, file_cache_ttl, health_port, who_page_size, roles, user_colors, echo, slow_mode, rate_limit, rate_burst, room_max_members, room_rate_limits, heartbeat_interval, heartbeat_misses, history_size, log_file, strict, auth_password, auth_token_file, max_clients, tls_cert, tls_key, binary, zstd_dictionary, netsim } => {
             println!("Starting server on port {}", port);
-            server::start_server(port).await?;
+            let auth: Arc<dyn Authenticator> = match (auth_password, auth_token_file) {
+                (Some(password), _) => Arc::new(StaticPassword::new(password)),
+                (None, Some(path)) => Arc::new(TokenFile::new(path)),
+                (None, None) => Arc::new(NoAuth),
+            };
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => Some(tls::server_config(&cert, &key)?),
+                _ => None,
+            };
+            let zstd_dictionary = match zstd_dictionary {
+                Some(path) => Some(file_transfer::FileTransfer::load_dictionary(&path)?.into()),
+                None => None,
+            };
+            let mut room_limits: HashMap<String, server::RoomLimits> = HashMap::new();
+            for (room, max) in room_max_members {
+                room_limits.entry(room).or_default().max_members = Some(max);
+            }
+            for (room, rate) in room_rate_limits {
+                room_limits.entry(room).or_default().rate_limit = Some(rate);
+            }
+            server::start_server(server::ServerConfig {
+                port,
+                dedup_usernames,
+                file_cache_size,
+                file_cache_ttl: Duration::from_secs(file_cache_ttl),
+                health_port,
+                who_page_size,
+                roles: roles.into_iter().collect(),
+                user_colors: user_colors.into_iter().collect(),
+                echo,
+                slow_mode,
+                rate_limit,
+                rate_burst,
+                room_limits,
+                strict,
+                auth,
+                max_clients,
+                netsim: netsim.into_sim(),
+                heartbeat_interval: Some(Duration::from_secs(heartbeat_interval)).filter(|interval| !interval.is_zero()),
+                heartbeat_misses,
+                history_size,
+                log_file,
+                tls,
+                binary,
+                zstd_dictionary,
+            }).await?;
         }
-        Commands::Client { address, port, username } => {
+        Commands::TrainDictionary { output, max_size, samples } => {
+            let samples = samples
+                .iter()
+                .map(|path| std::fs::read(path).map_err(|e| format!("can't read {}: {}", path.display(), e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let dictionary = file_transfer::FileTransfer::train_dictionary(&samples, max_size)
+                .map_err(|e| format!("can't train a dictionary on these samples: {}", e))?;
+            std::fs::write(&output, &dictionary)?;
+            println!("Saved a {} byte dictionary to {}", dictionary.len(), output.display());
+        }
+        Commands::Client { address, port, username, screensaver, reconnect_delay, reconnect_max_delay, compact, bubble, color, no_color, credential, download_layout, overwrite, max_line_width, empty_enter, max_file_size, tls, ca_cert, time_format, binary, netsim } => {
             println!("Connecting to {}:{} as {}", address, port, username);
-            client::start_client(&address, port, &username).await?;
+            let tls = match (tls, ca_cert) {
+                (_, Some(ca_cert)) => Some(tls::client_config(Some(&ca_cert))?),
+                (true, None) => Some(tls::client_config(None)?),
+                (false, None) => None,
+            };
+            // Chat still works without an identity, it just isn't announced
+            let identity = identity::Identity::default_path().and_then(|path| match identity::Identity::load_or_create(&path) {
+                Ok(identity) => Some(identity),
+                Err(e) => {
+                    eprintln!("Warning: can't save identity to {}: {}", path.display(), e);
+                    None
+                }
+            });
+            let result = client::start_client(&address, port, &username, credential.as_deref(), ui::UiConfig {
+                screensaver_after: screensaver.map(Duration::from_secs),
+                reconnect_delay: Some(Duration::from_secs(reconnect_delay)).filter(|delay| !delay.is_zero()),
+                reconnect_max_delay: Duration::from_secs(reconnect_max_delay),
+                compact,
+                bubble,
+                download_layout,
+                overwrite,
+                max_line_width,
+                empty_enter,
+                netsim: netsim.into_sim(),
+                identity: identity.map(Arc::new),
+                color: if no_color { ui::ColorChoice::Never } else { color }.enabled(
+                    std::env::var_os("NO_COLOR").as_deref(),
+                    std::env::var_os("CLICOLOR_FORCE").as_deref(),
+                    std::io::stdout().is_terminal(),
+                ),
+                max_file_size,
+                tls,
+                time_format,
+                framing: if binary { framing::Framing::Binary } else { framing::Framing::Json },
+            }).await;
+            // A refused join is reported plainly, with an exit code per reason
+            if let Err(e) = result {
+                if let Some(rejection) = e.downcast_ref::<client::Rejection>() {
+                    eprintln!("{}", rejection);
+                    std::process::exit(rejection.exit_code());
+                }
+                return Err(e);
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `args` as the server's command line with only `vars` set.
+    fn server_with_env(args: &[&str], vars: &[(&str, &str)]) -> Result<Commands, clap::Error> {
+        let env = |name: &OsStr| vars.iter().find(|(var, _)| name == *var).map(|(_, value)| OsString::from(value));
+        let args = ["terminal-chat", "server"].iter().chain(args);
+        Ok(Cli::try_parse_with_env(args, env)?.command)
+    }
+
+    #[test]
+    fn rate_limiting_is_opt_in() {
+        let Commands::Server { rate_limit, .. } = server_with_env(&[], &[]).unwrap() else {
+            panic!("parsed as the server");
+        };
+        assert_eq!(rate_limit, 0.0);
+        let Commands::Server { rate_limit, .. } = server_with_env(&["--rate-limit", "5"], &[]).unwrap() else {
+            panic!("parsed as the server");
+        };
+        assert_eq!(rate_limit, 5.0);
+    }
+
+    #[test]
+    fn port_comes_from_the_environment_unless_given_as_a_flag() {
+        let env = [("TERMCHAT_PORT", "9191")];
+        let Commands::Server { port, .. } = server_with_env(&[], &env).unwrap() else {
+            panic!("parsed as the server");
+        };
+        assert_eq!(port, 9191);
+        let Commands::Server { port, .. } = server_with_env(&["--port", "7000"], &env).unwrap() else {
+            panic!("parsed as the server");
+        };
+        assert_eq!(port, 7000);
+    }
+
+    #[test]
+    fn environment_fills_lists_switches_and_required_pairs() {
+        let env = [
+            ("TERMCHAT_ROLES", "ana=admin,bob=mod"),
+            ("TERMCHAT_STRICT", "true"),
+            ("TERMCHAT_ECHO", "false"),
+            ("TERMCHAT_TLS_KEY", "key.pem"),
+        ];
+        let Commands::Server { roles, strict, echo, tls_cert, tls_key, .. } = server_with_env(&["--tls-cert", "cert.pem"], &env).unwrap() else {
+            panic!("parsed as the server");
+        };
+        assert_eq!(roles, [("ana".to_string(), "admin".to_string()), ("bob".to_string(), "mod".to_string())]);
+        assert!(strict && !echo);
+        assert_eq!((tls_cert, tls_key), (Some(PathBuf::from("cert.pem")), Some(PathBuf::from("key.pem"))));
+
+        // Values are checked as strictly as flags, and pairs still need both halves
+        assert!(server_with_env(&[], &[("TERMCHAT_ECHO", "yes")]).is_err());
+        assert!(server_with_env(&[], &[("TERMCHAT_PORT", "eighty")]).is_err());
+        assert!(server_with_env(&[], &[("TERMCHAT_TLS_CERT", "cert.pem")]).is_err());
+    }
+
+    #[test]
+    fn time_format_is_checked_when_parsed() {
+        let parse = |format: &str| Cli::try_parse_from(["terminal-chat", "client", "-u", "ana", "--time-format", format]);
+        let Commands::Client { time_format, .. } = parse("%d %b %H:%M").unwrap().command else {
+            panic!("parsed as the client");
+        };
+        assert_eq!(time_format, "%d %b %H:%M");
+        let err = parse("%Q").err().expect("an unknown specifier is refused");
+        assert!(err.to_string().contains("invalid time format '%Q'"), "{}", err);
+    }
+}
@@ -7,13 +7,141 @@ pub enum Message {
         username: String,
         content: String,
         timestamp: SystemTime,
+        /// Server-assigned role of the author, e.g. "admin"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        role: Option<String>,
+        /// Server-assigned color name for the author, e.g. "cyan"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color: Option<String>,
+        /// Id chosen by the sending client and echoed back by the server so
+        /// the sender can match the broadcast to its pending message
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        local_id: Option<String>,
+        /// Seconds after which clients remove the message, for `/ephemeral`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+        /// Assigned by the server when it broadcasts the message, so it can
+        /// be referred to later
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        /// Position in the room's chat, counting from 1, given by the server
+        /// to chat it keeps for replay, so clients can tell when they
+        /// missed some
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        /// Sent with `/me`, shown as `* alice waves`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        action: bool,
     },
     File {
         username: String,
         filename: String,
+        /// Size of the file itself, before any compression
         size: u64,
+        #[serde(with = "serde_bytes")]
         data: Vec<u8>,
         timestamp: SystemTime,
+        /// Transfer id, set when the payload is served in reply to a fetch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        /// CRC-32 of `data`, so a damaged payload can be told apart; absent
+        /// from older peers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        crc32: Option<u32>,
+        /// How `data` is compressed, if it is; `crc32` is of the compressed
+        /// bytes
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec: Option<Codec>,
+        /// Length of `data` when it is compressed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compressed_size: Option<u64>,
+    },
+    /// Announces a shared file; the payload is fetched on demand by id.
+    /// `size` is the file's own size, however it travels.
+    FileAvailable {
+        id: String,
+        filename: String,
+        size: u64,
+        sender: String,
+        timestamp: SystemTime,
+    },
+    /// Opens a chunked file transfer: `total` `FileChunk`s follow, then a
+    /// `FileEnd`.
+    FileStart {
+        transfer_id: String,
+        username: String,
+        filename: String,
+        /// Size of the file itself, before any compression
+        size: u64,
+        total: u64,
+        timestamp: SystemTime,
+        /// Id of the shared file, set when the server streams it in reply to
+        /// a fetch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        /// How the payload is compressed, if it is
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec: Option<Codec>,
+        /// Bytes the chunks add up to when the payload is compressed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compressed_size: Option<u64>,
+    },
+    /// One piece of a chunked transfer; `seq` counts from 0 to `total - 1`
+    /// and chunks may arrive in any order.
+    FileChunk {
+        transfer_id: String,
+        seq: u64,
+        total: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// Closes a chunked transfer once every chunk has been sent.
+    FileEnd {
+        transfer_id: String,
+        timestamp: SystemTime,
+        /// CRC-32 of the whole payload, worked out as it was sent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        crc32: Option<u32>,
+    },
+    /// Reply to a fetch for a file the server no longer has.
+    FileUnavailable {
+        id: String,
+        timestamp: SystemTime,
+    },
+    /// Replaces the content of the chat message with id `target_id`. Sent by
+    /// its author; the server passes it on only if the author matches.
+    Edit {
+        target_id: String,
+        new_content: String,
+        timestamp: SystemTime,
+    },
+    /// Removes the chat message with id `target_id`, under the same rules
+    /// as `Edit`.
+    Delete {
+        target_id: String,
+        timestamp: SystemTime,
+    },
+    /// Asks the server again for the chat of `room` numbered `from` to
+    /// `to`, which the client missed.
+    Backfill {
+        room: String,
+        from: u64,
+        to: u64,
+    },
+    /// Reply to a `Backfill` for chat `from` to `to` that the server no
+    /// longer keeps.
+    BackfillUnavailable {
+        room: String,
+        from: u64,
+        to: u64,
+        timestamp: SystemTime,
+    },
+    /// A direct message, delivered to the recipient and echoed to the sender.
+    Private {
+        from: String,
+        to: String,
+        content: String,
+        timestamp: SystemTime,
     },
     UserJoined {
         username: String,
@@ -23,30 +151,273 @@ pub enum Message {
         username: String,
         timestamp: SystemTime,
     },
+    /// Everyone online, sorted, each name once. Sent to a client when it
+    /// joins and when it asks with `/users`; `UserJoined` and `UserLeft`
+    /// keep it current from there.
+    UserList {
+        users: Vec<String>,
+        timestamp: SystemTime,
+        /// Those of `users` who are `/away`; resent whenever it changes
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        away: Vec<String>,
+    },
+    /// Tells a client which room it is in: sent after the welcome and each
+    /// time `/join` or `/part` moves it. Chat only reaches clients in the
+    /// same room.
+    RoomJoined {
+        room: String,
+        timestamp: SystemTime,
+    },
+    /// Tells the author of chat message `id` that it was handed on to the
+    /// `recipients` others in its room.
+    Delivered {
+        id: String,
+        recipients: usize,
+        timestamp: SystemTime,
+    },
+    /// A user's long-term public key, hex encoded. Clients send their own
+    /// after joining; the server passes it on under the sender's name.
+    Identity {
+        username: String,
+        public_key: String,
+        timestamp: SystemTime,
+    },
+    /// The server's reply to an `Identity` from a client: a one-off public
+    /// key the client must answer with `IdentityProof` before the identity
+    /// is accepted.
+    IdentityChallenge {
+        challenge: String,
+        timestamp: SystemTime,
+    },
+    /// The answer to an `IdentityChallenge`, hex encoded: derived from the
+    /// shared secret between the challenge and the identity key, which only
+    /// the key's owner can compute, and bound to the TLS session.
+    IdentityProof {
+        proof: String,
+        timestamp: SystemTime,
+    },
     System {
         content: String,
         timestamp: SystemTime,
     },
+    /// Sent instead of `Welcome` when the server turns a client away; the
+    /// connection closes after it.
+    Rejected {
+        reason: RejectReason,
+        detail: String,
+        timestamp: SystemTime,
+    },
+    /// Sent right after the username when the server wants a credential
+    /// line before letting the client in.
+    AuthRequired {
+        timestamp: SystemTime,
+    },
+    Welcome {
+        username: String,
+        timestamp: SystemTime,
+        /// What the server supports; absent from older servers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server: Option<ServerInfo>,
+    },
+    /// A zstd dictionary for file payloads, sent by a server that has one
+    /// right after its welcome. Senders compress with it and receivers
+    /// need it to decompress.
+    ZstdDictionary {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// Sent by the server every heartbeat interval; clients answer with
+    /// `Pong` so a silently dropped connection can be told from an idle one.
+    Ping {
+        timestamp: SystemTime,
+    },
+    Pong {
+        timestamp: SystemTime,
+    },
+    /// A message kind introduced by a newer peer, kept as its raw JSON so
+    /// the stream can carry on past it. Never sent.
+    #[serde(skip)]
+    Unknown {
+        raw: String,
+    },
+}
+
+/// Why a server refused a join.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RejectReason {
+    Full,
+    AuthFailed,
+    InvalidUsername,
+    /// Someone is already connected under the requested name
+    UsernameTaken,
+    /// Sent mid-session: the same user signed in from another connection
+    Replaced,
+    /// A reason this build doesn't know about
+    #[serde(other)]
+    Other,
+}
+
+/// How a file payload is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    /// Zstandard, with the server's dictionary if it shared one
+    Zstd,
+    /// A codec this build doesn't know about
+    #[serde(other)]
+    Other,
+}
+
+/// Version of the client/server message protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Capabilities a server announces in its welcome.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub protocol: u32,
+    /// Optional features and commands enabled on this server, e.g. "files"
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Largest file payload the server will share, in bytes
+    pub max_file_size: u64,
 }
 
 impl Message {
-    pub fn new_text(username: String, content: String) -> Self {
+    pub fn new_text(
+        username: String,
+        content: String,
+        role: Option<String>,
+        color: Option<String>,
+    ) -> Self {
         Message::Text {
             username,
             content,
             timestamp: SystemTime::now(),
+            role,
+            color,
+            local_id: None,
+            ttl: None,
+            id: None,
+            seq: None,
+            action: false,
         }
     }
 
+    /// A file of `size` bytes whose payload `data` is compressed with
+    /// `codec`, or is the file as it is.
     #[allow(dead_code)]
-    pub fn new_file(username: String, filename: String, data: Vec<u8>) -> Self {
-        let size = data.len() as u64;
+    pub fn new_file(username: String, filename: String, size: u64, data: Vec<u8>, codec: Option<Codec>) -> Self {
+        let crc32 = Some(crc32fast::hash(&data));
+        let compressed_size = codec.map(|_| data.len() as u64);
         Message::File {
             username,
             filename,
             size,
             data,
             timestamp: SystemTime::now(),
+            id: None,
+            crc32,
+            codec,
+            compressed_size,
+        }
+    }
+
+    pub fn new_file_available(id: String, filename: String, size: u64, sender: String) -> Self {
+        Message::FileAvailable {
+            id,
+            filename,
+            size,
+            sender,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_file_unavailable(id: String) -> Self {
+        Message::FileUnavailable {
+            id,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_file_start(
+        transfer_id: String,
+        username: String,
+        filename: String,
+        size: u64,
+        total: u64,
+        id: Option<String>,
+        codec: Option<Codec>,
+        compressed_size: Option<u64>,
+    ) -> Self {
+        Message::FileStart {
+            transfer_id,
+            username,
+            filename,
+            size,
+            total,
+            timestamp: SystemTime::now(),
+            id,
+            codec,
+            compressed_size,
+        }
+    }
+
+    pub fn new_file_end(transfer_id: String, crc32: Option<u32>) -> Self {
+        Message::FileEnd {
+            transfer_id,
+            timestamp: SystemTime::now(),
+            crc32,
+        }
+    }
+
+    pub fn new_edit(target_id: String, new_content: String) -> Self {
+        Message::Edit {
+            target_id,
+            new_content,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_delete(target_id: String) -> Self {
+        Message::Delete {
+            target_id,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_backfill(room: String, from: u64, to: u64) -> Self {
+        Message::Backfill { room, from, to }
+    }
+
+    pub fn new_backfill_unavailable(room: String, from: u64, to: u64) -> Self {
+        Message::BackfillUnavailable {
+            room,
+            from,
+            to,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_private(from: String, to: String, content: String) -> Self {
+        Message::Private {
+            from,
+            to,
+            content,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_ping() -> Self {
+        Message::Ping {
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_pong() -> Self {
+        Message::Pong {
+            timestamp: SystemTime::now(),
         }
     }
 
@@ -64,6 +435,15 @@ impl Message {
         }
     }
 
+    pub fn new_user_list(users: Vec<String>, away: Vec<String>) -> Self {
+        Message::UserList {
+            users,
+            timestamp: SystemTime::now(),
+            away,
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn new_system(content: String) -> Self {
         Message::System {
             content,
@@ -71,11 +451,172 @@ impl Message {
         }
     }
 
+    pub fn new_rejected(reason: RejectReason, detail: String) -> Self {
+        Message::Rejected {
+            reason,
+            detail,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_auth_required() -> Self {
+        Message::AuthRequired {
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_delivered(id: String, recipients: usize) -> Self {
+        Message::Delivered {
+            id,
+            recipients,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_room_joined(room: String) -> Self {
+        Message::RoomJoined {
+            room,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_identity(username: String, public_key: String) -> Self {
+        Message::Identity {
+            username,
+            public_key,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_identity_challenge(challenge: String) -> Self {
+        Message::IdentityChallenge {
+            challenge,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_identity_proof(proof: String) -> Self {
+        Message::IdentityProof {
+            proof,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn new_welcome(username: String, server: ServerInfo) -> Self {
+        Message::Welcome {
+            username,
+            timestamp: SystemTime::now(),
+            server: Some(server),
+        }
+    }
+
+    pub fn new_zstd_dictionary(data: Vec<u8>) -> Self {
+        Message::ZstdDictionary { data }
+    }
+
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
     }
 
     pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(json)
+        serde_json::from_str(json).or_else(|err| {
+            // Only an unrecognized tag is tolerated; a known kind with bad
+            // fields is still an error
+            let kind = match serde_json::from_str::<serde_json::Value>(json) {
+                Ok(serde_json::Value::Object(fields)) if fields.len() == 1 => fields.into_iter().next().map(|(kind, _)| kind),
+                Ok(serde_json::Value::String(kind)) => Some(kind),
+                _ => None,
+            };
+            match kind {
+                Some(kind) if !Message::kinds().contains(&kind.as_str()) => Ok(Message::Unknown { raw: json.to_string() }),
+                _ => Err(err),
+            }
+        })
+    }
+
+    /// The tags of the kinds this build can read, as serde names them.
+    fn kinds() -> &'static [&'static str] {
+        /// Hands the derived `Deserialize` nothing but records the variant
+        /// names it asks for.
+        struct Probe<'a>(&'a mut &'static [&'static str]);
+
+        impl<'de> serde::Deserializer<'de> for Probe<'_> {
+            type Error = serde::de::value::Error;
+
+            fn deserialize_any<V: serde::de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+                Err(serde::de::Error::custom("only an enum can be probed"))
+            }
+
+            fn deserialize_enum<V: serde::de::Visitor<'de>>(
+                self,
+                _: &'static str,
+                variants: &'static [&'static str],
+                _: V,
+            ) -> Result<V::Value, Self::Error> {
+                *self.0 = variants;
+                Err(serde::de::Error::custom("probed"))
+            }
+
+            serde::forward_to_deserialize_any! {
+                bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+                unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier ignored_any
+            }
+        }
+
+        let mut kinds: &'static [&'static str] = &[];
+        let _ = Message::deserialize(Probe(&mut kinds));
+        kinds
+    }
+}
+
+/// Whether `content` mentions `username` as `@username`, ignoring case. The
+/// mention has to end with the name, so `@bob` isn't found in `@bobby`.
+pub fn mentions(content: &str, username: &str) -> bool {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let content = content.to_lowercase();
+    let mention = format!("@{}", username.to_lowercase());
+    content.match_indices(&mention).any(|(at, _)| {
+        let before = content[..at].chars().next_back();
+        let after = content[at + mention.len()..].chars().next();
+        !before.is_some_and(is_name_char) && !after.is_some_and(is_name_char)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn action_survives_a_round_trip() {
+        let mut waves = Message::new_text("alice".to_string(), "waves".to_string(), None, None);
+        if let Message::Text { action, .. } = &mut waves {
+            *action = true;
+        }
+        let json = waves.to_json().unwrap();
+        match Message::from_json(&json).unwrap() {
+            Message::Text { username, content, action, .. } => {
+                assert_eq!((username.as_str(), content.as_str(), action), ("alice", "waves", true));
+            }
+            other => panic!("expected text, got {:?}", other),
+        }
+
+        // Plain text leaves the flag out, and text without it is no action
+        let plain = Message::new_text("alice".to_string(), "hi".to_string(), None, None).to_json().unwrap();
+        assert!(!plain.contains("action"));
+        assert!(matches!(Message::from_json(&plain).unwrap(), Message::Text { action: false, .. }));
+    }
+
+    #[test]
+    fn only_kinds_this_build_lacks_are_read_as_unknown() {
+        assert!(Message::kinds().contains(&"Text") && Message::kinds().contains(&"Edit"));
+        let future = r#"{"Reaction":{"target_id":"1","emoji":"+1"}}"#;
+        assert!(matches!(Message::from_json(future).unwrap(), Message::Unknown { raw } if raw == future));
+        assert!(matches!(Message::from_json(r#""Shrug""#).unwrap(), Message::Unknown { .. }));
+
+        // A kind we know with fields we can't read is still an error, as is
+        // anything that isn't a tagged message at all
+        assert!(Message::from_json(r#"{"Edit":{"target_id":1}}"#).is_err());
+        assert!(Message::from_json(r#"{"Reaction":{},"Text":{}}"#).is_err());
+        assert!(Message::from_json("[1, 2]").is_err());
     }
 }
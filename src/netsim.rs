@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+/// Hidden command line flags that make a connection behave like a poor
+/// network. For testing only: frames are delayed and dropped on purpose.
+#[derive(Debug, Clone, clap::Args)]
+pub struct NetSimArgs {
+    /// Delay every frame by MS milliseconds, plus up to JITTER more
+    #[arg(long, value_name = "MS[,JITTER]", value_parser = parse_latency, hide = true)]
+    simulate_latency: Option<(u64, u64)>,
+    /// Drop this percentage of frames
+    #[arg(long, value_name = "PCT", value_parser = clap::value_parser!(u8).range(0..=100), hide = true)]
+    simulate_drop: Option<u8>,
+    /// Seed for the simulated jitter and drops, so a run can be repeated
+    #[arg(long, value_name = "SEED", default_value = "1", hide = true)]
+    simulate_seed: u64,
+}
+
+impl NetSimArgs {
+    /// The simulation to run, `None` when no flag asks for one.
+    pub fn into_sim(self) -> Option<NetSim> {
+        if self.simulate_latency.is_none() && self.simulate_drop.is_none() {
+            return None;
+        }
+        let (latency, jitter) = self.simulate_latency.unwrap_or_default();
+        Some(NetSim {
+            latency: Duration::from_millis(latency),
+            jitter: Duration::from_millis(jitter),
+            drop_percent: self.simulate_drop.unwrap_or(0),
+            seed: self.simulate_seed,
+        })
+    }
+}
+
+fn parse_latency(value: &str) -> Result<(u64, u64), String> {
+    let (latency, jitter) = value.split_once(',').unwrap_or((value, "0"));
+    let parse = |ms: &str| ms.trim().parse::<u64>().map_err(|_| format!("expected milliseconds, got {:?}", ms));
+    Ok((parse(latency)?, parse(jitter)?))
+}
+
+/// Latency, jitter and loss applied to each frame passing through a link.
+#[derive(Debug, Clone, Copy)]
+pub struct NetSim {
+    latency: Duration,
+    jitter: Duration,
+    drop_percent: u8,
+    seed: u64,
+}
+
+impl NetSim {
+    /// One direction of a connection; `stream` keeps directions from
+    /// sharing a random sequence.
+    pub fn link(&self, stream: u64) -> Link {
+        Link {
+            sim: *self,
+            // xorshift gets stuck at zero
+            state: (self.seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15)).max(1),
+        }
+    }
+}
+
+/// Frames go through a link one at a time, so delays never reorder them.
+pub struct Link {
+    sim: NetSim,
+    state: u64,
+}
+
+impl Link {
+    /// Waits out the next frame's delay, returning false if it is dropped.
+    pub async fn pass(&mut self) -> bool {
+        if self.next() % 100 < self.sim.drop_percent as u64 {
+            return false;
+        }
+        let jitter_ms = self.sim.jitter.as_millis() as u64;
+        let jitter = Duration::from_millis(if jitter_ms == 0 { 0 } else { self.next() % (jitter_ms + 1) });
+        tokio::time::sleep(self.sim.latency + jitter).await;
+        true
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
@@ -1,121 +1,2834 @@
-use crate::message::Message;
-use std::collections::HashMap;
+use crate::auth::{AuthResult, Authenticator};
+use crate::chat_log::{self, ChatLog};
+use crate::file_cache::FileCache;
+use crate::file_transfer::{payload_size, FileTransfer, Reassembler, TRANSFER_TIMEOUT};
+use crate::framing::{self, Frame, Framing, Outgoing, FRAME_OVERHEAD, MAX_FRAME};
+use crate::health::{self, HealthState};
+use crate::identity;
+use crate::message::{mentions, Message, RejectReason, ServerInfo, PROTOCOL_VERSION};
+use crate::netsim::NetSim;
+use crate::tls::{self, ChannelBinding, StreamReader, StreamWriter};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, Mutex};
 use uuid::Uuid;
 
 type ClientId = Uuid;
 type Clients = Arc<Mutex<HashMap<ClientId, ClientInfo>>>;
+type SharedFiles = Arc<Mutex<FileCache>>;
+/// Every room with anyone in it or chat to replay, by name.
+type SharedRooms = Arc<Mutex<HashMap<String, Room>>>;
+type SharedAuthors = Arc<Mutex<Authors>>;
+/// When each `/timeout` ends, by username, so reconnecting doesn't lift it.
+type SharedMutes = Arc<Mutex<HashMap<String, Instant>>>;
+
+/// A message for the fan-out task to deliver, to everyone or only to the
+/// clients in `room`.
+struct Broadcast {
+    room: Option<String>,
+    json: String,
+}
+
+impl Broadcast {
+    fn everyone(json: String) -> Self {
+        Broadcast { room: None, json }
+    }
+
+    fn to_room(room: &str, json: String) -> Self {
+        Broadcast { room: Some(room.to_string()), json }
+    }
+}
+
+/// Messages queued for a single client before it is considered too slow
+/// and disconnected.
+const CLIENT_QUEUE_SIZE: usize = 1024;
+
+/// Events held for each `ChatServer` subscriber; one that falls further
+/// behind misses the oldest.
+const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// Recent chat messages whose authors are remembered, and so can still be
+/// edited or deleted.
+const EDITABLE_MESSAGES: usize = 10_000;
+
+/// The room every client starts in, which always exists.
+const DEFAULT_ROOM: &str = "#general";
+
+/// Longest accepted room name, in characters, not counting the `#`.
+const MAX_ROOM_LEN: usize = 32;
+
+/// Commands the server itself answers; anything else starting with `/` is
+/// rejected in strict mode.
+const SERVER_COMMANDS: &[&str] = &[
+    "/fetch", "/who", "/slowmode", "/stats", "/nick", "/timeout", "/msg", "/debug-state", "/sessions", "/users", "/me",
+    "/join", "/part", "/away", "/back", "/roomlimit",
+];
+
+/// Longest accepted username in strict mode, in characters.
+const MAX_USERNAME_LEN: usize = 32;
 
 #[derive(Debug)]
-#[allow(dead_code)]
 struct ClientInfo {
     username: String,
-    sender: tokio::sync::mpsc::UnboundedSender<String>,
+    /// The only strong handle to this client's outgoing queue; removing the
+    /// entry from the map closes the connection.
+    sender: mpsc::Sender<Arc<Outgoing>>,
+    /// The hex public key the client proved it holds, if any
+    public_key: Option<String>,
+    /// Where the client connected from, for `/sessions`
+    address: Option<SocketAddr>,
+    connected_at: Instant,
+    /// The room whose chat the client sees and posts to
+    room: String,
+    /// Set by `/away`, on every session of the user alike
+    away: Option<Away>,
+}
+
+/// Who is in a room, and what was said there lately.
+#[derive(Debug, Default)]
+struct Room {
+    members: HashSet<ClientId>,
+    /// The most recent chat messages, as sent, for replaying to joiners
+    history: VecDeque<String>,
+    limits: RoomLimits,
+    /// The number given to the room's latest chat
+    last_seq: u64,
+    /// Chat numbered up to here has dropped out of the history, so can't be
+    /// sent again
+    evicted_through: u64,
+}
+
+/// Caps on one room, on top of the server-wide settings.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RoomLimits {
+    /// Members at once, `None` for as many as the server takes
+    pub max_members: Option<usize>,
+    /// Messages per second each member may post there on average, `None`
+    /// for the server's `rate_limit`
+    pub rate_limit: Option<f64>,
+}
+
+impl RoomLimits {
+    fn describe(&self, config: &ServerConfig) -> String {
+        let members = match self.max_members {
+            Some(max) => format!("at most {} members", max),
+            None => "no member limit".to_string(),
+        };
+        let rate = self.rate_limit.unwrap_or(config.rate_limit);
+        let rate = if rate > 0.0 { format!("{}/s each", rate) } else { "no rate limit".to_string() };
+        let source = if self.rate_limit.is_some() { "" } else { " (server default)" };
+        format!("{}, {}{}", members, rate, source)
+    }
+}
+
+impl Room {
+    /// A room with `history` reloaded from the chat log. Numbering goes on
+    /// from its latest chat, and anything before its oldest is gone.
+    fn with_history(history: VecDeque<String>) -> Self {
+        let seqs: Vec<u64> = history.iter().filter_map(|json| chat_seq(json)).collect();
+        Room {
+            last_seq: seqs.iter().max().copied().unwrap_or_default(),
+            evicted_through: seqs.iter().min().map_or(0, |first| first.saturating_sub(1)),
+            history,
+            ..Room::default()
+        }
+    }
+
+    /// The history to replay to a joiner; the newest are kept if it is
+    /// longer than a client's queue has room for.
+    fn replay(&self) -> impl Iterator<Item = &String> {
+        self.history.iter().skip(self.history.len().saturating_sub(CLIENT_QUEUE_SIZE / 2))
+    }
+}
+
+/// Takes `client_id` out of every room, forgetting rooms left with neither
+/// members nor history, except the default one.
+fn leave_rooms(rooms: &mut HashMap<String, Room>, client_id: ClientId) {
+    rooms.retain(|name, room| {
+        room.members.remove(&client_id);
+        // Numbering carries on if the room is used again, so clients
+        // coming back can still tell what they missed
+        name == DEFAULT_ROOM || !room.members.is_empty() || !room.history.is_empty() || room.limits != RoomLimits::default() || room.last_seq > 0
+    });
+}
+
+/// A user's `/away` state.
+#[derive(Debug, Clone)]
+struct Away {
+    /// As given to `/away`; may be empty
+    reason: String,
+    /// Who has had the auto-reply since the user went away
+    notified: HashSet<String>,
+}
+
+/// Who sent each recent chat message, and in which room, by message id, so
+/// only the author can edit or delete it and the change reaches the same
+/// room. The oldest are forgotten past `EDITABLE_MESSAGES`.
+#[derive(Debug, Default)]
+struct Authors {
+    by_id: HashMap<String, (String, String)>,
+    order: VecDeque<String>,
+}
+
+impl Authors {
+    fn insert(&mut self, id: String, username: String, room: String) {
+        if self.order.len() == EDITABLE_MESSAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.by_id.remove(&oldest);
+            }
+        }
+        self.order.push_back(id.clone());
+        self.by_id.insert(id, (username, room));
+    }
+
+    /// The room of message `id` if `username` may change it, else why not.
+    fn check_owner(&self, id: &str, username: &str) -> Result<String, &'static str> {
+        match self.by_id.get(id) {
+            Some((author, room)) if author == username => Ok(room.clone()),
+            Some(_) => Err("You can only edit or delete your own messages"),
+            None => Err("That message no longer exists or is too old to change"),
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.by_id.remove(id);
+    }
+}
+
+/// How the server resolves a join whose username is already connected.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum DedupMode {
+    /// Turn the newcomer away so every connected name is unique
+    Reject,
+    /// Let several clients share the same name, e.g. one user on multiple devices
+    Allow,
+    /// Rename the newcomer to the first free `name2`, `name3`, ...
+    Suffix,
+    /// Keep a single session per name by disconnecting the older one
+    Replace,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub dedup_usernames: DedupMode,
+    /// Upper bound on the total size of cached file payloads, in bytes
+    pub file_cache_size: u64,
+    /// How long a shared file stays fetchable
+    pub file_cache_ttl: Duration,
+    /// Port for the optional HTTP `/healthz` and `/readyz` probes
+    pub health_port: Option<u16>,
+    /// Maximum number of names listed per `/who` page
+    pub who_page_size: usize,
+    /// Role tags by username, shown next to the author on their messages
+    pub roles: HashMap<String, String>,
+    /// Author colors by username, overriding the clients' own choice
+    pub user_colors: HashMap<String, String>,
+    /// Send each client's messages back to that client only
+    pub echo: bool,
+    /// Minimum seconds between posts per user, 0 to disable
+    pub slow_mode: u64,
+    /// Messages per second each connection may send on average, 0 to
+    /// disable
+    pub rate_limit: f64,
+    /// Messages a connection may send at once before the rate limit applies
+    pub rate_burst: u32,
+    /// Limits of particular rooms, by name; `/roomlimit` changes them
+    pub room_limits: HashMap<String, RoomLimits>,
+    /// Disconnect clients on protocol violations
+    pub strict: bool,
+    /// Checks each joining client's credential
+    pub auth: Arc<dyn Authenticator>,
+    /// Connections accepted at once; further joins are turned away
+    pub max_clients: Option<usize>,
+    /// Simulated latency and loss on messages to clients, for testing
+    pub netsim: Option<NetSim>,
+    /// How often clients are pinged, `None` to never check
+    pub heartbeat_interval: Option<Duration>,
+    /// Heartbeats a client may miss before it is disconnected
+    pub heartbeat_misses: u32,
+    /// Chat messages of each room kept to replay to joiners, 0 to keep none
+    pub history_size: usize,
+    /// Where every broadcast is appended, with its room, and history
+    /// reloaded from
+    pub log_file: Option<PathBuf>,
+    /// Accept TLS connections only, with this certificate and key
+    pub tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
+    /// Also accept clients that use binary framing
+    pub binary: bool,
+    /// Shared with every client as it joins, for zstd-compressed files
+    pub zstd_dictionary: Option<Arc<[u8]>>,
+}
+
+/// What happens on a server, for an application embedding it to follow
+/// through `ChatServer::subscribe`.
+// Read by embedding applications rather than the binary itself
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A connection joined as `username`; a user signed in twice joins twice
+    UserJoined { username: String, address: Option<SocketAddr> },
+    /// A message went out to everyone, or to everyone in `room`
+    MessageBroadcast { room: Option<String>, message: Message },
+    /// A file was cached and announced under `id`
+    FileShared { username: String, filename: String, size: u64, id: String },
+    /// One of `username`'s connections closed
+    UserLeft { username: String },
+    /// Something went wrong, with the connection at `address` if it
+    /// concerns one
+    Error { address: Option<SocketAddr>, error: String },
+}
+
+/// A server bound to its port, serving clients once `run` is called.
+/// Applications embedding it subscribe to its events first.
+pub struct ChatServer {
+    listener: TcpListener,
+    config: ServerConfig,
+    events: broadcast::Sender<ServerEvent>,
+}
+
+impl ChatServer {
+    pub async fn bind(config: ServerConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
+        Ok(ChatServer::on(listener, config))
+    }
+
+    /// A server on an already bound listener.
+    fn on(listener: TcpListener, config: ServerConfig) -> Self {
+        ChatServer { listener, config, events: broadcast::channel(EVENT_QUEUE_SIZE).0 }
+    }
+
+    /// Every event from now on.
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Serves clients until accepting fails.
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        serve_clients(self.listener, self.config, Arc::new(Mutex::new(HashMap::new())), self.events).await
+    }
+}
+
+pub async fn start_server(config: ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    ChatServer::bind(config).await?.run().await
 }
 
-pub async fn start_server(port: u16) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
-    let (broadcast_tx, _) = broadcast::channel(100);
+/// Runs the server with `clients` as its map of connected clients, sending
+/// what happens to `events`.
+async fn serve_clients(
+    listener: TcpListener,
+    config: ServerConfig,
+    clients: Clients,
+    events: broadcast::Sender<ServerEvent>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let port = listener.local_addr()?.port();
+    let files: SharedFiles = Arc::new(Mutex::new(FileCache::new(
+        config.file_cache_size,
+        config.file_cache_ttl,
+    )));
+    let mut rooms: HashMap<String, Room> = HashMap::new();
+    let mut log = None;
+    if let Some(path) = &config.log_file {
+        match chat_log::read_tail(path, config.history_size, DEFAULT_ROOM, is_replayable) {
+            Ok((tails, skipped)) => {
+                if skipped > 0 {
+                    eprintln!("Skipped {} unreadable lines in chat log {}", skipped, path.display());
+                }
+                let loaded: usize = tails.values().map(VecDeque::len).sum();
+                println!("Loaded {} messages of history in {} rooms from {}", loaded, tails.len(), path.display());
+                rooms = tails.into_iter().map(|(name, history)| (name, Room::with_history(history))).collect();
+            }
+            Err(e) => eprintln!("Can't read chat log {} ({}), starting with no history", path.display(), e),
+        }
+        log = Some(ChatLog::open(path).map_err(|e| format!("can't open chat log {}: {}", path.display(), e))?);
+    }
+    rooms.entry(DEFAULT_ROOM.to_string()).or_default();
+    for (name, limits) in &config.room_limits {
+        let name = room_name(name).map_err(|e| format!("room limit for {}: {}", name, e))?;
+        if name == DEFAULT_ROOM && limits.max_members.is_some() {
+            return Err(format!("{} holds everyone who connects, limit it with --max-clients", DEFAULT_ROOM).into());
+        }
+        rooms.entry(name).or_default().limits = *limits;
+    }
+    let rooms: SharedRooms = Arc::new(Mutex::new(rooms));
+    let (broadcast_tx, broadcast_rx) = mpsc::unbounded_channel();
+    tokio::spawn(fan_out(broadcast_rx, clients.clone(), rooms.clone(), config.history_size, log, events.clone()));
+
+    let health_state = Arc::new(HealthState::default());
+    if let Some(health_port) = config.health_port {
+        let health_state = health_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(health_port, health_state).await {
+                eprintln!("Health check server failed: {}", e);
+            }
+        });
+    }
 
     println!("Server listening on port {}", port);
+    if config.echo {
+        println!("Echo mode: messages are returned to their sender only");
+    }
+    if config.strict {
+        println!("Strict mode: clients are disconnected on protocol violations");
+    }
+    if config.tls.is_some() {
+        println!("TLS: only encrypted connections are accepted");
+    }
+    health_state.set_alive(true);
+    health_state.set_ready(true);
 
+    let shared = Shared {
+        clients,
+        files,
+        rooms,
+        authors: Arc::new(Mutex::new(Authors::default())),
+        mutes: Arc::new(Mutex::new(HashMap::new())),
+        broadcast_tx,
+        events,
+        slow_mode: Arc::new(AtomicU64::new(config.slow_mode)),
+        config,
+    };
     loop {
-        let (socket, addr) = listener.accept().await?;
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                health_state.set_ready(false);
+                health_state.set_alive(false);
+                return Err(e.into());
+            }
+        };
         println!("New connection from: {}", addr);
 
-        let clients = clients.clone();
-        let broadcast_tx = broadcast_tx.clone();
-
+        let shared = shared.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_client(socket, clients, broadcast_tx).await {
+            let address = socket.peer_addr().ok();
+            let (reader, writer, binding) = match shared.config.tls.clone() {
+                Some(tls_config) => match tls::accept(tls_config, socket).await {
+                    Ok(halves) => halves,
+                    Err(e) => {
+                        eprintln!("TLS handshake with {} failed: {}", addr, e);
+                        shared.emit(ServerEvent::Error { address, error: format!("TLS handshake failed: {}", e) });
+                        return;
+                    }
+                },
+                None => tls::split_plain(socket),
+            };
+            if let Err(e) = handle_client(reader, writer, address, binding, shared.clone()).await {
                 eprintln!("Error handling client {}: {}", addr, e);
+                shared.emit(ServerEvent::Error { address, error: e.to_string() });
             }
         });
     }
 }
 
-async fn handle_client(
-    socket: TcpStream,
+/// The server-wide state every connection works on.
+#[derive(Clone)]
+struct Shared {
+    config: ServerConfig,
     clients: Clients,
-    broadcast_tx: broadcast::Sender<String>,
+    files: SharedFiles,
+    rooms: SharedRooms,
+    authors: SharedAuthors,
+    mutes: SharedMutes,
+    broadcast_tx: mpsc::UnboundedSender<Broadcast>,
+    events: broadcast::Sender<ServerEvent>,
+    /// Minimum seconds between posts, which `/slowmode` changes
+    slow_mode: Arc<AtomicU64>,
+}
+
+impl Shared {
+    /// Queues `message` for every connected client.
+    fn broadcast(&self, message: &Message) {
+        let _ = self.broadcast_tx.send(Broadcast::everyone(message.to_json().unwrap_or_default()));
+    }
+
+    /// Queues `message` for the clients in `room`.
+    fn broadcast_to_room(&self, room: &str, message: &Message) {
+        let _ = self.broadcast_tx.send(Broadcast::to_room(room, message.to_json().unwrap_or_default()));
+    }
+
+    /// Tells any subscribers about `event`.
+    fn emit(&self, event: ServerEvent) {
+        let _ = self.events.send(event);
+    }
+}
+
+async fn handle_client(
+    reader: StreamReader,
+    mut writer: StreamWriter,
+    address: Option<SocketAddr>,
+    binding: ChannelBinding,
+    shared: Shared,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let config = &shared.config;
     let client_id = Uuid::new_v4();
-    let mut broadcast_rx = broadcast_tx.subscribe();
+    let (tx, mut rx) = mpsc::channel::<Arc<Outgoing>>(CLIENT_QUEUE_SIZE);
+    // The reader only holds a weak handle so the map stays the queue's owner
+    let reply_tx = tx.downgrade();
 
-    // Split the socket for reading and writing
-    let (reader, mut writer) = socket.into_split();
     let mut reader = BufReader::new(reader);
 
+    // A binary client's first frame starts with a zero length byte, which
+    // a username line never does
+    let framing = if config.binary && reader.fill_buf().await?.first() == Some(&0) {
+        Framing::Binary
+    } else {
+        Framing::Json
+    };
+
     // Read username from first message
     let mut username_line = String::new();
-    reader.read_line(&mut username_line).await?;
-    let username = username_line.trim().to_string();
+    framing::read_frame(&mut reader, framing, &mut username_line, FRAME_OVERHEAD).await?;
+    let requested = username_line.trim().to_string();
+    if config.strict {
+        if let Err(violation) = check_username(&requested) {
+            return reject(&mut writer, framing, RejectReason::InvalidUsername, violation).await;
+        }
+    }
 
-    // Add client to the map (we'll remove the unused sender field later)
-    {
-        let mut clients_guard = clients.lock().await;
-        clients_guard.insert(client_id, ClientInfo {
-            username: username.clone(),
-            sender: tokio::sync::mpsc::unbounded_channel().0, // Placeholder
-        });
+    // Ask for a credential when the backend wants one; it comes on the next line
+    let mut credential = String::new();
+    if config.auth.requires_credential() {
+        framing::write_message(&mut writer, framing, &Message::new_auth_required()).await?;
+        writer.flush().await?;
+        framing::read_frame(&mut reader, framing, &mut credential, FRAME_OVERHEAD).await?;
+    }
+    let credential = credential.trim_end_matches(['\r', '\n']);
+    if let AuthResult::Rejected(reason) = config.auth.authenticate(&requested, credential.as_bytes()).await {
+        return reject(&mut writer, framing, RejectReason::AuthFailed, reason).await;
+    }
+
+    // Add client to the map, unless the server is at capacity or the name is taken
+    let joined = {
+        let mut clients_guard = shared.clients.lock().await;
+        if config.max_clients.is_some_and(|max| clients_guard.len() >= max) {
+            let detail = format!("{} users are connected, try again later", config.max_clients.unwrap_or_default());
+            Err((RejectReason::Full, detail))
+        } else if config.dedup_usernames == DedupMode::Reject && clients_guard.values().any(|c| c.username == requested) {
+            Err((RejectReason::UsernameTaken, format!("{} is already connected", requested)))
+        } else {
+            let username = match config.dedup_usernames {
+                DedupMode::Reject | DedupMode::Allow | DedupMode::Replace => requested,
+                DedupMode::Suffix => unique_username(&clients_guard, &requested),
+            };
+            let already_online = clients_guard.values().any(|c| c.username == username);
+            if config.dedup_usernames == DedupMode::Replace {
+                // Sent as a rejection so the old client doesn't reconnect and bounce us
+                let notice = Message::new_rejected(RejectReason::Replaced, "signed in from another connection".to_string());
+                let notice = Outgoing::new(notice.to_json()?);
+                clients_guard.retain(|_, client| {
+                    if client.username != username {
+                        return true;
+                    }
+                    // Dropping the entry closes that session once the notice is written
+                    let _ = client.sender.try_send(notice.clone());
+                    false
+                });
+            }
+            // Queued ahead of anything broadcast once we're in the map, so
+            // nothing is missed or seen twice
+            let mut rooms = shared.rooms.lock().await;
+            let general = rooms.entry(DEFAULT_ROOM.to_string()).or_default();
+            for json in general.replay() {
+                let _ = tx.try_send(Outgoing::new(json.clone()));
+            }
+            general.members.insert(client_id);
+            // Another session of an away user is away too
+            let away = clients_guard.values().find(|c| c.username == username).and_then(|c| c.away.clone());
+            clients_guard.insert(client_id, ClientInfo {
+                username: username.clone(),
+                sender: tx,
+                public_key: None,
+                address,
+                connected_at: Instant::now(),
+                room: DEFAULT_ROOM.to_string(),
+                away,
+            });
+            Ok((username, already_online))
+        }
+    };
+    let (username, already_online) = match joined {
+        Ok(joined) => joined,
+        Err((reason, detail)) => return reject(&mut writer, framing, reason, detail).await,
+    };
+
+    shared.emit(ServerEvent::UserJoined { username: username.clone(), address });
+    // Broadcast user joined, unless this is another session of someone online
+    if !already_online {
+        shared.broadcast(&Message::new_user_joined(username.clone()));
+    }
+
+    // Send welcome message, which also tells the client its effective username
+    let welcome_msg = Message::new_welcome(username.clone(), server_info(config));
+    framing::write_message(&mut writer, framing, &welcome_msg).await?;
+    framing::write_message(&mut writer, framing, &Message::new_room_joined(DEFAULT_ROOM.to_string())).await?;
+    if let Some(dictionary) = &config.zstd_dictionary {
+        framing::write_message(&mut writer, framing, &Message::new_zstd_dictionary(dictionary.to_vec())).await?;
+    }
+
+    // Let late joiners know about files that are still fetchable
+    let cached_files = shared.files.lock().await.files();
+    for notice in cached_files.iter().filter_map(file_notice) {
+        framing::write_message(&mut writer, framing, &notice).await?;
+    }
+
+    // ...and about the keys of everyone already here
+    let identities: Vec<Message> = shared
+        .clients
+        .lock()
+        .await
+        .values()
+        .filter_map(|client| Some(Message::new_identity(client.username.clone(), client.public_key.clone()?)))
+        .collect();
+    for identity in identities {
+        framing::write_message(&mut writer, framing, &identity).await?;
     }
 
-    // Broadcast user joined
-    let join_msg = Message::new_user_joined(username.clone());
-    let _ = broadcast_tx.send(join_msg.to_json()?);
+    // ...and who is online, for the roster; joins and leaves queued since
+    // we entered the map only repeat what it already reflects
+    let roster = user_list(&shared.clients).await;
+    framing::write_message(&mut writer, framing, &roster).await?;
+    writer.flush().await?;
 
-    // Send welcome message
-    let welcome_msg = Message::new_system(format!("Welcome to the chat, {}!", username));
-    writer.write_all(format!("{}\n", welcome_msg.to_json()?).as_bytes()).await?;
+    let mut link = config.netsim.map(|sim| sim.link(0));
+    // Anything the client sends, a pong included, shows it is still there
+    let connected_at = Instant::now();
+    let last_seen = Arc::new(AtomicU64::new(0));
+    let last_seen_for_reader = last_seen.clone();
+    let heartbeat = config.heartbeat_interval.map(|interval| (interval, interval * config.heartbeat_misses.max(1)));
 
     // Handle incoming messages from this client
-    let broadcast_tx_for_reader = broadcast_tx.clone();
-    let username_for_reader = username.clone();
-    let clients_for_reader = clients.clone();
-    
-    tokio::spawn(async move {
+    let mut session = Session {
+        shared: shared.clone(),
+        client_id,
+        username: username.clone(),
+        room: DEFAULT_ROOM.to_string(),
+        reply_tx,
+        binding,
+        last_post: None,
+        pending_identity: None,
+        assembler: Reassembler::new(config.file_cache_size, TRANSFER_TIMEOUT),
+        rate_limit: TokenBucket::new(config.rate_limit, config.rate_burst),
+        throttled: false,
+    };
+    let reader_task = tokio::spawn(async move {
         let mut line = String::new();
-        
-        while reader.read_line(&mut line).await.unwrap_or(0) > 0 {
-            let trimmed = line.trim();
-            if !trimmed.is_empty() {
-                // Check if it's a file message
-                if trimmed.starts_with("FILE:") {
-                    // Forward the file message as-is
-                    let _ = broadcast_tx_for_reader.send(trimmed[5..].to_string());
-                } else {
-                    // Create regular text message and send as JSON
-                    let msg = Message::new_text(username_for_reader.clone(), trimmed.to_string());
-                    let _ = broadcast_tx_for_reader.send(msg.to_json().unwrap_or_default());
+        loop {
+            let frame = match framing::read_frame(&mut reader, framing, &mut line, MAX_FRAME).await {
+                Ok(Frame::End) => break,
+                Ok(frame) => frame,
+                // Oversized frames are skipped unread
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    session.shared.emit(ServerEvent::Error { address, error: format!("{}: {}", session.username, e) });
+                    if session.shared.config.strict {
+                        session.reply(&protocol_error(&e.to_string()));
+                        break;
+                    }
+                    session.notice(format!("Message dropped: {}", e));
+                    line.clear();
+                    continue;
                 }
-            }
+                Err(_) => break,
+            };
+            last_seen_for_reader.store(connected_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+            let decoded = match frame {
+                Frame::Message(message) => Some(message),
+                _ => decode_line(line.trim()),
+            };
+            let flow = session.handle(decoded, &line).await;
             line.clear();
+            if flow.is_break() {
+                break;
+            }
         }
-        
-        // Client disconnected
-        let leave_msg = Message::new_user_left(username_for_reader.clone());
-        let _ = broadcast_tx_for_reader.send(leave_msg.to_json().unwrap_or_default());
-        
-        let mut clients_guard = clients_for_reader.lock().await;
-        clients_guard.remove(&client_id);
+
+        remove_client(&session.shared, session.client_id, &session.username).await;
     });
 
-    // Handle outgoing messages to this client
+    // Handle outgoing messages to this client, both broadcast and targeted,
+    // pinging it every heartbeat and dropping it once it has gone quiet
+    // Without a heartbeat the ticker is never polled past its first tick,
+    // but its period must still leave room to schedule the next one
+    let mut ticker = tokio::time::interval(heartbeat.map_or(Duration::from_secs(3600), |(interval, _)| interval));
+    ticker.tick().await;
     loop {
-        match broadcast_rx.recv().await {
-            Ok(json_msg) => {
-                // Send all messages to this client (including their own for now)
-                if writer.write_all(format!("{}\n", json_msg).as_bytes()).await.is_err() {
+        let outgoing = tokio::select! {
+            outgoing = rx.recv() => match outgoing {
+                Some(outgoing) => outgoing,
+                None => break,
+            },
+            _ = ticker.tick(), if heartbeat.is_some() => {
+                let (_, timeout) = heartbeat.unwrap_or_default();
+                let silent = connected_at.elapsed().saturating_sub(Duration::from_millis(last_seen.load(Ordering::Relaxed)));
+                if silent >= timeout {
+                    println!("Disconnecting {}: no response for {}s", username, silent.as_secs());
+                    reader_task.abort();
+                    remove_client(&shared, client_id, &username).await;
                     break;
                 }
+                Outgoing::new(Message::new_ping().to_json()?)
             }
-            Err(_) => break,
+        };
+        if let Some(link) = &mut link {
+            if !link.pass().await {
+                continue;
+            }
+        }
+        if framing::write_outgoing(&mut writer, framing, &outgoing).await.is_err() || writer.flush().await.is_err() {
+            break;
         }
     }
 
     Ok(())
 }
+
+/// One connection as the server reads it: who the client is, which room
+/// it is in, and what its messages carry over from one to the next. Each
+/// kind of message and each command has a method of its own.
+struct Session {
+    shared: Shared,
+    client_id: ClientId,
+    /// Owned by the reader alone, so `/nick` only has to update it and the map
+    username: String,
+    room: String,
+    /// A weak handle, so the client map stays the queue's owner
+    reply_tx: mpsc::WeakSender<Arc<Outgoing>>,
+    binding: ChannelBinding,
+    last_post: Option<Instant>,
+    /// The key the client claimed and the challenge it has yet to answer
+    pending_identity: Option<(String, identity::Challenge)>,
+    /// Chunked uploads still waiting for pieces, held to the cache size
+    assembler: Reassembler,
+    rate_limit: TokenBucket,
+    /// Set while messages are being dropped, so the sender is warned once
+    throttled: bool,
+}
+
+impl Session {
+    /// Queues an already encoded message for this client only.
+    fn reply_json(&self, json: String) {
+        if let Some(tx) = self.reply_tx.upgrade() {
+            let _ = tx.try_send(Outgoing::new(json));
+        }
+    }
+
+    fn reply(&self, message: &Message) {
+        self.reply_json(message.to_json().unwrap_or_default());
+    }
+
+    /// Tells this client something as a system message.
+    fn notice(&self, text: impl Into<String>) {
+        self.reply(&Message::new_system(text.into()));
+    }
+
+    /// Sends `message` to `room`, or in echo mode straight back to this
+    /// client instead.
+    fn publish(&self, room: &str, message: &Message) {
+        if self.shared.config.echo {
+            self.reply(message);
+        } else {
+            self.shared.broadcast_to_room(room, message);
+        }
+    }
+
+    /// Acts on one message from the client, `decoded` unless it came as a
+    /// raw `line`. `Break` means the connection should be closed.
+    async fn handle(&mut self, decoded: Option<Message>, line: &str) -> ControlFlow<()> {
+        let decoded = match decoded {
+            Some(Message::Pong { .. }) => return ControlFlow::Continue(()),
+            Some(Message::Identity { public_key, .. }) => {
+                self.claim_identity(public_key);
+                return ControlFlow::Continue(());
+            }
+            Some(Message::IdentityProof { proof, .. }) => {
+                self.prove_identity(&proof).await;
+                return ControlFlow::Continue(());
+            }
+            // Chunked uploads are put back together first, then shared like
+            // a file sent whole
+            Some(chunk @ (Message::FileStart { .. } | Message::FileChunk { .. } | Message::FileEnd { .. })) => match self.reassemble(chunk) {
+                Some(file) => Some(file),
+                None => return ControlFlow::Continue(()),
+            },
+            decoded => decoded,
+        };
+        let text = match &decoded {
+            Some(Message::Text { content, .. }) => content.trim(),
+            Some(_) => "",
+            None => line.trim(),
+        }
+        .to_string();
+        if self.shared.config.strict {
+            if let Some(violation) = strict_violation(decoded.as_ref(), &text) {
+                self.reply(&protocol_error(&violation));
+                return ControlFlow::Break(());
+            }
+        }
+        if (decoded.is_some() || !text.is_empty()) && self.within_rate_limit().await {
+            self.dispatch(decoded, &text).await;
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Takes a token for one more message, warning the client the first
+    /// time it runs out. Each room may set its own rate.
+    async fn within_rate_limit(&mut self) -> bool {
+        self.rate_limit.rate = self
+            .shared
+            .rooms
+            .lock()
+            .await
+            .get(&self.room)
+            .and_then(|room| room.limits.rate_limit)
+            .unwrap_or(self.shared.config.rate_limit);
+        if self.rate_limit.rate <= 0.0 {
+            return true;
+        }
+        if self.rate_limit.take() {
+            self.throttled = false;
+            return true;
+        }
+        if !std::mem::replace(&mut self.throttled, true) {
+            self.notice(format!("You are sending messages too fast (limit {}/s), some were dropped", self.rate_limit.rate));
+        }
+        false
+    }
+
+    /// Routes a message that got past the rate limit to its handler: files,
+    /// edits and deletions by kind, commands by name, and anything else is
+    /// posted as chat.
+    async fn dispatch(&mut self, decoded: Option<Message>, text: &str) {
+        if let Some(remaining) = mute_remaining(&self.shared.mutes, &self.username, is_post(decoded.as_ref(), text)).await {
+            self.notice(format!("You are timed out, you can post again in {}s", remaining.as_secs_f64().ceil() as u64));
+            return;
+        }
+        let (local_id, ttl) = match decoded {
+            Some(Message::Edit { target_id, new_content, .. }) => return self.edit(target_id, new_content.trim()).await,
+            Some(Message::Delete { target_id, .. }) => return self.delete(target_id).await,
+            Some(file @ Message::File { .. }) => return self.share_file(file).await,
+            Some(Message::Backfill { room, from, to }) => return self.backfill(&room, from, to).await,
+            Some(Message::Text { local_id, ttl, .. }) => (local_id, ttl),
+            _ => (None, None),
+        };
+        let (command, args) = text.split_once(char::is_whitespace).map_or((text, ""), |(command, args)| (command, args.trim()));
+        match (command, args) {
+            ("/fetch", id) if !id.is_empty() => self.fetch(id).await,
+            ("/who", page) => self.who(page).await,
+            ("/users", "") => self.reply(&user_list(&self.shared.clients).await),
+            ("/slowmode", seconds) => self.set_slow_mode(seconds),
+            ("/join", room) => self.change_room(room_name(room)).await,
+            ("/part", "") => self.part().await,
+            ("/roomlimit", limit) => self.room_limit(limit).await,
+            ("/away", reason) => self.set_away(Some(reason)).await,
+            ("/back", "") => self.set_away(None).await,
+            ("/nick", name) => self.nick(name).await,
+            ("/timeout", args) => self.time_out(args).await,
+            ("/msg", args) => self.private_message(args).await,
+            ("/sessions", args) => {
+                for line in sessions(&self.shared.clients, self.client_id, args).await {
+                    self.notice(line);
+                }
+            }
+            ("/debug-state", "") => self.debug_state().await,
+            ("/stats", "") => self.stats().await,
+            ("/me", "") => self.notice("Usage: /me <action>"),
+            _ => self.post(text, local_id, ttl).await,
+        }
+    }
+
+    /// An identity is only taken once the client proves it holds the key,
+    /// so this answers a claimed key with a challenge.
+    fn claim_identity(&mut self, public_key: String) {
+        let challenge = match identity::parse_key(&public_key) {
+            Some(_) => identity::Challenge::new().map_err(|e| format!("Identity not checked: {}", e)),
+            None => Err("Identity rejected: expected a 64 digit hex public key".to_string()),
+        };
+        match challenge {
+            Ok(challenge) => {
+                self.reply(&Message::new_identity_challenge(challenge.public_key()));
+                self.pending_identity = Some((public_key, challenge));
+            }
+            Err(reason) => self.notice(reason),
+        }
+    }
+
+    async fn prove_identity(&mut self, proof: &str) {
+        match self.pending_identity.take() {
+            Some((public_key, challenge)) if challenge.verify(&public_key, proof, self.binding) => {
+                if let Some(client) = self.shared.clients.lock().await.get_mut(&self.client_id) {
+                    client.public_key = Some(public_key.clone());
+                }
+                // Announced under the name the server knows, never the one claimed
+                self.shared.broadcast(&Message::new_identity(self.username.clone(), public_key));
+            }
+            _ => self.notice("Identity rejected: the proof doesn't match the key"),
+        }
+    }
+
+    /// Adds a piece of a chunked upload, returning the whole file once the
+    /// last piece is in.
+    fn reassemble(&mut self, chunk: Message) -> Option<Message> {
+        for filename in self.assembler.expire() {
+            self.notice(format!("File {} timed out before all of it arrived", filename));
+        }
+        match self.assembler.receive(chunk) {
+            Ok(file) => file,
+            Err(reason) => {
+                self.notice(reason);
+                None
+            }
+        }
+    }
+
+    async fn edit(&mut self, target_id: String, new_content: &str) {
+        if new_content.is_empty() {
+            self.notice("An edit needs the new text; delete the message instead");
+            return;
+        }
+        let owner = self.shared.authors.lock().await.check_owner(&target_id, &self.username);
+        match owner {
+            Ok(room) => self.publish(&room, &Message::new_edit(target_id, new_content.to_string())),
+            Err(reason) => self.notice(reason),
+        }
+    }
+
+    async fn delete(&mut self, target_id: String) {
+        let mut authors = self.shared.authors.lock().await;
+        match authors.check_owner(&target_id, &self.username) {
+            Ok(room) => {
+                authors.remove(&target_id);
+                self.publish(&room, &Message::new_delete(target_id));
+            }
+            Err(reason) => self.notice(reason),
+        }
+    }
+
+    /// Keeps the payload of a file sent whole or reassembled, and only
+    /// announces it to the room.
+    async fn share_file(&mut self, file: Message) {
+        let Message::File { filename, size, data, timestamp, crc32, codec, compressed_size, .. } = file else {
+            return;
+        };
+        // A claimed size that disagrees with the payload would
+        // mislead size limits and what other clients display
+        let payload = payload_size(size, compressed_size);
+        if payload != data.len() as u64 || (codec.is_some() && compressed_size.is_none()) {
+            self.notice(format!("File rejected: declared size {} does not match the {} bytes sent", payload, data.len()));
+            return;
+        }
+        // Refuse a damaged upload rather than offer it to everyone
+        if !FileTransfer::checksum_matches(&data, crc32) {
+            self.notice("File rejected: its checksum doesn't match the data sent");
+            return;
+        }
+        if let Some(wait) = self.slow_mode_wait() {
+            self.notice(slow_mode_notice(wait));
+            return;
+        }
+        // Decompressed, it must still fit what we announced as the limit
+        let max_size = self.shared.config.file_cache_size;
+        if size > max_size {
+            self.notice(format!("File rejected: {} bytes is over the {} byte limit", size, max_size));
+            return;
+        }
+        let id = Uuid::new_v4().to_string();
+        let event = ServerEvent::FileShared { username: self.username.clone(), filename: filename.clone(), size, id: id.clone() };
+        let message = Message::File {
+            username: self.username.clone(),
+            filename,
+            size,
+            data,
+            timestamp,
+            id: Some(id.clone()),
+            crc32,
+            codec,
+            compressed_size,
+        };
+        let notice = file_notice(&message);
+        if self.shared.files.lock().await.insert(id, message, payload) {
+            self.shared.emit(event);
+            if let Some(notice) = notice {
+                self.publish(&self.room, &notice);
+            }
+        } else {
+            self.notice("File is too large to share on this server");
+        }
+    }
+
+    /// Sends this client the chat of its room numbered `from` to `to` again,
+    /// as far as the history still has it, first saying which part is gone.
+    /// Chat deleted since is left out, as from the history.
+    async fn backfill(&mut self, room: &str, from: u64, to: u64) {
+        if room != self.room || from > to {
+            return;
+        }
+        let (found, evicted_through) = match self.shared.rooms.lock().await.get(room) {
+            Some(kept) => {
+                let found: Vec<String> = kept
+                    .history
+                    .iter()
+                    .filter(|json| chat_seq(json).is_some_and(|seq| (from..=to).contains(&seq)))
+                    .cloned()
+                    .collect();
+                (found, kept.evicted_through)
+            }
+            None => (Vec::new(), u64::MAX),
+        };
+        if from <= evicted_through {
+            self.reply(&Message::new_backfill_unavailable(room.to_string(), from, to.min(evicted_through)));
+        }
+        // Waits for queue space, as a fetch does, so none of it is dropped
+        let Some(tx) = self.reply_tx.upgrade() else {
+            return;
+        };
+        for json in found {
+            if tx.send(Outgoing::new(json)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Streams the payload of a shared file to this client only, waiting
+    /// for queue space so no chunk is dropped.
+    async fn fetch(&mut self, id: &str) {
+        let file = self.shared.files.lock().await.get(id);
+        match (file.as_ref().and_then(FileTransfer::stream_message), self.reply_tx.upgrade()) {
+            (Some(stream), Some(tx)) => {
+                for frame in stream.flatten() {
+                    if tx.send(Outgoing::new(frame.to_json().unwrap_or_default())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            (None, _) => self.reply(&Message::new_file_unavailable(id.to_string())),
+            _ => {}
+        }
+    }
+
+    async fn who(&mut self, page: &str) {
+        let page = match page {
+            "" => Some(1),
+            arg => arg.parse::<usize>().ok().filter(|&page| page >= 1),
+        };
+        let who = match page {
+            Some(page) => {
+                let usernames = sorted_usernames(&self.shared.clients).await;
+                who_page(&usernames, page, self.shared.config.who_page_size)
+            }
+            None => "Usage: /who [page]".to_string(),
+        };
+        self.notice(who);
+    }
+
+    fn set_slow_mode(&mut self, seconds: &str) {
+        if !is_moderator(&self.shared.config, &self.username) {
+            self.notice("Only admins and moderators can change slow mode");
+        } else if let Ok(seconds) = seconds.parse::<u64>() {
+            self.shared.slow_mode.store(seconds, Ordering::Relaxed);
+            let announcement = if seconds == 0 {
+                format!("Slow mode disabled by {}", self.username)
+            } else {
+                format!("Slow mode set to {}s by {}", seconds, self.username)
+            };
+            self.shared.broadcast(&Message::new_system(announcement));
+        } else {
+            self.notice("Usage: /slowmode <seconds>");
+        }
+    }
+
+    /// Leaving a room means going back to the main one.
+    async fn part(&mut self) {
+        let target = if self.room == DEFAULT_ROOM {
+            Err(format!("You can't leave {}", DEFAULT_ROOM))
+        } else {
+            Ok(DEFAULT_ROOM.to_string())
+        };
+        self.change_room(target).await;
+    }
+
+    /// Moves the client to `target` for `/join` and `/part`, unless it is
+    /// full, and catches it up on what was said there.
+    async fn change_room(&mut self, target: Result<String, String>) {
+        let room = match target {
+            Ok(room) if room == self.room => return self.notice(format!("You are already in {}", room)),
+            Ok(room) => room,
+            Err(reason) => return self.notice(reason),
+        };
+        {
+            let mut clients_guard = self.shared.clients.lock().await;
+            let mut rooms = self.shared.rooms.lock().await;
+            let max_members = rooms
+                .get(&room)
+                .and_then(|joining| joining.limits.max_members.filter(|&max| joining.members.len() >= max));
+            if let Some(max) = max_members {
+                self.notice(format!("{} is full ({} members), try again later", room, max));
+                return;
+            }
+            let left = Message::new_system(format!("{} left {}", self.username, self.room));
+            self.shared.broadcast_to_room(&self.room, &left);
+            if let Some(client) = clients_guard.get_mut(&self.client_id) {
+                client.room = room.clone();
+            }
+            leave_rooms(&mut rooms, self.client_id);
+            let joined = rooms.entry(room.clone()).or_default();
+            joined.members.insert(self.client_id);
+            self.reply(&Message::new_room_joined(room.clone()));
+            // Catch up on what was said there; the client skips what it
+            // already has
+            for json in joined.replay() {
+                self.reply_json(json.clone());
+            }
+        }
+        let joined = Message::new_system(format!("{} joined {}", self.username, room));
+        self.shared.broadcast_to_room(&room, &joined);
+        self.room = room;
+    }
+
+    /// `/roomlimit` on its own describes the current room's limits; admins
+    /// can change them with `/roomlimit members|rate <value|off>`.
+    async fn room_limit(&mut self, limit: &str) {
+        let config = &self.shared.config;
+        let notice = if limit.is_empty() {
+            let limits = self.shared.rooms.lock().await.get(&self.room).map(|room| room.limits).unwrap_or_default();
+            format!("{}: {}", self.room, limits.describe(config))
+        } else if config.roles.get(&self.username).map(String::as_str) != Some("admin") {
+            "Only admins can change room limits".to_string()
+        } else {
+            let mut rooms = self.shared.rooms.lock().await;
+            let room = rooms.entry(self.room.clone()).or_default();
+            match set_room_limit(&mut room.limits, &self.room, limit) {
+                Ok(()) => {
+                    let announcement = format!("{} set the limits of {}: {}", self.username, self.room, room.limits.describe(config));
+                    self.shared.broadcast_to_room(&self.room, &Message::new_system(announcement));
+                    return;
+                }
+                Err(reason) => reason,
+            }
+        };
+        self.notice(notice);
+    }
+
+    /// `/away [reason]` with a reason, or `/back` with `None`.
+    async fn set_away(&mut self, reason: Option<&str>) {
+        if !set_away(&self.shared.clients, &self.username, reason).await {
+            self.notice("You aren't away");
+            return;
+        }
+        let announcement = match reason {
+            Some(reason) => away_notice(&self.username, reason),
+            None => format!("{} is back", self.username),
+        };
+        self.shared.broadcast(&Message::new_system(announcement));
+        self.shared.broadcast(&user_list(&self.shared.clients).await);
+    }
+
+    async fn nick(&mut self, requested: &str) {
+        // Timeouts go by name, so a new one would shake it off
+        if mute_remaining(&self.shared.mutes, &self.username, true).await.is_some() {
+            self.notice("You can't change your name while timed out");
+            return;
+        }
+        if let Err(reason) = rename(&self.shared.clients, self.client_id, &self.username, requested, &self.shared.config).await {
+            self.notice(reason);
+            return;
+        }
+        let announcement = format!("{} is now known as {}", self.username, requested);
+        self.username = requested.to_string();
+        // A fresh welcome tells the client its new name
+        self.reply(&Message::new_welcome(self.username.clone(), server_info(&self.shared.config)));
+        self.shared.broadcast(&Message::new_system(announcement));
+        // No join or leave covers a rename, so resend the roster
+        self.shared.broadcast(&user_list(&self.shared.clients).await);
+        // Keys are looked up by name, so announce ours under the new one
+        let public_key = self.shared.clients.lock().await.get(&self.client_id).and_then(|client| client.public_key.clone());
+        if let Some(public_key) = public_key {
+            self.shared.broadcast(&Message::new_identity(self.username.clone(), public_key));
+        }
+    }
+
+    async fn time_out(&mut self, args: &str) {
+        let mut args = args.split_whitespace();
+        if !is_moderator(&self.shared.config, &self.username) {
+            self.notice("Only admins and moderators can time out users");
+        } else if let (Some(target), Some(Ok(seconds)), None) = (args.next(), args.next().map(str::parse::<u64>), args.next()) {
+            if time_out(&self.shared.clients, &self.shared.mutes, target, Duration::from_secs(seconds)).await {
+                let announcement = if seconds == 0 {
+                    format!("{}'s timeout was lifted by {}", target, self.username)
+                } else {
+                    format!("{} was timed out for {}s by {}", target, seconds, self.username)
+                };
+                self.shared.broadcast(&Message::new_system(announcement));
+            } else {
+                self.notice(format!("No user named {} is connected", target));
+            }
+        } else {
+            self.notice("Usage: /timeout <user> <seconds>");
+        }
+    }
+
+    async fn private_message(&mut self, args: &str) {
+        let mut args = args.splitn(2, char::is_whitespace);
+        let (Some(to), Some(content)) = (args.next().filter(|to| !to.is_empty()), args.next().map(str::trim).filter(|content| !content.is_empty())) else {
+            self.notice("Usage: /msg <user> <text>");
+            return;
+        };
+        let json = Message::new_private(self.username.clone(), to.to_string(), content.to_string()).to_json().unwrap_or_default();
+        match send_private(&self.shared.clients, to, &json).await {
+            // The sender's echo confirms delivery
+            Ok(()) => {
+                self.reply_json(json);
+                for notice in away_replies(&self.shared.clients, &self.username, None, |name| name == to).await {
+                    self.notice(notice);
+                }
+            }
+            Err(reason) => self.notice(reason),
+        }
+    }
+
+    async fn debug_state(&mut self) {
+        let shared = &self.shared;
+        let lines = if shared.config.roles.get(&self.username).map(String::as_str) == Some("admin") {
+            debug_state(&shared.clients, &shared.rooms, &shared.files, &shared.mutes, shared.slow_mode.load(Ordering::Relaxed)).await
+        } else {
+            vec!["Only admins can dump the server state".to_string()]
+        };
+        for line in lines {
+            self.notice(line);
+        }
+    }
+
+    async fn stats(&mut self) {
+        let stats = self.shared.files.lock().await.stats();
+        self.notice(format!(
+            "File cache: {} files, {}/{} bytes, ttl {}s, {} hits, {} misses, {} evictions",
+            stats.files, stats.bytes, stats.max_bytes, stats.ttl.as_secs(),
+            stats.hits, stats.misses, stats.evictions,
+        ));
+    }
+
+    /// Posts chat text, or a `/me` action, to the client's room.
+    async fn post(&mut self, text: &str, local_id: Option<String>, ttl: Option<u64>) {
+        if let Some(wait) = self.slow_mode_wait() {
+            self.notice(slow_mode_notice(wait));
+            return;
+        }
+        let config = &self.shared.config;
+        let (content, action) = match text.strip_prefix("/me ") {
+            Some(action) => (action.trim(), true),
+            None => (text, false),
+        };
+        let id = Uuid::new_v4().to_string();
+        self.shared.authors.lock().await.insert(id.clone(), self.username.clone(), self.room.clone());
+        let msg = Message::Text {
+            username: self.username.clone(),
+            content: content.to_string(),
+            timestamp: SystemTime::now(),
+            role: config.roles.get(&self.username).cloned(),
+            color: config.user_colors.get(&self.username).cloned(),
+            local_id,
+            ttl,
+            id: Some(id),
+            seq: None,
+            action,
+        };
+        self.publish(&self.room, &msg);
+        for notice in away_replies(&self.shared.clients, &self.username, Some(&self.room), |name| mentions(content, name)).await {
+            self.notice(notice);
+        }
+    }
+
+    /// How much longer this client must wait to post under slow mode, or
+    /// `None` if it may post now.
+    fn slow_mode_wait(&mut self) -> Option<Duration> {
+        let interval = Duration::from_secs(self.shared.slow_mode.load(Ordering::Relaxed));
+        let exempt = is_moderator(&self.shared.config, &self.username);
+        slow_mode_wait(&mut self.last_post, interval, exempt)
+    }
+}
+
+fn slow_mode_notice(wait: Duration) -> String {
+    format!("Slow mode is on, wait {}s before posting again", wait.as_secs_f64().ceil() as u64)
+}
+
+/// Takes a disconnected client out of the map and its room, and announces
+/// that it left, once its user's last session is gone. `username` stands in
+/// if the entry was already dropped, e.g. for falling behind.
+async fn remove_client(shared: &Shared, client_id: ClientId, username: &str) {
+    let mut clients_guard = shared.clients.lock().await;
+    let username = clients_guard.remove(&client_id).map_or(username.to_string(), |client| client.username);
+    leave_rooms(&mut *shared.rooms.lock().await, client_id);
+    shared.emit(ServerEvent::UserLeft { username: username.clone() });
+    let still_online = clients_guard.values().any(|c| c.username == username);
+    drop(clients_guard);
+    if !still_online {
+        shared.broadcast(&Message::new_user_left(username));
+    }
+}
+
+async fn sorted_usernames(clients: &Clients) -> Vec<String> {
+    let mut usernames: Vec<String> = clients.lock().await.values().map(|c| c.username.clone()).collect();
+    usernames.sort();
+    usernames
+}
+
+/// The roster: everyone online, sorted and each name once however many
+/// sessions share it, and which of them are away.
+async fn user_list(clients: &Clients) -> Message {
+    let clients_guard = clients.lock().await;
+    let mut users: Vec<String> = clients_guard.values().map(|c| c.username.clone()).collect();
+    let mut away: Vec<String> = clients_guard.values().filter(|c| c.away.is_some()).map(|c| c.username.clone()).collect();
+    drop(clients_guard);
+    for names in [&mut users, &mut away] {
+        names.sort();
+        names.dedup();
+    }
+    Message::new_user_list(users, away)
+}
+
+/// Marks every session of `username` away for `reason`, or back for
+/// `None`, forgetting who has had the auto-reply. False for `/back` when
+/// the user wasn't away.
+async fn set_away(clients: &Clients, username: &str, reason: Option<&str>) -> bool {
+    let mut changed = false;
+    for client in clients.lock().await.values_mut().filter(|c| c.username == username) {
+        changed |= reason.is_some() || client.away.is_some();
+        client.away = reason.map(|reason| Away { reason: reason.to_string(), notified: HashSet::new() });
+    }
+    changed
+}
+
+/// The auto-replies owed to `sender` by the away users `addressed` picks
+/// out: one per user until they next go away or come back. A mention in
+/// `room` only counts for those in that room; direct messages, with no
+/// room, reach them anywhere.
+async fn away_replies(clients: &Clients, sender: &str, room: Option<&str>, addressed: impl Fn(&str) -> bool) -> Vec<String> {
+    // Keyed by user, as each of their sessions records the sender
+    let mut replies = BTreeMap::new();
+    for client in clients.lock().await.values_mut() {
+        if client.username == sender || !addressed(&client.username) || room.is_some_and(|room| room != client.room) {
+            continue;
+        }
+        if let Some(away) = &mut client.away {
+            if away.notified.insert(sender.to_string()) {
+                replies.insert(client.username.clone(), away_notice(&client.username, &away.reason));
+            }
+        }
+    }
+    replies.into_values().collect()
+}
+
+/// `alice is away: lunch`, or just `alice is away` without a reason.
+fn away_notice(username: &str, reason: &str) -> String {
+    if reason.is_empty() {
+        format!("{} is away", username)
+    } else {
+        format!("{} is away: {}", username, reason)
+    }
+}
+
+/// Formats one page of the sorted online list, e.g.
+/// `Online (120): a, b, c ... and 117 more (/who 2 for more)`.
+fn who_page(usernames: &[String], page: usize, page_size: usize) -> String {
+    let page_size = page_size.max(1);
+    let pages = usernames.len().div_ceil(page_size).max(1);
+    if page > pages {
+        return format!("No page {} of /who, there are {} pages", page, pages);
+    }
+    let start = (page - 1) * page_size;
+    let end = (start + page_size).min(usernames.len());
+    let mut reply = format!("Online ({}): {}", usernames.len(), usernames[start..end].join(", "));
+    let remaining = usernames.len() - end;
+    if remaining > 0 {
+        reply.push_str(&format!(" ... and {} more (/who {} for more)", remaining, page + 1));
+    }
+    reply
+}
+
+/// Delivers every broadcast to all clients, or all in its room, in a single
+/// global order. A
+/// client whose queue is full is dropped rather than silently skipped, so
+/// everyone who stays connected sees the same sequence.
+async fn fan_out(
+    mut broadcast_rx: mpsc::UnboundedReceiver<Broadcast>,
+    clients: Clients,
+    rooms: SharedRooms,
+    history_size: usize,
+    mut log: Option<ChatLog>,
+    events: broadcast::Sender<ServerEvent>,
+) {
+    while let Some(Broadcast { room, json: mut json_msg }) = broadcast_rx.recv().await {
+        let mut decoded = Message::from_json(&json_msg).ok();
+        // Chat kept for replay is numbered per room here, where its order is
+        // settled, so clients can tell when they missed some
+        if let (Some(room), Some(Message::Text { ttl: None, seq, .. })) = (&room, &mut decoded) {
+            let mut rooms = rooms.lock().await;
+            let numbered = rooms.entry(room.clone()).or_default();
+            numbered.last_seq += 1;
+            *seq = Some(numbered.last_seq);
+            if history_size == 0 {
+                numbered.evicted_through = numbered.last_seq;
+            }
+            if let Some(json) = decoded.as_ref().and_then(|message| message.to_json().ok()) {
+                json_msg = json;
+            }
+        }
+        if let (Some(log), Some(message)) = (&mut log, &decoded) {
+            if let Err(e) = log.append(room.as_deref(), message) {
+                eprintln!("Can't write chat log: {}", e);
+                let _ = events.send(ServerEvent::Error { address: None, error: format!("can't write chat log: {}", e) });
+            }
+        }
+        if let Some(message) = &decoded {
+            let _ = events.send(ServerEvent::MessageBroadcast { room: room.clone(), message: message.clone() });
+        }
+        let mut clients_guard = clients.lock().await;
+        // Recorded under the clients lock, which joiners hold while replaying;
+        // what went to everyone is the default room's
+        if history_size > 0 && decoded.as_ref().is_some_and(is_replayable) {
+            let mut rooms = rooms.lock().await;
+            let kept = rooms.entry(room.clone().unwrap_or_else(|| DEFAULT_ROOM.to_string())).or_default();
+            // A deleted message isn't replayed at all, nor are its edits
+            if let Some(Message::Delete { target_id, .. }) = &decoded {
+                kept.history.retain(|json| !Message::from_json(json).is_ok_and(|message| concerns(&message, target_id)));
+            }
+            if kept.history.len() == history_size {
+                if let Some(seq) = kept.history.pop_front().as_deref().and_then(chat_seq) {
+                    kept.evicted_through = seq;
+                }
+            }
+            kept.history.push_back(json_msg.clone());
+        }
+        let outgoing = Outgoing::new(json_msg);
+        // Whose chat this is, if its author is waiting to hear it arrived
+        let author = match &decoded {
+            Some(Message::Text { username, id: Some(id), local_id: Some(_), .. }) => Some((username.as_str(), id.as_str())),
+            _ => None,
+        };
+        let mut recipients = 0;
+        clients_guard.retain(|_, client| {
+            if room.as_ref().is_some_and(|room| *room != client.room) {
+                return true;
+            }
+            match client.sender.try_send(outgoing.clone()) {
+                Ok(()) => {
+                    recipients += usize::from(author.is_some_and(|(username, _)| username != client.username));
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    eprintln!("Disconnecting {}: too far behind", client.username);
+                    let error = format!("disconnected {}: too far behind", client.username);
+                    let _ = events.send(ServerEvent::Error { address: client.address, error });
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+        if let Some((username, id)) = author {
+            let ack = Outgoing::new(Message::new_delivered(id.to_string(), recipients).to_json().unwrap_or_default());
+            for client in clients_guard.values().filter(|client| client.username == username) {
+                let _ = client.sender.try_send(ack.clone());
+            }
+        }
+    }
+}
+
+/// Whether a broadcast belongs in the history replayed to new joiners: chat
+/// text and changes to it, and not ephemeral messages, which should be gone
+/// by then. Joins, files and keys reach new joiners by other means.
+fn is_replayable(message: &Message) -> bool {
+    matches!(message, Message::Text { ttl: None, .. } | Message::Edit { .. } | Message::Delete { .. })
+}
+
+/// The number a kept chat message was given, if `json` is one.
+fn chat_seq(json: &str) -> Option<u64> {
+    match Message::from_json(json).ok()? {
+        Message::Text { seq, .. } => seq,
+        _ => None,
+    }
+}
+
+/// Whether `message` is the chat message `id` or an edit of it.
+fn concerns(message: &Message, id: &str) -> bool {
+    match message {
+        Message::Text { id: Some(text_id), .. } => text_id == id,
+        Message::Edit { target_id, .. } => target_id == id,
+        _ => false,
+    }
+}
+
+/// How much longer a user must wait before posting under slow mode, or
+/// `None` if they may post now, in which case the post time is recorded.
+fn slow_mode_wait(last_post: &mut Option<Instant>, interval: Duration, exempt: bool) -> Option<Duration> {
+    let wait = last_post
+        .map(|last| interval.saturating_sub(last.elapsed()))
+        .unwrap_or_default();
+    if !exempt && !wait.is_zero() {
+        return Some(wait);
+    }
+    *last_post = Some(Instant::now());
+    None
+}
+
+/// Allows `burst` messages at once, refilled at `rate` per second.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        TokenBucket { rate, burst, tokens: burst, refilled: Instant::now() }
+    }
+
+    /// Whether one more message may go through now, using up a token if so.
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * self.rate).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Queues a direct message for every session of `to`. A session whose
+/// connection has already gone is dropped from the map on the way.
+async fn send_private(clients: &Clients, to: &str, json: &str) -> Result<(), String> {
+    let mut clients_guard = clients.lock().await;
+    let mut found = false;
+    let mut delivered = false;
+    let outgoing = Outgoing::new(json.to_string());
+    clients_guard.retain(|_, client| {
+        if client.username != to {
+            return true;
+        }
+        found = true;
+        match client.sender.try_send(outgoing.clone()) {
+            Ok(()) => {
+                delivered = true;
+                true
+            }
+            Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Closed(_)) => false,
+        }
+    });
+    match (found, delivered) {
+        (false, _) => Err(format!("No user named {} is connected", to)),
+        (true, false) => Err(format!("{} is no longer connected", to)),
+        (true, true) => Ok(()),
+    }
+}
+
+/// A snapshot of the server's shared state for `/debug-state`. Only counts,
+/// sizes and room names are reported; usernames, addresses and credentials
+/// are left out.
+async fn debug_state(clients: &Clients, rooms: &SharedRooms, files: &SharedFiles, mutes: &SharedMutes, slow_mode: u64) -> Vec<String> {
+    let now = Instant::now();
+    let muted = mutes.lock().await.values().filter(|until| **until > now).count();
+    let (connections, users, queued, deepest) = {
+        let clients_guard = clients.lock().await;
+        let backlog = |client: &ClientInfo| client.sender.max_capacity() - client.sender.capacity();
+        let mut users: Vec<&str> = clients_guard.values().map(|c| c.username.as_str()).collect();
+        users.sort();
+        users.dedup();
+        (
+            clients_guard.len(),
+            users.len(),
+            clients_guard.values().map(backlog).sum::<usize>(),
+            clients_guard.values().map(backlog).max().unwrap_or(0),
+        )
+    };
+    let mut occupied: Vec<(String, usize)> = rooms
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, room)| !room.members.is_empty())
+        .map(|(name, room)| (name.clone(), room.members.len()))
+        .collect();
+    occupied.sort();
+    let members: Vec<String> = occupied.iter().map(|(name, count)| format!("{} {}", name, count)).collect();
+    let stats = files.lock().await.stats();
+    vec![
+        format!("Connections: {} ({} distinct users, {} timed out)", connections, users, muted),
+        format!("Rooms: {} in use ({})", occupied.len(), members.join(", ")),
+        format!("Outgoing queues: {} messages waiting, deepest {}/{}", queued, deepest, CLIENT_QUEUE_SIZE),
+        format!("Shared files: {} cached, {}/{} bytes", stats.files, stats.bytes, stats.max_bytes),
+        format!("Slow mode: {}", if slow_mode == 0 { "off".to_string() } else { format!("{}s", slow_mode) }),
+    ]
+}
+
+/// `/sessions` lists the connections holding the same proven identity key
+/// as `client_id`; `/sessions end <id>` closes one of the others.
+async fn sessions(clients: &Clients, client_id: ClientId, args: &str) -> Vec<String> {
+    let mut clients_guard = clients.lock().await;
+    let Some(key) = clients_guard.get(&client_id).and_then(|client| client.public_key.clone()) else {
+        return vec!["Sessions are matched by identity key, and this connection hasn't proven one".to_string()];
+    };
+    let short_id = |id: &ClientId| id.simple().to_string()[..8].to_string();
+    let mut own: Vec<(&ClientId, &ClientInfo)> = clients_guard
+        .iter()
+        .filter(|(_, client)| client.public_key.as_deref() == Some(key.as_str()))
+        .collect();
+    own.sort_by_key(|(_, client)| client.connected_at);
+
+    let mut args = args.split_whitespace();
+    match (args.next(), args.next(), args.next()) {
+        (None, _, _) => {
+            let mut lines = vec![format!("Your sessions ({}):", own.len())];
+            for (id, client) in &own {
+                let address = client.address.map_or("unknown address".to_string(), |address| address.to_string());
+                let this = if **id == client_id { ", this session" } else { "" };
+                lines.push(format!("  {} {} from {}, connected {}s ago{}",
+                    short_id(id), client.username, address, client.connected_at.elapsed().as_secs(), this));
+            }
+            if own.len() == 1 {
+                lines.push("This is your only session".to_string());
+            }
+            lines
+        }
+        (Some("end"), Some(target), None) => {
+            let matches: Vec<ClientId> = own.iter().map(|(id, _)| **id).filter(|id| short_id(id).starts_with(target)).collect();
+            match matches.as_slice() {
+                [] => vec![format!("No session of yours matches {}", target)],
+                [id] if *id == client_id => vec!["That's this session; quit instead".to_string()],
+                [id] => {
+                    // Sent as a rejection so the ended client doesn't reconnect
+                    if let Some(client) = clients_guard.remove(id) {
+                        let notice = Message::new_rejected(RejectReason::Replaced, "ended from another session".to_string());
+                        let _ = client.sender.try_send(Outgoing::new(notice.to_json().unwrap_or_default()));
+                    }
+                    vec![format!("Ended session {}", short_id(id))]
+                }
+                _ => vec![format!("{} matches more than one session, give more of the id", target)],
+            }
+        }
+        _ => vec!["Usage: /sessions [end <id>]".to_string()],
+    }
+}
+
+/// Time left on `username`'s `/timeout`, if they are still muted and
+/// `posting`. Commands stay available so a muted user can still read and
+/// look around.
+async fn mute_remaining(mutes: &SharedMutes, username: &str, posting: bool) -> Option<Duration> {
+    if !posting {
+        return None;
+    }
+    let mut mutes = mutes.lock().await;
+    let remaining = mutes.get(username)?.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        mutes.remove(username);
+        return None;
+    }
+    Some(remaining)
+}
+
+/// Mutes `username` for `duration`, zero lifting an earlier timeout.
+/// Returns false if nobody by that name is connected.
+async fn time_out(clients: &Clients, mutes: &SharedMutes, username: &str, duration: Duration) -> bool {
+    if !clients.lock().await.values().any(|c| c.username == username) {
+        return false;
+    }
+    let mut mutes = mutes.lock().await;
+    if duration.is_zero() {
+        mutes.remove(username);
+    } else {
+        mutes.insert(username.to_string(), Instant::now() + duration);
+    }
+    true
+}
+
+/// Whether a client message puts something in front of others, which a
+/// `/timeout` stops: chat text, `/me`, `/msg` and `/away` messages, and
+/// files, edits and deletions. Other commands only look things up or
+/// change the client's own state.
+fn is_post(decoded: Option<&Message>, text: &str) -> bool {
+    match decoded {
+        Some(Message::File { .. } | Message::Edit { .. } | Message::Delete { .. }) => true,
+        Some(Message::Text { .. }) | None => {
+            let command = text.split_whitespace().next().unwrap_or("");
+            !is_server_command(text) || ["/msg", "/me", "/away"].contains(&command)
+        }
+        Some(_) => false,
+    }
+}
+
+/// Admins and moderators may run moderation commands and skip slow mode.
+fn is_moderator(config: &ServerConfig, username: &str) -> bool {
+    matches!(config.roles.get(username).map(String::as_str), Some("admin" | "mod" | "moderator"))
+}
+
+/// Decodes a line sent as a JSON `Message`. Only text and file messages are
+/// accepted from clients; anything else is left to be handled as raw text.
+fn decode_line(line: &str) -> Option<Message> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    match Message::from_json(line).ok()? {
+        msg @ (Message::File { .. }
+        | Message::FileStart { .. }
+        | Message::FileChunk { .. }
+        | Message::FileEnd { .. }
+        | Message::Text { .. }
+        | Message::Edit { .. }
+        | Message::Delete { .. }
+        | Message::Backfill { .. }
+        | Message::Pong { .. }
+        | Message::Identity { .. }
+        | Message::IdentityProof { .. }) => Some(msg),
+        _ => None,
+    }
+}
+
+/// The capabilities announced to clients when they join.
+fn server_info(config: &ServerConfig) -> ServerInfo {
+    let mut features: Vec<String> = ["files", "chunks", "identity", "gzip", "zstd", "edit"].iter().map(|feature| feature.to_string()).collect();
+    features.extend(SERVER_COMMANDS.iter().map(|command| command.trim_start_matches('/').to_string()));
+    if config.echo {
+        features.push("echo".to_string());
+    }
+    if config.strict {
+        features.push("strict".to_string());
+    }
+    ServerInfo {
+        protocol: PROTOCOL_VERSION,
+        features,
+        max_file_size: config.file_cache_size,
+    }
+}
+
+/// Describes why a line breaks the protocol, for strict mode: clients must
+/// send JSON text or file messages, and only use commands the server
+/// knows. Frames over the limit are caught as they are read.
+fn strict_violation(decoded: Option<&Message>, text: &str) -> Option<String> {
+    if decoded.is_none() {
+        return Some("expected a JSON text or file message".to_string());
+    }
+    let command = text.split_whitespace().next().unwrap_or("");
+    if command.starts_with('/') && !SERVER_COMMANDS.contains(&command) {
+        return Some(format!("unknown command {}", command));
+    }
+    None
+}
+
+fn is_server_command(text: &str) -> bool {
+    SERVER_COMMANDS.contains(&text.split_whitespace().next().unwrap_or(""))
+}
+
+/// Applies `/roomlimit members|rate <value|off>` to the limits of `room`;
+/// `off` goes back to the server-wide setting.
+fn set_room_limit(limits: &mut RoomLimits, room: &str, arg: &str) -> Result<(), String> {
+    const USAGE: &str = "Usage: /roomlimit [members|rate <value|off>]";
+    let (kind, value) = arg.split_once(char::is_whitespace).ok_or(USAGE)?;
+    let value = value.trim();
+    match kind {
+        "members" if room == DEFAULT_ROOM => Err(format!("{} holds everyone who connects, it can't be limited", DEFAULT_ROOM)),
+        "members" if value == "off" => {
+            limits.max_members = None;
+            Ok(())
+        }
+        "members" => {
+            limits.max_members = Some(value.parse().ok().filter(|&max| max > 0).ok_or("The member limit must be a positive number")?);
+            Ok(())
+        }
+        "rate" if value == "off" => {
+            limits.rate_limit = None;
+            Ok(())
+        }
+        "rate" => {
+            limits.rate_limit = Some(value.parse().ok().filter(|rate: &f64| rate.is_finite() && *rate >= 0.0).ok_or("The rate must be messages per second, 0 for none")?);
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+/// The room a `/join` argument names, as `#name` in lower case.
+fn room_name(arg: &str) -> Result<String, String> {
+    let name = arg.strip_prefix('#').unwrap_or(arg);
+    if name.is_empty() {
+        Err("Usage: /join <room>".to_string())
+    } else if name.chars().count() > MAX_ROOM_LEN {
+        Err(format!("Room names can be at most {} characters", MAX_ROOM_LEN))
+    } else if name.chars().any(|c| c.is_whitespace() || c.is_control() || c == '#') {
+        Err("Room names can't contain spaces, control characters or #".to_string())
+    } else {
+        Ok(format!("#{}", name.to_lowercase()))
+    }
+}
+
+/// Strict-mode username rules: non-empty, short, and free of whitespace and
+/// control characters.
+fn check_username(username: &str) -> Result<(), String> {
+    if username.is_empty() {
+        Err("username must not be empty".to_string())
+    } else if username.chars().count() > MAX_USERNAME_LEN {
+        Err(format!("username is longer than {} characters", MAX_USERNAME_LEN))
+    } else if username.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("username must not contain whitespace or control characters".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Renames a connected client for `/nick`, refusing names that are empty,
+/// taken by another client, or would pick up someone else's role.
+async fn rename(clients: &Clients, client_id: ClientId, current: &str, requested: &str, config: &ServerConfig) -> Result<(), String> {
+    if requested.is_empty() {
+        return Err("Usage: /nick <new name>".to_string());
+    }
+    if requested == current {
+        return Err(format!("You are already {}", current));
+    }
+    if config.strict {
+        check_username(requested).map_err(|violation| format!("Can't rename: {}", violation))?;
+    }
+    // Names are what credentials and roles are checked against
+    if config.auth.requires_credential() {
+        return Err("Names can't be changed on this server".to_string());
+    }
+    if config.roles.contains_key(requested) {
+        return Err(format!("The name {} is reserved", requested));
+    }
+    let mut clients_guard = clients.lock().await;
+    if clients_guard.iter().any(|(id, client)| *id != client_id && client.username == requested) {
+        return Err(format!("The name {} is already taken", requested));
+    }
+    if let Some(client) = clients_guard.get_mut(&client_id) {
+        client.username = requested.to_string();
+    }
+    Ok(())
+}
+
+/// Turns a client away during the join; the connection closes after this.
+async fn reject(writer: &mut StreamWriter, framing: Framing, reason: RejectReason, detail: String) -> Result<(), Box<dyn std::error::Error>> {
+    let msg = Message::new_rejected(reason, detail);
+    framing::write_message(writer, framing, &msg).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+fn protocol_error(violation: &str) -> Message {
+    Message::new_system(format!("Protocol error: {}, disconnecting", violation))
+}
+
+/// Builds the `FileAvailable` notice announcing a cached file message.
+fn file_notice(message: &Message) -> Option<Message> {
+    match message {
+        Message::File { username, filename, size, id: Some(id), .. } => Some(
+            Message::new_file_available(id.clone(), filename.clone(), *size, username.clone()),
+        ),
+        _ => None,
+    }
+}
+
+/// Returns `requested` if no connected client uses it, otherwise the first
+/// free `requested2`, `requested3`, ...
+fn unique_username(clients: &HashMap<ClientId, ClientInfo>, requested: &str) -> String {
+    let taken = |name: &str| clients.values().any(|c| c.username == name);
+    if !taken(requested) {
+        return requested.to_string();
+    }
+    (2..)
+        .map(|n| format!("{}{}", requested, n))
+        .find(|candidate| !taken(candidate))
+        .expect("suffix space is unbounded")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::auth::{AuthFuture, NoAuth};
+    use crate::file_transfer::Compressor;
+    use crate::message::Codec;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::TcpStream;
+
+    /// The server's settings when started without flags, minus the
+    /// heartbeat and rate limit, which only get in a test's way.
+    pub(crate) fn test_config() -> ServerConfig {
+        ServerConfig {
+            port: 0,
+            dedup_usernames: DedupMode::Reject,
+            file_cache_size: 100 * 1024 * 1024,
+            file_cache_ttl: Duration::from_secs(3600),
+            health_port: None,
+            who_page_size: 50,
+            roles: HashMap::new(),
+            user_colors: HashMap::new(),
+            echo: false,
+            slow_mode: 0,
+            rate_limit: 0.0,
+            rate_burst: 10,
+            room_limits: HashMap::new(),
+            strict: false,
+            auth: Arc::new(NoAuth),
+            max_clients: None,
+            netsim: None,
+            heartbeat_interval: None,
+            heartbeat_misses: 3,
+            history_size: 200,
+            log_file: None,
+            tls: None,
+            binary: false,
+            zstd_dictionary: None,
+        }
+    }
+
+    /// Starts a server on a free local port and returns the port.
+    pub(crate) async fn start(config: ServerConfig) -> u16 {
+        start_with_clients(config).await.0
+    }
+
+    /// Starts a server as `start` does, also handing back its client map.
+    async fn start_with_clients(config: ServerConfig) -> (u16, Clients) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind a local port");
+        let port = listener.local_addr().expect("bound address").port();
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let served = clients.clone();
+        tokio::spawn(async move {
+            let _ = serve_clients(listener, config, served, broadcast::channel(EVENT_QUEUE_SIZE).0).await;
+        });
+        (port, clients)
+    }
+
+    /// A connection speaking newline-delimited JSON, as the client does.
+    pub(crate) struct TestClient {
+        username: String,
+        reader: BufReader<OwnedReadHalf>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl TestClient {
+        /// Joins as `username` and reads up to the roster, the last part of
+        /// the welcome.
+        pub(crate) async fn join(port: u16, username: &str) -> TestClient {
+            let mut client = TestClient::connect(port, username).await;
+            client.recv_until(|msg| matches!(msg, Message::UserList { .. })).await;
+            client
+        }
+
+        /// Connects and asks for `username`, leaving the server's answer
+        /// unread.
+        pub(crate) async fn connect(port: u16, username: &str) -> TestClient {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.expect("connect to the test server");
+            let (reader, writer) = stream.into_split();
+            let mut client = TestClient { username: username.to_string(), reader: BufReader::new(reader), writer };
+            client.send_line(username).await;
+            client
+        }
+
+        /// The username the server let us in under.
+        pub(crate) async fn recv_welcome(&mut self) -> String {
+            match self.recv_until(|msg| matches!(msg, Message::Welcome { .. })).await {
+                Message::Welcome { username, .. } => username,
+                _ => unreachable!(),
+            }
+        }
+
+        pub(crate) async fn send_line(&mut self, line: &str) {
+            self.writer.write_all(format!("{}\n", line).as_bytes()).await.expect("write to the server");
+        }
+
+        pub(crate) async fn send(&mut self, msg: &Message) {
+            self.send_line(&msg.to_json().expect("serializable message")).await;
+        }
+
+        /// Sends `text` as chat, which is also how commands go.
+        pub(crate) async fn say(&mut self, text: &str) {
+            let msg = Message::new_text(self.username.clone(), text.to_string(), None, None);
+            self.send(&msg).await;
+        }
+
+        /// The next message, failing the test if none comes within 5s.
+        pub(crate) async fn recv(&mut self) -> Message {
+            self.try_recv(Duration::from_secs(5)).await.expect("a message from the server")
+        }
+
+        /// The next message within `wait`, `None` on silence or a closed
+        /// connection.
+        pub(crate) async fn try_recv(&mut self, wait: Duration) -> Option<Message> {
+            let mut line = String::new();
+            match tokio::time::timeout(wait, self.reader.read_line(&mut line)).await {
+                Ok(Ok(n)) if n > 0 => Some(Message::from_json(line.trim()).expect("a JSON message")),
+                _ => None,
+            }
+        }
+
+        /// Skips messages until one matches `wanted`.
+        pub(crate) async fn recv_until(&mut self, wanted: impl Fn(&Message) -> bool) -> Message {
+            loop {
+                let msg = self.recv().await;
+                if wanted(&msg) {
+                    return msg;
+                }
+            }
+        }
+
+        /// Skips messages until one matches `wanted`, `None` if none does
+        /// within `wait`.
+        pub(crate) async fn try_recv_until(&mut self, wait: Duration, wanted: impl Fn(&Message) -> bool) -> Option<Message> {
+            let deadline = Instant::now() + wait;
+            loop {
+                let msg = self.try_recv(deadline.saturating_duration_since(Instant::now())).await?;
+                if wanted(&msg) {
+                    return Some(msg);
+                }
+            }
+        }
+
+        /// Skips messages until a `System` notice, returning its text.
+        pub(crate) async fn recv_system(&mut self) -> String {
+            match self.recv_until(|msg| matches!(msg, Message::System { .. })).await {
+                Message::System { content, .. } => content,
+                _ => unreachable!(),
+            }
+        }
+
+        /// Skips messages until a `System` notice starting with `prefix`,
+        /// returning its text.
+        pub(crate) async fn recv_notice(&mut self, prefix: &str) -> String {
+            match self.recv_until(|msg| matches!(msg, Message::System { content, .. } if content.starts_with(prefix))).await {
+                Message::System { content, .. } => content,
+                _ => unreachable!(),
+            }
+        }
+
+        /// Skips messages until chat text, returning its content.
+        pub(crate) async fn recv_text(&mut self) -> String {
+            match self.recv_until(|msg| matches!(msg, Message::Text { .. })).await {
+                Message::Text { content, .. } => content,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_user_can_post_again_once_the_timeout_elapses() {
+        let mut config = test_config();
+        config.roles.insert("mia".to_string(), "mod".to_string());
+        let port = start(config).await;
+        let mut mia = TestClient::join(port, "mia").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        mia.say("/timeout bob 2").await;
+        assert_eq!(bob.recv_system().await, "bob was timed out for 2s by mia");
+        bob.say("hello?").await;
+        assert!(bob.recv_system().await.starts_with("You are timed out, you can post again in"));
+        // Looking around is still allowed
+        bob.say("/who").await;
+        assert_eq!(bob.recv_system().await, "Online (2): bob, mia");
+        bob.say("/msg mia let me talk").await;
+        assert!(bob.recv_system().await.starts_with("You are timed out"));
+
+        // Reconnecting doesn't lift it
+        drop(bob);
+        mia.recv_until(|msg| matches!(msg, Message::UserLeft { username, .. } if username == "bob")).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        bob.say("back again").await;
+        assert!(bob.recv_system().await.starts_with("You are timed out"));
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        bob.say("can I talk now?").await;
+        assert_eq!(bob.recv_text().await, "can I talk now?");
+        assert_eq!(mia.recv_text().await, "can I talk now?");
+    }
+
+    #[tokio::test]
+    async fn client_that_stops_answering_pings_is_reaped() {
+        let mut config = test_config();
+        config.heartbeat_interval = Some(Duration::from_millis(100));
+        config.heartbeat_misses = 3;
+        let port = start(config).await;
+        let mut ghost = TestClient::join(port, "ghost").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        // Bob answers every ping until the server gives up on the ghost
+        loop {
+            match bob.recv().await {
+                Message::Ping { .. } => bob.send(&Message::new_pong()).await,
+                Message::UserLeft { username, .. } if username == "ghost" => break,
+                _ => {}
+            }
+        }
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            while ghost.try_recv(Duration::from_secs(1)).await.is_some() {}
+        });
+        assert!(closed.await.is_ok(), "the ghost's connection is closed");
+
+        bob.say("still here").await;
+        assert_eq!(bob.recv_text().await, "still here");
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_skipped_and_the_connection_kept() {
+        // The limit is one chunk's worth however large files may be
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+
+        ana.send_line(&"x".repeat(MAX_FRAME + 1)).await;
+        assert!(ana.recv_system().await.starts_with("Message dropped: line exceeds the"));
+        ana.say("still here").await;
+        assert_eq!(ana.recv_text().await, "still here");
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_disconnects_in_strict_mode() {
+        let mut config = test_config();
+        config.strict = true;
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+
+        ana.send_line(&"x".repeat(MAX_FRAME + 1)).await;
+        assert!(ana.recv_system().await.starts_with("Protocol error: line exceeds the"));
+        assert!(ana.try_recv(Duration::from_secs(1)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn chat_stays_in_its_room() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut cat = TestClient::join(port, "cat").await;
+
+        for client in [&mut ana, &mut bob] {
+            client.say("/join #rust").await;
+            client.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        }
+        ana.say("borrowck!").await;
+        assert_eq!(bob.recv_text().await, "borrowck!");
+        cat.say("anyone here?").await;
+        assert_eq!(cat.recv_text().await, "anyone here?");
+        assert_eq!(ana.recv_text().await, "borrowck!");
+        assert!(ana.try_recv(Duration::from_millis(300)).await.is_none_or(|msg| !matches!(msg, Message::Text { .. })));
+    }
+
+    #[tokio::test]
+    async fn late_joiner_gets_the_latest_history_in_order() {
+        let mut config = test_config();
+        config.history_size = 3;
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        for n in 1..=5 {
+            ana.say(&format!("message {}", n)).await;
+            ana.recv_text().await;
+        }
+
+        let mut bob = TestClient::connect(port, "bob").await;
+        for n in 3..=5 {
+            assert_eq!(bob.recv_text().await, format!("message {}", n));
+        }
+        // Replay comes before anything said after the join
+        ana.say("live").await;
+        assert_eq!(bob.recv_text().await, "live");
+    }
+
+    #[tokio::test]
+    async fn backfill_resends_kept_chat_and_reports_what_is_gone() {
+        let mut config = test_config();
+        config.history_size = 2;
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut seqs = Vec::new();
+        for text in ["a", "b", "c", "d"] {
+            ana.say(text).await;
+            let Message::Text { seq, .. } = ana.recv_until(|msg| matches!(msg, Message::Text { .. })).await else {
+                unreachable!()
+            };
+            seqs.push(seq);
+        }
+        // Ephemeral chat isn't kept, so isn't numbered either
+        let mut ephemeral = Message::new_text("ana".to_string(), "gone soon".to_string(), None, None);
+        if let Message::Text { ttl, .. } = &mut ephemeral {
+            *ttl = Some(60);
+        }
+        ana.send(&ephemeral).await;
+        let Message::Text { seq, .. } = ana.recv_until(|msg| matches!(msg, Message::Text { .. })).await else {
+            unreachable!()
+        };
+        assert_eq!(seq, None);
+        assert_eq!(seqs, [Some(1), Some(2), Some(3), Some(4)]);
+
+        // Only the last two are still kept
+        ana.send(&Message::new_backfill(DEFAULT_ROOM.to_string(), 1, 3)).await;
+        let Message::BackfillUnavailable { room, from, to, .. } = ana.recv().await else {
+            panic!("the server says what it no longer has first");
+        };
+        assert_eq!((room.as_str(), from, to), (DEFAULT_ROOM, 1, 2));
+        let Message::Text { content, seq, .. } = ana.recv().await else {
+            panic!("then resends what it has");
+        };
+        assert_eq!((content.as_str(), seq), ("c", Some(3)));
+
+        // Another room's chat isn't sent to a client outside it
+        ana.send(&Message::new_backfill("#elsewhere".to_string(), 1, 1)).await;
+        ana.say("after").await;
+        assert_eq!(ana.recv_text().await, "after");
+    }
+
+    #[tokio::test]
+    async fn joining_a_room_replays_only_its_history() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        ana.say("in general").await;
+        ana.recv_text().await;
+        ana.say("/join #rust").await;
+        ana.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        ana.say("in rust").await;
+        ana.recv_text().await;
+
+        let mut bob = TestClient::join(port, "bob").await;
+        bob.say("/join #rust").await;
+        bob.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        assert_eq!(bob.recv_text().await, "in rust");
+    }
+
+    #[tokio::test]
+    async fn subscribers_hear_who_joined_and_what_was_said() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind a local port");
+        let port = listener.local_addr().expect("bound address").port();
+        let server = ChatServer::on(listener, test_config());
+        let mut events = server.subscribe();
+        tokio::spawn(async move {
+            let _ = server.run().await;
+        });
+
+        let mut ana = TestClient::join(port, "ana").await;
+        ana.say("hello").await;
+        ana.recv_text().await;
+
+        let wait = Duration::from_secs(5);
+        match tokio::time::timeout(wait, events.recv()).await.expect("an event").expect("a live server") {
+            ServerEvent::UserJoined { username, address } => {
+                assert_eq!(username, "ana");
+                assert!(address.is_some());
+            }
+            other => panic!("expected ana to join first, got {:?}", other),
+        }
+        loop {
+            let event = tokio::time::timeout(wait, events.recv()).await.expect("an event").expect("a live server");
+            if let ServerEvent::MessageBroadcast { room, message: Message::Text { username, content, .. } } = event {
+                assert_eq!((room.as_deref(), username.as_str(), content.as_str()), (Some(DEFAULT_ROOM), "ana", "hello"));
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn history_of_every_room_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("terminal-chat-server-tests-{}", std::process::id()));
+        let mut config = test_config();
+        config.log_file = Some(dir.join("rooms.log"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let port = start(config.clone()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        ana.say("in general").await;
+        ana.recv_text().await;
+        ana.say("/join #rust").await;
+        ana.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        ana.say("in rust").await;
+        ana.recv_text().await;
+
+        let port = start(config).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        assert_eq!(bob.recv_text().await, "in general");
+        bob.say("/join #rust").await;
+        bob.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        assert_eq!(bob.recv_text().await, "in rust");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn full_room_turns_joiners_away_while_others_accept_them() {
+        let mut config = test_config();
+        config.room_limits.insert("#small".to_string(), RoomLimits { max_members: Some(1), rate_limit: None });
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        ana.say("/join #small").await;
+        ana.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        bob.say("/join #small").await;
+        assert_eq!(bob.recv_notice("#small").await, "#small is full (1 members), try again later");
+        bob.say("/join #big").await;
+        let joined = bob.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        assert!(matches!(joined, Message::RoomJoined { room, .. } if room == "#big"));
+
+        // A seat frees up once its holder leaves
+        ana.say("/part").await;
+        ana.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        bob.say("/join #small").await;
+        let joined = bob.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        assert!(matches!(joined, Message::RoomJoined { room, .. } if room == "#small"));
+    }
+
+    #[tokio::test]
+    async fn admins_set_a_room_rate_that_applies_there_only() {
+        let mut config = test_config();
+        config.roles.insert("ana".to_string(), "admin".to_string());
+        config.rate_burst = 2;
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        bob.say("/roomlimit rate 0.1").await;
+        assert_eq!(bob.recv_notice("Only").await, "Only admins can change room limits");
+        for client in [&mut ana, &mut bob] {
+            client.say("/join #slow").await;
+            client.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        }
+        ana.say("/roomlimit rate 0.1").await;
+        assert_eq!(bob.recv_notice("ana set").await, "ana set the limits of #slow: no member limit, 0.1/s each");
+
+        bob.say("one").await;
+        bob.say("two").await;
+        bob.say("three").await;
+        assert_eq!(bob.recv_notice("You are").await, "You are sending messages too fast (limit 0.1/s), some were dropped");
+
+        // Lifted, the server's setting, none, applies again
+        ana.say("/roomlimit rate off").await;
+        assert_eq!(bob.recv_notice("ana set").await, "ana set the limits of #slow: no member limit, no rate limit (server default)");
+        bob.say("four").await;
+        bob.recv_until(|msg| matches!(msg, Message::Text { content, .. } if content == "four")).await;
+    }
+
+    #[tokio::test]
+    async fn away_replies_come_once_per_sender_and_only_for_mentions_in_the_same_room() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        ana.say("/away lunch").await;
+        bob.recv_notice("ana is away").await;
+
+        // Mentioned from another room: no reply
+        bob.say("/join #rust").await;
+        bob.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        bob.say("@ana are you there?").await;
+        bob.recv_text().await;
+        bob.say("/who").await;
+        assert!(bob.recv_system().await.starts_with("Online"));
+
+        // In the same room, once, however often
+        bob.say("/part").await;
+        bob.recv_notice("bob joined #general").await;
+        bob.say("@ana ping").await;
+        assert_eq!(bob.recv_notice("ana").await, "ana is away: lunch");
+        bob.say("@ana ping again").await;
+        bob.recv_until(|msg| matches!(msg, Message::Text { content, .. } if content == "@ana ping again")).await;
+        bob.say("/who").await;
+        assert!(bob.recv_system().await.starts_with("Online"));
+
+        // Direct messages reach her from anywhere, but she has replied already
+        let mut cat = TestClient::join(port, "cat").await;
+        cat.say("/join #rust").await;
+        cat.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+        cat.say("/msg ana hello").await;
+        assert_eq!(cat.recv_notice("ana").await, "ana is away: lunch");
+    }
+
+    #[tokio::test]
+    async fn sent_chat_is_acknowledged_with_its_recipient_count() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        let mut msg = Message::new_text("ana".to_string(), "hi".to_string(), None, None);
+        if let Message::Text { local_id, .. } = &mut msg {
+            *local_id = Some("l1".to_string());
+        }
+        ana.send(&msg).await;
+        let echo_id = match ana.recv_until(|msg| matches!(msg, Message::Text { .. })).await {
+            Message::Text { id, local_id, .. } => {
+                assert_eq!(local_id.as_deref(), Some("l1"));
+                id.expect("the server numbers chat")
+            }
+            _ => unreachable!(),
+        };
+        match ana.recv().await {
+            Message::Delivered { id, recipients, .. } => assert_eq!((id, recipients), (echo_id, 1)),
+            other => panic!("expected an ack, got {:?}", other),
+        }
+        assert_eq!(bob.recv_text().await, "hi");
+
+        // Chat without a local id, such as from a script, is not acknowledged
+        bob.say("hello").await;
+        bob.recv_text().await;
+        assert!(bob.try_recv(Duration::from_millis(300)).await.is_none_or(|msg| !matches!(msg, Message::Delivered { .. })));
+    }
+
+    #[tokio::test]
+    async fn taken_username_is_turned_away_and_a_free_one_let_in() {
+        let port = start(test_config()).await;
+        let mut alice = TestClient::join(port, "alice").await;
+
+        let mut second = TestClient::connect(port, "alice").await;
+        match second.recv().await {
+            Message::Rejected { reason, detail, .. } => {
+                assert_eq!(reason, RejectReason::UsernameTaken);
+                assert_eq!(detail, "alice is already connected");
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+
+        let mut alicia = TestClient::connect(port, "alicia").await;
+        assert_eq!(alicia.recv_welcome().await, "alicia");
+        // The first alice was left connected
+        alice.say("still here").await;
+        assert_eq!(alicia.recv_text().await, "still here");
+    }
+
+    #[tokio::test]
+    async fn suffix_mode_renames_a_second_alice() {
+        let mut config = test_config();
+        config.dedup_usernames = DedupMode::Suffix;
+        let port = start(config).await;
+        let mut first = TestClient::connect(port, "alice").await;
+        assert_eq!(first.recv_welcome().await, "alice");
+        let mut second = TestClient::connect(port, "alice").await;
+        assert_eq!(second.recv_welcome().await, "alice2");
+    }
+
+    #[tokio::test]
+    async fn file_payload_goes_only_to_the_client_that_fetches_it() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut cat = TestClient::join(port, "cat").await;
+
+        ana.send(&Message::new_file("ana".to_string(), "notes.txt".to_string(), 5, b"hello".to_vec(), None)).await;
+        let announced = |msg: &Message| matches!(msg, Message::FileAvailable { .. } | Message::File { .. });
+        let Message::FileAvailable { id, filename, size, .. } = bob.recv_until(announced).await else {
+            panic!("bob was sent the payload instead of a notice");
+        };
+        assert_eq!((filename.as_str(), size), ("notes.txt", 5));
+        assert!(matches!(cat.recv_until(announced).await, Message::FileAvailable { .. }));
+
+        bob.say(&format!("/fetch {}", id)).await;
+        let mut data = Vec::new();
+        loop {
+            match bob.recv().await {
+                Message::FileChunk { data: chunk, .. } => data.extend(chunk),
+                Message::FileEnd { .. } => break,
+                _ => {}
+            }
+        }
+        assert_eq!(data, b"hello");
+        assert!(cat.try_recv(Duration::from_millis(300)).await.is_none_or(|msg| !matches!(msg, Message::FileChunk { .. })));
+    }
+
+    #[tokio::test]
+    async fn healthz_answers_200_while_the_server_runs() {
+        let health_port = std::net::TcpListener::bind("127.0.0.1:0").and_then(|probe| probe.local_addr()).expect("a free port").port();
+        let mut config = test_config();
+        config.health_port = Some(health_port);
+        let port = start(config).await;
+        TestClient::join(port, "ana").await;
+
+        let mut response = String::new();
+        for _ in 0..50 {
+            if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", health_port)).await {
+                stream.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").await.unwrap();
+                tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response).await.unwrap();
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "got {:?}", response);
+    }
+
+    #[tokio::test]
+    async fn who_lists_everyone_sorted_to_the_asker_only() {
+        let port = start(test_config()).await;
+        let mut carol = TestClient::join(port, "carol").await;
+        let _ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        bob.say("/who").await;
+        assert_eq!(bob.recv_notice("Online").await, "Online (3): ana, bob, carol");
+        let who = |msg: &Message| matches!(msg, Message::System { content, .. } if content.starts_with("Online"));
+        assert!(carol.try_recv_until(Duration::from_millis(300), who).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn who_on_a_large_server_is_paged() {
+        let mut config = test_config();
+        config.who_page_size = 2;
+        let port = start(config).await;
+        let mut clients = Vec::new();
+        for name in ["eve", "dan", "cat", "bob", "ana"] {
+            clients.push(TestClient::join(port, name).await);
+        }
+        let ana = clients.last_mut().unwrap();
+        ana.say("/who").await;
+        assert_eq!(ana.recv_notice("Online").await, "Online (5): ana, bob ... and 3 more (/who 2 for more)");
+        ana.say("/who 3").await;
+        assert_eq!(ana.recv_notice("Online").await, "Online (5): eve");
+        ana.say("/who 4").await;
+        assert_eq!(ana.recv_notice("No page").await, "No page 4 of /who, there are 3 pages");
+    }
+
+    #[tokio::test]
+    async fn debug_state_counts_the_clients_and_rooms_in_use() {
+        let mut config = test_config();
+        config.roles.insert("ana".to_string(), "admin".to_string());
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let _carol = TestClient::join(port, "carol").await;
+        bob.say("/join #dev").await;
+        bob.recv_until(|msg| matches!(msg, Message::RoomJoined { .. })).await;
+
+        ana.say("/debug-state").await;
+        assert_eq!(ana.recv_notice("Connections:").await, "Connections: 3 (3 distinct users, 0 timed out)");
+        assert_eq!(ana.recv_notice("Rooms:").await, "Rooms: 2 in use (#dev 1, #general 2)");
+
+        // Only admins get to see it
+        bob.say("/debug-state").await;
+        assert_eq!(bob.recv_notice("Only").await, "Only admins can dump the server state");
+    }
+
+    #[tokio::test]
+    async fn admin_messages_carry_the_role_tag_to_recipients() {
+        let mut config = test_config();
+        config.roles.insert("ana".to_string(), "admin".to_string());
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        ana.say("hello").await;
+        match bob.recv_until(|msg| matches!(msg, Message::Text { .. })).await {
+            Message::Text { role, .. } => assert_eq!(role.as_deref(), Some("admin")),
+            _ => unreachable!(),
+        }
+        bob.say("hi").await;
+        match ana.recv_until(|msg| matches!(msg, Message::Text { username, .. } if username == "bob")).await {
+            Message::Text { role, .. } => assert_eq!(role, None),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn echo_mode_sends_each_message_back_to_its_sender_only() {
+        let mut config = test_config();
+        config.echo = true;
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        for text in ["one", "two", "ünïcödé 👋"] {
+            ana.say(text).await;
+            assert_eq!(ana.recv_text().await, text);
+        }
+        assert!(bob.try_recv_until(Duration::from_millis(300), |msg| matches!(msg, Message::Text { .. })).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn every_client_sees_the_same_order_under_load() {
+        let port = start(test_config()).await;
+        let mut clients = Vec::new();
+        for name in ["ana", "bob", "cat"] {
+            clients.push(TestClient::join(port, name).await);
+        }
+        for n in 0..50 {
+            for client in &mut clients {
+                let text = format!("{} {}", client.username, n);
+                client.say(&text).await;
+            }
+        }
+        let mut orders = Vec::new();
+        for client in &mut clients {
+            let mut order = Vec::new();
+            while order.len() < 150 {
+                order.push(client.recv_text().await);
+            }
+            orders.push(order);
+        }
+        assert_eq!(orders[0], orders[1]);
+        assert_eq!(orders[0], orders[2]);
+    }
+
+    #[tokio::test]
+    async fn direct_message_goes_to_its_target_and_back_to_the_sender_only() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut carol = TestClient::join(port, "carol").await;
+
+        ana.say("/msg bob just between us").await;
+        for client in [&mut bob, &mut ana] {
+            match client.recv_until(|msg| matches!(msg, Message::Private { .. })).await {
+                Message::Private { from, to, content, .. } => {
+                    assert_eq!((from.as_str(), to.as_str(), content.as_str()), ("ana", "bob", "just between us"));
+                }
+                _ => unreachable!(),
+            }
+        }
+        let leaked = |msg: &Message| matches!(msg, Message::Private { .. } | Message::Text { .. });
+        assert!(carol.try_recv_until(Duration::from_millis(300), leaked).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn direct_message_reaches_every_session_of_its_target() {
+        let mut config = test_config();
+        config.dedup_usernames = DedupMode::Allow;
+        let port = start(config).await;
+        let mut phone = TestClient::join(port, "bob").await;
+        let mut laptop = TestClient::join(port, "bob").await;
+        let mut ana = TestClient::join(port, "ana").await;
+        ana.say("/msg bob ping").await;
+        for session in [&mut phone, &mut laptop] {
+            match session.recv_until(|msg| matches!(msg, Message::Private { .. })).await {
+                Message::Private { from, content, .. } => assert_eq!((from.as_str(), content.as_str()), ("ana", "ping")),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_mode_rejects_a_second_post_until_the_window_passes() {
+        let mut config = test_config();
+        config.slow_mode = 1;
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        ana.say("one").await;
+        assert_eq!(bob.recv_text().await, "one");
+        ana.say("two").await;
+        assert_eq!(ana.recv_notice("Slow mode").await, "Slow mode is on, wait 1s before posting again");
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        ana.say("three").await;
+        assert_eq!(bob.recv_text().await, "three");
+    }
+
+    #[tokio::test]
+    async fn large_file_travels_from_one_client_to_another_intact() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+        // Bytes that would break a newline-delimited stream if sent raw
+        let payload: Vec<u8> = (0..300_000u32).map(|n| (n % 251) as u8).collect();
+
+        // Too big for one frame, so it goes in chunks as the client sends it
+        let file = Message::new_file("ana".to_string(), "blob.bin".to_string(), payload.len() as u64, payload.clone(), None);
+        for piece in FileTransfer::stream_message(&file).unwrap() {
+            ana.send(&piece.unwrap()).await;
+        }
+        let Message::FileAvailable { id, .. } = bob.recv_until(|msg| matches!(msg, Message::FileAvailable { .. })).await else {
+            unreachable!()
+        };
+        bob.say(&format!("/fetch {}", id)).await;
+        let mut data = Vec::new();
+        loop {
+            match bob.recv().await {
+                Message::FileChunk { data: chunk, .. } => data.extend(chunk),
+                Message::FileEnd { crc32, .. } => {
+                    assert_eq!(crc32, Some(crc32fast::hash(&payload)));
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(data, payload);
+        // The stream is still in step afterwards
+        ana.say("after").await;
+        assert_eq!(bob.recv_text().await, "after");
+    }
+
+    #[tokio::test]
+    async fn zstd_dictionary_is_shared_with_joiners_and_files_compressed_with_it_pass_through() {
+        let samples: Vec<Vec<u8>> = (0..200).map(|n| format!("{{\"level\":\"info\",\"order\":{},\"status\":\"shipped\"}}\n", n).into_bytes()).collect();
+        let dictionary = FileTransfer::train_dictionary(&samples, 2048).unwrap();
+        let mut config = test_config();
+        config.zstd_dictionary = Some(dictionary.clone().into());
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::connect(port, "bob").await;
+        let Message::Welcome { server: Some(server), .. } = bob.recv_until(|msg| matches!(msg, Message::Welcome { .. })).await else {
+            panic!("the welcome says what the server supports");
+        };
+        assert!(server.features.iter().any(|feature| feature == "zstd"));
+        let Message::ZstdDictionary { data: shared } = bob.recv_until(|msg| matches!(msg, Message::ZstdDictionary { .. })).await else {
+            unreachable!()
+        };
+        assert_eq!(shared, dictionary);
+        bob.recv_until(|msg| matches!(msg, Message::UserList { .. })).await;
+
+        let original = b"{\"level\":\"info\",\"order\":4242,\"status\":\"shipped\"}\n".to_vec();
+        let (data, codec) = FileTransfer::compress(original.clone(), Compressor::Zstd { dictionary: Some(&dictionary) });
+        assert_eq!(codec, Some(Codec::Zstd));
+        ana.send(&Message::new_file("ana".to_string(), "order.json".to_string(), original.len() as u64, data, codec)).await;
+        let Message::FileAvailable { id, .. } = bob.recv_until(|msg| matches!(msg, Message::FileAvailable { .. })).await else {
+            unreachable!()
+        };
+        bob.say(&format!("/fetch {}", id)).await;
+        let mut reassembler = Reassembler::new(1 << 20, TRANSFER_TIMEOUT);
+        let file = loop {
+            let msg = bob.recv().await;
+            if matches!(msg, Message::FileStart { .. } | Message::FileChunk { .. } | Message::FileEnd { .. }) {
+                if let Some(file) = reassembler.receive(msg).unwrap() {
+                    break file;
+                }
+            }
+        };
+        let Message::File { data, codec: Some(codec), .. } = file else {
+            panic!("the file keeps its codec");
+        };
+        assert_eq!(FileTransfer::decompress(&data, codec, Some(&shared), 1 << 20).unwrap(), original);
+    }
+
+    #[tokio::test]
+    async fn message_pushed_into_a_clients_sender_reaches_its_socket() {
+        let (port, clients) = start_with_clients(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let sender = clients.lock().await.values().find(|c| c.username == "ana").map(|c| c.sender.clone()).unwrap();
+
+        let json = Message::new_system("just for ana".to_string()).to_json().unwrap();
+        sender.send(Outgoing::new(json)).await.unwrap();
+        assert_eq!(ana.recv_notice("just for").await, "just for ana");
+    }
+
+    #[tokio::test]
+    async fn shared_file_is_announced_under_the_senders_own_name() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        let data = b"quarterly numbers".to_vec();
+        ana.send(&Message::new_file("mallory".to_string(), "report.txt".to_string(), data.len() as u64, data.clone(), None)).await;
+        let Message::FileAvailable { id, filename, size, sender, .. } = bob.recv_until(|msg| matches!(msg, Message::FileAvailable { .. })).await else {
+            unreachable!()
+        };
+        assert_eq!((filename.as_str(), size, sender.as_str()), ("report.txt", data.len() as u64, "ana"));
+
+        // The file itself carries the connection's name too
+        bob.say(&format!("/fetch {}", id)).await;
+        match bob.recv_until(|msg| matches!(msg, Message::File { .. } | Message::FileStart { .. })).await {
+            Message::File { username, .. } | Message::FileStart { username, .. } => assert_eq!(username, "ana"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_author_can_edit_or_delete_a_message() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        ana.say("teh typo").await;
+        let Message::Text { id: Some(id), .. } = bob.recv_until(|msg| matches!(msg, Message::Text { .. })).await else {
+            panic!("broadcast text should carry an id")
+        };
+
+        bob.send(&Message::new_edit(id.clone(), "hijacked".to_string())).await;
+        assert_eq!(bob.recv_system().await, "You can only edit or delete your own messages");
+        bob.send(&Message::new_delete(id.clone())).await;
+        assert_eq!(bob.recv_system().await, "You can only edit or delete your own messages");
+        let is_change = |msg: &Message| matches!(msg, Message::Edit { .. } | Message::Delete { .. });
+        assert!(ana.try_recv_until(Duration::from_millis(300), is_change).await.is_none());
+
+        ana.send(&Message::new_edit(id.clone(), "the typo".to_string())).await;
+        match bob.recv_until(is_change).await {
+            Message::Edit { target_id, new_content, .. } => assert_eq!((target_id, new_content.as_str()), (id.clone(), "the typo")),
+            other => panic!("expected the edit, got {:?}", other),
+        }
+        ana.send(&Message::new_delete(id.clone())).await;
+        assert!(matches!(bob.recv_until(is_change).await, Message::Delete { target_id, .. } if target_id == id));
+
+        // Once deleted there is nothing left to change
+        ana.send(&Message::new_edit(id, "again".to_string())).await;
+        assert_eq!(ana.recv_system().await, "That message no longer exists or is too old to change");
+    }
+
+    #[tokio::test]
+    async fn file_whose_size_disagrees_with_its_data_is_rejected() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let mut bob = TestClient::join(port, "bob").await;
+
+        ana.send(&Message::new_file("ana".to_string(), "small.txt".to_string(), 10, b"a lot more than ten bytes".to_vec(), None)).await;
+        let notice = ana.recv_notice("File rejected").await;
+        assert_eq!(notice, "File rejected: declared size 10 does not match the 25 bytes sent");
+        assert!(bob.try_recv_until(Duration::from_millis(300), |msg| matches!(msg, Message::FileAvailable { .. })).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_message_disconnects_only_in_strict_mode() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        // Passed on as plain text, as from a netcat user
+        ana.send_line("{\"Text\":{\"content\":").await;
+        assert_eq!(ana.recv_text().await, "{\"Text\":{\"content\":");
+        ana.say("still here").await;
+        assert_eq!(ana.recv_text().await, "still here");
+
+        let mut config = test_config();
+        config.strict = true;
+        let port = start(config).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        ana.send_line("{\"Text\":{\"content\":").await;
+        assert!(ana.recv_notice("Protocol error").await.ends_with("disconnecting"));
+        assert!(ana.try_recv_until(Duration::from_secs(2), |_| true).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn direct_message_to_a_departed_user_gets_a_notice() {
+        let port = start(test_config()).await;
+        let mut ana = TestClient::join(port, "ana").await;
+        let bob = TestClient::join(port, "bob").await;
+        drop(bob);
+        ana.recv_until(|msg| matches!(msg, Message::UserLeft { username, .. } if username == "bob")).await;
+        ana.say("/msg bob are you there?").await;
+        assert_eq!(ana.recv_notice("No user").await, "No user named bob is connected");
+    }
+
+    /// Lets in only those who say the password.
+    #[derive(Debug)]
+    struct Sesame;
+
+    impl Authenticator for Sesame {
+        fn authenticate<'a>(&'a self, _username: &'a str, credential: &'a [u8]) -> AuthFuture<'a> {
+            Box::pin(async move {
+                if credential == b"open sesame" {
+                    AuthResult::Accepted
+                } else {
+                    AuthResult::Rejected("that's not it".to_string())
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_authenticator_decides_who_joins() {
+        let mut config = test_config();
+        config.auth = Arc::new(Sesame);
+        let port = start(config).await;
+        for (credential, admitted) in [("open sesame", true), ("open barley", false)] {
+            let mut client = TestClient::connect(port, "ana").await;
+            assert!(matches!(client.recv().await, Message::AuthRequired { .. }));
+            client.send_line(credential).await;
+            match client.recv_until(|msg| matches!(msg, Message::Welcome { .. } | Message::Rejected { .. })).await {
+                Message::Welcome { .. } => assert!(admitted),
+                Message::Rejected { reason, detail, .. } => {
+                    assert!(!admitted);
+                    assert_eq!((reason, detail.as_str()), (RejectReason::AuthFailed, "that's not it"));
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{self, RootCertStore};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// The read half of a connection, plain or TLS.
+pub type StreamReader = Box<dyn AsyncRead + Unpin + Send>;
+/// The write half of a connection, plain or TLS.
+pub type StreamWriter = Box<dyn AsyncWrite + Unpin + Send>;
+/// A value both ends of one TLS session derive and nobody else can, for
+/// tying proofs to the connection; `None` on plain connections.
+pub type ChannelBinding = Option<[u8; 32]>;
+
+/// Exporter label for the channel binding (RFC 5705).
+const BINDING_LABEL: &[u8] = b"EXPORTER-terminal-chat-channel-binding";
+
+/// Server TLS settings from a PEM certificate chain and private key.
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<rustls::ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io::Error::other(format!("can't read certificate {}: {}", cert.display(), e)))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| io::Error::other(format!("can't read private key {}: {}", key.display(), e)))?;
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(config))
+}
+
+/// Client TLS settings trusting the certificates in `ca_cert`, or the
+/// usual public roots if none is given.
+pub fn client_config(ca_cert: Option<&Path>) -> io::Result<Arc<rustls::ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_cert {
+        Some(path) => {
+            for cert in CertificateDer::pem_file_iter(path)
+                .map_err(|e| io::Error::other(format!("can't read CA certificate {}: {}", path.display(), e)))?
+            {
+                let cert = cert.map_err(|e| io::Error::other(format!("can't read CA certificate {}: {}", path.display(), e)))?;
+                roots.add(cert).map_err(io::Error::other)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Splits a plain connection into boxed halves.
+pub fn split_plain(stream: TcpStream) -> (StreamReader, StreamWriter, ChannelBinding) {
+    let (reader, writer) = stream.into_split();
+    (Box::new(reader), Box::new(writer), None)
+}
+
+/// Runs the server side of the TLS handshake on an accepted connection.
+pub async fn accept(config: Arc<rustls::ServerConfig>, stream: TcpStream) -> io::Result<(StreamReader, StreamWriter, ChannelBinding)> {
+    let stream = TlsAcceptor::from(config).accept(stream).await?;
+    let binding = stream.get_ref().1.export_keying_material([0u8; 32], BINDING_LABEL, None).map_err(io::Error::other)?;
+    let (reader, writer) = tokio::io::split(stream);
+    Ok((Box::new(reader), Box::new(writer), Some(binding)))
+}
+
+/// Runs the client side of the TLS handshake, checking the server's
+/// certificate against `host`.
+pub async fn connect(config: Arc<rustls::ClientConfig>, host: &str, stream: TcpStream) -> io::Result<(StreamReader, StreamWriter, ChannelBinding)> {
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", host, e)))?;
+    let stream = TlsConnector::from(config).connect(server_name, stream).await?;
+    let binding = stream.get_ref().1.export_keying_material([0u8; 32], BINDING_LABEL, None).map_err(io::Error::other)?;
+    let (reader, writer) = tokio::io::split(stream);
+    Ok((Box::new(reader), Box::new(writer), Some(binding)))
+}
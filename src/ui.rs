@@ -1,141 +1,747 @@
-use crate::message::Message;
+use crate::client::{self, Connection};
+use crate::file_transfer::{Compressor, DownloadLayout};
+use crate::framing::Framing;
+use crate::gaps::GapDetector;
+use crate::history::{self, InputHistory};
+use crate::identity::Identity;
+use crate::message::{mentions, Message, RejectReason, ServerInfo, PROTOCOL_VERSION};
+use crate::netsim::NetSim;
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, MouseEvent, MouseEventKind, MouseButton},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
+use std::ffi::OsStr;
 use std::io;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::process::Command;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use arboard::Clipboard;
 use glob::glob;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct FileInfo {
     pub filename: String,
     pub size: u64,
-    pub data: Vec<u8>,
+    /// `None` until the payload has been fetched from the server
+    pub data: Option<Vec<u8>>,
     pub sender: String,
+    /// Server transfer id for files announced with `FileAvailable`
+    pub id: Option<String>,
+    /// Set once the server reports it no longer has the payload
+    pub unavailable: bool,
+    /// When the file was shared
+    pub received: SystemTime,
+}
+
+/// What the file list is ordered by.
+#[derive(Clone, Copy, PartialEq)]
+enum FileSortKey {
+    Received,
+    Name,
+    Size,
+    Sender,
+}
+
+impl FileSortKey {
+    fn next(self) -> Self {
+        match self {
+            FileSortKey::Received => FileSortKey::Name,
+            FileSortKey::Name => FileSortKey::Size,
+            FileSortKey::Size => FileSortKey::Sender,
+            FileSortKey::Sender => FileSortKey::Received,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            FileSortKey::Received => "time",
+            FileSortKey::Name => "name",
+            FileSortKey::Size => "size",
+            FileSortKey::Sender => "sender",
+        }
+    }
+}
+
+/// A rendered chat line and the user it came from, if any.
+struct ChatLine {
+    text: String,
+    author: Option<String>,
+    /// Byte ranges of `text` drawn with an ANSI style, kept out of `text`
+    /// so selection and copy see plain characters
+    styles: Vec<(Range<usize>, &'static str)>,
+    /// Delivery state of our own messages, `None` for everything else
+    send_state: Option<SendState>,
+    /// The chat message this line shows, for the message viewer
+    source: Option<Message>,
+    /// When an ephemeral message is removed from the chat
+    expires: Option<Instant>,
+    /// Someone else's message with an @mention of us, drawn highlighted
+    mentioned: bool,
+}
+
+/// One terminal row of the chat area: part of a wrapped chat line.
+struct ChatRow {
+    /// Index of the line in `messages`
+    index: usize,
+    /// The part of the line's text on this row
+    bytes: Range<usize>,
+    /// Columns the row is pushed right by
+    indent: usize,
+    /// Whether this is the line's final row
+    last: bool,
+}
+
+/// A Ctrl+F search through the chat.
+struct ChatSearch {
+    query: String,
+    /// Match `query` as a regular expression instead of plain text
+    regex: bool,
+    /// The compiled query, or why it doesn't compile
+    pattern: Result<regex::Regex, String>,
+    /// Still typing the query; once entered, n/N move between hits
+    typing: bool,
+    /// Message index of the hit being shown
+    current: Option<usize>,
+    /// Scroll position to go back to when the search is cancelled
+    saved_offset: Option<usize>,
+    saved_messages_at_scroll: usize,
+}
+
+impl ChatSearch {
+    fn new(saved_offset: Option<usize>, saved_messages_at_scroll: usize) -> Self {
+        let mut search = ChatSearch {
+            query: String::new(),
+            regex: false,
+            pattern: Err(String::new()),
+            typing: true,
+            current: None,
+            saved_offset,
+            saved_messages_at_scroll,
+        };
+        search.compile();
+        search
+    }
+
+    /// Rebuilds `pattern` after the query or mode changed. Plain queries
+    /// match case-insensitively anywhere in the line.
+    fn compile(&mut self) {
+        let source = if self.regex { self.query.clone() } else { regex::escape(&self.query) };
+        self.pattern = regex::RegexBuilder::new(&source)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| e.to_string().lines().last().unwrap_or_default().to_string());
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        !self.query.is_empty() && self.pattern.as_ref().is_ok_and(|pattern| pattern.is_match(text))
+    }
+}
+
+/// A message we sent that the server hasn't echoed back yet.
+struct PendingSend {
+    /// Index of its local copy in `messages`
+    index: usize,
+    sent_at: Instant,
+    content: String,
+}
+
+/// Lifecycle of a message we sent, shown as a glyph after the line.
+#[derive(Clone, Copy, PartialEq)]
+enum SendState {
+    /// Shown locally, waiting for the server to broadcast it back
+    Sending,
+    /// The server accepted and broadcast it
+    Sent,
+    /// The server handed it on to the rest of the room
+    Delivered,
+    /// The send errored or the server never echoed it
+    Failed,
+}
+
+impl SendState {
+    fn glyph(self) -> &'static str {
+        match self {
+            SendState::Sending => "\x1b[2m…\x1b[0m",
+            SendState::Sent => "\x1b[32m✓\x1b[0m",
+            SendState::Delivered => "\x1b[32m✓✓\x1b[0m",
+            SendState::Failed => "\x1b[31m✗ not sent (Ctrl+R to retry)\x1b[0m",
+        }
+    }
 }
 
 pub struct ChatUI {
     username: String,
-    messages: Vec<String>,
+    messages: Vec<ChatLine>,
     input: String,
-    message_sender: mpsc::UnboundedSender<String>,
+    /// Position of the cursor in `input`, in characters
+    cursor_pos: usize,
     message_receiver: mpsc::UnboundedReceiver<Message>,
     ui_sender: mpsc::UnboundedSender<Message>,
     // Selection state
     selection_start: Option<(usize, usize)>, // (message_index, char_index)
     selection_end: Option<(usize, usize)>,
     selecting: bool,
+    selected_message: Option<usize>,
+    /// First message shown while scrolled back through history, `None` to
+    /// follow the newest; new messages don't move it
+    chat_scroll_offset: Option<usize>,
+    /// How many messages there were when scrolling back began
+    messages_at_scroll: usize,
+    search: Option<ChatSearch>,
     // File management
     received_files: Vec<FileInfo>,
+    file_sort: FileSortKey,
+    file_sort_descending: bool,
+    /// Fetches in flight, by file id, with when they were requested
+    requested_files: HashMap<String, Instant>,
+    pending_downloads: HashSet<String>,
     // Tab completion
     completion_candidates: Vec<String>,
     completion_index: usize,
     last_tab_input: String,
+    /// Input before the word being completed, for mentions, commands and
+    /// `/msg` recipients
+    completion_base: Option<String>,
+    /// Users believed to be online, from join/leave events and message authors
+    known_users: BTreeSet<String>,
+    /// Who the server says is online, for the sidebar; `None` until it
+    /// sends a roster
+    online: Option<BTreeSet<String>>,
+    /// Those of `online` who are away, shown dimmed
+    away: BTreeSet<String>,
+    /// The room the server has us in, for the title; `None` on servers
+    /// without rooms
+    room: Option<String>,
+    /// Server ids of the chat messages shown, so history the server
+    /// replays after a reconnect isn't shown twice
+    seen_ids: HashSet<String>,
+    /// Which numbered chat of the current room has arrived, so any skipped
+    /// can be asked for again
+    gaps: GapDetector,
     // UI state
     mode: UIMode,
     file_viewer_index: Option<usize>,
+    /// Hex (true) or text view chosen with H; `None` picks by content
+    file_viewer_hex: Option<bool>,
+    message_viewer_index: Option<usize>,
+    /// Filter typed into the command palette and the highlighted match
+    palette_filter: String,
+    palette_selected: usize,
     scroll_offset: usize,
+    pending_confirm: Option<PendingConfirm>,
+    input_history: InputHistory,
+    /// Entry recalled with Up/Down, `None` when editing a fresh line
+    history_index: Option<usize>,
+    /// The fresh line set aside while browsing history
+    history_stash: String,
+    /// Where the unsent input is kept across a crash
+    draft_path: Option<PathBuf>,
+    /// Input as last written to the draft file, and when
+    draft_saved: String,
+    draft_saved_at: Instant,
+    debug_view: bool,
+    /// Rooms whose @mentions don't ring the bell, set by `/mute`; `""`
+    /// stands for a server without rooms
+    muted_rooms: HashSet<String>,
+    /// Our messages awaiting the server's echo, by local id
+    pending_sends: HashMap<String, PendingSend>,
+    /// Text of the most recent message that failed to send, for Ctrl+R
+    last_failed: Option<String>,
+    config: UiConfig,
+    /// The current session, `None` after `/disconnect` or a failed `/connect`
+    connection: Option<Connection>,
+    /// Set while retrying a connection that dropped
+    reconnect: Option<Reconnect>,
+    /// Sent when a server asks for one, including after `/connect`
+    credential: Option<String>,
+    /// Capabilities from the server's welcome, if it sent any
+    server_info: Option<ServerInfo>,
+    /// The zstd dictionary the server shared, if it has one
+    zstd_dictionary: Option<Vec<u8>>,
+    /// Whether this connection's first welcome has arrived; later ones
+    /// follow a `/nick`
+    welcomed: bool,
+    last_activity: Instant,
+    screensaver: bool,
+    /// Set while `run` has the terminal; until then nothing is drawn and
+    /// the bell stays quiet, so the UI can be driven without a screen
+    in_terminal: bool,
+}
+
+/// Client display and download preferences from the command line.
+pub struct UiConfig {
+    /// Idle time after which the screensaver replaces the chat, if enabled
+    pub screensaver_after: Option<Duration>,
+    /// First wait before reconnecting a dropped connection, `None` to stay
+    /// disconnected
+    pub reconnect_delay: Option<Duration>,
+    pub reconnect_max_delay: Duration,
+    /// Drop the separator rules to fit more messages on small terminals
+    pub compact: bool,
+    /// Right-align our own messages, like a phone messenger
+    pub bubble: bool,
+    pub download_layout: DownloadLayout,
+    /// Replace an existing download of the same name instead of renaming
+    pub overwrite: bool,
+    /// Split outgoing chat lines longer than this many characters
+    pub max_line_width: Option<usize>,
+    pub empty_enter: EmptyEnter,
+    /// Simulated latency and loss on this client's connection, for testing
+    pub netsim: Option<NetSim>,
+    /// Keypair announced to servers that support identities
+    pub identity: Option<Arc<Identity>>,
+    /// Draw names, roles and other highlights in color
+    pub color: bool,
+    /// Largest file we send or accept, in bytes
+    pub max_file_size: u64,
+    /// Connect over TLS with these settings instead of in plaintext
+    pub tls: Option<Arc<tokio_rustls::rustls::ClientConfig>>,
+    /// strftime-style pattern for timestamps, shown in local time
+    pub time_format: String,
+    /// How messages are delimited on the wire
+    pub framing: Framing,
+}
+
+/// What Enter does when the input line is empty or only whitespace.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum EmptyEnter {
+    /// Nothing, unless a message is selected, which is copied
+    None,
+    /// Drop any selection and scroll back down to the newest message
+    Latest,
+    /// Start or extend a multi-line message with a blank line, as Alt+Enter
+    Newline,
+}
+
+/// Whether the client draws in color.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// When output is a terminal, unless `NO_COLOR` or `CLICOLOR_FORCE` say otherwise
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Settles `Auto` from the `NO_COLOR` and `CLICOLOR_FORCE` variables
+    /// and whether output is a terminal. `NO_COLOR` with any value turns
+    /// color off; `CLICOLOR_FORCE` set to anything but 0 turns it on even
+    /// without a terminal. An explicit choice overrides both.
+    pub fn enabled(self, no_color: Option<&OsStr>, clicolor_force: Option<&OsStr>, is_terminal: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto if no_color.is_some() => false,
+            ColorChoice::Auto if clicolor_force.is_some_and(|force| force != "0") => true,
+            ColorChoice::Auto => is_terminal,
+        }
+    }
+}
+
+/// The terminal modes `run` managed to switch on, so teardown undoes only
+/// those.
+struct TerminalModes {
+    alternate_screen: bool,
+    mouse_capture: bool,
+}
+
+impl TerminalModes {
+    fn restore(
+        &self,
+        disable_mouse_capture: impl FnOnce() -> io::Result<()>,
+        leave_alternate_screen: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self.mouse_capture {
+            disable_mouse_capture()?;
+        }
+        if self.alternate_screen {
+            leave_alternate_screen()?;
+        }
+        Ok(())
+    }
+}
+
+/// A dropped connection being retried with exponential backoff.
+struct Reconnect {
+    address: String,
+    port: u16,
+    /// Wait before the next attempt, doubled after each failure
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+/// An action waiting for the user to answer a y/n prompt.
+enum PendingConfirm {
+    PasteAsFile { filename: String, data: Vec<u8> },
+    RestoreDraft { text: String },
+    SendFile { filepath: String, filename: String, size: u64 },
+    ClearMessages,
+    DeleteMessage { id: String },
 }
 
+/// Port `/connect` uses when none is given, matching the command line default.
+const DEFAULT_PORT: u16 = 8080;
+
+/// Where downloaded files are saved.
+const DOWNLOAD_DIR: &str = "downloads";
+
+/// Number of submitted lines kept in the persisted input history.
+const HISTORY_LIMIT: usize = 500;
+
+/// How often the unsent input line is written to the draft file.
+const DRAFT_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Clipboard text longer than this (or spanning lines) is offered as a file.
+const PASTE_TEXT_LIMIT: usize = 500;
+
+/// How long a sent message may wait for the server's echo before it is
+/// marked as failed.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commands offered by Tab completion, both ours and the server's.
+const COMMANDS: &[&str] = &[
+    "/away", "/back", "/clear", "/conn", "/connect", "/debug", "/delete", "/disconnect", "/edit", "/ephemeral",
+    "/file", "/join", "/me", "/msg", "/mute", "/nick", "/open-downloads", "/part", "/paste", "/sessions",
+    "/roomlimit", "/slowmode", "/stats", "/test-clipboard", "/timeout", "/unmute", "/users", "/who",
+];
+
+/// Files larger than this many bytes are only sent after a y/n prompt.
+const CONFIRM_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How long to wait for a fetched payload before asking the server again.
+const FETCH_RETRY: Duration = Duration::from_secs(30);
+
+/// Narrowest terminal the layout is drawn in; below it, or with too few
+/// rows, a notice asks for a bigger window instead.
+const MIN_WIDTH: u16 = 20;
+
+/// Columns taken by the online-user sidebar, its border included.
+const SIDEBAR_WIDTH: u16 = 18;
+
+/// The sidebar is left out when it would squeeze the messages narrower than
+/// this many columns.
+const SIDEBAR_MIN_CHAT_WIDTH: u16 = 40;
+
+/// Most rows a multi-line input grows to before it scrolls.
+const MAX_INPUT_ROWS: u16 = 6;
+
+/// Lines moved per mouse wheel tick.
+const WHEEL_SCROLL_LINES: usize = 3;
+
 #[derive(PartialEq)]
 enum UIMode {
     Chat,
     FileViewer,
     FileList,
+    MessageViewer,
+    CommandPalette,
+}
+
+/// What picking a command palette entry does.
+#[derive(Clone, Copy)]
+enum PaletteAction {
+    /// Submit this input line as if typed
+    Run(&'static str),
+    /// Put this in the input for the user to finish
+    Insert(&'static str),
+    Files,
+    Quit,
+}
+
+/// A command palette entry: its name, the command or key it stands for,
+/// and what choosing it does.
+struct PaletteEntry {
+    label: &'static str,
+    hint: &'static str,
+    action: PaletteAction,
 }
 
+const PALETTE: &[PaletteEntry] = &[
+    PaletteEntry { label: "Send a file", hint: "/file <path>", action: PaletteAction::Insert("/file ") },
+    PaletteEntry { label: "Paste clipboard", hint: "/paste", action: PaletteAction::Run("/paste") },
+    PaletteEntry { label: "Browse received files", hint: "F1", action: PaletteAction::Files },
+    PaletteEntry { label: "Open downloads folder", hint: "/open-downloads", action: PaletteAction::Run("/open-downloads") },
+    PaletteEntry { label: "List online users", hint: "/who [page]", action: PaletteAction::Run("/who") },
+    PaletteEntry { label: "Join a room", hint: "/join <room>", action: PaletteAction::Insert("/join #") },
+    PaletteEntry { label: "Leave the room, back to #general", hint: "/part", action: PaletteAction::Run("/part") },
+    PaletteEntry { label: "Change your name", hint: "/nick <name>", action: PaletteAction::Insert("/nick ") },
+    PaletteEntry { label: "Set yourself away", hint: "/away [message]", action: PaletteAction::Insert("/away ") },
+    PaletteEntry { label: "Come back from away", hint: "/back", action: PaletteAction::Run("/back") },
+    PaletteEntry { label: "Send a disappearing message", hint: "/ephemeral <secs> <text>", action: PaletteAction::Insert("/ephemeral ") },
+    PaletteEntry { label: "Message a user privately", hint: "/msg <user> <text>", action: PaletteAction::Insert("/msg ") },
+    PaletteEntry { label: "Describe an action", hint: "/me <action>", action: PaletteAction::Insert("/me ") },
+    PaletteEntry { label: "Edit your last or selected message", hint: "/edit [text]", action: PaletteAction::Run("/edit") },
+    PaletteEntry { label: "Delete your last or selected message", hint: "/delete", action: PaletteAction::Run("/delete") },
+    PaletteEntry { label: "Server statistics", hint: "/stats", action: PaletteAction::Run("/stats") },
+    PaletteEntry { label: "Set slow mode", hint: "/slowmode <secs>", action: PaletteAction::Insert("/slowmode ") },
+    PaletteEntry { label: "Show or set this room's limits", hint: "/roomlimit [members|rate <n|off>]", action: PaletteAction::Insert("/roomlimit") },
+    PaletteEntry { label: "Time out a user", hint: "/timeout <user> <secs>", action: PaletteAction::Insert("/timeout ") },
+    PaletteEntry { label: "List or end your other sessions", hint: "/sessions [end <id>]", action: PaletteAction::Insert("/sessions") },
+    PaletteEntry { label: "Connection details", hint: "/conn", action: PaletteAction::Run("/conn") },
+    PaletteEntry { label: "Connect to a server", hint: "/connect <host[:port]>", action: PaletteAction::Insert("/connect ") },
+    PaletteEntry { label: "Disconnect", hint: "/disconnect", action: PaletteAction::Run("/disconnect") },
+    PaletteEntry { label: "Clear the chat screen", hint: "/clear", action: PaletteAction::Run("/clear") },
+    PaletteEntry { label: "Mute this room's mentions", hint: "/mute", action: PaletteAction::Run("/mute") },
+    PaletteEntry { label: "Unmute this room's mentions", hint: "/unmute", action: PaletteAction::Run("/unmute") },
+    PaletteEntry { label: "Toggle raw JSON debug view", hint: "/debug", action: PaletteAction::Run("/debug") },
+    PaletteEntry { label: "Test clipboard", hint: "/test-clipboard", action: PaletteAction::Run("/test-clipboard") },
+    PaletteEntry { label: "Quit", hint: "Ctrl+Q", action: PaletteAction::Quit },
+];
+
 impl ChatUI {
     pub fn new(
         username: String,
-        message_sender: mpsc::UnboundedSender<String>,
+        credential: Option<String>,
+        config: UiConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_draft_path(username, credential, config, history::draft_path())
+    }
+
+    /// A UI that keeps its unsent input at `draft_path`, offering to restore
+    /// whatever a previous run left there.
+    fn with_draft_path(
+        username: String,
+        credential: Option<String>,
+        config: UiConfig,
+        draft_path: Option<PathBuf>,
     ) -> Result<Self, Box<dyn Error>> {
         let (ui_sender, message_receiver) = mpsc::unbounded_channel();
+        let draft = draft_path.as_deref().and_then(history::load_draft);
         
-        Ok(ChatUI {
+        let mut ui = ChatUI {
             username,
             messages: Vec::new(),
             input: String::new(),
-            message_sender,
+            cursor_pos: 0,
             message_receiver,
             ui_sender,
             selection_start: None,
             selection_end: None,
             selecting: false,
+            selected_message: None,
+            chat_scroll_offset: None,
+            messages_at_scroll: 0,
+            search: None,
             received_files: Vec::new(),
+            file_sort: FileSortKey::Received,
+            file_sort_descending: false,
+            requested_files: HashMap::new(),
+            pending_downloads: HashSet::new(),
             completion_candidates: Vec::new(),
             completion_index: 0,
             last_tab_input: String::new(),
+            completion_base: None,
+            known_users: BTreeSet::new(),
+            online: None,
+            away: BTreeSet::new(),
+            room: None,
+            seen_ids: HashSet::new(),
+            gaps: GapDetector::default(),
             mode: UIMode::Chat,
             file_viewer_index: None,
+            file_viewer_hex: None,
+            message_viewer_index: None,
+            palette_filter: String::new(),
+            palette_selected: 0,
             scroll_offset: 0,
-        })
+            pending_confirm: None,
+            input_history: InputHistory::default_path()
+                .map(|path| InputHistory::load(&path, HISTORY_LIMIT))
+                .unwrap_or_else(|| InputHistory::new(HISTORY_LIMIT)),
+            history_index: None,
+            history_stash: String::new(),
+            draft_path,
+            draft_saved: draft.clone().unwrap_or_default(),
+            draft_saved_at: Instant::now(),
+            debug_view: false,
+            muted_rooms: HashSet::new(),
+            pending_sends: HashMap::new(),
+            last_failed: None,
+            config,
+            connection: None,
+            reconnect: None,
+            credential,
+            server_info: None,
+            zstd_dictionary: None,
+            welcomed: false,
+            last_activity: Instant::now(),
+            screensaver: false,
+            in_terminal: false,
+        };
+        // A draft survives only if the last run ended without sending or quitting
+        if let Some(text) = draft {
+            ui.push_notice(format!("* Recovered an unsent draft: \"{}\" Restore it? (y/n)", text));
+            ui.pending_confirm = Some(PendingConfirm::RestoreDraft { text });
+        }
+        Ok(ui)
     }
 
     pub fn get_sender(&self) -> mpsc::UnboundedSender<Message> {
         self.ui_sender.clone()
     }
 
+    pub fn set_connection(&mut self, connection: Connection) {
+        self.connection = Some(connection);
+        self.welcomed = false;
+        // Fetches the old connection was still waiting on resume here, or
+        // end in `FileUnavailable` if this server has no such file
+        let in_flight: Vec<String> = self.requested_files.keys().cloned().collect();
+        for id in in_flight {
+            self.send_fetch(&id);
+        }
+    }
+
+    /// Sends to the server, returning false when not connected.
+    fn send(&self, msg: Message) -> bool {
+        self.connection.as_ref().is_some_and(|connection| connection.send(msg))
+    }
+
     pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
         // Setup terminal
         enable_raw_mode()?;
-        let mut stdout = io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+        let modes = self.enter_terminal_modes(
+            || execute!(io::stdout(), EnterAlternateScreen),
+            || execute!(io::stdout(), EnableMouseCapture),
+        );
 
+        self.in_terminal = true;
         let result = self.run_app().await;
+        self.in_terminal = false;
+
+        if let Some(path) = InputHistory::default_path() {
+            let secrets: Vec<&str> = self.credential.as_deref().into_iter().collect();
+            let _ = self.input_history.save(&path, &secrets);
+        }
+        if result.is_ok() {
+            self.save_draft("");
+        }
 
         // Restore terminal
         disable_raw_mode()?;
-        execute!(stdout, LeaveAlternateScreen, DisableMouseCapture)?;
+        modes.restore(
+            || execute!(io::stdout(), DisableMouseCapture),
+            || execute!(io::stdout(), LeaveAlternateScreen),
+        )?;
 
         result
     }
 
+    /// Switches on what the terminal supports. Limited terminals may lack
+    /// either mode; we carry on without rather than refusing to start.
+    fn enter_terminal_modes(
+        &mut self,
+        enter_alternate_screen: impl FnOnce() -> io::Result<()>,
+        enable_mouse_capture: impl FnOnce() -> io::Result<()>,
+    ) -> TerminalModes {
+        let modes = TerminalModes {
+            alternate_screen: enter_alternate_screen().is_ok(),
+            mouse_capture: enable_mouse_capture().is_ok(),
+        };
+        if !modes.mouse_capture {
+            self.push_notice("* This terminal does not support mouse capture; select messages with Alt+↑/↓ instead".to_string());
+        }
+        modes
+    }
+
     async fn run_app(&mut self) -> Result<(), Box<dyn Error>> {
         loop {
             self.draw()?;
 
             // Handle events with timeout
             if event::poll(Duration::from_millis(100))? {
-                match event::read()? {
+                let event = event::read()?;
+                // Any input wakes the screen; the waking key itself is swallowed
+                if matches!(event, Event::Key(_) | Event::Mouse(_)) && self.record_activity() {
+                    continue;
+                }
+                match event {
                     Event::Key(key) if key.kind == KeyEventKind::Press => {
                         let should_exit = match self.mode {
                             UIMode::Chat => self.handle_chat_key(key).await?,
                             UIMode::FileViewer => self.handle_file_viewer_key(key)?,
                             UIMode::FileList => self.handle_file_list_key(key)?,
+                            UIMode::MessageViewer => self.handle_message_viewer_key(key),
+                            UIMode::CommandPalette => self.handle_palette_key(key).await?,
                         };
                         if should_exit {
                             break;
                         }
                     }
                     Event::Mouse(mouse) => {
-                        if self.mode == UIMode::Chat {
-                            self.handle_mouse_event(mouse)?;
-                        }
+                        self.handle_mouse_event(mouse)?;
                     }
                     _ => {}
                 }
             }
 
-            // Handle incoming messages
-            while let Ok(msg) = self.message_receiver.try_recv() {
-                self.add_message(msg);
+            self.receive_messages();
+            self.expire_pending_sends();
+            self.expire_messages();
+            if self.connection.as_ref().is_some_and(Connection::is_closed) {
+                self.connection_lost();
+            }
+            if self.reconnect.as_ref().is_some_and(|reconnect| Instant::now() >= reconnect.next_attempt) {
+                self.try_reconnect().await;
             }
+            if self.input != self.draft_saved && self.draft_saved_at.elapsed() >= DRAFT_SAVE_INTERVAL {
+                self.save_draft(&self.input.clone());
+            }
+            self.check_idle();
         }
 
         Ok(())
     }
 
+    /// Shows what the connection has received since the last pass. Each
+    /// message counts as activity, so it wakes the screensaver.
+    fn receive_messages(&mut self) {
+        while let Ok(msg) = self.message_receiver.try_recv() {
+            self.add_message(msg);
+            self.record_activity();
+        }
+    }
+
+    /// Starts the screensaver once nothing has happened for its idle time.
+    fn check_idle(&mut self) {
+        if let Some(idle) = self.config.screensaver_after {
+            if !self.screensaver && self.last_activity.elapsed() >= idle {
+                self.screensaver = true;
+            }
+        }
+    }
+
+    /// Resets the idle timer, returning whether this woke the screensaver.
+    fn record_activity(&mut self) -> bool {
+        self.last_activity = Instant::now();
+        std::mem::replace(&mut self.screensaver, false)
+    }
+
     fn draw(&self) -> Result<(), Box<dyn Error>> {
+        if !self.in_terminal {
+            return Ok(());
+        }
+        let (width, height) = crossterm::terminal::size()?;
+        if let Some(rows) = self.too_small_rows(width, height) {
+            return Self::draw_too_small(&rows, width);
+        }
+        if self.screensaver {
+            return self.draw_screensaver();
+        }
         match self.mode {
             UIMode::Chat => self.draw_chat()?,
             UIMode::FileViewer => self.draw_file_viewer()?,
             UIMode::FileList => self.draw_file_list()?,
+            UIMode::MessageViewer => self.draw_message_viewer()?,
+            UIMode::CommandPalette => self.draw_palette()?,
         }
         Ok(())
     }
@@ -148,67 +754,188 @@ impl ChatUI {
         execute!(io::stdout(), crossterm::cursor::MoveTo(0, 0))?;
 
         // Draw title
-        let title = format!("Terminal Chat - {} (Ctrl+Q: quit, /file <path>: send, F1: files, Ctrl+C: copy, /test-clipboard)", self.username);
-        print!("{}", title);
-        execute!(io::stdout(), crossterm::cursor::MoveTo(0, 1))?;
-        print!("{}", "=".repeat(width as usize));
-
-        // Draw messages (leave space for title, separator, input separator, and input)
-        let message_height = height.saturating_sub(4) as usize;
-        let start_idx = if self.messages.len() > message_height {
-            self.messages.len() - message_height
-        } else {
-            0
-        };
+        let muted = if self.room_muted() { " (muted)" } else { "" };
+        let room = self.room.as_ref().map_or(String::new(), |room| format!(" in {}", room)) + muted;
+        let title = format!("Terminal Chat - {}{} (Ctrl+Q: quit, Ctrl+P: commands, /file <path>: send, Alt+Enter: new line, F1: files, ↑/↓: history, Alt+↑/↓: select, Ctrl+C: copy, Alt+R: reply, Alt+V: view, Alt+L: copy link, Ctrl+F: search, /test-clipboard)", self.username, room);
+        print!("{}", fit_width(&title, width as usize));
+        self.draw_separator(1, width)?;
+
+        // Draw messages between the header and the input line, beside the
+        // sidebar when there's room for it
+        let (top, message_height) = self.message_area(height);
+        let chat_width = self.chat_width(width);
+        if chat_width < width {
+            self.draw_sidebar(chat_width, top, message_height)?;
+        }
+
+        for (i, row) in self.visible_rows(chat_width, message_height).into_iter().enumerate() {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(row.indent as u16, top + i as u16))?;
+            let line = &self.messages[row.index];
+            let text = &line.text[row.bytes.clone()];
 
-        for (i, msg) in self.messages[start_idx..].iter().enumerate() {
-            execute!(io::stdout(), crossterm::cursor::MoveTo(0, (i + 2) as u16))?;
-            
             // Highlight selected text
-            if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
-                let msg_idx = start_idx + i;
-                if msg_idx >= start.0 && msg_idx <= end.0 {
-                    self.print_with_selection(msg, msg_idx, start, end)?;
-                } else {
-                    print!("{}", msg);
+            if self.selected_message == Some(row.index) || self.search.as_ref().is_some_and(|search| search.current == Some(row.index)) {
+                print!("\x1b[7m{}\x1b[0m", text);
+            } else if let Some(selected) = self.selected_bytes(row.index) {
+                let from = selected.start.clamp(row.bytes.start, row.bytes.end);
+                let to = selected.end.clamp(from, row.bytes.end);
+                print!("{}", &line.text[row.bytes.start..from]);
+                if to > from {
+                    print!("\x1b[7m{}\x1b[0m", &line.text[from..to]); // Reverse video for selection
                 }
+                print!("{}", &line.text[to..row.bytes.end]);
             } else {
-                print!("{}", msg);
+                let hit = self.search.as_ref().is_some_and(|search| search.matches(&line.text));
+                let base = match (hit, line.mentioned, self.config.color) {
+                    (true, _, true) => "\x1b[30;43m",
+                    (true, _, false) => "\x1b[4m",
+                    (false, false, _) => "",
+                    (false, true, true) => "\x1b[100m",
+                    (false, true, false) => "\x1b[1m",
+                };
+                print_styled(line, row.bytes.clone(), base);
+            }
+            if row.last {
+                if let Some(state) = line.send_state {
+                    if self.config.color {
+                        print!(" {}", state.glyph());
+                    } else {
+                        print!(" {}", strip_ansi(state.glyph()));
+                    }
+                }
             }
         }
 
-        // Move to input separator line
-        execute!(io::stdout(), crossterm::cursor::MoveTo(0, height - 2))?;
-        print!("{}", "=".repeat(width as usize));
-        
-        // Move to input line
-        execute!(io::stdout(), crossterm::cursor::MoveTo(0, height - 1))?;
-        print!("> {}", self.input);
+        let input_rows = self.input_rows(height);
+        let input_top = height.saturating_sub(input_rows);
+        self.draw_separator(input_top.saturating_sub(1), width)?;
+        if self.chat_scroll_offset.is_some() {
+            let unseen = self.messages.len().saturating_sub(self.messages_at_scroll);
+            let indicator = if unseen > 0 {
+                format!(" ↓ {} new message{} (End: jump to latest) ", unseen, if unseen == 1 { "" } else { "s" })
+            } else {
+                " ↓ Scrolled back (End: jump to latest) ".to_string()
+            };
+            // Compact mode has no separator to sit on, so use the title row
+            let row = if self.config.compact { 0 } else { input_top.saturating_sub(1) };
+            let x = (width as usize).saturating_sub(indicator.chars().count());
+            execute!(io::stdout(), crossterm::cursor::MoveTo(x as u16, row))?;
+            print!("\x1b[7m{}\x1b[0m", fit_width(&indicator, width as usize));
+        }
         
-        // Position cursor at end of input
-        execute!(io::stdout(), crossterm::cursor::MoveTo((self.input.len() + 2) as u16, height - 1))?;
+        if let Some(search) = &self.search {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, height - 1))?;
+            let hits = self.search_hits();
+            let status = match (&search.pattern, search.current.and_then(|current| hits.iter().position(|&i| i == current))) {
+                _ if search.query.is_empty() => String::new(),
+                (Err(e), _) => format!(" ({})", e),
+                (Ok(_), Some(position)) => format!(" ({}/{})", position + 1, hits.len()),
+                (Ok(_), None) => " (no matches)".to_string(),
+            };
+            let keys = if search.typing {
+                "Enter: done, Ctrl+R: regex, Esc: cancel"
+            } else {
+                "n/N: older/newer, Ctrl+F: edit, Enter: stay here, Esc: cancel"
+            };
+            let prompt = format!("{}: {}", if search.regex { "Regex search" } else { "Search" }, search.query);
+            print!("{}", fit_width(&format!("{}{}  [{}]", prompt, status, keys), width as usize));
+            let cursor = prompt.width().min(width.saturating_sub(1) as usize);
+            execute!(io::stdout(), crossterm::cursor::MoveTo(cursor as u16, height - 1))?;
+            io::stdout().flush()?;
+            return Ok(());
+        }
+
+        // One row per input line, scrolled so the cursor's line shows and
+        // each line scrolled horizontally so the cursor stays visible
+        let lines: Vec<&str> = self.input.split('\n').collect();
+        let before_cursor: String = self.input.chars().take(self.cursor_pos).collect();
+        let cursor_line = before_cursor.matches('\n').count();
+        let cursor_in_line = before_cursor.rsplit('\n').next().unwrap_or_default().chars().count();
+        let first = (cursor_line + 1).saturating_sub(input_rows as usize);
+        let mut cursor = (2, input_top);
+        for (row, index) in (first..lines.len()).take(input_rows as usize).enumerate() {
+            let row = input_top + row as u16;
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, row))?;
+            let at = if index == cursor_line { cursor_in_line } else { 0 };
+            let (visible, column) = input_viewport(lines[index], at, (width as usize).saturating_sub(2));
+            print!("{}{}", if index == 0 { "> " } else { "  " }, visible);
+            if index == cursor_line {
+                cursor = (column as u16 + 2, row);
+            }
+        }
+
+        // Position cursor after the prompt, by display width
+        execute!(io::stdout(), crossterm::cursor::MoveTo(cursor.0, cursor.1))?;
         
         io::stdout().flush()?;
         Ok(())
     }
 
+    /// Smallest terminal the layout fits: the header, one row of content,
+    /// and the footer.
+    fn min_size(&self) -> (u16, u16) {
+        let footer = if self.config.compact { 1 } else { 2 };
+        (MIN_WIDTH, self.header_rows() + 1 + footer)
+    }
+
+    /// What a `width` by `height` terminal shows instead of the normal
+    /// layout, or `None` once it is at least `min_size`. The notice is
+    /// word-wrapped over the rows there are, so as much as possible shows.
+    fn too_small_rows(&self, width: u16, height: u16) -> Option<Vec<String>> {
+        let (min_width, min_height) = self.min_size();
+        if width >= min_width && height >= min_height {
+            return None;
+        }
+        let notice = format!("Terminal too small (need at least {}x{})", min_width, min_height);
+        Some(wrap_line(&notice, width as usize).into_iter().take(height as usize).collect())
+    }
+
+    fn draw_too_small(rows: &[String], width: u16) -> Result<(), Box<dyn Error>> {
+        execute!(io::stdout(), crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
+        for (row, text) in rows.iter().enumerate() {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, row as u16))?;
+            print!("{}", fit_width(text, width as usize));
+        }
+        io::stdout().flush()?;
+        Ok(())
+    }
+
+    /// A dimmed clock in the middle of an otherwise blank screen.
+    fn draw_screensaver(&self) -> Result<(), Box<dyn Error>> {
+        let (width, height) = crossterm::terminal::size()?;
+        execute!(io::stdout(), crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
+        let clock = self.format_time(SystemTime::now());
+        let x = (width as usize).saturating_sub(clock.len()) / 2;
+        execute!(io::stdout(), crossterm::cursor::MoveTo(x as u16, height / 2))?;
+        print!("\x1b[2m{}\x1b[0m", clock);
+        io::stdout().flush()?;
+        Ok(())
+    }
+
     fn draw_file_list(&self) -> Result<(), Box<dyn Error>> {
         let (width, _height) = crossterm::terminal::size()?;
         
         execute!(io::stdout(), crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
         execute!(io::stdout(), crossterm::cursor::MoveTo(0, 0))?;
 
-        print!("Received Files (ESC: back, Enter: view, D: download)");
-        execute!(io::stdout(), crossterm::cursor::MoveTo(0, 1))?;
-        print!("{}", "=".repeat(width as usize));
+        let direction = if self.file_sort_descending { "↓" } else { "↑" };
+        let header = format!("Received Files by {} {} (ESC: back, Enter: view, D: download, S: sort, R: reverse)",
+            self.file_sort.label(), direction);
+        print!("{}", fit_width(&header, width as usize));
+        self.draw_separator(1, width)?;
 
-        for (i, file) in self.received_files.iter().enumerate() {
-            execute!(io::stdout(), crossterm::cursor::MoveTo(0, (i + 2) as u16))?;
+        let top = self.header_rows();
+        for (i, &index) in self.sorted_file_indices().iter().enumerate() {
+            let file = &self.received_files[index];
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, top + i as u16))?;
             print!("{}. {} ({} bytes) from {}", i + 1, file.filename, file.size, file.sender);
+            if file.unavailable {
+                print!(" (no longer available)");
+            }
         }
 
         if self.received_files.is_empty() {
-            execute!(io::stdout(), crossterm::cursor::MoveTo(0, 2))?;
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, top))?;
             print!("No files received yet.");
         }
 
@@ -224,26 +951,45 @@ impl ChatUI {
 
         if let Some(index) = self.file_viewer_index {
             if let Some(file) = self.received_files.get(index) {
-                print!("File: {} ({} bytes) - ESC: back, D: download", file.filename, file.size);
-                execute!(io::stdout(), crossterm::cursor::MoveTo(0, 1))?;
-                print!("{}", "=".repeat(width as usize));
-
-                // Try to display file content as text
-                let content = String::from_utf8_lossy(&file.data);
-                let lines: Vec<&str> = content.lines().collect();
-                let display_height = height.saturating_sub(3) as usize;
+                let toggle = if self.file_viewer_hex_mode() { "H: text" } else { "H: hex" };
+                let header = format!("File: {} ({} bytes) - ESC: back, D: download, {}", file.filename, file.size, toggle);
+                print!("{}", fit_width(&header, width as usize));
+                self.draw_separator(1, width)?;
+
+                let top = self.header_rows();
+                if file.data.is_none() {
+                    execute!(io::stdout(), crossterm::cursor::MoveTo(0, top))?;
+                    if file.unavailable {
+                        print!("The server no longer has this file.");
+                    } else if file.size > self.config.max_file_size {
+                        print!("This file is over the {} byte limit.", self.config.max_file_size);
+                    } else {
+                        print!("Fetching file contents...");
+                    }
+                    io::stdout().flush()?;
+                    return Ok(());
+                }
+
+                let lines = self.file_viewer_content();
+                // Below the header, leaving the last row for the scroll status
+                let display_height = height.saturating_sub(top + 1) as usize;
                 
-                let start_line = self.scroll_offset;
+                let start_line = self.scroll_offset.min(lines.len().saturating_sub(1));
                 let end_line = (start_line + display_height).min(lines.len());
 
                 for (i, line) in lines[start_line..end_line].iter().enumerate() {
-                    execute!(io::stdout(), crossterm::cursor::MoveTo(0, (i + 2) as u16))?;
-                    print!("{}", line);
+                    execute!(io::stdout(), crossterm::cursor::MoveTo(0, top + i as u16))?;
+                    if self.file_viewer_hex_mode() {
+                        print!("{}", fit_width(line, width as usize));
+                    } else {
+                        print!("{}", line);
+                    }
                 }
 
                 if lines.len() > display_height {
                     execute!(io::stdout(), crossterm::cursor::MoveTo(0, height - 1))?;
-                    print!("Scroll: ↑/↓ arrows | Line {}/{}", start_line + 1, lines.len());
+                    let status = format!("Scroll: ↑/↓ arrows | Line {}/{}", start_line + 1, lines.len());
+                    print!("{}", fit_width(&status, width as usize));
                 }
             }
         }
@@ -252,659 +998,3679 @@ impl ChatUI {
         Ok(())
     }
 
-    fn print_with_selection(&self, msg: &str, msg_idx: usize, start: (usize, usize), end: (usize, usize)) -> Result<(), Box<dyn Error>> {
-        // Normalize selection order (ensure start comes before end)
-        let (norm_start, norm_end) = if start.0 > end.0 || (start.0 == end.0 && start.1 > end.1) {
-            (end, start)
-        } else {
-            (start, end)
-        };
-        
-        // Check if this message is within the selection range
-        if msg_idx < norm_start.0 || msg_idx > norm_end.0 {
-            print!("{}", msg);
-            return Ok(());
+    fn draw_message_viewer(&self) -> Result<(), Box<dyn Error>> {
+        let (width, height) = crossterm::terminal::size()?;
+
+        execute!(io::stdout(), crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
+        execute!(io::stdout(), crossterm::cursor::MoveTo(0, 0))?;
+
+        print!("{}", fit_width("Message - ESC: back", width as usize));
+        self.draw_separator(1, width)?;
+        let lines = self.message_viewer_lines(width);
+
+        let top = self.header_rows();
+        // Below the header, leaving the last row for the scroll status
+        let display_height = height.saturating_sub(top + 1) as usize;
+        let start_line = self.scroll_offset.min(lines.len().saturating_sub(1));
+        let end_line = (start_line + display_height).min(lines.len());
+        for (i, text) in lines[start_line..end_line].iter().enumerate() {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, top + i as u16))?;
+            print!("{}", text);
         }
-        
-        if msg_idx == norm_start.0 && msg_idx == norm_end.0 {
-            // Selection within single message
-            let start_char = norm_start.1.min(msg.len());
-            let end_char = norm_end.1.min(msg.len()).max(start_char);
-            
-            let before = &msg[..start_char];
-            let selected = &msg[start_char..end_char];
-            let after = &msg[end_char..];
-            
-            print!("{}", before);
-            if !selected.is_empty() {
-                print!("\x1b[7m{}\x1b[0m", selected); // Reverse video for selection
-            }
-            print!("{}", after);
-        } else if msg_idx == norm_start.0 {
-            // Start of multi-line selection
-            let start_char = norm_start.1.min(msg.len());
-            let before = &msg[..start_char];
-            let selected = &msg[start_char..];
-            
-            print!("{}", before);
-            if !selected.is_empty() {
-                print!("\x1b[7m{}\x1b[0m", selected);
-            }
-        } else if msg_idx == norm_end.0 {
-            // End of multi-line selection
-            let end_char = norm_end.1.min(msg.len());
-            let selected = &msg[..end_char];
-            let after = &msg[end_char..];
-            
-            if !selected.is_empty() {
-                print!("\x1b[7m{}\x1b[0m", selected);
-            }
-            print!("{}", after);
-        } else {
-            // Middle of multi-line selection
-            print!("\x1b[7m{}\x1b[0m", msg);
+
+        if lines.len() > display_height {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, height - 1))?;
+            let status = format!("Scroll: ↑/↓ arrows | Line {}/{}", start_line + 1, lines.len());
+            print!("{}", fit_width(&status, width as usize));
         }
+
+        io::stdout().flush()?;
         Ok(())
     }
 
-    async fn handle_chat_key(&mut self, key: crossterm::event::KeyEvent) -> Result<bool, Box<dyn Error>> {
-        match key.code {
-            KeyCode::Char('q') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
-                return Ok(true); // Signal to exit
-            }
-            KeyCode::Char('c') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
-                self.copy_selection()?;
-            }
-            KeyCode::F(1) => {
-                self.mode = UIMode::FileList;
-            }
-            KeyCode::Enter => {
-                if !self.input.trim().is_empty() {
-                    let text = self.input.clone();
-                    self.input.clear();
-                    self.completion_candidates.clear();
-                    
-                    // Check if it's a file command
-                    if text.starts_with("/file ") {
-                        self.handle_file_command(&text[6..]).await?;
-                    } else if text.starts_with("/test-clipboard") {
-                        self.test_clipboard_functionality()?;
-                    } else {
-                        // Send regular message
-                        let _ = self.message_sender.send(text);
-                    }
+    /// The message viewer's rows: metadata first, then the content wrapped
+    /// to the screen.
+    fn message_viewer_lines(&self, width: u16) -> Vec<String> {
+        let Some(line) = self.message_viewer_index.and_then(|index| self.messages.get(index)) else {
+            return Vec::new();
+        };
+        let mut lines = Vec::new();
+        match &line.source {
+            Some(Message::Text { username, content, timestamp, role, local_id, id, .. }) => {
+                lines.push(format!("From: {}", username));
+                if let Some(role) = role {
+                    lines.push(format!("Role: {}", role));
                 }
+                lines.push(format!("Sent: {}", self.format_time(*timestamp)));
+                // The server's id once it has one, our own until then
+                if let Some(id) = id.as_ref().or(local_id.as_ref()) {
+                    lines.push(format!("Id:   {}", id));
+                }
+                lines.push(String::new());
+                lines.extend(content.lines().flat_map(|l| wrap_line(l, width as usize)));
             }
-            KeyCode::Tab => {
-                self.handle_tab_completion()?;
-            }
-            KeyCode::Char(c) => {
-                self.input.push(c);
-                self.completion_candidates.clear();
-            }
-            KeyCode::Backspace => {
-                self.input.pop();
-                self.completion_candidates.clear();
-            }
-            KeyCode::Esc => {
-                self.clear_selection();
-            }
-            _ => {}
+            _ => lines.extend(wrap_line(&line.text, width as usize)),
         }
-        Ok(false) // Don't exit
+        lines
     }
 
-    fn handle_file_list_key(&mut self, key: crossterm::event::KeyEvent) -> Result<bool, Box<dyn Error>> {
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = UIMode::Chat;
-            }
-            KeyCode::Enter => {
-                if !self.received_files.is_empty() {
-                    self.file_viewer_index = Some(0);
-                    self.mode = UIMode::FileViewer;
-                    self.scroll_offset = 0;
-                }
-            }
-            KeyCode::Char(c) if c.is_ascii_digit() => {
-                let index = c.to_digit(10).unwrap() as usize;
-                if index > 0 && index <= self.received_files.len() {
-                    self.file_viewer_index = Some(index - 1);
-                    self.mode = UIMode::FileViewer;
-                    self.scroll_offset = 0;
-                }
-            }
-            KeyCode::Char('d') | KeyCode::Char('D') => {
-                self.download_all_files()?;
-            }
-            _ => {}
+    /// Number of lines of text in the file being viewed, 0 until it arrives.
+    fn file_viewer_lines(&self) -> usize {
+        self.file_viewer_content().len()
+    }
+
+    fn file_viewer_data(&self) -> Option<&[u8]> {
+        self.file_viewer_index
+            .and_then(|index| self.received_files.get(index))
+            .and_then(|file| file.data.as_deref())
+    }
+
+    /// Whether the viewer shows a hex dump: as toggled with H, otherwise
+    /// for files that look binary.
+    fn file_viewer_hex_mode(&self) -> bool {
+        self.file_viewer_hex
+            .unwrap_or_else(|| self.file_viewer_data().is_some_and(looks_binary))
+    }
+
+    /// The viewed file's rows, as text lines or hex dump rows.
+    fn file_viewer_content(&self) -> Vec<String> {
+        let Some(data) = self.file_viewer_data() else {
+            return Vec::new();
+        };
+        if self.file_viewer_hex_mode() {
+            hex_dump(data)
+        } else {
+            String::from_utf8_lossy(data).lines().map(str::to_string).collect()
         }
-        Ok(false) // Don't exit
     }
 
-    fn handle_file_viewer_key(&mut self, key: crossterm::event::KeyEvent) -> Result<bool, Box<dyn Error>> {
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = UIMode::FileList;
-            }
-            KeyCode::Up => {
-                if self.scroll_offset > 0 {
-                    self.scroll_offset -= 1;
-                }
-            }
-            KeyCode::Down => {
-                self.scroll_offset += 1;
-            }
-            KeyCode::Char('d') | KeyCode::Char('D') => {
-                if let Some(index) = self.file_viewer_index {
-                    self.download_file(index)?;
-                }
-            }
-            _ => {}
+    /// Changes screen, resetting the state that belonged to the old one.
+    /// A viewer is only entered while its index still points at something;
+    /// otherwise we land on the screen it was opened from.
+    fn switch_mode(&mut self, mode: UIMode) {
+        let mode = match mode {
+            UIMode::FileViewer if self.file_viewer_index.is_none_or(|i| i >= self.received_files.len()) => UIMode::FileList,
+            UIMode::MessageViewer if self.message_viewer_index.is_none_or(|i| i >= self.messages.len()) => UIMode::Chat,
+            mode => mode,
+        };
+        if mode != UIMode::FileViewer {
+            self.file_viewer_index = None;
         }
-        Ok(false) // Don't exit
+        self.file_viewer_hex = None;
+        if mode != UIMode::MessageViewer {
+            self.message_viewer_index = None;
+        }
+        if mode == UIMode::CommandPalette {
+            self.palette_filter.clear();
+            self.palette_selected = 0;
+        }
+        self.scroll_offset = 0;
+        self.mode = mode;
     }
 
-    fn handle_mouse_event(&mut self, mouse: MouseEvent) -> Result<(), Box<dyn Error>> {
-        match mouse.kind {
-            MouseEventKind::Down(MouseButton::Left) => {
-                self.start_selection(mouse.column, mouse.row);
-            }
-            MouseEventKind::Drag(MouseButton::Left) => {
-                self.update_selection(mouse.column, mouse.row);
-            }
-            MouseEventKind::Up(MouseButton::Left) => {
-                self.end_selection();
+    fn draw_palette(&self) -> Result<(), Box<dyn Error>> {
+        let (width, height) = crossterm::terminal::size()?;
+
+        execute!(io::stdout(), crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
+        execute!(io::stdout(), crossterm::cursor::MoveTo(0, 0))?;
+
+        print!("{}", fit_width("Commands (ESC: back, ↑/↓: select, Enter: run, type to filter)", width as usize));
+        self.draw_separator(1, width)?;
+
+        let top = self.header_rows();
+        let matches = self.palette_matches();
+        let rows = height.saturating_sub(top + 1) as usize;
+        for (i, entry) in matches.iter().take(rows).enumerate() {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, top + i as u16))?;
+            let row = fit_width(&format!("{:<28} {}", entry.label, entry.hint), width as usize);
+            if i == self.palette_selected {
+                print!("\x1b[7m{}\x1b[0m", row);
+            } else {
+                print!("{}", row);
             }
-            _ => {}
         }
+        if matches.is_empty() {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, top))?;
+            print!("No matching commands.");
+        }
+
+        execute!(io::stdout(), crossterm::cursor::MoveTo(0, height - 1))?;
+        print!("> {}", self.palette_filter);
+        io::stdout().flush()?;
         Ok(())
     }
 
-    fn start_selection(&mut self, x: u16, y: u16) {
-        // Only allow selection in the message area (y >= 2 and below the separator)
-        let (_, height) = crossterm::terminal::size().unwrap_or((80, 24));
-        if y >= 2 && y < height.saturating_sub(2) {
-            // Calculate the actual message index based on scroll position
-            let message_height = height.saturating_sub(4) as usize;
-            let start_idx = if self.messages.len() > message_height {
-                self.messages.len() - message_height
-            } else {
-                0
-            };
-            
-            let relative_msg_index = (y - 2) as usize;
-            let actual_msg_index = start_idx + relative_msg_index;
-            
-            // Ensure we don't go beyond available messages
-            if actual_msg_index < self.messages.len() {
-                let char_index = x as usize;
-                self.selection_start = Some((actual_msg_index, char_index));
-                self.selecting = true;
-                self.selection_end = None; // Clear previous end selection
-            }
+    /// Rows taken by a screen's title and, outside compact mode, the
+    /// separator under it.
+    fn header_rows(&self) -> u16 {
+        if self.config.compact { 1 } else { 2 }
+    }
+
+    /// First row and row count of the chat message area, which sits between
+    /// the header and the input line (plus its separator outside compact mode).
+    fn message_area(&self, height: u16) -> (u16, usize) {
+        let top = self.header_rows();
+        let footer = if self.config.compact { 0 } else { 1 } + self.input_rows(height);
+        (top, height.saturating_sub(top + footer) as usize)
+    }
+
+    /// Rows taken by the input: one per line, up to `MAX_INPUT_ROWS` and
+    /// always leaving a row for messages.
+    fn input_rows(&self, height: u16) -> u16 {
+        if self.search.is_some() {
+            return 1;
         }
+        let separator = if self.config.compact { 0 } else { 1 };
+        let spare = height.saturating_sub(self.header_rows() + separator + 1);
+        let lines = self.input.split('\n').count().min(MAX_INPUT_ROWS as usize) as u16;
+        lines.min(spare).max(1)
     }
 
-    fn update_selection(&mut self, x: u16, y: u16) {
-        if !self.selecting {
-            return;
+    /// Columns left for messages once the online-user sidebar, if shown, has
+    /// its share.
+    fn chat_width(&self, width: u16) -> u16 {
+        if self.online.is_some() && width >= SIDEBAR_MIN_CHAT_WIDTH + SIDEBAR_WIDTH {
+            width - SIDEBAR_WIDTH
+        } else {
+            width
         }
-        
-        let (_, height) = crossterm::terminal::size().unwrap_or((80, 24));
-        if y >= 2 && y < height.saturating_sub(2) {
-            // Calculate the actual message index based on scroll position
-            let message_height = height.saturating_sub(4) as usize;
-            let start_idx = if self.messages.len() > message_height {
-                self.messages.len() - message_height
-            } else {
-                0
-            };
-            
-            let relative_msg_index = (y - 2) as usize;
-            let actual_msg_index = start_idx + relative_msg_index;
-            
-            // Ensure we don't go beyond available messages
-            if actual_msg_index < self.messages.len() {
-                let char_index = x as usize;
-                self.selection_end = Some((actual_msg_index, char_index));
+    }
+
+    /// The online-user list down the right of the message area, starting at
+    /// column `left`. Names that don't fit are counted in a last row.
+    fn draw_sidebar(&self, left: u16, top: u16, rows: usize) -> Result<(), Box<dyn Error>> {
+        let Some(online) = &self.online else {
+            return Ok(());
+        };
+        let columns = (SIDEBAR_WIDTH - 2) as usize;
+        let room = rows.saturating_sub(1);
+        let shown = if online.len() > room { room.saturating_sub(1) } else { online.len() };
+        let mut names = online.iter();
+        for row in 0..rows {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(left, top + row as u16))?;
+            print!("│ ");
+            if row == 0 {
+                print!("{}", fit_width(&format!("Online ({})", online.len()), columns));
+            } else if row <= shown {
+                let Some(name) = names.next() else {
+                    continue;
+                };
+                let color = if self.config.color { username_color(name) } else { "" };
+                let bold = if *name == self.username { "\x1b[1m" } else { "" };
+                let dim = if self.away.contains(name) { "\x1b[2m" } else { "" };
+                print!("{}{}{}{}\x1b[0m", color, bold, dim, fit_width(name, columns));
+            } else if row == shown + 1 && shown < online.len() {
+                print!("{}", fit_width(&format!("+{} more", online.len() - shown), columns));
             }
         }
+        Ok(())
     }
 
-    fn end_selection(&mut self) {
-        self.selecting = false;
+    /// Draws a full-width rule at `row`; compact mode has none.
+    fn draw_separator(&self, row: u16, width: u16) -> Result<(), Box<dyn Error>> {
+        if !self.config.compact {
+            execute!(io::stdout(), crossterm::cursor::MoveTo(0, row))?;
+            print!("{}", "=".repeat(width as usize));
+        }
+        Ok(())
     }
 
-    fn clear_selection(&mut self) {
-        self.selection_start = None;
-        self.selection_end = None;
-        self.selecting = false;
+    /// A chat message wrapped to `width`, one entry per terminal row. In
+    /// bubble layout our own messages are pushed right so their longest row
+    /// ends at the right edge; everything else starts at the left.
+    fn message_rows(&self, index: usize, width: u16) -> Vec<ChatRow> {
+        let line = &self.messages[index];
+        // The send state glyph follows the last row, so leave room for it
+        let glyph = line.send_state.map_or(0, |state| 1 + visible_width(state.glyph()));
+        let ranges = wrap_ranges(&line.text, (width as usize).saturating_sub(glyph));
+        let indent = if self.config.bubble && line.author.as_deref() == Some(self.username.as_str()) {
+            let widest = ranges.iter().map(|range| line.text[range.clone()].width()).max().unwrap_or(0);
+            (width as usize).saturating_sub(widest + glyph)
+        } else {
+            0
+        };
+        let count = ranges.len();
+        ranges
+            .into_iter()
+            .enumerate()
+            .map(|(i, bytes)| ChatRow { index, bytes, indent, last: i + 1 == count })
+            .collect()
     }
 
-    fn copy_to_system_clipboard(&self, text: &str) -> Result<(), Box<dyn Error>> {
-        // Try system commands first as they're more reliable
-        #[cfg(target_os = "linux")]
-        {
-            // Try xclip first
-            if let Ok(mut child) = Command::new("xclip")
-                .arg("-selection")
-                .arg("clipboard")
-                .stdin(std::process::Stdio::piped())
-                .spawn()
-            {
-                if let Some(mut stdin) = child.stdin.take() {
-                    use std::io::Write;
-                    if let Ok(_) = stdin.write_all(text.as_bytes()) {
-                        // Explicitly close stdin
-                        std::mem::drop(stdin);
-                        if let Ok(status) = child.wait() {
-                            if status.success() {
-                                return Ok(());
-                            }
-                        }
-                    }
-                }
-            }
+    /// The rows shown in a chat area `message_height` rows tall: the newest
+    /// messages, or the scrolled-back view, moved as needed to keep the
+    /// keyboard-selected message in view.
+    fn visible_rows(&self, width: u16, message_height: usize) -> Vec<ChatRow> {
+        let Some(newest) = self.messages.len().checked_sub(1) else {
+            return Vec::new();
+        };
+        let ending_at = |last| self.rows_ending_at(last, width, message_height);
+        let starting_at = |first| self.rows_starting_at(first, width, message_height);
 
-            // Try xsel as fallback
-            if let Ok(mut child) = Command::new("xsel")
-                .arg("--clipboard")
-                .arg("--input")
-                .stdin(std::process::Stdio::piped())
-                .spawn()
-            {
-                if let Some(mut stdin) = child.stdin.take() {
-                    use std::io::Write;
-                    if let Ok(_) = stdin.write_all(text.as_bytes()) {
-                        // Explicitly close stdin
-                        std::mem::drop(stdin);
-                        if let Ok(status) = child.wait() {
-                            if status.success() {
-                                return Ok(());
-                            }
-                        }
-                    }
-                }
+        let rows = self
+            .chat_scroll_offset
+            .and_then(starting_at)
+            .unwrap_or_else(|| ending_at(newest));
+        let Some(selected) = self.selected_message.filter(|&selected| selected <= newest) else {
+            return rows;
+        };
+        let top_shown = rows.first().is_some_and(|row| row.index < selected || (row.index == selected && row.bytes.start == 0));
+        let bottom_shown = rows.last().is_some_and(|row| row.index > selected || (row.index == selected && row.last));
+        if !top_shown {
+            starting_at(selected).unwrap_or_else(|| ending_at(newest))
+        } else if !bottom_shown {
+            ending_at(selected)
+        } else {
+            rows
+        }
+    }
+
+    /// Rows ending with message `last`, cutting off the oldest at the top.
+    fn rows_ending_at(&self, last: usize, width: u16, message_height: usize) -> Vec<ChatRow> {
+        let mut rows = Vec::new();
+        for index in (0..=last).rev() {
+            let mut above = self.message_rows(index, width);
+            above.append(&mut rows);
+            rows = above;
+            if rows.len() >= message_height {
+                break;
             }
+        }
+        rows.drain(..rows.len().saturating_sub(message_height));
+        rows
+    }
 
-            // Try wl-copy for Wayland
-            if let Ok(mut child) = Command::new("wl-copy")
-                .stdin(std::process::Stdio::piped())
-                .spawn()
-            {
-                if let Some(mut stdin) = child.stdin.take() {
-                    use std::io::Write;
-                    if let Ok(_) = stdin.write_all(text.as_bytes()) {
-                        // Explicitly close stdin
-                        std::mem::drop(stdin);
-                        if let Ok(status) = child.wait() {
-                            if status.success() {
-                                return Ok(());
-                            }
-                        }
-                    }
-                }
+    /// Rows starting with message `first`, or None when they don't fill the
+    /// area and the view would sit past the newest message.
+    fn rows_starting_at(&self, first: usize, width: u16, message_height: usize) -> Option<Vec<ChatRow>> {
+        let mut rows = Vec::new();
+        for index in first..self.messages.len() {
+            rows.append(&mut self.message_rows(index, width));
+            if rows.len() >= message_height {
+                rows.truncate(message_height);
+                return Some(rows);
             }
         }
+        None
+    }
 
-        #[cfg(target_os = "macos")]
-        {
-            if let Ok(mut child) = Command::new("pbcopy")
-                .stdin(std::process::Stdio::piped())
-                .spawn()
-            {
-                if let Some(mut stdin) = child.stdin.take() {
-                    use std::io::Write;
-                    if let Ok(_) = stdin.write_all(text.as_bytes()) {
-                        // Explicitly close stdin
-                        std::mem::drop(stdin);
-                        if let Ok(status) = child.wait() {
-                            if status.success() {
-                                return Ok(());
-                            }
-                        }
-                    }
-                }
+    /// Index of the first message shown, even if only partly.
+    fn visible_start(&self, width: u16, message_height: usize) -> usize {
+        self.visible_rows(width, message_height).first().map_or(0, |row| row.index)
+    }
+
+    /// Message and column within its text under screen position (x, y) in
+    /// the chat area. Past the end of a row counts as the end of that row.
+    fn chat_position(&self, x: u16, y: u16) -> Option<(usize, usize)> {
+        let (width, height) = crossterm::terminal::size().unwrap_or((80, 24));
+        let (top, message_height) = self.message_area(height);
+        let offset = (y as usize).checked_sub(top as usize)?;
+        let row = self.visible_rows(self.chat_width(width), message_height).into_iter().nth(offset)?;
+        let text = &self.messages[row.index].text;
+        let column = (x as usize).saturating_sub(row.indent).min(text[row.bytes.clone()].width());
+        Some((row.index, text[..row.bytes.start].width() + column))
+    }
+
+    /// Byte range of message `index` covered by the mouse selection.
+    fn selected_bytes(&self, index: usize) -> Option<Range<usize>> {
+        let (Some(start), Some(end)) = (self.selection_start, self.selection_end) else {
+            return None;
+        };
+        // Normalize selection order (ensure start comes before end)
+        let (start, end) = if start.0 > end.0 || (start.0 == end.0 && start.1 > end.1) {
+            (end, start)
+        } else {
+            (start, end)
+        };
+        if index < start.0 || index > end.0 {
+            return None;
+        }
+        let text = &self.messages.get(index)?.text;
+        let from = if index == start.0 { column_to_byte(text, start.1) } else { 0 };
+        let to = if index == end.0 { column_to_byte(text, end.1).max(from) } else { text.len() };
+        Some(from..to)
+    }
+
+    /// Moves the chat view `rows` messages back (negative) or forward
+    /// through history, returning to following the newest at the bottom.
+    fn scroll_chat(&mut self, rows: isize) {
+        let (width, height) = crossterm::terminal::size().unwrap_or((80, 24));
+        let width = self.chat_width(width);
+        let (_, message_height) = self.message_area(height);
+        let start = self.visible_start(width, message_height).saturating_add_signed(rows);
+        self.selected_message = None;
+        self.scroll_chat_to(start, width, message_height);
+    }
+
+    /// Scrolls the chat so message `index` ends at the bottom of the view.
+    fn scroll_to_message(&mut self, index: usize) {
+        let (width, height) = crossterm::terminal::size().unwrap_or((80, 24));
+        let width = self.chat_width(width);
+        let (_, message_height) = self.message_area(height);
+        // A message cut off at the top would push `index` out of view
+        let start = self
+            .rows_ending_at(index, width, message_height)
+            .first()
+            .map_or(index, |row| if row.bytes.start == 0 { row.index } else { row.index + 1 })
+            .min(index);
+        self.scroll_chat_to(start, width, message_height);
+    }
+
+    /// Shows the chat from message `start` on, or follows the newest
+    /// messages again once they would all be in view.
+    fn scroll_chat_to(&mut self, start: usize, width: u16, message_height: usize) {
+        let latest = self
+            .messages
+            .len()
+            .checked_sub(1)
+            .and_then(|newest| self.rows_ending_at(newest, width, message_height).first().map(|row| row.index))
+            .unwrap_or(0);
+        if start >= latest {
+            self.chat_scroll_offset = None;
+        } else {
+            if self.chat_scroll_offset.is_none() {
+                self.messages_at_scroll = self.messages.len();
             }
+            self.chat_scroll_offset = Some(start);
         }
+    }
 
-        #[cfg(target_os = "windows")]
-        {
-            if let Ok(mut child) = Command::new("clip")
-                .stdin(std::process::Stdio::piped())
-                .spawn()
-            {
-                if let Some(mut stdin) = child.stdin.take() {
-                    use std::io::Write;
-                    if let Ok(_) = stdin.write_all(text.as_bytes()) {
-                        // Explicitly close stdin
-                        std::mem::drop(stdin);
-                        if let Ok(status) = child.wait() {
-                            if status.success() {
-                                return Ok(());
-                            }
-                        }
-                    }
+    async fn handle_chat_key(&mut self, key: crossterm::event::KeyEvent) -> Result<bool, Box<dyn Error>> {
+        if let Some(pending) = self.pending_confirm.take() {
+            match key.code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => self.run_confirmed(pending),
+                KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
+                    self.push_notice("* Cancelled".to_string());
                 }
+                _ => self.pending_confirm = Some(pending),
             }
+            return Ok(false);
+        }
+        if self.search.is_some() {
+            return Ok(self.handle_search_key(key));
         }
 
-        // Try arboard as fallback
-        match Clipboard::new() {
-            Ok(mut clipboard) => {
-                match clipboard.set_text(text.to_string()) {
-                    Ok(_) => {
-                        // Verify the clipboard content
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                        match clipboard.get_text() {
-                            Ok(clipboard_text) => {
-                                if clipboard_text == text {
-                                    return Ok(());
-                                } else {
-                                    return Err("Clipboard verification failed - content mismatch".into());
-                                }
-                            }
-                            Err(e) => {
-                                return Err(format!("Clipboard verification failed: {}", e).into());
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        return Err(format!("Arboard clipboard set failed: {}", e).into());
+        match key.code {
+            KeyCode::Char('q') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                return Ok(true); // Signal to exit
+            }
+            KeyCode::Char('f') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                self.search = Some(ChatSearch::new(self.chat_scroll_offset, self.messages_at_scroll));
+            }
+            KeyCode::Char('p') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                self.switch_mode(UIMode::CommandPalette);
+            }
+            KeyCode::Char('c') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                if self.selection_start.is_none() && self.selected_message.is_some() {
+                    self.copy_selected_message()?;
+                } else {
+                    self.copy_selection()?;
+                }
+            }
+            KeyCode::Char('v') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                self.paste_into_input();
+            }
+            KeyCode::Char('r') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                self.reply_to_selected_message();
+            }
+            KeyCode::Char('i') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                self.copy_selected_id(false);
+            }
+            KeyCode::Char('l') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                self.copy_selected_id(true);
+            }
+            KeyCode::Char('v') if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                if let Some(index) = self.selected_message {
+                    self.message_viewer_index = Some(index);
+                    self.switch_mode(UIMode::MessageViewer);
+                }
+            }
+            KeyCode::Char('r') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                // Put the last failed message back in the input to retry it
+                if let Some(text) = self.last_failed.take() {
+                    self.set_input(text);
+                }
+            }
+            KeyCode::PageUp => {
+                let (_, height) = crossterm::terminal::size().unwrap_or((80, 24));
+                self.scroll_chat(-(self.message_area(height).1 as isize));
+            }
+            KeyCode::PageDown => {
+                let (_, height) = crossterm::terminal::size().unwrap_or((80, 24));
+                self.scroll_chat(self.message_area(height).1 as isize);
+            }
+            KeyCode::Up if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                self.scroll_chat(-1);
+            }
+            KeyCode::Down if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                self.scroll_chat(1);
+            }
+            KeyCode::Home => {
+                self.cursor_pos = 0;
+            }
+            // End finishes the line first, then jumps the chat to the latest message
+            KeyCode::End if self.cursor_pos < self.input.chars().count() => {
+                self.cursor_pos = self.input.chars().count();
+            }
+            KeyCode::End => {
+                self.chat_scroll_offset = None;
+                self.selected_message = None;
+            }
+            KeyCode::Left => {
+                self.cursor_pos = self.cursor_pos.saturating_sub(1);
+            }
+            KeyCode::Right => {
+                self.cursor_pos = (self.cursor_pos + 1).min(self.input.chars().count());
+            }
+            KeyCode::Up if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                self.selected_message = match self.selected_message {
+                    Some(index) => Some(index.saturating_sub(1)),
+                    None => self.messages.len().checked_sub(1),
+                };
+            }
+            KeyCode::Down if key.modifiers.contains(crossterm::event::KeyModifiers::ALT) => {
+                // Moving past the newest message leaves selection mode
+                self.selected_message = self
+                    .selected_message
+                    .map(|index| index + 1)
+                    .filter(|&index| index < self.messages.len());
+            }
+            KeyCode::Up => {
+                self.recall_history(-1);
+            }
+            KeyCode::Down => {
+                self.recall_history(1);
+            }
+            KeyCode::F(1) => {
+                self.switch_mode(UIMode::FileList);
+            }
+            // Alt+Enter works in most terminals, Shift+Enter only in some
+            KeyCode::Enter if key.modifiers.intersects(crossterm::event::KeyModifiers::ALT | crossterm::event::KeyModifiers::SHIFT) => {
+                self.insert_newline();
+            }
+            KeyCode::Enter if self.input.trim().is_empty() => match self.config.empty_enter {
+                EmptyEnter::None => {
+                    if self.selected_message.is_some() {
+                        self.copy_selected_message()?;
                     }
                 }
+                EmptyEnter::Latest => {
+                    self.clear_selection();
+                    self.selected_message = None;
+                    self.chat_scroll_offset = None;
+                }
+                EmptyEnter::Newline => self.insert_newline(),
+            },
+            KeyCode::Enter if !self.input.trim().is_empty() => {
+                let text = self.input.clone();
+                self.input.clear();
+                self.cursor_pos = 0;
+                self.history_index = None;
+                self.completion_candidates.clear();
+                self.input_history.push(&text);
+                self.save_draft("");
+                self.chat_scroll_offset = None;
+                self.submit_input(text).await?;
             }
-            Err(e) => {
-                return Err(format!("Failed to create arboard clipboard: {}", e).into());
+            KeyCode::Tab => {
+                self.handle_tab_completion()?;
+            }
+            KeyCode::Char(c) => {
+                let at = self.cursor_byte();
+                self.input.insert(at, c);
+                self.cursor_pos += 1;
+                self.completion_candidates.clear();
+            }
+            KeyCode::Backspace if self.cursor_pos > 0 => {
+                self.cursor_pos -= 1;
+                let at = self.cursor_byte();
+                self.input.remove(at);
+                self.completion_candidates.clear();
+            }
+            KeyCode::Delete if self.cursor_pos < self.input.chars().count() => {
+                let at = self.cursor_byte();
+                self.input.remove(at);
+                self.completion_candidates.clear();
+            }
+            KeyCode::Esc => {
+                self.clear_selection();
+                self.selected_message = None;
             }
+            _ => {}
+        }
+        Ok(false) // Don't exit
+    }
+
+    /// Runs a submitted input line: a client command, or a message (or
+    /// server command) sent to the server.
+    async fn submit_input(&mut self, text: String) -> Result<(), Box<dyn Error>> {
+        // Check if it's a file command
+        if let Some(filepath) = text.strip_prefix("/file ") {
+            self.handle_file_command(filepath).await?;
+        } else if text.starts_with("/test-clipboard") {
+            self.test_clipboard_functionality()?;
+        } else if text.trim() == "/paste" {
+            self.handle_paste_command();
+        } else if text.trim() == "/open-downloads" {
+            self.open_downloads();
+        } else if text == "/ephemeral" || text.starts_with("/ephemeral ") {
+            self.handle_ephemeral_command(&text["/ephemeral".len()..]);
+        } else if text.trim() == "/conn" {
+            self.show_connection_info();
+        } else if text.trim() == "/disconnect" {
+            self.disconnect();
+        } else if text == "/connect" || text.starts_with("/connect ") {
+            self.handle_connect_command(&text["/connect".len()..]).await;
+        } else if text.trim() == "/clear" {
+            if self.messages.is_empty() {
+                self.push_notice("* Nothing to clear".to_string());
+            } else {
+                self.push_notice(format!("* Clear all {} messages from this screen? Others still see them (y/n)", self.messages.len()));
+                self.pending_confirm = Some(PendingConfirm::ClearMessages);
+            }
+        } else if text == "/edit" || text.starts_with("/edit ") {
+            self.handle_edit_command(&text["/edit".len()..]);
+        } else if text.trim() == "/delete" {
+            self.handle_delete_command();
+        } else if text.trim() == "/mute" || text.trim() == "/unmute" {
+            let room = self.room.clone().unwrap_or_default();
+            let name = if room.is_empty() { "this server".to_string() } else { room.clone() };
+            let notice = if text.trim() == "/mute" {
+                if self.muted_rooms.insert(room) {
+                    format!("* Muted {}, mentions there won't ring the bell", name)
+                } else {
+                    format!("* {} is already muted", name)
+                }
+            } else if self.muted_rooms.remove(&room) {
+                format!("* Unmuted {}", name)
+            } else {
+                format!("* {} isn't muted", name)
+            };
+            self.push_notice(notice);
+        } else if text.trim() == "/debug" {
+            self.debug_view = !self.debug_view;
+            let state = if self.debug_view { "on" } else { "off" };
+            self.push_notice(format!("* Raw JSON debug view {}", state));
+        } else {
+            // Send regular message
+            self.send_text(text);
+        }
+        Ok(())
+    }
+
+    /// Keys while searching the chat: typing edits the query, then n/N
+    /// step through the hits. Returns true to exit.
+    fn handle_search_key(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        let control = key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL);
+        let Some(search) = self.search.as_mut() else {
+            return false;
+        };
+        match key.code {
+            KeyCode::Char('q') if control => return true,
+            KeyCode::Esc => {
+                // Back to where the chat was before searching
+                self.chat_scroll_offset = search.saved_offset;
+                self.messages_at_scroll = search.saved_messages_at_scroll;
+                self.search = None;
+            }
+            KeyCode::Char('r') if control => {
+                search.regex = !search.regex;
+                search.compile();
+                search.current = None;
+                self.jump_to_hit(true);
+            }
+            KeyCode::Char('f') if control => {
+                search.typing = true;
+            }
+            KeyCode::Enter if search.typing => {
+                search.typing = false;
+            }
+            KeyCode::Enter => {
+                // Stay at the hit shown
+                self.search = None;
+            }
+            KeyCode::Char(c) if search.typing => {
+                search.query.push(c);
+                search.compile();
+                search.current = None;
+                self.jump_to_hit(true);
+            }
+            KeyCode::Backspace if search.typing => {
+                search.query.pop();
+                search.compile();
+                search.current = None;
+                self.jump_to_hit(true);
+            }
+            KeyCode::Char('n') => self.jump_to_hit(true),
+            KeyCode::Char('N') => self.jump_to_hit(false),
+            _ => {}
+        }
+        false
+    }
+
+    /// Indices of the messages matching the search, oldest first.
+    fn search_hits(&self) -> Vec<usize> {
+        let Some(search) = &self.search else {
+            return Vec::new();
+        };
+        (0..self.messages.len()).filter(|&i| search.matches(&self.messages[i].text)).collect()
+    }
+
+    /// Shows the next hit older (or newer) than the current one, starting
+    /// from the newest.
+    fn jump_to_hit(&mut self, older: bool) {
+        let hits = self.search_hits();
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        let next = match search.current {
+            None => hits.last().copied(),
+            Some(current) if older => hits.iter().rev().find(|&&i| i < current).copied(),
+            Some(current) => hits.iter().find(|&&i| i > current).copied(),
+        };
+        if let Some(index) = next {
+            search.current = Some(index);
+            self.scroll_to_message(index);
+        }
+    }
+
+    fn handle_file_list_key(&mut self, key: crossterm::event::KeyEvent) -> Result<bool, Box<dyn Error>> {
+        match key.code {
+            KeyCode::Esc => {
+                self.switch_mode(UIMode::Chat);
+            }
+            KeyCode::Enter => {
+                if let Some(&index) = self.sorted_file_indices().first() {
+                    self.open_file_viewer(index);
+                }
+            }
+            KeyCode::Char(c) if c.is_ascii_digit() => {
+                // Numbers follow the list as displayed
+                let position = c.to_digit(10).unwrap() as usize;
+                if let Some(index) = position.checked_sub(1).and_then(|p| self.sorted_file_indices().get(p).copied()) {
+                    self.open_file_viewer(index);
+                }
+            }
+            KeyCode::Char('s') | KeyCode::Char('S') => {
+                self.file_sort = self.file_sort.next();
+            }
+            KeyCode::Char('r') | KeyCode::Char('R') => {
+                self.file_sort_descending = !self.file_sort_descending;
+            }
+            KeyCode::Char('d') | KeyCode::Char('D') => {
+                self.download_all_files()?;
+            }
+            _ => {}
+        }
+        Ok(false) // Don't exit
+    }
+
+    fn handle_file_viewer_key(&mut self, key: crossterm::event::KeyEvent) -> Result<bool, Box<dyn Error>> {
+        match key.code {
+            KeyCode::Esc => {
+                self.switch_mode(UIMode::FileList);
+            }
+            KeyCode::Up if self.scroll_offset > 0 => {
+                self.scroll_offset -= 1;
+            }
+            KeyCode::Down if self.scroll_offset + 1 < self.file_viewer_lines() => {
+                self.scroll_offset += 1;
+            }
+            KeyCode::Char('h') | KeyCode::Char('H') if self.file_viewer_data().is_some() => {
+                self.file_viewer_hex = Some(!self.file_viewer_hex_mode());
+                self.scroll_offset = 0;
+            }
+            KeyCode::Char('d') | KeyCode::Char('D') => {
+                if let Some(index) = self.file_viewer_index {
+                    self.download_file(index)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(false) // Don't exit
+    }
+
+    async fn handle_palette_key(&mut self, key: crossterm::event::KeyEvent) -> Result<bool, Box<dyn Error>> {
+        match key.code {
+            KeyCode::Esc => {
+                self.switch_mode(UIMode::Chat);
+            }
+            KeyCode::Up => {
+                self.palette_selected = self.palette_selected.saturating_sub(1);
+            }
+            KeyCode::Down if self.palette_selected + 1 < self.palette_matches().len() => {
+                self.palette_selected += 1;
+            }
+            KeyCode::Enter => {
+                let Some(entry) = self.palette_matches().get(self.palette_selected).copied() else {
+                    return Ok(false);
+                };
+                self.switch_mode(match entry.action {
+                    PaletteAction::Files => UIMode::FileList,
+                    _ => UIMode::Chat,
+                });
+                match entry.action {
+                    PaletteAction::Run(command) => self.submit_input(command.to_string()).await?,
+                    PaletteAction::Insert(command) => {
+                        self.set_input(command.to_string());
+                        self.completion_candidates.clear();
+                    }
+                    PaletteAction::Files => {}
+                    PaletteAction::Quit => return Ok(true),
+                }
+            }
+            KeyCode::Char(c) => {
+                self.palette_filter.push(c);
+                self.palette_selected = 0;
+            }
+            KeyCode::Backspace => {
+                self.palette_filter.pop();
+                self.palette_selected = 0;
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    /// Palette entries matching the typed filter, in palette order.
+    fn palette_matches(&self) -> Vec<&'static PaletteEntry> {
+        PALETTE
+            .iter()
+            .filter(|entry| fuzzy_match(&self.palette_filter, &format!("{} {}", entry.label, entry.hint)))
+            .collect()
+    }
+
+    fn handle_message_viewer_key(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Esc => {
+                self.switch_mode(UIMode::Chat);
+            }
+            KeyCode::Up if self.scroll_offset > 0 => {
+                self.scroll_offset -= 1;
+            }
+            KeyCode::Down => {
+                let (width, _) = crossterm::terminal::size().unwrap_or((80, 24));
+                if self.scroll_offset + 1 < self.message_viewer_lines(width).len() {
+                    self.scroll_offset += 1;
+                }
+            }
+            _ => {}
+        }
+        false // Don't exit
+    }
+
+    fn handle_mouse_event(&mut self, mouse: MouseEvent) -> Result<(), Box<dyn Error>> {
+        if let MouseEventKind::ScrollUp | MouseEventKind::ScrollDown = mouse.kind {
+            self.scroll_wheel(mouse.kind == MouseEventKind::ScrollDown);
+            return Ok(());
+        }
+        if self.mode != UIMode::Chat {
+            return Ok(());
+        }
+        match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                self.start_selection(mouse.column, mouse.row);
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                self.update_selection(mouse.column, mouse.row);
+            }
+            MouseEventKind::Up(MouseButton::Left) => {
+                self.end_selection();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Scrolls whatever the current mode shows by one wheel tick, leaving
+    /// any selection in progress alone.
+    fn scroll_wheel(&mut self, down: bool) {
+        let lines = match self.mode {
+            UIMode::Chat => {
+                let rows = WHEEL_SCROLL_LINES as isize;
+                self.scroll_chat(if down { rows } else { -rows });
+                return;
+            }
+            UIMode::FileViewer => self.file_viewer_lines(),
+            UIMode::MessageViewer => {
+                let (width, _) = crossterm::terminal::size().unwrap_or((80, 24));
+                self.message_viewer_lines(width).len()
+            }
+            UIMode::FileList | UIMode::CommandPalette => return,
+        };
+        self.scroll_offset = if down {
+            (self.scroll_offset + WHEEL_SCROLL_LINES).min(lines.saturating_sub(1))
+        } else {
+            self.scroll_offset.saturating_sub(WHEEL_SCROLL_LINES)
+        };
+    }
+
+    fn start_selection(&mut self, x: u16, y: u16) {
+        // Only allow selection in the message area
+        if let Some(position) = self.chat_position(x, y) {
+            self.selection_start = Some(position);
+            self.selecting = true;
+            self.selection_end = None; // Clear previous end selection
+        }
+    }
+
+    fn update_selection(&mut self, x: u16, y: u16) {
+        if !self.selecting {
+            return;
+        }
+        if let Some(position) = self.chat_position(x, y) {
+            self.selection_end = Some(position);
+        }
+    }
+
+    fn end_selection(&mut self) {
+        self.selecting = false;
+    }
+
+    fn clear_selection(&mut self) {
+        self.selection_start = None;
+        self.selection_end = None;
+        self.selecting = false;
+    }
+
+    fn copy_to_system_clipboard(&self, text: &str) -> Result<(), Box<dyn Error>> {
+        // Try system commands first as they're more reliable
+        #[cfg(target_os = "linux")]
+        {
+            // Try xclip first
+            if let Ok(mut child) = Command::new("xclip")
+                .arg("-selection")
+                .arg("clipboard")
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+            {
+                if let Some(mut stdin) = child.stdin.take() {
+                    use std::io::Write;
+                    if stdin.write_all(text.as_bytes()).is_ok() {
+                        // Explicitly close stdin
+                        std::mem::drop(stdin);
+                        if let Ok(status) = child.wait() {
+                            if status.success() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Try xsel as fallback
+            if let Ok(mut child) = Command::new("xsel")
+                .arg("--clipboard")
+                .arg("--input")
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+            {
+                if let Some(mut stdin) = child.stdin.take() {
+                    use std::io::Write;
+                    if stdin.write_all(text.as_bytes()).is_ok() {
+                        // Explicitly close stdin
+                        std::mem::drop(stdin);
+                        if let Ok(status) = child.wait() {
+                            if status.success() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Try wl-copy for Wayland
+            if let Ok(mut child) = Command::new("wl-copy")
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+            {
+                if let Some(mut stdin) = child.stdin.take() {
+                    use std::io::Write;
+                    if stdin.write_all(text.as_bytes()).is_ok() {
+                        // Explicitly close stdin
+                        std::mem::drop(stdin);
+                        if let Ok(status) = child.wait() {
+                            if status.success() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            if let Ok(mut child) = Command::new("pbcopy")
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+            {
+                if let Some(mut stdin) = child.stdin.take() {
+                    use std::io::Write;
+                    if stdin.write_all(text.as_bytes()).is_ok() {
+                        // Explicitly close stdin
+                        std::mem::drop(stdin);
+                        if let Ok(status) = child.wait() {
+                            if status.success() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            if let Ok(mut child) = Command::new("clip")
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+            {
+                if let Some(mut stdin) = child.stdin.take() {
+                    use std::io::Write;
+                    if stdin.write_all(text.as_bytes()).is_ok() {
+                        // Explicitly close stdin
+                        std::mem::drop(stdin);
+                        if let Ok(status) = child.wait() {
+                            if status.success() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Try arboard as fallback
+        match Clipboard::new() {
+            Ok(mut clipboard) => {
+                match clipboard.set_text(text.to_string()) {
+                    Ok(_) => {
+                        // Verify the clipboard content
+                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        match clipboard.get_text() {
+                            Ok(clipboard_text) => {
+                                if clipboard_text == text {
+                                    Ok(())
+                                } else {
+                                    Err("Clipboard verification failed - content mismatch".into())
+                                }
+                            }
+                            Err(e) => {
+                                Err(format!("Clipboard verification failed: {}", e).into())
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        Err(format!("Arboard clipboard set failed: {}", e).into())
+                    }
+                }
+            }
+            Err(e) => {
+                Err(format!("Failed to create arboard clipboard: {}", e).into())
+            }
+        }
+    }
+
+    /// The text between the selection's ends, in either order, with a
+    /// newline between messages.
+    fn selected_text(&self) -> Option<String> {
+        let (start, end) = (self.selection_start?, self.selection_end?);
+        let mut selected_text = String::new();
+
+        // Normalize selection order
+        let (norm_start, norm_end) = if start.0 > end.0 || (start.0 == end.0 && start.1 > end.1) {
+            (end, start)
+        } else {
+            (start, end)
+        };
+
+        let max_msg_idx = self.messages.len().saturating_sub(1);
+        for msg_idx in norm_start.0..=norm_end.0.min(max_msg_idx) {
+            if let Some(msg) = self.messages.get(msg_idx).map(|line| &line.text) {
+                if norm_start.0 == norm_end.0 {
+                    // Single line selection
+                    let start_char = column_to_byte(msg, norm_start.1);
+                    let end_char = column_to_byte(msg, norm_end.1).max(start_char);
+                    if end_char > start_char {
+                        selected_text.push_str(&msg[start_char..end_char]);
+                    }
+                } else if msg_idx == norm_start.0 {
+                    // First line
+                    let start_char = column_to_byte(msg, norm_start.1);
+                    selected_text.push_str(&msg[start_char..]);
+                    if msg_idx < norm_end.0 {
+                        selected_text.push('\n');
+                    }
+                } else if msg_idx == norm_end.0 {
+                    // Last line
+                    let end_char = column_to_byte(msg, norm_end.1);
+                    selected_text.push_str(&msg[..end_char]);
+                } else {
+                    // Middle lines
+                    selected_text.push_str(msg);
+                    selected_text.push('\n');
+                }
+            }
+        }
+        Some(selected_text)
+    }
+
+    fn copy_selection(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(selected_text) = self.selected_text() {
+            if !selected_text.is_empty() {
+                // Debug: show what we're trying to copy
+                let debug_text = copy_preview(&selected_text);
+                
+                self.push_notice(format!("* Attempting to copy: '{}'", debug_text));
+                
+                match self.copy_to_system_clipboard(&selected_text) {
+                    Ok(_) => {
+                        self.push_notice("* Successfully copied to clipboard!".to_string());
+                        
+                        // Test if we can read it back
+                        if let Ok(mut clipboard) = Clipboard::new() {
+                            match clipboard.get_text() {
+                                Ok(clipboard_content) => {
+                                    if clipboard_content == selected_text {
+                                        self.push_notice("* Clipboard verification: SUCCESS".to_string());
+                                    } else {
+                                        self.push_notice("* Clipboard verification: FAILED - content differs".to_string());
+                                    }
+                                }
+                                Err(e) => {
+                                    self.push_notice(format!("* Clipboard read test failed: {}", e));
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        self.push_notice(format!("* Failed to copy to clipboard: {}", e));
+                        // Save to a temporary file as fallback
+                        let fallback_path = if cfg!(windows) {
+                            "C:\\temp\\terminal_chat_selection.txt"
+                        } else {
+                            "/tmp/terminal_chat_selection.txt"
+                        };
+                        
+                        match std::fs::write(fallback_path, &selected_text) {
+                            Ok(_) => {
+                                self.push_notice(format!("* Text saved to {}", fallback_path));
+                            }
+                            Err(write_err) => {
+                                self.push_notice(format!("* Could not save to file: {}", write_err));
+                                self.push_notice(format!("* Selected text: '{}'", debug_text));
+                            }
+                        }
+                    }
+                }
+            } else {
+                self.push_notice("* No text selected to copy".to_string());
+            }
+            
+            // Clear selection after copying
+            self.clear_selection();
+        } else {
+            self.push_notice("* No text selected".to_string());
+        }
+        Ok(())
+    }
+
+    fn copy_selected_message(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(text) = self.selected_message_text() else {
+            return Ok(());
+        };
+        match self.copy_to_system_clipboard(&text) {
+            Ok(_) => self.push_notice("* Copied message to clipboard".to_string()),
+            Err(e) => self.push_notice(format!("* Failed to copy to clipboard: {}", e)),
+        }
+        Ok(())
+    }
+
+    /// The line Ctrl+C copies while a message is selected.
+    fn selected_message_text(&self) -> Option<String> {
+        self.selected_message.and_then(|i| self.messages.get(i)).map(|line| line.text.clone())
+    }
+
+    /// Copies the server-assigned id of the selected message, or with
+    /// `permalink` a `termchat://host:port/id` link to it.
+    fn copy_selected_id(&mut self, permalink: bool) {
+        let text = match self.selected_message_id(permalink) {
+            Ok(text) => text,
+            Err(notice) => {
+                self.push_notice(notice.to_string());
+                return;
+            }
+        };
+        let what = if permalink { "link" } else { "id" };
+        match self.copy_to_system_clipboard(&text) {
+            Ok(_) => self.push_notice(format!("* Copied message {} to clipboard: {}", what, text)),
+            Err(e) => self.push_notice(format!("* Failed to copy to clipboard: {}", e)),
+        }
+    }
+
+    /// The id or link `copy_selected_id` copies, or the notice saying why
+    /// there is none.
+    fn selected_message_id(&self, permalink: bool) -> Result<String, &'static str> {
+        let line = self.selected_message.and_then(|i| self.messages.get(i)).ok_or("* Select a message with Alt+Up/Down first")?;
+        let Some(Message::Text { id: Some(id), .. }) = &line.source else {
+            return Err("* That message has no id");
+        };
+        match (permalink, &self.connection) {
+            (true, Some(connection)) => Ok(format!("termchat://{}/{}", connection.info.server, id)),
+            (true, None) => Err("* Not connected, so there is no server to link to"),
+            (false, _) => Ok(id.clone()),
+        }
+    }
+
+    /// Steps through submitted lines, older for a negative `step`. Recalled
+    /// lines are copies, so editing one leaves the history as it was;
+    /// stepping past the newest brings back the line being typed.
+    fn recall_history(&mut self, step: isize) {
+        let len = self.input_history.entries().len();
+        let index = match (self.history_index, step < 0) {
+            (None, true) if len > 0 => {
+                self.history_stash = self.input.clone();
+                len - 1
+            }
+            (None, _) => return,
+            (Some(index), true) => index.saturating_sub(1),
+            (Some(index), false) if index + 1 < len => index + 1,
+            (Some(_), false) => {
+                self.history_index = None;
+                let stash = std::mem::take(&mut self.history_stash);
+                self.set_input(stash);
+                return;
+            }
+        };
+        self.history_index = Some(index);
+        self.set_input(self.input_history.entries()[index].clone());
+        self.completion_candidates.clear();
+    }
+
+    /// Replaces the input line, leaving the cursor at its end.
+    fn set_input(&mut self, text: String) {
+        self.cursor_pos = text.chars().count();
+        self.input = text;
+    }
+
+    /// Byte offset of the cursor in `input`.
+    fn cursor_byte(&self) -> usize {
+        self.input.char_indices().nth(self.cursor_pos).map_or(self.input.len(), |(offset, _)| offset)
+    }
+
+    /// Breaks the input at the cursor, for multi-line messages.
+    fn insert_newline(&mut self) {
+        let at = self.cursor_byte();
+        self.input.insert(at, '\n');
+        self.cursor_pos += 1;
+        self.completion_candidates.clear();
+    }
+
+    /// Starts a reply by mentioning the author of the selected message.
+    fn reply_to_selected_message(&mut self) {
+        let author = self
+            .selected_message
+            .and_then(|i| self.messages.get(i))
+            .and_then(|line| line.author.clone());
+        if let Some(author) = author {
+            self.set_input(format!("@{} {}", author, self.input));
+            self.selected_message = None;
+        }
+    }
+
+    fn handle_tab_completion(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.cycle_completion() {
+            return Ok(());
+        }
+        self.completion_base = None;
+        if self.complete_mention() || self.complete_command() {
+            return Ok(());
+        }
+        if self.input.starts_with("/file ") {
+            let path_part = &self.input[6..];
+            
+            // If this is a new tab completion or the input changed
+            if self.completion_candidates.is_empty() || self.last_tab_input != path_part {
+                self.completion_candidates = self.get_file_completions(path_part)?;
+                self.completion_index = 0;
+                self.last_tab_input = path_part.to_string();
+            } else {
+                // Cycle through candidates
+                self.completion_index = (self.completion_index + 1) % self.completion_candidates.len().max(1);
+            }
+
+            if !self.completion_candidates.is_empty() {
+                let completion = &self.completion_candidates[self.completion_index];
+                self.set_input(format!("/file {}", completion));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves to the next match when Tab is pressed again right after a
+    /// word completion with several matches.
+    fn cycle_completion(&mut self) -> bool {
+        let Some(base) = &self.completion_base else {
+            return false;
+        };
+        if self.completion_candidates.len() < 2 || self.input != self.last_tab_input {
+            return false;
+        }
+        self.completion_index = (self.completion_index + 1) % self.completion_candidates.len();
+        self.set_input(format!("{}{} ", base, self.completion_candidates[self.completion_index]));
+        self.last_tab_input = self.input.clone();
+        true
+    }
+
+    /// Replaces the word after `base` with the first of `candidates`,
+    /// remembering them for [`Self::cycle_completion`].
+    fn start_completion(&mut self, base: String, candidates: Vec<String>) {
+        self.completion_candidates = candidates;
+        self.completion_index = 0;
+        if let Some(first) = self.completion_candidates.first() {
+            self.set_input(format!("{}{} ", base, first));
+            self.last_tab_input = self.input.clone();
+            self.completion_base = Some(base);
+        }
+    }
+
+    /// Other users whose names start with `partial`, ignoring case.
+    fn users_matching(&self, partial: &str) -> Vec<String> {
+        let partial = partial.to_lowercase();
+        self.online
+            .as_ref()
+            .unwrap_or(&self.known_users)
+            .iter()
+            .filter(|user| **user != self.username && user.to_lowercase().starts_with(&partial))
+            .cloned()
+            .collect()
+    }
+
+    /// Completes a trailing `@partial` against known online users. Returns
+    /// false if the input doesn't end in a mention.
+    fn complete_mention(&mut self) -> bool {
+        let word_start = self.input.rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let Some(partial) = self.input[word_start..].strip_prefix('@') else {
+            return false;
+        };
+        let candidates = self.users_matching(partial);
+        self.start_completion(format!("{}@", &self.input[..word_start]), candidates);
+        true
+    }
+
+    /// Completes a partial `/command`, or the recipient of `/msg`. Returns
+    /// false for any other input.
+    fn complete_command(&mut self) -> bool {
+        if let Some(partial) = self.input.strip_prefix("/msg ").filter(|partial| !partial.contains(' ')) {
+            let candidates = self.users_matching(partial);
+            self.start_completion("/msg ".to_string(), candidates);
+            return true;
+        }
+        if !self.input.starts_with('/') || self.input.contains(' ') {
+            return false;
+        }
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(self.input.as_str()))
+            .map(|command| command.to_string())
+            .collect();
+        self.start_completion(String::new(), candidates);
+        true
+    }
+
+    fn get_file_completions(&self, partial_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut completions = Vec::new();
+        
+        let search_pattern = if partial_path.is_empty() {
+            "*".to_string()
+        } else {
+            format!("{}*", partial_path)
+        };
+
+        for path in glob(&search_pattern)?.flatten() {
+            if let Some(path_str) = path.to_str() {
+                completions.push(path_str.to_string());
+            }
+        }
+
+        // Also try in current directory if no path separator
+        if !partial_path.contains('/') && !partial_path.contains('\\') {
+            let current_dir_pattern = format!("./{}", search_pattern);
+            for path in glob(&current_dir_pattern)?.flatten() {
+                if let Some(path_str) = path.to_str() {
+                    if let Some(filename) = path_str.strip_prefix("./") {
+                        completions.push(filename.to_string());
+                    }
+                }
+            }
+        }
+
+        completions.sort();
+        completions.dedup();
+        Ok(completions)
+    }
+
+    fn open_file_viewer(&mut self, index: usize) {
+        if index >= self.received_files.len() {
+            return;
+        }
+        self.file_viewer_index = Some(index);
+        self.switch_mode(UIMode::FileViewer);
+        self.fetch_file(index);
+    }
+
+    /// Indices into `received_files` in the order the file list shows them.
+    fn sorted_file_indices(&self) -> Vec<usize> {
+        let files = &self.received_files;
+        let mut order: Vec<usize> = (0..files.len()).collect();
+        match self.file_sort {
+            FileSortKey::Received => order.sort_by_key(|&i| files[i].received),
+            FileSortKey::Name => order.sort_by_key(|&i| files[i].filename.to_lowercase()),
+            FileSortKey::Size => order.sort_by_key(|&i| files[i].size),
+            FileSortKey::Sender => order.sort_by_key(|&i| files[i].sender.to_lowercase()),
+        }
+        if self.file_sort_descending {
+            order.reverse();
+        }
+        order
+    }
+
+    /// Asks the server for the payload of an announced file, unless a
+    /// request is already in flight. A request that got no answer is sent
+    /// again after `FETCH_RETRY`.
+    fn fetch_file(&mut self, index: usize) {
+        if let Some(FileInfo { data: None, id: Some(id), unavailable: false, size, .. }) = self.received_files.get(index) {
+            // Never buffer more than we're willing to accept
+            if *size > self.config.max_file_size {
+                return;
+            }
+            let in_flight = self
+                .requested_files
+                .get(id)
+                .is_some_and(|requested| requested.elapsed() < FETCH_RETRY);
+            if !in_flight {
+                let id = id.clone();
+                self.send_fetch(&id);
+            }
+        }
+    }
+
+    fn send_fetch(&mut self, id: &str) {
+        self.requested_files.insert(id.to_string(), Instant::now());
+        self.send(Message::new_text(self.username.clone(), format!("/fetch {}", id), None, None));
+    }
+
+    /// Stores a fetched payload and completes any download waiting for it.
+    fn receive_file_data(&mut self, id: &str, data: Vec<u8>) {
+        self.requested_files.remove(id);
+        let Some(index) = self.received_files.iter().position(|f| f.id.as_deref() == Some(id)) else {
+            return;
+        };
+        self.received_files[index].data = Some(data);
+        if self.pending_downloads.remove(id) {
+            let _ = self.download_file(index);
+        }
+    }
+
+    fn download_file(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        if let Some(file) = self.received_files.get(index) {
+            use crate::file_transfer::FileTransfer;
+            if file.unavailable {
+                self.push_notice(format!("* {} is no longer available on the server", file.filename));
+                return Ok(());
+            }
+            if file.data.is_none() && file.size > self.config.max_file_size {
+                let notice = format!("* Not downloading {}: {} bytes is over the {} byte limit", file.filename, file.size, self.config.max_file_size);
+                self.push_notice(notice);
+                return Ok(());
+            }
+            let Some(data) = &file.data else {
+                // Save it once the payload arrives
+                if let Some(id) = &file.id {
+                    self.pending_downloads.insert(id.clone());
+                }
+                self.push_notice(format!("* Fetching {}...", file.filename));
+                self.fetch_file(index);
+                return Ok(());
+            };
+            let msg = Message::File {
+                username: file.sender.clone(),
+                filename: file.filename.clone(),
+                size: file.size,
+                data: data.clone(),
+                timestamp: SystemTime::now(),
+                id: file.id.clone(),
+                // Checked and decompressed when the payload arrived
+                crc32: None,
+                codec: None,
+                compressed_size: None,
+            };
+            
+            match FileTransfer::save_file(&msg, DOWNLOAD_DIR, self.config.download_layout, self.config.overwrite) {
+                Ok(path) => {
+                    self.push_notice(format!("* File downloaded to: {}", path));
+                }
+                Err(e) => {
+                    self.push_notice(format!("* Error downloading file: {}", e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn download_all_files(&mut self) -> Result<(), Box<dyn Error>> {
+        for i in 0..self.received_files.len() {
+            self.download_file(i)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `msg` is someone else's chat mentioning us.
+    fn mentions_us(&self, msg: &Message) -> bool {
+        matches!(msg, Message::Text { username, content, .. }
+            if *username != self.username && mentions(content, &self.username))
+    }
+
+    /// Whether the room we're in is muted. Only its chat reaches us, so
+    /// that is where anything arriving was said.
+    fn room_muted(&self) -> bool {
+        self.muted_rooms.contains(self.room.as_deref().unwrap_or_default())
+    }
+
+    fn add_message(&mut self, msg: Message) {
+        if self.debug_view {
+            self.push_notice(format!("<- {}", debug_json(&msg)));
+        }
+        if let Message::Text { id: Some(id), .. } = &msg {
+            if !self.seen_ids.insert(id.clone()) {
+                return;
+            }
+        }
+        if let Message::Text { seq: Some(seq), .. } = &msg {
+            if let Some((room, missed)) = self.gaps.receive(*seq) {
+                self.send(Message::new_backfill(room, *missed.start(), *missed.end()));
+            }
+        }
+        match &msg {
+            Message::UserJoined { username, .. }
+            | Message::Text { username, .. }
+            | Message::Welcome { username, .. } => {
+                self.known_users.insert(username.clone());
+            }
+            Message::FileAvailable { sender, .. } => {
+                self.known_users.insert(sender.clone());
+            }
+            Message::UserLeft { username, .. } => {
+                self.known_users.remove(username);
+            }
+            _ => {}
+        }
+        match &msg {
+            Message::UserList { users, away, .. } => {
+                self.known_users.extend(users.iter().cloned());
+                self.online = Some(users.iter().cloned().collect());
+                self.away = away.iter().cloned().collect();
+            }
+            Message::UserJoined { username, .. } => {
+                if let Some(online) = &mut self.online {
+                    online.insert(username.clone());
+                }
+            }
+            Message::UserLeft { username, .. } => {
+                if let Some(online) = &mut self.online {
+                    online.remove(username);
+                }
+                self.away.remove(username);
+            }
+            _ => {}
+        }
+        let source = matches!(msg, Message::Text { .. }).then(|| msg.clone());
+        // Counted from arrival rather than the sender's clock
+        let expires = match &msg {
+            Message::Text { ttl: Some(ttl), .. } => Some(Instant::now() + Duration::from_secs(*ttl)),
+            _ => None,
+        };
+        let mentioned = self.mentions_us(&msg);
+        if mentioned && !self.room_muted() && self.in_terminal {
+            print!("\x07");
+            let _ = io::stdout().flush();
+        }
+        let author = match &msg {
+            Message::Text { username, .. } | Message::File { username, .. } => Some(username.clone()),
+            Message::FileAvailable { sender, .. } => Some(sender.clone()),
+            Message::Private { from, .. } => Some(from.clone()),
+            _ => None,
+        };
+        let mut styles = Vec::new();
+        let formatted = match &msg {
+            Message::Text { .. } => self.format_text(&msg, &mut styles),
+            Message::Edit { target_id, new_content, .. } => {
+                self.apply_edit(target_id, new_content);
+                return;
+            }
+            Message::Delivered { id, .. } => {
+                self.mark_delivered(id);
+                return;
+            }
+            Message::RoomJoined { room, timestamp } => {
+                self.room = Some(room.clone());
+                self.gaps.enter(room);
+                format!("[{}] * You are in {}", self.format_time(*timestamp), room)
+            }
+            Message::Delete { target_id, .. } => {
+                self.apply_delete(target_id);
+                return;
+            }
+            Message::File { username, filename, size, timestamp, data, id, crc32, codec, compressed_size } => {
+                use crate::file_transfer::{payload_size, FileTransfer};
+
+                let limit = self.config.max_file_size;
+                let payload = payload_size(*size, *compressed_size);
+                let checked = if payload != data.len() as u64 {
+                    Err(format!("declared size {} does not match the {} bytes received", payload, data.len()))
+                } else if *size > limit {
+                    Err(format!("{} bytes is over the {} byte limit", size, limit))
+                } else if !FileTransfer::checksum_matches(data, *crc32) {
+                    Err("its checksum doesn't match, it was damaged in transit".to_string())
+                } else {
+                    match codec {
+                        Some(codec) => FileTransfer::decompress(data, *codec, self.zstd_dictionary.as_deref(), limit).and_then(|data| {
+                            if data.len() as u64 == *size {
+                                Ok(data)
+                            } else {
+                                Err(format!("it decompresses to {} bytes, not the {} it declared", data.len(), size))
+                            }
+                        }),
+                        None => Ok(data.clone()),
+                    }
+                };
+                match checked {
+                    Err(problem) => {
+                        if let Some(id) = id {
+                            self.requested_files.remove(id);
+                            self.pending_downloads.remove(id);
+                        }
+                        format!("[{}] * Discarded {} from {}: {}",
+                            self.format_time(*timestamp), filename, username, problem)
+                    }
+                    Ok(data) => {
+                        if let Some(id) = id {
+                            // Payload we fetched for an announced file
+                            self.receive_file_data(id, data);
+                            return;
+                        }
+                        // Store the file for later viewing/downloading
+                        let size = data.len();
+                        self.received_files.push(FileInfo {
+                            filename: filename.clone(),
+                            size: size as u64,
+                            data: Some(data),
+                            sender: username.clone(),
+                            id: None,
+                            unavailable: false,
+                            received: *timestamp,
+                        });
+                        format!("[{}] {} shared file: {} ({} bytes) - Press F1 to view files",
+                            self.format_time(*timestamp), username, filename, size)
+                    }
+                }
+            }
+            Message::FileAvailable { id, filename, size, sender, timestamp } => {
+                self.received_files.push(FileInfo {
+                    filename: filename.clone(),
+                    size: *size,
+                    data: None,
+                    sender: sender.clone(),
+                    id: Some(id.clone()),
+                    unavailable: false,
+                    received: *timestamp,
+                });
+                format!("[{}] {} shared file: {} ({} bytes) - Press F1 to view files",
+                    self.format_time(*timestamp), sender, filename, size)
+            }
+            Message::FileUnavailable { id, timestamp } => {
+                // Abandon the fetch; nothing partial was written to disk
+                self.requested_files.remove(id);
+                self.pending_downloads.remove(id);
+                let Some(file) = self.received_files.iter_mut().find(|f| f.id.as_deref() == Some(id.as_str())) else {
+                    return;
+                };
+                file.unavailable = true;
+                let filename = file.filename.clone();
+                format!("[{}] * {} is no longer available on the server", self.format_time(*timestamp), filename)
+            }
+            Message::UserJoined { username, timestamp } => {
+                format!("[{}] * {} joined the chat", self.format_time(*timestamp), username)
+            }
+            Message::UserLeft { username, timestamp } => {
+                format!("[{}] * {} left the chat", self.format_time(*timestamp), username)
+            }
+            Message::Private { from, to, content, timestamp } => {
+                let line = if *from == self.username {
+                    format!("[{}] [DM to {}] {}", self.format_time(*timestamp), to, content)
+                } else {
+                    format!("[{}] [DM from {}] {}", self.format_time(*timestamp), from, content)
+                };
+                styles.push((0..line.len(), "\x1b[35m"));
+                line
+            }
+            Message::System { content, timestamp } => {
+                format!("[{}] * {}", self.format_time(*timestamp), content)
+            }
+            Message::Welcome { username, timestamp, server } => {
+                self.server_info = server.clone();
+                if std::mem::replace(&mut self.welcomed, true) {
+                    // Renamed by /nick; the server announces it to everyone
+                    self.known_users.remove(&self.username);
+                    self.username = username.clone();
+                    return;
+                }
+                // The server may have renamed us to avoid a collision
+                if *username != self.username {
+                    self.push_notice(format!("[{}] * Username {} was taken, you are now {}",
+                        self.format_time(*timestamp), self.username, username));
+                    self.username = username.clone();
+                }
+                format!("[{}] * Welcome to the chat, {}!", self.format_time(*timestamp), username)
+            }
+            Message::Rejected { reason: RejectReason::Replaced, detail, timestamp } => {
+                // Reconnecting would only knock the other session off in turn
+                self.connection = None;
+                self.reconnect = None;
+                format!("[{}] * Disconnected: {}", self.format_time(*timestamp), detail)
+            }
+            Message::Rejected { detail, timestamp, .. } => {
+                format!("[{}] * Connection refused: {}", self.format_time(*timestamp), detail)
+            }
+            Message::BackfillUnavailable { room, from, to, timestamp } => {
+                let count = to.saturating_sub(*from) + 1;
+                format!("[{}] * {} missed message{} in {} {} no longer available", self.format_time(*timestamp),
+                    count, if count == 1 { "" } else { "s" }, room, if count == 1 { "is" } else { "are" })
+            }
+            // Only ever sent to the server
+            Message::Backfill { .. } => return,
+            // Answered by the client during the handshake
+            Message::AuthRequired { .. } => return,
+            Message::ZstdDictionary { data } => {
+                self.zstd_dictionary = Some(data.clone());
+                return;
+            }
+            // Heartbeats are answered by the connection itself
+            Message::Ping { .. }
+            | Message::Pong { .. }
+            | Message::Identity { .. }
+            | Message::IdentityChallenge { .. }
+            | Message::IdentityProof { .. } => return,
+            // Shown in the sidebar rather than the chat
+            Message::UserList { .. } => return,
+            // Reassembled by the connection, which passes on the whole file
+            Message::FileStart { .. } | Message::FileChunk { .. } | Message::FileEnd { .. } => return,
+            // Sent by a newer server; nothing to show
+            Message::Unknown { .. } => return,
+        };
+        if !self.config.color {
+            styles.clear();
+        }
+        
+        // The server's echo of a message we sent replaces its local copy
+        if let Message::Text { username, local_id: Some(local_id), .. } = &msg {
+            if *username == self.username {
+                if let Some(PendingSend { index, .. }) = self.pending_sends.remove(local_id) {
+                    if let Some(line) = self.messages.get_mut(index) {
+                        *line = ChatLine { text: formatted, author, styles, send_state: Some(SendState::Sent), source, expires, mentioned };
+                        return;
+                    }
+                }
+            }
+        }
+
+        self.messages.push(ChatLine { text: formatted, author, styles, send_state: None, source, expires, mentioned });
+    }
+
+    /// Renders a chat text message as a line, adding the ranges to style.
+    fn format_text(&self, msg: &Message, styles: &mut Vec<(Range<usize>, &'static str)>) -> String {
+        let Message::Text { username, content, timestamp, role, color, ttl, action, .. } = msg else {
+            return String::new();
+        };
+        let mut line = format!("[{}] ", self.format_time(*timestamp));
+        if *action {
+            line.push_str("* ");
+        }
+        if let Some(role) = role {
+            let tag = format!("[{}]", role);
+            styles.push((line.len()..line.len() + tag.len(), role_style(role)));
+            line.push_str(&tag);
+            line.push(' ');
+        }
+        let style = color.as_deref().and_then(ansi_color).unwrap_or_else(|| username_color(username));
+        if *action {
+            styles.push((line.len()..line.len() + username.len(), style));
+            line.push_str(&format!("{} {}", username, content));
+        } else {
+            styles.push((line.len()..line.len() + username.len() + 1, style));
+            line.push_str(&format!("{}: {}", username, content));
+        }
+        if let Some(ttl) = ttl {
+            line.push_str(&format!(" (disappears after {}s)", ttl));
+        }
+        line
+    }
+
+    /// Index of the chat line showing the message with server id `id`.
+    fn message_index(&self, id: &str) -> Option<usize> {
+        self.messages
+            .iter()
+            .rposition(|line| matches!(&line.source, Some(Message::Text { id: Some(line_id), .. }) if line_id == id))
+    }
+
+    /// Shows the new text of an edited message in its place.
+    fn apply_edit(&mut self, target_id: &str, new_content: &str) {
+        let Some(index) = self.message_index(target_id) else {
+            return;
+        };
+        let Some(mut source) = self.messages[index].source.take() else {
+            return;
+        };
+        if let Message::Text { content, .. } = &mut source {
+            *content = new_content.to_string();
+        }
+        let mut styles = Vec::new();
+        let mut text = self.format_text(&source, &mut styles);
+        text.push_str(" (edited)");
+        if !self.config.color {
+            styles.clear();
+        }
+        let mentioned = matches!(&source, Message::Text { username, .. } if *username != self.username)
+            && mentions(new_content, &self.username);
+        self.clear_selection_on(index);
+        let line = &mut self.messages[index];
+        line.text = text;
+        line.styles = styles;
+        line.source = Some(source);
+        line.mentioned = mentioned;
+    }
+
+    /// Leaves a note where a deleted message was, dropping its text.
+    fn apply_delete(&mut self, target_id: &str) {
+        let Some(index) = self.message_index(target_id) else {
+            return;
+        };
+        let Some(Message::Text { username, timestamp, .. }) = self.messages[index].source.take() else {
+            return;
+        };
+        let text = format!("[{}] * {} deleted a message", self.format_time(timestamp), username);
+        self.clear_selection_on(index);
+        if self.message_viewer_index == Some(index) && self.mode == UIMode::MessageViewer {
+            self.switch_mode(UIMode::Chat);
+        }
+        let line = &mut self.messages[index];
+        line.text = text;
+        line.styles.clear();
+        line.expires = None;
+        line.mentioned = false;
+    }
+
+    /// Drops a text selection touching line `index`, whose text is changing.
+    fn clear_selection_on(&mut self, index: usize) {
+        let selection = [self.selection_start, self.selection_end];
+        if selection.iter().flatten().any(|&(i, _)| i == index) {
+            self.clear_selection();
+        }
+    }
+
+    fn push_notice(&mut self, text: String) {
+        self.messages.push(ChatLine { text, author: None, styles: Vec::new(), send_state: None, source: None, expires: None, mentioned: false });
+    }
+
+    /// Removes ephemeral messages whose time is up.
+    fn expire_messages(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<usize> = (0..self.messages.len())
+            .filter(|&i| self.messages[i].expires.is_some_and(|expires| expires <= now))
+            .collect();
+        for index in expired.into_iter().rev() {
+            self.remove_message(index);
+        }
+    }
+
+    /// Empties the chat, along with everything that points into `messages`.
+    /// Messages still waiting for the server's echo show up again when it
+    /// arrives.
+    fn clear_messages(&mut self) {
+        self.messages.clear();
+        self.clear_selection();
+        self.selected_message = None;
+        self.message_viewer_index = None;
+        self.chat_scroll_offset = None;
+        self.messages_at_scroll = 0;
+        self.pending_sends.clear();
+        self.search = None;
+    }
+
+    /// Drops a line from the chat, keeping everything that points into
+    /// `messages` by index on the same lines.
+    fn remove_message(&mut self, index: usize) {
+        self.messages.remove(index);
+        let shift = |i: usize| if i > index { i - 1 } else { i };
+        self.selected_message = self.selected_message.filter(|&i| i != index).map(shift);
+        if self.message_viewer_index == Some(index) && self.mode == UIMode::MessageViewer {
+            self.switch_mode(UIMode::Chat);
+        }
+        self.message_viewer_index = self.message_viewer_index.map(shift);
+        self.clear_selection_on(index);
+        self.selection_start = self.selection_start.map(|(i, c)| (shift(i), c));
+        self.selection_end = self.selection_end.map(|(i, c)| (shift(i), c));
+        self.chat_scroll_offset = self.chat_scroll_offset.map(shift);
+        if index < self.messages_at_scroll {
+            self.messages_at_scroll -= 1;
+        }
+        if let Some(search) = &mut self.search {
+            search.current = search.current.filter(|&i| i != index).map(shift);
+            search.saved_offset = search.saved_offset.map(shift);
+            if index < search.saved_messages_at_scroll {
+                search.saved_messages_at_scroll -= 1;
+            }
+        }
+        self.pending_sends.retain(|_, pending| pending.index != index);
+        for pending in self.pending_sends.values_mut() {
+            pending.index = shift(pending.index);
+        }
+    }
+
+    /// Marks sent messages the server hasn't echoed in time as failed.
+    fn expire_pending_sends(&mut self) {
+        let expired: Vec<String> = self
+            .pending_sends
+            .iter()
+            .filter(|(_, pending)| pending.sent_at.elapsed() >= SEND_TIMEOUT)
+            .map(|(local_id, _)| local_id.clone())
+            .collect();
+        for local_id in expired {
+            if let Some(pending) = self.pending_sends.remove(&local_id) {
+                self.fail_send(pending.index, pending.content);
+            }
+        }
+    }
+
+    fn fail_send(&mut self, index: usize, content: String) {
+        self.mark_send_state(index, SendState::Failed);
+        self.last_failed = Some(content);
+    }
+
+    /// Moves our sent message `id` on to delivered, once the server says
+    /// the room has it.
+    fn mark_delivered(&mut self, id: &str) {
+        let line = self.messages.iter_mut().rev().find(|line| {
+            line.send_state == Some(SendState::Sent)
+                && matches!(&line.source, Some(Message::Text { id: Some(text_id), .. }) if text_id == id)
+        });
+        if let Some(line) = line {
+            line.send_state = Some(SendState::Delivered);
+        }
+    }
+
+    fn mark_send_state(&mut self, index: usize, state: SendState) {
+        if let Some(line) = self.messages.get_mut(index) {
+            line.send_state = Some(state);
+        }
+    }
+
+    fn format_time(&self, time: SystemTime) -> String {
+        if time.duration_since(UNIX_EPOCH).is_ok() {
+            chrono::DateTime::<chrono::Local>::from(time).format(&self.config.time_format).to_string()
+        } else {
+            "??:??:??".to_string()
+        }
+    }
+
+    fn test_clipboard_functionality(&mut self) -> Result<(), Box<dyn Error>> {
+        let test_text = "Terminal Chat Clipboard Test";
+        self.push_notice("* Testing clipboard functionality...".to_string());
+        
+        match self.copy_to_system_clipboard(test_text) {
+            Ok(_) => {
+                self.push_notice("* Clipboard test: SUCCESS".to_string());
+                
+                // Try to read it back
+                if let Ok(mut clipboard) = Clipboard::new() {
+                    match clipboard.get_text() {
+                        Ok(content) => {
+                            if content == test_text {
+                                self.push_notice("* Clipboard read-back: SUCCESS".to_string());
+                            } else {
+                                self.push_notice(format!("* Clipboard read-back: FAILED - got '{}'", content));
+                            }
+                        }
+                        Err(e) => {
+                            self.push_notice(format!("* Clipboard read-back failed: {}", e));
+                        }
+                    }
+                } else {
+                    self.push_notice("* Could not create clipboard for read-back test".to_string());
+                }
+            }
+            Err(e) => {
+                self.push_notice(format!("* Clipboard test: FAILED - {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_file_command(&mut self, filepath: &str) -> Result<(), Box<dyn Error>> {
+        use crate::file_transfer::FileTransfer;
+
+        let (filename, size) = match FileTransfer::get_file_info(filepath) {
+            Ok(info) => info,
+            Err(e) => {
+                self.push_notice(format!("* Can't send file: {}", e));
+                return Ok(());
+            }
+        };
+        if size > self.config.max_file_size {
+            self.push_notice(format!("* Can't send {}: {} bytes is over the {} byte limit", filename, size, self.config.max_file_size));
+        } else if size > CONFIRM_FILE_SIZE {
+            self.push_notice(format!("* Send {} ({} bytes)? (y/n)", filename, size));
+            self.pending_confirm = Some(PendingConfirm::SendFile { filepath: filepath.to_string(), filename, size });
+        } else {
+            self.send_file_from_disk(filepath, &filename, size);
+        }
+        Ok(())
+    }
+
+    fn send_file_from_disk(&mut self, filepath: &str, filename: &str, size: u64) {
+        use crate::file_transfer::FileTransfer;
+
+        // Only servers that pass the codec on are sent compressed files
+        let compressor = self.compressor();
+        // Servers that take chunks get the file streamed from disk
+        if self.server_supports("chunks") {
+            match FileTransfer::stream_file(filepath, &self.username, self.config.max_file_size, compressor) {
+                Ok(stream) => {
+                    if let Some(connection) = &self.connection {
+                        connection.send_file(stream);
+                    }
+                    self.push_notice(format!("Sending file: {} ({} bytes)", filename, size));
+                }
+                Err(e) => {
+                    self.push_notice(format!("Error reading file {}: {}", filepath, e));
+                }
+            }
+            return;
+        }
+        match FileTransfer::read_file_with_username(filepath, &self.username, self.config.max_file_size, compressor) {
+            Ok(file_msg) => {
+                self.send_file_message(&file_msg);
+                self.push_notice(format!("Sending file: {} ({} bytes)", filename, size));
+            }
+            Err(e) => {
+                self.push_notice(format!("Error reading file {}: {}", filepath, e));
+            }
+        }
+    }
+
+    /// How to compress files for this server: zstd with its dictionary if it
+    /// takes zstd, else gzip, else not at all.
+    fn compressor(&self) -> Option<Compressor<'_>> {
+        if self.server_supports("zstd") {
+            Some(Compressor::Zstd { dictionary: self.zstd_dictionary.as_deref() })
+        } else if self.server_supports("gzip") {
+            Some(Compressor::Gzip)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the server announced `feature` in its welcome.
+    fn server_supports(&self, feature: &str) -> bool {
+        self.server_info.as_ref().is_some_and(|info| info.features.iter().any(|f| f == feature))
+    }
+
+    fn send_file_message(&self, file_msg: &Message) {
+        self.send(file_msg.clone());
+    }
+
+    /// Sends a chat line or server command; the server fills in our role
+    /// and color. Chat lines are shown right away and tracked until the
+    /// server echoes them back.
+    fn send_text(&mut self, text: String) {
+        if text.starts_with('/') {
+            self.send(Message::new_text(self.username.clone(), text, None, None));
+            return;
+        }
+        self.send_chat_lines(text, None);
+    }
+
+    /// The line of our own message that `/edit` and `/delete` act on: the
+    /// selected message, or else the latest we sent. Says why in the chat
+    /// when there is none.
+    fn own_message_target(&mut self) -> Option<usize> {
+        // Older servers would post the edit as chat
+        if !self.server_supports("edit") {
+            self.push_notice("* This server doesn't support editing or deleting messages".to_string());
+            return None;
+        }
+        let own = |line: &ChatLine, username: &str| {
+            matches!(&line.source, Some(Message::Text { username: author, id: Some(_), .. }) if author == username)
+        };
+        let target = match self.selected_message {
+            Some(index) => Some(index).filter(|&i| self.messages.get(i).is_some_and(|line| own(line, &self.username))),
+            None => self.messages.iter().rposition(|line| own(line, &self.username)),
+        };
+        if target.is_none() {
+            let reason = match self.selected_message {
+                Some(_) => "* You can only edit or delete your own messages",
+                None => "* You haven't sent any messages to change",
+            };
+            self.push_notice(reason.to_string());
+        }
+        target
+    }
+
+    /// `/edit <text>`: replaces the text of our selected or latest message.
+    /// Without text, puts the message in the input line to rework.
+    fn handle_edit_command(&mut self, args: &str) {
+        let Some(index) = self.own_message_target() else {
+            return;
+        };
+        let Some(Message::Text { id: Some(id), content, .. }) = &self.messages[index].source else {
+            return;
+        };
+        let new_content = args.trim();
+        if new_content.is_empty() {
+            let text = format!("/edit {}", content);
+            self.set_input(text);
+        } else if self.send(Message::new_edit(id.clone(), new_content.to_string())) {
+            self.selected_message = None;
+        } else {
+            self.push_notice("* Not connected".to_string());
+        }
+    }
+
+    /// `/delete`: removes our selected or latest message for everyone,
+    /// after a y/n prompt.
+    fn handle_delete_command(&mut self) {
+        let Some(index) = self.own_message_target() else {
+            return;
+        };
+        let Some(Message::Text { id: Some(id), content, .. }) = &self.messages[index].source else {
+            return;
+        };
+        let id = id.clone();
+        let prompt = format!("* Delete \"{}\" for everyone? (y/n)", content);
+        self.push_notice(prompt);
+        self.pending_confirm = Some(PendingConfirm::DeleteMessage { id });
+    }
+
+    /// `/ephemeral <seconds> <text>`: a message everyone's client removes
+    /// once `seconds` have passed.
+    fn handle_ephemeral_command(&mut self, args: &str) {
+        let mut args = args.trim_start().splitn(2, char::is_whitespace);
+        let ttl = args.next().and_then(|secs| secs.parse::<u64>().ok()).filter(|&secs| secs > 0);
+        match (ttl, args.next().map(str::trim).filter(|text| !text.is_empty())) {
+            (Some(ttl), Some(text)) => self.send_chat_lines(text.to_string(), Some(ttl)),
+            _ => self.push_notice("* Usage: /ephemeral <seconds> <text>".to_string()),
+        }
+    }
+
+    fn send_chat_lines(&mut self, text: String, ttl: Option<u64>) {
+        match self.config.max_line_width {
+            // A multi-line message is sent whole, so code keeps its layout
+            Some(max) if !text.contains('\n') => split_message(&text, max).into_iter().for_each(|part| self.send_chat_line(part, ttl)),
+            _ => self.send_chat_line(text, ttl),
+        }
+    }
+
+    fn send_chat_line(&mut self, text: String, ttl: Option<u64>) {
+        let local_id = Uuid::new_v4().to_string();
+        let msg = Message::Text {
+            username: self.username.clone(),
+            content: text.clone(),
+            timestamp: SystemTime::now(),
+            role: None,
+            color: None,
+            local_id: Some(local_id.clone()),
+            ttl,
+            id: None,
+            seq: None,
+            action: false,
+        };
+        let index = self.messages.len();
+        let prefix = format!("[{}] ", self.format_time(SystemTime::now()));
+        let mut styles = Vec::new();
+        if self.config.color {
+            styles.push((prefix.len()..prefix.len() + self.username.len() + 1, username_color(&self.username)));
+        }
+        self.messages.push(ChatLine {
+            text: format!("{}{}: {}", prefix, self.username, text),
+            author: Some(self.username.clone()),
+            styles,
+            send_state: Some(SendState::Sending),
+            source: Some(msg.clone()),
+            expires: ttl.map(|ttl| Instant::now() + Duration::from_secs(ttl)),
+            mentioned: false,
+        });
+        if self.send(msg) {
+            self.pending_sends.insert(local_id, PendingSend { index, sent_at: Instant::now(), content: text });
+        } else {
+            self.fail_send(index, text);
+        }
+    }
+
+    /// Sends the clipboard as a message, or offers to send it as a file when
+    /// it is too long or spans several lines.
+    fn handle_paste_command(&mut self) {
+        let text = match Clipboard::new().and_then(|mut clipboard| clipboard.get_text()) {
+            Ok(text) => text,
+            Err(e) => {
+                self.push_notice(format!("* Could not read clipboard: {}", e));
+                return;
+            }
+        };
+        self.send_clipboard_text(text);
+    }
+
+    fn send_clipboard_text(&mut self, text: String) {
+        if text.trim().is_empty() {
+            self.push_notice("* Clipboard is empty".to_string());
+        } else if text.chars().count() <= PASTE_TEXT_LIMIT && !text.contains('\n') {
+            self.send_text(text);
+        } else {
+            let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let filename = format!("clipboard-{}.txt", secs);
+            self.push_notice(format!("* Clipboard has {} bytes, send it as {}? (y/n)", text.len(), filename));
+            self.pending_confirm = Some(PendingConfirm::PasteAsFile { filename, data: text.into_bytes() });
+        }
+    }
+
+    /// Inserts the clipboard text at the cursor, keeping its line breaks
+    /// and turning tabs into spaces.
+    fn paste_into_input(&mut self) {
+        let text = match Clipboard::new().and_then(|mut clipboard| clipboard.get_text()) {
+            Ok(text) => text,
+            Err(e) => {
+                self.push_notice(format!("* Could not read clipboard: {}", e));
+                return;
+            }
+        };
+        let text: String = text
+            .lines()
+            .collect::<Vec<_>>()
+            .join("\n")
+            .chars()
+            .filter(|c| !c.is_control() || c.is_whitespace())
+            .map(|c| if c.is_whitespace() && c != '\n' { ' ' } else { c })
+            .collect();
+        if text.is_empty() {
+            self.push_notice("* Clipboard is empty".to_string());
+            return;
+        }
+        let at = self.cursor_byte();
+        self.input.insert_str(at, &text);
+        self.cursor_pos += text.chars().count();
+        self.completion_candidates.clear();
+    }
+
+    /// Opens the download directory in the system file manager.
+    fn open_downloads(&mut self) {
+        self.open_folder(Path::new(DOWNLOAD_DIR), |path| open::that_detached(path));
+    }
+
+    /// Creates `dir` if need be and hands its full path to `opener`.
+    fn open_folder(&mut self, dir: &Path, opener: impl FnOnce(&Path) -> io::Result<()>) {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            self.push_notice(format!("* Could not create {}: {}", dir.display(), e));
+            return;
+        }
+        let path = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.into());
+        match opener(&path) {
+            Ok(()) => self.push_notice(format!("* Opened {}", path.display())),
+            Err(e) => self.push_notice(format!("* Could not open {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Leaves the current server, keeping the chat log.
+    fn disconnect(&mut self) {
+        if self.reconnect.take().is_some() {
+            self.push_notice("* Stopped reconnecting".to_string());
+            return;
+        }
+        let Some(connection) = self.connection.take() else {
+            self.push_notice("* Not connected".to_string());
+            return;
+        };
+        self.server_info = None;
+        self.zstd_dictionary = None;
+        self.known_users.clear();
+        self.online = None;
+        self.away.clear();
+        self.room = None;
+        self.gaps.leave();
+        self.push_notice(format!("* Disconnected from {}", connection.info.server));
+    }
+
+    /// `/connect <address> [port]`: switches to another server under the
+    /// same username. A failed attempt leaves us disconnected.
+    async fn handle_connect_command(&mut self, args: &str) {
+        let mut parts = args.split_whitespace();
+        let (Some(address), port) = (parts.next(), parts.next()) else {
+            self.push_notice("* Usage: /connect <address> [port]".to_string());
+            return;
+        };
+        let Ok(port) = port.map_or(Ok(DEFAULT_PORT), str::parse::<u16>) else {
+            self.push_notice("* Port must be a number between 0 and 65535".to_string());
+            return;
+        };
+        self.reconnect = None;
+        if self.connection.is_some() {
+            self.disconnect();
+        }
+        self.push_notice(format!("* Connecting to {}:{}...", address, port));
+        self.draw().ok();
+        let ui_tx = self.get_sender();
+        match client::connect(address, port, &self.username, self.credential.as_deref(), ui_tx, self.config.netsim, self.config.identity.clone(), self.config.max_file_size, self.config.tls.clone(), self.config.framing).await {
+            Ok(connection) => self.set_connection(connection),
+            Err(e) => self.push_notice(format!("* Could not connect to {}:{}: {}. Not connected.", address, port, e)),
+        }
+    }
+
+    /// Starts retrying after the server went away, unless reconnecting is
+    /// turned off.
+    fn connection_lost(&mut self) {
+        let Some(connection) = self.connection.take() else {
+            return;
+        };
+        self.server_info = None;
+        self.zstd_dictionary = None;
+        self.known_users.clear();
+        self.online = None;
+        self.away.clear();
+        let Some(delay) = self.config.reconnect_delay else {
+            self.push_notice(format!("* Lost connection to {}. Not connected.", connection.info.server));
+            return;
+        };
+        self.push_notice(format!("* Lost connection to {}, reconnecting in {}s...", connection.info.server, delay.as_secs()));
+        self.reconnect = Some(Reconnect {
+            address: connection.info.address.clone(),
+            port: connection.info.port,
+            delay,
+            next_attempt: Instant::now() + delay,
+        });
+    }
+
+    async fn try_reconnect(&mut self) {
+        let Some(mut reconnect) = self.reconnect.take() else {
+            return;
+        };
+        let ui_tx = self.get_sender();
+        match client::connect(&reconnect.address, reconnect.port, &self.username, self.credential.as_deref(), ui_tx, self.config.netsim, self.config.identity.clone(), self.config.max_file_size, self.config.tls.clone(), self.config.framing).await {
+            Ok(connection) => {
+                self.push_notice(format!("* Reconnected to {}", connection.info.server));
+                self.set_connection(connection);
+            }
+            // Retrying can't fix a refused credential or name
+            Err(e) if e.downcast_ref::<client::Rejection>().is_some_and(|rejection| {
+                matches!(rejection.reason, RejectReason::AuthFailed | RejectReason::InvalidUsername)
+            }) => {
+                self.push_notice(format!("* {}. Not connected.", e));
+            }
+            Err(e) => {
+                reconnect.delay = (reconnect.delay * 2).min(self.config.reconnect_max_delay);
+                reconnect.next_attempt = Instant::now() + reconnect.delay;
+                self.push_notice(format!("* Reconnect failed: {}, retrying in {}s...", e, reconnect.delay.as_secs()));
+                self.reconnect = Some(reconnect);
+            }
+        }
+    }
+
+    /// Prints what is known about the connection and the server's
+    /// announced capabilities.
+    fn show_connection_info(&mut self) {
+        let Some(info) = self.connection.as_ref().map(|connection| &connection.info) else {
+            self.push_notice("* Not connected; use /connect <address> [port]".to_string());
+            return;
+        };
+        let describe = |addr: Option<SocketAddr>| addr.map_or("unknown".to_string(), |addr| addr.to_string());
+        let lines = [
+            format!("* Server: {} ({})", info.server, describe(info.peer)),
+            format!("* Local address: {}", describe(info.local)),
+            format!("* Connected at: {}", self.format_time(info.connected_at)),
+            format!(
+                "* Transport: {}, {}, {}",
+                if info.tls { "TLS over TCP" } else { "plain TCP" },
+                match self.config.framing {
+                    Framing::Json => "newline-delimited JSON",
+                    Framing::Binary => "length-prefixed MessagePack",
+                },
+                match self.compressor() {
+                    Some(Compressor::Zstd { dictionary: Some(_) }) => "files zstd-compressed with the server's dictionary",
+                    Some(Compressor::Zstd { dictionary: None }) => "files zstd-compressed",
+                    Some(Compressor::Gzip) => "files gzipped",
+                    None => "no compression",
+                },
+            ),
+            format!("* Identity key: {}", self.config.identity.as_ref().map_or("none".to_string(), |identity| identity.public_key())),
+        ];
+        for line in lines {
+            self.push_notice(line);
+        }
+        match self.server_info.clone() {
+            Some(info) => {
+                self.push_notice(format!("* Protocol: version {} (client speaks {})", info.protocol, PROTOCOL_VERSION));
+                self.push_notice(format!("* Server features: {}", info.features.join(", ")));
+                self.push_notice(format!("* Max file size: {} bytes", info.max_file_size));
+            }
+            None => self.push_notice("* The server did not announce its protocol version or features".to_string()),
+        }
+    }
+
+    fn run_confirmed(&mut self, pending: PendingConfirm) {
+        match pending {
+            PendingConfirm::PasteAsFile { filename, data } => {
+                let size = data.len() as u64;
+                let (data, codec) = match self.compressor() {
+                    Some(compressor) => crate::file_transfer::FileTransfer::compress(data, compressor),
+                    None => (data, None),
+                };
+                let file_msg = Message::new_file(self.username.clone(), filename.clone(), size, data, codec);
+                self.send_file_message(&file_msg);
+                self.push_notice(format!("Sending file: {}", filename));
+            }
+            PendingConfirm::RestoreDraft { text } => {
+                self.set_input(text);
+            }
+            PendingConfirm::SendFile { filepath, filename, size } => {
+                self.send_file_from_disk(&filepath, &filename, size);
+            }
+            PendingConfirm::ClearMessages => self.clear_messages(),
+            PendingConfirm::DeleteMessage { id } => {
+                if !self.send(Message::new_delete(id)) {
+                    self.push_notice("* Not connected".to_string());
+                }
+                self.selected_message = None;
+            }
+        }
+    }
+
+    /// Writes the unsent input line to the draft file, or removes the file
+    /// when `draft` is empty.
+    fn save_draft(&mut self, draft: &str) {
+        if let Some(path) = &self.draft_path {
+            let _ = history::save_draft(path, draft);
+        }
+        self.draft_saved = draft.to_string();
+        self.draft_saved_at = Instant::now();
+    }
+}
+
+/// Prints the `row` bytes of a chat line, wrapping its styled ranges in
+/// ANSI codes. `base` styles the whole row, under those ranges.
+fn print_styled(line: &ChatLine, row: Range<usize>, base: &str) {
+    let mut styles: Vec<&(Range<usize>, &str)> = line.styles.iter().collect();
+    styles.sort_by_key(|(range, _)| range.start);
+    let mut pos = row.start;
+    print!("{}", base);
+    for (range, style) in styles {
+        // Only the part of the range on this row
+        let start = range.start.clamp(row.start, row.end);
+        let end = range.end.clamp(row.start, row.end);
+        let (Some(before), Some(styled)) = (line.text.get(pos..start), line.text.get(start..end)) else {
+            continue;
+        };
+        if !styled.is_empty() {
+            print!("{}{}{}\x1b[0m{}", before, style, styled, base);
+            pos = end;
+        }
+    }
+    print!("{}", &line.text[pos..row.end]);
+    if !base.is_empty() {
+        print!("\x1b[0m");
+    }
+}
+
+/// Display width of `text` ignoring the ANSI style sequences in it.
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip to the end of the sequence, e.g. `\x1b[31m`
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            width += c.width().unwrap_or(0);
+        }
+    }
+    width
+}
+
+/// `text` with its ANSI style sequences removed.
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// The wire form of a message, with file payloads summarized by size.
+fn debug_json(msg: &Message) -> String {
+    match msg {
+        Message::File { data, .. } => {
+            let mut summary = msg.clone();
+            if let Message::File { data, .. } = &mut summary {
+                data.clear();
+            }
+            summary
+                .to_json()
+                .unwrap_or_default()
+                .replacen("\"data\":[]", &format!("\"data\":\"<{} bytes>\"", data.len()), 1)
+        }
+        Message::Unknown { raw } => raw.clone(),
+        _ => msg.to_json().unwrap_or_default(),
+    }
+}
+
+/// Splits `text` into lines of at most `max` characters, breaking between
+/// words. A `` `code` `` span is kept on one line when it fits; a word or
+/// span longer than `max` is cut where it has to be.
+fn split_message(text: &str, max: usize) -> Vec<String> {
+    let max = max.max(1);
+    let mut tokens: Vec<String> = Vec::new();
+    let mut in_code = false;
+    for word in text.split_whitespace() {
+        match tokens.last_mut() {
+            Some(span) if in_code => {
+                span.push(' ');
+                span.push_str(word);
+            }
+            _ => tokens.push(word.to_string()),
+        }
+        if word.matches('`').count() % 2 == 1 {
+            in_code = !in_code;
+        }
+    }
+
+    // A span too long for one line breaks between its words like any text
+    let tokens = tokens.into_iter().flat_map(|token| {
+        if token.chars().count() > max {
+            token.split(' ').map(str::to_string).collect()
+        } else {
+            vec![token]
+        }
+    });
+
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for token in tokens {
+        let token_len = token.chars().count();
+        let line_len = line.chars().count();
+        if line_len > 0 && line_len + 1 + token_len <= max {
+            line.push(' ');
+            line.push_str(&token);
+            continue;
+        }
+        if line_len > 0 {
+            lines.push(std::mem::take(&mut line));
+        }
+        let chars: Vec<char> = token.chars().collect();
+        let mut pieces = chars.chunks(max).map(|piece| piece.iter().collect::<String>()).peekable();
+        while let Some(piece) = pieces.next() {
+            if pieces.peek().is_some() {
+                lines.push(piece);
+            } else {
+                line = piece;
+            }
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// The first 50 characters of copied text for the notice, newlines
+/// escaped; cut by character so emoji and accents never split.
+fn copy_preview(text: &str) -> String {
+    let preview = text.replace('\n', "\\n");
+    if preview.chars().count() > 50 {
+        format!("{}...", preview.chars().take(50).collect::<String>())
+    } else {
+        preview
+    }
+}
+
+/// Byte offset in `msg` of the character drawn at screen `column`, so
+/// selections always slice on a character boundary even around emoji and
+/// other wide or multibyte characters.
+fn column_to_byte(msg: &str, column: usize) -> usize {
+    let mut width = 0;
+    for (offset, c) in msg.char_indices() {
+        width += c.width().unwrap_or(0);
+        if width > column {
+            return offset;
+        }
+    }
+    msg.len()
+}
+
+/// Whether the characters of `query` appear in `text` in order, ignoring
+/// case, so "dwn" finds "Open downloads folder".
+fn fuzzy_match(query: &str, text: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .filter(|c| !c.is_whitespace())
+        .all(|q| text.any(|c| c == q))
+}
+
+/// Breaks `text` into rows of at most `columns` display cells, preferring
+/// to break after a space.
+fn wrap_line(text: &str, columns: usize) -> Vec<String> {
+    wrap_ranges(text, columns).into_iter().map(|range| text[range].to_string()).collect()
+}
+
+/// Byte ranges of the rows `wrap_line` breaks `text` into. A word longer
+/// than a row is cut where it has to be, and a space falling at the end of
+/// a full row is left out rather than starting the next one.
+fn wrap_ranges(text: &str, columns: usize) -> Vec<Range<usize>> {
+    let columns = columns.max(1);
+    let mut rows = Vec::new();
+    let mut row_start = 0;
+    let mut row_width = 0;
+    let mut last_space = None;
+    for (offset, c) in text.char_indices() {
+        if c == '\n' {
+            rows.push(row_start..offset);
+            row_start = offset + 1;
+            row_width = 0;
+            last_space = None;
+            continue;
+        }
+        let c_width = c.width().unwrap_or(0);
+        if row_width + c_width > columns && offset > row_start {
+            if c == ' ' {
+                rows.push(row_start..offset);
+                row_start = offset + 1;
+                row_width = 0;
+                last_space = None;
+                continue;
+            }
+            // Carry the unfinished word over to the next row
+            let end = match last_space {
+                Some(space) if space > row_start => space + 1,
+                _ => offset,
+            };
+            rows.push(row_start..end);
+            row_start = end;
+            row_width = text[end..offset].width();
+            last_space = None;
+        }
+        if c == ' ' {
+            last_space = Some(offset);
+        }
+        row_width += c_width;
+    }
+    if row_start < text.len() || rows.is_empty() {
+        rows.push(row_start..text.len());
+    }
+    rows
+}
+
+/// Cuts `text` to `columns` display cells, ending in an ellipsis when
+/// anything was dropped, so a header never wraps onto the next row.
+fn fit_width(text: &str, columns: usize) -> String {
+    let width = |c: char| c.width().unwrap_or(0);
+    if text.chars().map(width).sum::<usize>() <= columns {
+        return text.to_string();
+    }
+    let Some(available) = columns.checked_sub(1) else {
+        return String::new();
+    };
+    let mut fitted = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        if used + width(c) > available {
+            break;
+        }
+        used += width(c);
+        fitted.push(c);
+    }
+    // A wide character that didn't fit leaves a cell to pad
+    fitted.push_str(&" ".repeat(available - used));
+    fitted.push('…');
+    fitted
+}
+
+/// Bytes per hex dump row.
+const HEX_ROW_BYTES: usize = 16;
+
+/// A classic hex dump of `data`: per row the offset, the bytes in hex and
+/// the printable ones as ASCII.
+fn hex_dump(data: &[u8]) -> Vec<String> {
+    data.chunks(HEX_ROW_BYTES)
+        .enumerate()
+        .map(|(row, bytes)| {
+            let mut line = format!("{:08x} ", row * HEX_ROW_BYTES);
+            for i in 0..HEX_ROW_BYTES {
+                if i % 8 == 0 {
+                    line.push(' ');
+                }
+                match bytes.get(i) {
+                    Some(byte) => line.push_str(&format!("{:02x} ", byte)),
+                    None => line.push_str("   "),
+                }
+            }
+            let ascii: String = bytes
+                .iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            line.push_str(&format!(" |{}|", ascii));
+            line
+        })
+        .collect()
+}
+
+/// Guesses whether `data` is binary from its first few kilobytes: any NUL
+/// byte, or mostly bytes that aren't printable text.
+fn looks_binary(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(8192)];
+    if sample.contains(&0) {
+        return true;
+    }
+    // Multi-byte UTF-8 is text; a sample cut mid-character still counts
+    let utf8 = match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    let unprintable = sample
+        .iter()
+        .filter(|&&byte| match byte {
+            b'\t' | b'\n' | b'\r' | 0x0c | 0x1b => false,
+            0x80.. => !utf8,
+            byte => byte.is_ascii_control(),
+        })
+        .count();
+    unprintable * 10 > sample.len() * 3
+}
+
+/// Picks the part of `input` to show in `columns` terminal cells so that the
+/// cursor (a char index) stays in view. Returns that text and the cursor's
+/// display column within it.
+fn input_viewport(input: &str, cursor: usize, columns: usize) -> (String, usize) {
+    let chars: Vec<char> = input.chars().collect();
+    let cursor = cursor.min(chars.len());
+    let width = |c: &char| c.width().unwrap_or(0);
+
+    // Scroll just far enough that the cursor cell itself fits
+    let mut start = 0;
+    let mut before_cursor: usize = chars[..cursor].iter().map(width).sum();
+    while start < cursor && before_cursor + 1 > columns {
+        before_cursor -= width(&chars[start]);
+        start += 1;
+    }
+
+    let mut visible = String::new();
+    let mut used = 0;
+    for c in &chars[start..] {
+        if used + width(c) > columns {
+            break;
+        }
+        used += width(c);
+        visible.push(*c);
+    }
+    (visible, before_cursor)
+}
+
+/// Color names the server may assign to users.
+pub const COLOR_NAMES: &[&str] = &["red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// The ANSI foreground code for a color name from `COLOR_NAMES`.
+pub fn ansi_color(name: &str) -> Option<&'static str> {
+    match name {
+        "red" => Some("\x1b[31m"),
+        "green" => Some("\x1b[32m"),
+        "yellow" => Some("\x1b[33m"),
+        "blue" => Some("\x1b[34m"),
+        "magenta" => Some("\x1b[35m"),
+        "cyan" => Some("\x1b[36m"),
+        "white" => Some("\x1b[37m"),
+        _ => None,
+    }
+}
+
+/// Colors given to users the server hasn't assigned one to.
+const USERNAME_COLORS: &[&str] = &["\x1b[31m", "\x1b[32m", "\x1b[33m", "\x1b[34m", "\x1b[35m", "\x1b[36m"];
+
+/// A color picked from the username, so each user keeps the same one on
+/// every line and in every session.
+fn username_color(username: &str) -> &'static str {
+    // FNV-1a, which unlike the std hasher is stable between releases
+    let hash = username.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3));
+    USERNAME_COLORS[(hash % USERNAME_COLORS.len() as u64) as usize]
+}
+
+/// Admins stand out in bold red, moderators in yellow, other roles in bold.
+fn role_style(role: &str) -> &'static str {
+    match role {
+        "admin" => "\x1b[1;31m",
+        "mod" | "moderator" => "\x1b[1;33m",
+        _ => "\x1b[1m",
+    }
+}
+
+use std::io::Write;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{self, tests::TestClient};
+    use crossterm::event::KeyModifiers;
+    use std::sync::Once;
+
+    /// The client's settings when started without flags.
+    fn test_config() -> UiConfig {
+        UiConfig {
+            screensaver_after: None,
+            reconnect_delay: None,
+            reconnect_max_delay: Duration::from_secs(60),
+            compact: false,
+            bubble: false,
+            download_layout: DownloadLayout::Flat,
+            overwrite: false,
+            max_line_width: None,
+            empty_enter: EmptyEnter::None,
+            netsim: None,
+            identity: None,
+            color: false,
+            max_file_size: 100 * 1024 * 1024,
+            tls: None,
+            time_format: "%H:%M:%S".to_string(),
+            framing: Framing::Json,
+        }
+    }
+
+    /// A UI for `amy`, kept away from the real input history and draft.
+    fn test_ui_with(config: UiConfig) -> ChatUI {
+        test_ui_as("amy", config)
+    }
+
+    fn test_ui_as(username: &str, config: UiConfig) -> ChatUI {
+        isolate_data_dir();
+        ChatUI::new(username.to_string(), None, config).expect("UI without a terminal")
+    }
+
+    /// Points the input history and draft at a scratch directory.
+    fn isolate_data_dir() {
+        static DATA_DIR: Once = Once::new();
+        DATA_DIR.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("terminal-chat-tests-{}", std::process::id()));
+            std::env::set_var("XDG_DATA_HOME", dir);
+        });
+    }
+
+    fn test_ui() -> ChatUI {
+        test_ui_with(test_config())
+    }
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> crossterm::event::KeyEvent {
+        crossterm::event::KeyEvent::new(code, modifiers)
+    }
+
+    /// A UI scrolled back with a message selected, as Enter finds it.
+    fn scrolled_back_ui(empty_enter: EmptyEnter) -> ChatUI {
+        let mut ui = test_ui_with(UiConfig { empty_enter, ..test_config() });
+        for n in 0..5 {
+            ui.push_notice(format!("* line {}", n));
+        }
+        ui.chat_scroll_offset = Some(1);
+        ui.selected_message = Some(1);
+        ui
+    }
+
+    #[tokio::test]
+    async fn empty_enter_does_nothing_by_default() {
+        let mut ui = scrolled_back_ui(EmptyEnter::None);
+        ui.selected_message = None;
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
+        assert_eq!(ui.chat_scroll_offset, Some(1));
+        assert_eq!(ui.input, "");
+        assert_eq!(ui.messages.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn empty_enter_can_jump_to_the_latest_message() {
+        let mut ui = scrolled_back_ui(EmptyEnter::Latest);
+        ui.input = "   ".to_string();
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
+        assert_eq!(ui.chat_scroll_offset, None);
+        assert_eq!(ui.selected_message, None);
+        // Alt+Enter still breaks the line
+        ui.input.clear();
+        ui.cursor_pos = 0;
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::ALT)).await.unwrap();
+        assert_eq!(ui.input, "\n");
+    }
+
+    #[tokio::test]
+    async fn empty_enter_can_insert_a_blank_line() {
+        let mut ui = scrolled_back_ui(EmptyEnter::Newline);
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
+        assert_eq!(ui.input, "\n\n");
+        assert_eq!(ui.cursor_pos, 2);
+        assert_eq!(ui.chat_scroll_offset, Some(1));
+    }
+
+    #[test]
+    fn selection_slices_emoji_on_character_boundaries() {
+        let mut ui = test_ui();
+        ui.push_notice("héllo 👋🌍 wörld".to_string());
+        ui.push_notice("日本語のテキスト".to_string());
+        // Columns 6..10 cover both emoji, two columns each
+        ui.selection_start = Some((0, 6));
+        ui.selection_end = Some((0, 10));
+        assert_eq!(ui.selected_text().as_deref(), Some("👋🌍"));
+        // Ends that land inside a wide character round to its start
+        ui.selection_start = Some((1, 5));
+        ui.selection_end = Some((0, 7));
+        assert_eq!(ui.selected_text().as_deref(), Some("👋🌍 wörld\n日本"));
+    }
+
+    #[test]
+    fn copy_preview_cuts_long_emoji_text_by_character() {
+        let text = "🎉".repeat(30) + "\n" + &"é".repeat(30);
+        let preview = copy_preview(&text);
+        assert!(preview.ends_with("..."));
+        assert_eq!(preview.trim_end_matches("...").chars().count(), 50);
+        assert!(preview.starts_with(&"🎉".repeat(30)));
+        assert!(preview.contains("\\n"));
+        assert_eq!(copy_preview("short 👋"), "short 👋");
+    }
+
+    #[tokio::test]
+    async fn mention_in_a_muted_room_does_not_ring_the_bell() {
+        let mut ui = test_ui();
+        let mention = || Message::new_text("bob".to_string(), "hey @amy".to_string(), None, None);
+        ui.add_message(Message::new_room_joined("#noisy".to_string()));
+        ui.submit_input("/mute".to_string()).await.unwrap();
+        assert!(ui.room_muted());
+        assert!(ui.mentions_us(&mention()));
+
+        // Still shown, and highlighted, just without the bell
+        ui.add_message(mention());
+        assert!(ui.messages.last().is_some_and(|line| line.mentioned && line.text.contains("hey @amy")));
+
+        // Other rooms ring as before, and muting is undone by /unmute
+        ui.add_message(Message::new_room_joined("#quiet".to_string()));
+        assert!(!ui.room_muted());
+        ui.add_message(Message::new_room_joined("#noisy".to_string()));
+        assert!(ui.room_muted());
+        ui.submit_input("/unmute".to_string()).await.unwrap();
+        assert!(!ui.room_muted());
+    }
+
+    #[tokio::test]
+    async fn own_chat_goes_from_sending_to_sent_to_delivered() {
+        let mut ui = test_ui();
+        let mut echo = Message::new_text("amy".to_string(), "hi".to_string(), None, None);
+        if let Message::Text { local_id, .. } = &mut echo {
+            *local_id = Some("l1".to_string());
+        }
+        ui.messages.push(ChatLine {
+            text: "amy: hi".to_string(),
+            author: Some("amy".to_string()),
+            styles: Vec::new(),
+            send_state: Some(SendState::Sending),
+            source: Some(echo.clone()),
+            expires: None,
+            mentioned: false,
+        });
+        ui.pending_sends.insert("l1".to_string(), PendingSend { index: 0, sent_at: Instant::now(), content: "hi".to_string() });
+
+        if let Message::Text { id, .. } = &mut echo {
+            *id = Some("m1".to_string());
+        }
+        ui.add_message(echo);
+        assert!(ui.messages[0].send_state == Some(SendState::Sent));
+        ui.add_message(Message::new_delivered("m2".to_string(), 1));
+        assert!(ui.messages[0].send_state == Some(SendState::Sent));
+        ui.add_message(Message::new_delivered("m1".to_string(), 1));
+        assert!(ui.messages[0].send_state == Some(SendState::Delivered));
+        assert_eq!(ui.messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn alt_arrows_move_the_selection_that_copy_uses() {
+        let mut ui = test_ui();
+        for n in 0..3 {
+            ui.push_notice(format!("* line {}", n));
+        }
+        let alt = |code| key(code, KeyModifiers::ALT);
+        ui.handle_chat_key(alt(KeyCode::Up)).await.unwrap();
+        assert_eq!(ui.selected_message, Some(2));
+        ui.handle_chat_key(alt(KeyCode::Up)).await.unwrap();
+        ui.handle_chat_key(alt(KeyCode::Up)).await.unwrap();
+        ui.handle_chat_key(alt(KeyCode::Up)).await.unwrap();
+        assert_eq!(ui.selected_message, Some(0));
+        ui.handle_chat_key(alt(KeyCode::Down)).await.unwrap();
+        assert_eq!(ui.selected_message_text().as_deref(), Some("* line 1"));
+
+        // Past the newest message the selection ends
+        ui.handle_chat_key(alt(KeyCode::Down)).await.unwrap();
+        ui.handle_chat_key(alt(KeyCode::Down)).await.unwrap();
+        assert_eq!(ui.selected_message, None);
+        assert_eq!(ui.selected_message_text(), None);
+    }
+
+    #[tokio::test]
+    async fn typing_in_the_palette_narrows_it_and_enter_runs_the_pick() {
+        let mut ui = test_ui();
+        ui.handle_chat_key(key(KeyCode::Char('p'), KeyModifiers::CONTROL)).await.unwrap();
+        assert!(ui.mode == UIMode::CommandPalette);
+        assert_eq!(ui.palette_matches().len(), PALETTE.len());
+
+        for c in "nick".chars() {
+            ui.handle_palette_key(key(KeyCode::Char(c), KeyModifiers::NONE)).await.unwrap();
+        }
+        let labels: Vec<&str> = ui.palette_matches().iter().map(|entry| entry.label).collect();
+        assert_eq!(labels, ["Change your name"]);
+        // A command that needs arguments goes into the input to finish
+        ui.handle_palette_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
+        assert!(ui.mode == UIMode::Chat);
+        assert_eq!(ui.input, "/nick ");
+
+        // One that doesn't is run straight away
+        ui.set_input(String::new());
+        ui.push_notice("* something to clear".to_string());
+        ui.handle_chat_key(key(KeyCode::Char('p'), KeyModifiers::CONTROL)).await.unwrap();
+        for c in "clear".chars() {
+            ui.handle_palette_key(key(KeyCode::Char(c), KeyModifiers::NONE)).await.unwrap();
+        }
+        assert!(ui.palette_matches().len() < PALETTE.len());
+        assert_eq!(ui.palette_matches()[0].hint, "/clear");
+        ui.handle_palette_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
+        assert!(ui.mode == UIMode::Chat);
+        assert!(matches!(ui.pending_confirm, Some(PendingConfirm::ClearMessages)));
+    }
+
+    #[tokio::test]
+    async fn terminal_without_alternate_screen_or_mouse_still_runs() {
+        let mut ui = test_ui();
+        let unsupported = || Err(io::Error::new(io::ErrorKind::Unsupported, "not on this terminal"));
+        let modes = ui.enter_terminal_modes(unsupported, unsupported);
+        assert!(!modes.alternate_screen && !modes.mouse_capture);
+        assert!(ui.messages.last().unwrap().text.contains("does not support mouse capture"));
+
+        // Selection still works from the keyboard
+        ui.handle_chat_key(key(KeyCode::Up, KeyModifiers::ALT)).await.unwrap();
+        assert!(ui.selected_message_text().unwrap().contains("mouse capture"));
+
+        // Teardown leaves alone what was never switched on
+        modes.restore(|| panic!("mouse capture was never on"), || panic!("never left the main screen")).unwrap();
+
+        // When only the mouse is missing, only the screen is restored
+        let modes = ui.enter_terminal_modes(|| Ok(()), unsupported);
+        let mut left = false;
+        modes.restore(|| panic!("mouse capture was never on"), || { left = true; Ok(()) }).unwrap();
+        assert!(left);
+    }
+
+    #[tokio::test]
+    async fn ephemeral_message_is_removed_once_its_ttl_passes() {
+        let mut ui = test_ui();
+        let mut secret = Message::new_text("bob".to_string(), "the door code is 4321".to_string(), None, None);
+        if let Message::Text { ttl, .. } = &mut secret {
+            *ttl = Some(1);
         }
+        ui.add_message(secret);
+        ui.add_message(Message::new_text("bob".to_string(), "see you there".to_string(), None, None));
+        // Viewing it when it goes doesn't leave the viewer on the next line
+        ui.selected_message = Some(0);
+        ui.message_viewer_index = Some(0);
+        ui.switch_mode(UIMode::MessageViewer);
+
+        ui.expire_messages();
+        assert_eq!(ui.messages.len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        ui.expire_messages();
+        assert_eq!(ui.messages.len(), 1);
+        assert!(ui.messages[0].text.contains("see you there"));
+        assert!(ui.mode == UIMode::Chat);
+        assert_eq!(ui.selected_message, None);
     }
 
-    fn copy_selection(&mut self) -> Result<(), Box<dyn Error>> {
-        if let (Some(start), Some(end)) = (self.selection_start, self.selection_end) {
-            let mut selected_text = String::new();
-            
-            // Normalize selection order
-            let (norm_start, norm_end) = if start.0 > end.0 || (start.0 == end.0 && start.1 > end.1) {
-                (end, start)
-            } else {
-                (start, end)
-            };
+    #[test]
+    fn bubble_layout_right_aligns_our_messages_only() {
+        let mut ui = test_ui_as("amy", UiConfig { bubble: true, ..test_config() });
+        ui.add_message(Message::new_text("amy".to_string(), "mine".to_string(), None, None));
+        ui.add_message(Message::new_text("bob".to_string(), "theirs".to_string(), None, None));
+        ui.add_message(Message::new_text("amy".to_string(), "a longer one of mine that has to wrap".to_string(), None, None));
+        let ends = |row: &ChatRow| row.indent + ui.messages[row.index].text[row.bytes.clone()].width();
 
-            let max_msg_idx = self.messages.len().saturating_sub(1);
-            for msg_idx in norm_start.0..=norm_end.0.min(max_msg_idx) {
-                if let Some(msg) = self.messages.get(msg_idx) {
-                    if norm_start.0 == norm_end.0 {
-                        // Single line selection
-                        let start_char = norm_start.1.min(msg.len());
-                        let end_char = norm_end.1.min(msg.len()).max(start_char);
-                        if end_char > start_char {
-                            selected_text.push_str(&msg[start_char..end_char]);
-                        }
-                    } else if msg_idx == norm_start.0 {
-                        // First line
-                        let start_char = norm_start.1.min(msg.len());
-                        selected_text.push_str(&msg[start_char..]);
-                        if msg_idx < norm_end.0 {
-                            selected_text.push('\n');
-                        }
-                    } else if msg_idx == norm_end.0 {
-                        // Last line
-                        let end_char = norm_end.1.min(msg.len());
-                        selected_text.push_str(&msg[..end_char]);
-                    } else {
-                        // Middle lines
-                        selected_text.push_str(msg);
-                        selected_text.push('\n');
-                    }
-                }
+        let mine = ui.message_rows(0, 40);
+        assert_eq!(mine.len(), 1);
+        assert!(mine[0].indent > 0);
+        assert_eq!(ends(&mine[0]), 40);
+        assert_eq!(ui.message_rows(1, 40)[0].indent, 0);
+
+        // Wrapped rows line up on one left edge, the widest reaching the right
+        let wrapped = ui.message_rows(2, 20);
+        assert!(wrapped.len() > 1);
+        assert!(wrapped.iter().all(|row| row.indent == wrapped[0].indent && ends(row) <= 20));
+        assert!(wrapped.iter().any(|row| ends(row) == 20));
+
+        ui.config.bubble = false;
+        assert_eq!(ui.message_rows(0, 40)[0].indent, 0);
+    }
+
+    #[tokio::test]
+    async fn message_viewer_shows_the_whole_message_and_who_sent_it() {
+        let mut ui = test_ui();
+        let long = "word ".repeat(30);
+        let mut msg = Message::new_text("bob".to_string(), format!("first line\n{}", long.trim_end()), Some("admin".to_string()), None);
+        if let Message::Text { timestamp, id, .. } = &mut msg {
+            *timestamp = UNIX_EPOCH + Duration::from_secs(90_000);
+            *id = Some("m-42".to_string());
+        }
+        let sent = ui.format_time(UNIX_EPOCH + Duration::from_secs(90_000));
+        ui.add_message(msg);
+
+        ui.handle_chat_key(key(KeyCode::Up, KeyModifiers::ALT)).await.unwrap();
+        ui.handle_chat_key(key(KeyCode::Char('v'), KeyModifiers::ALT)).await.unwrap();
+        assert!(ui.mode == UIMode::MessageViewer);
+        let lines = ui.message_viewer_lines(40);
+        assert_eq!(lines[..5], ["From: bob", "Role: admin", &format!("Sent: {}", sent), "Id:   m-42", ""]);
+        // Every word is there, wrapped to the width rather than cut off
+        assert_eq!(lines[5], "first line");
+        assert!(lines[6..].iter().all(|l| visible_width(l) <= 40));
+        assert_eq!(lines[6..].join(" ").split_whitespace().count(), 30);
+
+        ui.handle_message_viewer_key(key(KeyCode::Esc, KeyModifiers::NONE));
+        assert!(ui.mode == UIMode::Chat);
+    }
+
+    /// A UI for `username` connected to a test server on `port`, past the
+    /// server's welcome.
+    async fn connected_ui(username: &str, port: u16) -> ChatUI {
+        let mut ui = test_ui_as(username, test_config());
+        ui.handle_connect_command(&format!(" 127.0.0.1 {}", port)).await;
+        assert!(ui.connection.is_some(), "connected to the test server");
+        pump_until(&mut ui, |ui| ui.server_info.is_some()).await;
+        ui
+    }
+
+    /// Shows what the connection received until `done`, as the event loop
+    /// would, failing the test after 5s.
+    async fn pump_until(ui: &mut ChatUI, done: impl Fn(&ChatUI) -> bool) {
+        let pumped = tokio::time::timeout(Duration::from_secs(5), async {
+            while !done(ui) {
+                let msg = ui.message_receiver.recv().await.expect("the connection is open");
+                ui.add_message(msg);
             }
+        });
+        pumped.await.expect("the awaited messages within 5s");
+    }
 
-            if !selected_text.is_empty() {
-                // Debug: show what we're trying to copy
-                let debug_text = if selected_text.len() > 50 {
-                    format!("{}...", &selected_text[..50].replace('\n', "\\n"))
-                } else {
-                    selected_text.replace('\n', "\\n")
-                };
-                
-                self.messages.push(format!("* Attempting to copy: '{}'", debug_text));
-                
-                match self.copy_to_system_clipboard(&selected_text) {
-                    Ok(_) => {
-                        self.messages.push("* Successfully copied to clipboard!".to_string());
-                        
-                        // Test if we can read it back
-                        if let Ok(mut clipboard) = Clipboard::new() {
-                            match clipboard.get_text() {
-                                Ok(clipboard_content) => {
-                                    if clipboard_content == selected_text {
-                                        self.messages.push("* Clipboard verification: SUCCESS".to_string());
-                                    } else {
-                                        self.messages.push("* Clipboard verification: FAILED - content differs".to_string());
-                                    }
-                                }
-                                Err(e) => {
-                                    self.messages.push(format!("* Clipboard read test failed: {}", e));
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        self.messages.push(format!("* Failed to copy to clipboard: {}", e));
-                        // Save to a temporary file as fallback
-                        let fallback_path = if cfg!(windows) {
-                            "C:\\temp\\terminal_chat_selection.txt"
-                        } else {
-                            "/tmp/terminal_chat_selection.txt"
-                        };
-                        
-                        match std::fs::write(fallback_path, &selected_text) {
-                            Ok(_) => {
-                                self.messages.push(format!("* Text saved to {}", fallback_path));
-                            }
-                            Err(write_err) => {
-                                self.messages.push(format!("* Could not save to file: {}", write_err));
-                                self.messages.push(format!("* Selected text: '{}'", debug_text));
-                            }
-                        }
-                    }
+    #[tokio::test]
+    async fn skipped_chat_is_asked_for_again_and_shown() {
+        let port = server::tests::start(server::tests::test_config()).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut ui = connected_ui("amy", port).await;
+        for text in ["one", "two", "three"] {
+            bob.say(text).await;
+        }
+        let shown = |ui: &ChatUI, text: &str| ui.messages.iter().any(|line| matches!(&line.source, Some(Message::Text { content, .. }) if content == text));
+        // "two" is lost on the way, as over a lossy link
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !shown(&ui, "three") {
+                let msg = ui.message_receiver.recv().await.expect("the connection is open");
+                if !matches!(&msg, Message::Text { content, .. } if content == "two") {
+                    ui.add_message(msg);
                 }
-            } else {
-                self.messages.push("* No text selected to copy".to_string());
             }
-            
-            // Clear selection after copying
-            self.clear_selection();
-        } else {
-            self.messages.push("* No text selected".to_string());
+        })
+        .await
+        .expect("the chat within 5s");
+        assert!(!shown(&ui, "two"));
+
+        // Seeing "three" right after "one" sent a backfill, which brings it
+        pump_until(&mut ui, |ui| shown(ui, "two")).await;
+        assert_eq!(ui.messages.iter().filter(|line| matches!(&line.source, Some(Message::Text { content, .. }) if content == "two")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn copied_id_and_link_belong_to_the_selected_message() {
+        let port = server::tests::start(server::tests::test_config()).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut ui = connected_ui("amy", port).await;
+        for text in ["first", "second"] {
+            bob.say(text).await;
         }
-        Ok(())
+        let is_text = |line: &ChatLine| matches!(line.source, Some(Message::Text { .. }));
+        pump_until(&mut ui, |ui| ui.messages.iter().filter(|line| is_text(line)).count() == 2).await;
+        let ids: Vec<(usize, String)> = ui
+            .messages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| match &line.source {
+                Some(Message::Text { id: Some(id), .. }) => Some((i, id.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0].1, ids[1].1);
+
+        assert_eq!(ui.selected_message_id(false), Err("* Select a message with Alt+Up/Down first"));
+        ui.selected_message = Some(ids[0].0);
+        assert_eq!(ui.selected_message_id(false).as_deref(), Ok(ids[0].1.as_str()));
+        let server = ui.connection.as_ref().unwrap().info.server.clone();
+        assert_eq!(ui.selected_message_id(true), Ok(format!("termchat://{}/{}", server, ids[0].1)));
+
+        ui.push_notice("* no id here".to_string());
+        ui.selected_message = Some(ui.messages.len() - 1);
+        assert_eq!(ui.selected_message_id(false), Err("* That message has no id"));
     }
 
-    fn handle_tab_completion(&mut self) -> Result<(), Box<dyn Error>> {
-        if self.input.starts_with("/file ") {
-            let path_part = &self.input[6..];
-            
-            // If this is a new tab completion or the input changed
-            if self.completion_candidates.is_empty() || self.last_tab_input != path_part {
-                self.completion_candidates = self.get_file_completions(path_part)?;
-                self.completion_index = 0;
-                self.last_tab_input = path_part.to_string();
-            } else {
-                // Cycle through candidates
-                self.completion_index = (self.completion_index + 1) % self.completion_candidates.len().max(1);
+    #[tokio::test]
+    async fn multi_line_message_arrives_as_one_block() {
+        let port = server::tests::start(server::tests::test_config()).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut ui = connected_ui("amy", port).await;
+        let type_text = async |ui: &mut ChatUI, text: &str| {
+            for c in text.chars() {
+                ui.handle_chat_key(key(KeyCode::Char(c), KeyModifiers::NONE)).await.unwrap();
             }
+        };
+        type_text(&mut ui, "fn main() {").await;
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::ALT)).await.unwrap();
+        type_text(&mut ui, "    println!(\"hi\");").await;
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::ALT)).await.unwrap();
+        type_text(&mut ui, "}").await;
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
 
-            if !self.completion_candidates.is_empty() {
-                let completion = &self.completion_candidates[self.completion_index];
-                self.input = format!("/file {}", completion);
-            }
+        let block = "fn main() {\n    println!(\"hi\");\n}";
+        assert_eq!(bob.recv_text().await, block);
+        // Our own copy comes back whole too
+        pump_until(&mut ui, |ui| {
+            ui.messages.iter().any(|line| matches!(&line.source, Some(Message::Text { content, .. }) if content == block))
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn pasted_text_is_sent_as_a_message() {
+        let port = server::tests::start(server::tests::test_config()).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut ui = connected_ui("amy", port).await;
+        ui.send_clipboard_text("from the clipboard".to_string());
+        assert_eq!(bob.recv_text().await, "from the clipboard");
+
+        // Long or multi-line text waits for a y/n to go as a file instead
+        ui.send_clipboard_text("one\ntwo".to_string());
+        assert!(matches!(ui.pending_confirm, Some(PendingConfirm::PasteAsFile { .. })));
+    }
+
+    #[tokio::test]
+    async fn long_message_is_split_at_words_under_the_width_cap() {
+        let words: Vec<String> = (0..50).map(|i| format!("word{:05}", i)).collect();
+        let text = words.join(" ") + "!";
+        assert_eq!(text.chars().count(), 500);
+
+        let port = server::tests::start(server::tests::test_config()).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut ui = connected_ui("amy", port).await;
+        ui.config.max_line_width = Some(200);
+        ui.send_chat_lines(text, None);
+
+        // Twenty 9-character words fill 199 columns; the rest go last
+        assert_eq!(bob.recv_text().await, words[..20].join(" "));
+        assert_eq!(bob.recv_text().await, words[20..40].join(" "));
+        assert_eq!(bob.recv_text().await, words[40..].join(" ") + "!");
+    }
+
+    #[test]
+    fn split_keeps_a_code_span_on_one_line() {
+        assert_eq!(split_message("run `cargo test --all` now", 18), ["run", "`cargo test --all`", "now"]);
+    }
+
+    #[test]
+    fn color_follows_the_flag_then_no_color_then_clicolor_force() {
+        let set = |value: &'static str| Some(OsStr::new(value));
+        // (choice, NO_COLOR, CLICOLOR_FORCE, terminal, expected)
+        let cases = [
+            (ColorChoice::Auto, None, None, true, true),
+            (ColorChoice::Auto, None, None, false, false),
+            (ColorChoice::Auto, set("1"), None, true, false),
+            (ColorChoice::Auto, set(""), None, true, false),
+            (ColorChoice::Auto, None, set("1"), false, true),
+            (ColorChoice::Auto, None, set("0"), false, false),
+            (ColorChoice::Auto, None, set("0"), true, true),
+            (ColorChoice::Auto, set("1"), set("1"), true, false),
+            (ColorChoice::Always, set("1"), None, false, true),
+            (ColorChoice::Never, None, set("1"), true, false),
+        ];
+        for (choice, no_color, clicolor_force, terminal, expected) in cases {
+            assert_eq!(
+                choice.enabled(no_color, clicolor_force, terminal),
+                expected,
+                "{:?} with NO_COLOR={:?} CLICOLOR_FORCE={:?} on a terminal: {}",
+                choice,
+                no_color,
+                clicolor_force,
+                terminal
+            );
         }
-        Ok(())
     }
 
-    fn get_file_completions(&self, partial_path: &str) -> Result<Vec<String>, Box<dyn Error>> {
-        let mut completions = Vec::new();
-        
-        let search_pattern = if partial_path.is_empty() {
-            "*".to_string()
-        } else if partial_path.ends_with('/') {
-            format!("{}*", partial_path)
-        } else {
-            format!("{}*", partial_path)
-        };
+    #[test]
+    fn tiny_terminal_shows_the_too_small_notice() {
+        let mut ui = test_ui();
+        let (min_width, min_height) = ui.min_size();
+        assert_eq!(ui.too_small_rows(10, 2), Some(vec!["Terminal ".to_string(), "too small ".to_string()]));
+        let rows = ui.too_small_rows(min_width - 1, 24).unwrap();
+        assert_eq!(rows.concat(), format!("Terminal too small (need at least {}x{})", min_width, min_height));
+        assert!(rows.iter().all(|row| visible_width(row) < min_width as usize));
+        // Even a terminal with no room at all gets through
+        assert_eq!(ui.too_small_rows(0, 0), Some(Vec::new()));
+        assert_eq!(ui.too_small_rows(min_width, min_height), None);
 
-        for entry in glob(&search_pattern)? {
-            if let Ok(path) = entry {
-                if let Some(path_str) = path.to_str() {
-                    completions.push(path_str.to_string());
-                }
-            }
+        // Compact mode fits in one row less
+        ui.config.compact = true;
+        assert_eq!(ui.too_small_rows(min_width, min_height - 1), None);
+    }
+
+    #[test]
+    fn timestamps_follow_the_time_format() {
+        // 2023-11-14 22:13:20 UTC; minutes, seconds and the month are the
+        // same in any whole-hour timezone
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut ui = test_ui();
+        let default = ui.format_time(time);
+        assert_eq!(default.len(), 8);
+        assert!(default.ends_with(":13:20"), "{}", default);
+
+        ui.config.time_format = "%Y-%m %M:%S".to_string();
+        assert_eq!(ui.format_time(time), "2023-11 13:20");
+        ui.config.time_format = "at %M%% past".to_string();
+        assert_eq!(ui.format_time(time), "at 13% past");
+
+        // A clock set before 1970 can't be shown
+        assert_eq!(ui.format_time(UNIX_EPOCH - Duration::from_secs(1)), "??:??:??");
+    }
+
+    #[test]
+    fn hex_dump_shows_offsets_bytes_and_an_ascii_gutter() {
+        let rows = hex_dump(b"Hello, world!\x00\x01\xffABC");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 00 01 ff  |Hello, world!...|");
+        assert!(rows[1].starts_with("00000010  41 42 43    "), "{}", rows[1]);
+        assert!(rows[1].ends_with(" |ABC|"), "{}", rows[1]);
+        // A short last row keeps the gutter in line with the rest
+        assert_eq!(rows[1].find('|'), rows[0].find('|'));
+        assert!(hex_dump(b"").is_empty());
+
+        // Which files open in hex to begin with
+        assert!(looks_binary(b"GIF89a\x00\x01"));
+        assert!(!looks_binary("plain text, héllo\n".as_bytes()));
+    }
+
+    #[test]
+    fn wrapping_breaks_ascii_at_spaces_and_cuts_long_words() {
+        assert_eq!(wrap_line("the quick brown fox", 10), ["the quick ", "brown fox"]);
+        assert_eq!(wrap_line("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap_line("see https://example.com/a/long/path", 12), ["see ", "https://exam", "ple.com/a/lo", "ng/path"]);
+        // A space landing on the edge doesn't start the next row
+        assert_eq!(wrap_line("12345 6789", 5), ["12345", "6789"]);
+        assert_eq!(wrap_line("one\ntwo", 10), ["one", "two"]);
+        assert_eq!(wrap_line("", 10), [""]);
+    }
+
+    #[test]
+    fn wrapping_counts_display_cells_not_bytes() {
+        // Two cells a character: two fit in five columns, never a half
+        assert_eq!(wrap_line("日本語のテキスト", 5), ["日本", "語の", "テキ", "スト"]);
+        assert_eq!(wrap_line("héllo wörld", 6), ["héllo ", "wörld"]);
+        assert_eq!(wrap_line("ok 👍👍👍", 4), ["ok ", "👍👍", "👍"]);
+        for row in wrap_line("混ざった mixed テキスト text", 7) {
+            assert!(visible_width(&row) <= 7, "{:?} is too wide", row);
+        }
+    }
+
+    #[test]
+    fn cursor_in_long_wide_input_stays_in_the_viewport() {
+        let input = "日本語".repeat(10) + "abc";
+        // At the end: the last columns hold the tail, the cursor just past it
+        let (visible, column) = input_viewport(&input, input.chars().count(), 10);
+        assert_eq!(column, 9);
+        assert!(visible.ends_with("語abc") && visible_width(&visible) <= 10);
+        // In the middle, it scrolls just enough for the cursor's cell
+        let (visible, column) = input_viewport(&input, 20, 10);
+        assert_eq!(column, 8);
+        assert_eq!(visible, input.chars().skip(16).take(5).collect::<String>());
+        // Short input isn't scrolled at all
+        assert_eq!(input_viewport("héllo", 1, 10), ("héllo".to_string(), 1));
+    }
+
+    #[tokio::test]
+    async fn debug_view_shows_the_json_of_received_messages() {
+        let mut ui = test_ui();
+        let msg = Message::new_text("bob".to_string(), "hello".to_string(), None, None);
+        ui.submit_input("/debug".to_string()).await.unwrap();
+        let before = ui.messages.len();
+        ui.add_message(msg.clone());
+        let lines: Vec<&str> = ui.messages[before..].iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], format!("<- {}", msg.to_json().unwrap()));
+        assert!(lines[1].ends_with("bob: hello"));
+
+        ui.submit_input("/debug".to_string()).await.unwrap();
+        let before = ui.messages.len();
+        ui.add_message(Message::new_text("bob".to_string(), "again".to_string(), None, None));
+        assert_eq!(ui.messages.len(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn tab_completes_a_mention_to_a_connected_user() {
+        let mut ui = test_ui();
+        for name in ["alice", "bob"] {
+            ui.add_message(Message::new_user_joined(name.to_string()));
         }
+        ui.set_input("hi @a".to_string());
+        ui.handle_chat_key(key(KeyCode::Tab, KeyModifiers::NONE)).await.unwrap();
+        assert_eq!(ui.input, "hi @alice ");
+        // Nobody else matches, and we're never offered to ourselves
+        ui.set_input("@am".to_string());
+        ui.handle_chat_key(key(KeyCode::Tab, KeyModifiers::NONE)).await.unwrap();
+        assert_eq!(ui.input, "@am");
+    }
 
-        // Also try in current directory if no path separator
-        if !partial_path.contains('/') && !partial_path.contains('\\') {
-            let current_dir_pattern = format!("./{}", search_pattern);
-            for entry in glob(&current_dir_pattern)? {
-                if let Ok(path) = entry {
-                    if let Some(path_str) = path.to_str() {
-                        if let Some(filename) = path_str.strip_prefix("./") {
-                            completions.push(filename.to_string());
-                        }
-                    }
-                }
-            }
+    #[tokio::test]
+    async fn tab_completes_commands_and_msg_recipients() {
+        let mut ui = test_ui();
+        for name in ["alice", "bob", "alan"] {
+            ui.add_message(Message::new_user_joined(name.to_string()));
+        }
+        async fn tab(ui: &mut ChatUI) -> String {
+            ui.handle_chat_key(key(KeyCode::Tab, KeyModifiers::NONE)).await.unwrap();
+            ui.input.clone()
         }
 
-        completions.sort();
-        completions.dedup();
-        Ok(completions)
+        ui.set_input("/wh".to_string());
+        assert_eq!(tab(&mut ui).await, "/who ");
+        // Several matches cycle, back round to the first
+        ui.set_input("/m".to_string());
+        assert_eq!(tab(&mut ui).await, "/me ");
+        assert_eq!(tab(&mut ui).await, "/msg ");
+        assert_eq!(tab(&mut ui).await, "/mute ");
+        assert_eq!(tab(&mut ui).await, "/me ");
+        ui.set_input("/nope".to_string());
+        assert_eq!(tab(&mut ui).await, "/nope");
+
+        ui.set_input("/msg al".to_string());
+        assert_eq!(tab(&mut ui).await, "/msg alan ");
+        assert_eq!(tab(&mut ui).await, "/msg alice ");
+        ui.set_input("/msg B".to_string());
+        assert_eq!(tab(&mut ui).await, "/msg bob ");
+
+        // File paths complete as before
+        ui.set_input("/file src/mai".to_string());
+        assert_eq!(tab(&mut ui).await, "/file src/main.rs");
     }
 
-    fn download_file(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
-        if let Some(file) = self.received_files.get(index) {
-            use crate::file_transfer::FileTransfer;
-            let msg = Message::File {
-                username: file.sender.clone(),
-                filename: file.filename.clone(),
-                size: file.size,
-                data: file.data.clone(),
-                timestamp: SystemTime::now(),
-            };
-            
-            match FileTransfer::save_file(&msg, "downloads") {
-                Ok(path) => {
-                    self.messages.push(format!("* File downloaded to: {}", path));
-                }
-                Err(e) => {
-                    self.messages.push(format!("* Error downloading file: {}", e));
-                }
-            }
+    #[test]
+    fn server_assigned_color_is_used_for_the_author() {
+        let mut ui = test_ui_with(UiConfig { color: true, ..test_config() });
+        let mut msg = Message::new_text("bob".to_string(), "hi".to_string(), None, None);
+        if let Message::Text { color, .. } = &mut msg {
+            *color = Some("red".to_string());
         }
-        Ok(())
+        ui.add_message(msg);
+        let line = ui.messages.last().unwrap();
+        let author = line.text.find("bob:").unwrap();
+        assert!(line.styles.iter().any(|(range, style)| range.start == author && *style == "\x1b[31m"));
+
+        // Without one the client picks its own
+        ui.add_message(Message::new_text("bob".to_string(), "again".to_string(), None, None));
+        let line = ui.messages.last().unwrap();
+        assert!(line.styles.iter().any(|(_, style)| *style == username_color("bob")));
     }
 
-    fn download_all_files(&mut self) -> Result<(), Box<dyn Error>> {
-        for i in 0..self.received_files.len() {
-            self.download_file(i)?;
+    #[test]
+    fn open_downloads_hands_the_full_folder_path_to_the_opener() {
+        let mut ui = test_ui();
+        let dir = std::env::temp_dir().join(format!("terminal-chat-open-{}", std::process::id())).join("downloads");
+        let mut opened = None;
+        ui.open_folder(&dir, |path| {
+            opened = Some(path.to_path_buf());
+            Ok(())
+        });
+        assert!(dir.is_dir());
+        assert_eq!(opened, Some(std::fs::canonicalize(&dir).unwrap()));
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+
+        ui.open_folder(&dir, |_| Err(io::Error::other("no file manager")));
+        assert!(ui.messages.last().is_some_and(|line| line.text.contains("no file manager")));
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_sent_with_slash_file_reaches_another_client() {
+        let port = server::tests::start(server::tests::test_config()).await;
+        let mut amy = connected_ui("amy", port).await;
+        let mut bob = connected_ui("bob", port).await;
+        let path = std::env::temp_dir().join(format!("terminal-chat-send-{}.txt", std::process::id()));
+        std::fs::write(&path, "file contents").unwrap();
+
+        amy.submit_input(format!("/file {}", path.display())).await.unwrap();
+        pump_until(&mut bob, |bob| !bob.received_files.is_empty()).await;
+        bob.fetch_file(0);
+        pump_until(&mut bob, |bob| bob.received_files[0].data.is_some()).await;
+        std::fs::remove_file(&path).unwrap();
+        let file = &bob.received_files[0];
+        assert_eq!((file.filename.as_str(), file.sender.as_str()), (path.file_name().unwrap().to_str().unwrap(), "amy"));
+        assert_eq!(file.data.as_deref(), Some(&b"file contents"[..]));
+    }
+
+    #[test]
+    fn screensaver_starts_when_idle_and_an_incoming_message_wakes_it() {
+        let mut ui = test_ui_with(UiConfig { screensaver_after: Some(Duration::from_millis(50)), ..test_config() });
+        ui.check_idle();
+        assert!(!ui.screensaver);
+        std::thread::sleep(Duration::from_millis(60));
+        ui.check_idle();
+        assert!(ui.screensaver);
+
+        ui.get_sender().send(Message::new_text("bob".to_string(), "wake up".to_string(), None, None)).unwrap();
+        ui.receive_messages();
+        assert!(!ui.screensaver);
+        assert!(ui.messages.last().is_some_and(|line| line.text.ends_with("bob: wake up")));
+    }
+
+    #[tokio::test]
+    async fn fetch_cut_off_by_a_reconnect_resumes_or_aborts_cleanly() {
+        let mut config = server::tests::test_config();
+        config.file_cache_ttl = Duration::from_millis(500);
+        let port = server::tests::start(config).await;
+        let mut bob = TestClient::join(port, "bob").await;
+        let mut ui = connected_ui("amy", port).await;
+        for name in ["kept.txt", "gone.txt"] {
+            bob.send(&Message::new_file("bob".to_string(), name.to_string(), 4, b"data".to_vec(), None)).await;
         }
-        Ok(())
+        pump_until(&mut ui, |ui| ui.received_files.len() == 2).await;
+
+        // The first fetch is lost with the connection, and resumes on the next
+        ui.disconnect();
+        ui.fetch_file(0);
+        ui.handle_connect_command(&format!(" 127.0.0.1 {}", port)).await;
+        pump_until(&mut ui, |ui| ui.received_files[0].data.is_some()).await;
+        assert_eq!(ui.received_files[0].data.as_deref(), Some(&b"data"[..]));
+
+        // The second outlives the file on the server, so the download waiting
+        // for it is dropped and nothing half-written is left behind
+        ui.disconnect();
+        ui.fetch_file(1);
+        ui.pending_downloads.insert(ui.received_files[1].id.clone().unwrap());
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        ui.handle_connect_command(&format!(" 127.0.0.1 {}", port)).await;
+        pump_until(&mut ui, |ui| ui.received_files[1].unavailable).await;
+        assert!(ui.received_files[1].data.is_none());
+        assert!(ui.pending_downloads.is_empty() && ui.requested_files.is_empty());
+        assert!(!Path::new(DOWNLOAD_DIR).join("gone.txt").exists());
     }
 
-    fn add_message(&mut self, msg: Message) {
-        let formatted = match &msg {
-            Message::Text { username, content, timestamp } => {
-                format!("[{}] {}: {}", self.format_time(*timestamp), username, content)
-            }
-            Message::File { username, filename, size, timestamp, data } => {
-                // Store the file for later viewing/downloading
-                self.received_files.push(FileInfo {
-                    filename: filename.clone(),
-                    size: *size,
-                    data: data.clone(),
-                    sender: username.clone(),
-                });
-                format!("[{}] {} shared file: {} ({} bytes) - Press F1 to view files", 
-                    self.format_time(*timestamp), username, filename, size)
-            }
-            Message::UserJoined { username, timestamp } => {
-                format!("[{}] * {} joined the chat", self.format_time(*timestamp), username)
-            }
-            Message::UserLeft { username, timestamp } => {
-                format!("[{}] * {} left the chat", self.format_time(*timestamp), username)
-            }
-            Message::System { content, timestamp } => {
-                format!("[{}] * {}", self.format_time(*timestamp), content)
-            }
-        };
-        
-        self.messages.push(formatted);
+    #[test]
+    fn compact_mode_leaves_more_rows_for_messages() {
+        let default = test_ui();
+        let compact = test_ui_with(UiConfig { compact: true, ..test_config() });
+        assert_eq!(default.message_area(24), (2, 20));
+        assert_eq!(compact.message_area(24), (1, 22));
     }
 
-    fn format_time(&self, time: SystemTime) -> String {
-        if let Ok(duration) = time.duration_since(UNIX_EPOCH) {
-            let secs = duration.as_secs();
-            let hours = (secs / 3600) % 24;
-            let minutes = (secs / 60) % 60;
-            let seconds = secs % 60;
-            format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
-        } else {
-            "??:??:??".to_string()
+    #[tokio::test]
+    async fn ctrl_r_puts_a_failed_message_back_in_the_input() {
+        // Not connected, so the send fails
+        let mut ui = test_ui();
+        ui.submit_input("lost words".to_string()).await.unwrap();
+        assert!(ui.messages.last().is_some_and(|line| line.send_state == Some(SendState::Failed)));
+        assert_eq!(ui.input, "");
+
+        ui.handle_chat_key(key(KeyCode::Char('r'), KeyModifiers::CONTROL)).await.unwrap();
+        assert_eq!(ui.input, "lost words");
+        assert_eq!(ui.cursor_pos, "lost words".len());
+    }
+
+    #[test]
+    fn sorting_files_by_size_keeps_the_picked_file_right() {
+        let mut ui = test_ui();
+        for (name, size) in [("big.txt", 30), ("small.txt", 10), ("mid.txt", 20)] {
+            ui.add_message(Message::new_file("bob".to_string(), name.to_string(), size, vec![b'x'; size as usize], None));
         }
+        ui.switch_mode(UIMode::FileList);
+        let press = |ui: &mut ChatUI, c| ui.handle_file_list_key(key(KeyCode::Char(c), KeyModifiers::NONE)).unwrap();
+        press(&mut ui, 's');
+        press(&mut ui, 's');
+        let names = |ui: &ChatUI| ui.sorted_file_indices().iter().map(|&i| ui.received_files[i].filename.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&ui), ["small.txt", "mid.txt", "big.txt"]);
+        press(&mut ui, 'r');
+        assert_eq!(names(&ui), ["big.txt", "mid.txt", "small.txt"]);
+
+        // Numbers pick from the list as shown
+        press(&mut ui, '3');
+        assert_eq!(ui.file_viewer_index.map(|i| ui.received_files[i].filename.as_str()), Some("small.txt"));
     }
 
-    fn test_clipboard_functionality(&mut self) -> Result<(), Box<dyn Error>> {
-        let test_text = "Terminal Chat Clipboard Test";
-        self.messages.push("* Testing clipboard functionality...".to_string());
-        
-        match self.copy_to_system_clipboard(test_text) {
-            Ok(_) => {
-                self.messages.push("* Clipboard test: SUCCESS".to_string());
-                
-                // Try to read it back
-                if let Ok(mut clipboard) = Clipboard::new() {
-                    match clipboard.get_text() {
-                        Ok(content) => {
-                            if content == test_text {
-                                self.messages.push("* Clipboard read-back: SUCCESS".to_string());
-                            } else {
-                                self.messages.push(format!("* Clipboard read-back: FAILED - got '{}'", content));
-                            }
-                        }
-                        Err(e) => {
-                            self.messages.push(format!("* Clipboard read-back failed: {}", e));
-                        }
-                    }
-                } else {
-                    self.messages.push("* Could not create clipboard for read-back test".to_string());
-                }
-            }
-            Err(e) => {
-                self.messages.push(format!("* Clipboard test: FAILED - {}", e));
-            }
+    #[test]
+    fn file_with_a_flipped_byte_is_discarded_with_a_warning() {
+        let mut ui = test_ui();
+        let intact = Message::new_file("bob".to_string(), "report.txt".to_string(), 6, b"report".to_vec(), None);
+        let mut damaged = intact.clone();
+        if let Message::File { data, .. } = &mut damaged {
+            data[2] ^= 0x01;
         }
-        Ok(())
+
+        ui.add_message(damaged);
+        assert!(ui.received_files.is_empty());
+        let warning = &ui.messages.last().unwrap().text;
+        assert!(warning.ends_with("* Discarded report.txt from bob: its checksum doesn't match, it was damaged in transit"), "{}", warning);
+
+        ui.add_message(intact);
+        assert_eq!(ui.received_files.len(), 1);
+        assert_eq!(ui.received_files[0].data.as_deref(), Some(&b"report"[..]));
     }
 
-    async fn handle_file_command(&mut self, filepath: &str) -> Result<(), Box<dyn Error>> {
-        use crate::file_transfer::FileTransfer;
-        
-        match FileTransfer::read_file_with_username(filepath, &self.username) {
-            Ok(file_msg) => {
-                // Send the file message through the message sender
-                if let Ok(json) = file_msg.to_json() {
-                    let _ = self.message_sender.send(format!("FILE:{}", json));
-                }
-                self.messages.push(format!("Sending file: {}", filepath));
-            }
-            Err(e) => {
-                self.messages.push(format!("Error reading file {}: {}", filepath, e));
-            }
+    #[tokio::test]
+    async fn file_viewer_never_opens_on_a_file_that_is_gone() {
+        let mut ui = test_ui();
+        for name in ["first.txt", "second.txt"] {
+            ui.add_message(Message::new_file("bob".to_string(), name.to_string(), 5, b"hello".to_vec(), None));
         }
-        Ok(())
+        // A second F1 in the list changes nothing
+        ui.handle_chat_key(key(KeyCode::F(1), KeyModifiers::NONE)).await.unwrap();
+        ui.handle_file_list_key(key(KeyCode::F(1), KeyModifiers::NONE)).unwrap();
+        assert!(ui.mode == UIMode::FileList && ui.file_viewer_index.is_none());
+        ui.handle_file_list_key(key(KeyCode::Char('2'), KeyModifiers::NONE)).unwrap();
+        assert!(ui.mode == UIMode::FileViewer);
+        assert_eq!(ui.file_viewer_index, Some(1));
+
+        // The list shrinks under a viewer pointing past its end
+        ui.received_files.truncate(1);
+        ui.switch_mode(UIMode::FileViewer);
+        assert!(ui.mode == UIMode::FileList);
+        assert_eq!(ui.file_viewer_index, None);
+        assert!(ui.file_viewer_content().is_empty());
+        // and a number past the end no longer opens anything
+        ui.handle_file_list_key(key(KeyCode::Char('2'), KeyModifiers::NONE)).unwrap();
+        assert!(ui.mode == UIMode::FileList);
+        ui.handle_file_list_key(key(KeyCode::Char('1'), KeyModifiers::NONE)).unwrap();
+        assert_eq!(ui.file_viewer_index.map(|i| ui.received_files[i].filename.as_str()), Some("first.txt"));
     }
-}
 
-use std::io::Write;
+    #[tokio::test]
+    async fn conn_reports_the_negotiated_protocol_and_features() {
+        let mut config = server::tests::test_config();
+        config.strict = true;
+        let port = server::tests::start(config).await;
+        let mut ui = connected_ui("amy", port).await;
+        let before = ui.messages.len();
+        ui.submit_input("/conn".to_string()).await.unwrap();
+        let report: Vec<&str> = ui.messages[before..].iter().map(|line| line.text.as_str()).collect();
+        assert!(report.contains(&format!("* Protocol: version {} (client speaks {})", PROTOCOL_VERSION, PROTOCOL_VERSION).as_str()));
+        let features = report.iter().find_map(|line| line.strip_prefix("* Server features: ")).expect("a feature line");
+        let features: Vec<&str> = features.split(", ").collect();
+        assert!(features.contains(&"strict") && features.contains(&"who"));
+        assert!(!features.contains(&"echo"));
+    }
+
+    #[tokio::test]
+    async fn connect_switches_to_a_second_server() {
+        let first = server::tests::start(server::tests::test_config()).await;
+        let second = server::tests::start(server::tests::test_config()).await;
+        let mut old = TestClient::join(first, "bob").await;
+        let mut new = TestClient::join(second, "cat").await;
+        let mut ui = connected_ui("amy", first).await;
+
+        ui.submit_input(format!("/connect 127.0.0.1 {}", second)).await.unwrap();
+        new.recv_until(|msg| matches!(msg, Message::UserJoined { username, .. } if username == "amy")).await;
+        old.recv_until(|msg| matches!(msg, Message::UserLeft { username, .. } if username == "amy")).await;
+        new.say("welcome over").await;
+        pump_until(&mut ui, |ui| ui.messages.iter().any(|line| line.text.ends_with("cat: welcome over"))).await;
+        assert_eq!(ui.connection.as_ref().map(|connection| connection.info.port), Some(second));
+    }
+
+    #[test]
+    fn overlong_wide_title_is_cut_to_exactly_the_width() {
+        let title = "Terminal Chat - 日本語のユーザー in #雑談 (Ctrl+Q: quit)";
+        for columns in [10, 11, 20, 21] {
+            let fitted = fit_width(title, columns);
+            assert_eq!(visible_width(&fitted), columns, "{:?}", fitted);
+            assert!(fitted.ends_with('…'));
+        }
+        assert_eq!(fit_width("short", 10), "short");
+        assert_eq!(fit_width("日本", 0), "");
+    }
+
+    #[tokio::test]
+    async fn draft_left_by_a_crash_is_restored_and_cleared_once_sent() {
+        let path = std::env::temp_dir().join(format!("terminal-chat-draft-{}", std::process::id()));
+        history::save_draft(&path, "half a thought").unwrap();
+        isolate_data_dir();
+
+        let mut ui = ChatUI::with_draft_path("amy".to_string(), None, test_config(), Some(path.clone())).unwrap();
+        assert!(matches!(&ui.pending_confirm, Some(PendingConfirm::RestoreDraft { text }) if text == "half a thought"));
+        ui.handle_chat_key(key(KeyCode::Char('y'), KeyModifiers::NONE)).await.unwrap();
+        assert_eq!(ui.input, "half a thought");
+        assert!(path.exists());
+
+        ui.handle_chat_key(key(KeyCode::Enter, KeyModifiers::NONE)).await.unwrap();
+        assert!(!path.exists());
+        let next = ChatUI::with_draft_path("amy".to_string(), None, test_config(), Some(path)).unwrap();
+        assert!(next.pending_confirm.is_none());
+    }
+}